@@ -0,0 +1,50 @@
+import { template } from "./utils.js";
+
+function shortId(id) {
+	return id.slice(0, 7);
+}
+
+function formatRate(rate) {
+	return rate.toFixed(1) + "/s";
+}
+
+function renderClient(elem, client) {
+	elem.querySelector(".stats-client-id").innerText = shortId(client.client);
+	elem.querySelector(".stats-client-addr").innerText = client.addr || "";
+	elem.querySelector(".stats-client-identity").innerText = client.identity || "";
+	elem.querySelector(".stats-client-operations").innerText = formatRate(client.operationsPerSecond);
+	elem.querySelector(".stats-client-queue-depth").innerText = client.queueDepth;
+	elem.querySelector(".stats-client-queries").innerText = client.queries;
+}
+
+export default class StatsPage {
+	constructor(conn, system) {
+		this.template = template("template-stats-client");
+		this.clients = document.getElementById("stats-clients");
+		
+		system.addEventListener("event", ({ event, data }) => {
+			if (event == "stats") {
+				this.render(data);
+			}
+		});
+	}
+	
+	render(stats) {
+		document.getElementById("stats-waiting").style.display = "none";
+		document.getElementById("stats").style.display = "block";
+		
+		document.getElementById("stats-client-count").innerText = stats.clients;
+		document.getElementById("stats-object-count").innerText = stats.objects;
+		document.getElementById("stats-operations").innerText = formatRate(stats.operationsPerSecond);
+		document.getElementById("stats-queue-depth").innerText = stats.queueDepth;
+		document.getElementById("stats-storage-queue-depth").innerText = stats.storage ? stats.storage.queueDepth : "-";
+		
+		this.clients.innerHTML = "";
+		
+		for (let client of stats.topClients) {
+			let elem = this.template.cloneNode(true);
+			renderClient(elem, client);
+			this.clients.append(elem);
+		}
+	}
+}
@@ -25,7 +25,7 @@ fn find_files_recursive(dir: PathBuf, files: &mut Vec<PathBuf>) {
 
 fn get_git_hash() -> String {
 	if let Ok(contents) = fs::read_to_string(".cargo_vcs_info.json") {
-		if let Some(line) = contents.lines().map(|line| line.trim()).filter(|line| line.starts_with("\"sha1\":")).nth(0) {
+		if let Some(line) = contents.lines().map(|line| line.trim()).find(|line| line.starts_with("\"sha1\":")) {
 			if let Some(hash) = line.split("\"").nth(3) {
 				return hash.to_string();
 			}
@@ -67,7 +67,7 @@ fn build_admin_assets(admin_dir: PathBuf) {
 		let filename = file.as_path().strip_prefix(admin_dir.clone()).unwrap().to_str().unwrap();
 		
 		if profile == "release" {
-			let contents = fs::read(&file).unwrap();
+			let contents = fs::read(file).unwrap();
 			let compressed = deflate_bytes(&contents);
 			let file_dest_path = Path::new(&out_dir).join(format!("{}.deflate", index));
 			fs::write(&file_dest_path, compressed).unwrap();
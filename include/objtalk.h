@@ -0,0 +1,44 @@
+/*
+ * C API of the objtalk client, built with
+ * cargo rustc --release --lib --crate-type cdylib --features ffi
+ *
+ * Values, results and events are JSON strings. Functions returning a pointer
+ * return NULL on errors, the others 0 on success and -1 on errors.
+ * objtalk_last_error() describes the last error of the calling thread.
+ */
+
+#ifndef OBJTALK_H
+#define OBJTALK_H
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct objtalk_client objtalk_client;
+
+typedef void (*objtalk_callback)(const char *event, void *user_data);
+
+const char *objtalk_last_error(void);
+
+/* Connects to a tcp transport like "127.0.0.1:3001" and reconnects when the
+ * connection is lost. */
+objtalk_client *objtalk_connect(const char *addr);
+void objtalk_disconnect(objtalk_client *client);
+
+int objtalk_set(const objtalk_client *client, const char *name, const char *value);
+/* The result must be freed with objtalk_string_free. */
+char *objtalk_get(const objtalk_client *client, const char *pattern);
+/* The result must be freed with objtalk_string_free. */
+char *objtalk_invoke(const objtalk_client *client, const char *object, const char *method, const char *args);
+
+/* Calls callback on a thread of its own for the current objects and every
+ * change, like {"type":"change","object":{...}}. */
+int objtalk_subscribe(const objtalk_client *client, const char *pattern, objtalk_callback callback, void *user_data);
+
+void objtalk_string_free(char *s);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -1,26 +1,206 @@
+use chrono::{SecondsFormat, Utc};
 use clap::Clap;
-use objtalk::client::{HttpClient, Error};
+use colored::*;
+use futures::future::join_all;
+use futures::StreamExt;
+use objtalk::Object;
+use objtalk::client::{ClientBuilder, Error, ObjtalkClient, QueryEvent, RpcClient, RpcProvider};
+#[cfg(feature = "repl")]
+use objtalk::client::CachedStore;
+#[cfg(feature = "repl")]
+use rustyline::completion::Completer;
+#[cfg(feature = "repl")]
+use rustyline::error::ReadlineError;
+#[cfg(feature = "repl")]
+use rustyline::{Context, Editor, Helper, Highlighter, Hinter, Validator};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Write;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+#[cfg(feature = "mdns")]
+use objtalk::client::discovery;
 
 /*
 $ objtalk get <pattern>
 $ objtalk set <name> <value>
+$ objtalk watch <pattern>
+$ objtalk listen <pattern> [--event <name>]
+$ objtalk wait <pattern> [--condition <condition>] [--timeout <duration>]
+$ objtalk rm <pattern> [--yes]
+$ objtalk apply <file> [--batch]
+$ objtalk diff <old> <new>
+$ objtalk diff --live <pattern> <snapshot>
+$ objtalk repl
+$ objtalk bench [--workload set|get|query|invoke] [--connections <n>] [--requests <n>]
 */
 
-const VERSION_STRING: &'static str = env!("VERSION_STRING");
+const VERSION_STRING: &str = env!("VERSION_STRING");
 
 #[derive(Clap)]
 #[clap(version = VERSION_STRING)]
 struct Opts {
-	#[clap(short, long, default_value = "http://127.0.0.1:3000")]
-	url: String,
+	#[clap(short, long, about = "http(s)://, tcp://host:port or ws(s)://, defaults to $OBJTALK_URL, the config file or http://127.0.0.1:3000")]
+	url: Option<String>,
+	#[clap(short, long, about = "bearer token for servers that require authentication, defaults to $OBJTALK_TOKEN or the config file")]
+	token: Option<String>,
+	#[clap(short, long, default_value = "json", possible_values = &["table", "json", "jsonl", "value"], about = "how get and invoke print their results")]
+	output: Output,
 	#[clap(subcommand)]
 	command: Command,
 }
 
+/// Defaults from `~/.config/objtalk/cli.toml`, for options that aren't given
+/// on the command line or in the environment.
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct Config {
+	url: Option<String>,
+	token: Option<String>,
+}
+
+fn config_path() -> Option<std::path::PathBuf> {
+	let dir = match std::env::var_os("XDG_CONFIG_HOME") {
+		Some(dir) if !dir.is_empty() => std::path::PathBuf::from(dir),
+		_ => std::path::Path::new(&std::env::var_os("HOME")?).join(".config"),
+	};
+	
+	Some(dir.join("objtalk").join("cli.toml"))
+}
+
+/// A missing config file is fine, an invalid one is an error.
+fn read_config() -> Result<Config, String> {
+	let path = match config_path() {
+		Some(path) => path,
+		None => return Ok(Config::default()),
+	};
+	
+	match std::fs::read_to_string(&path) {
+		Ok(content) => toml::from_str(&content).map_err(|e| format!("invalid config file {}: {}", path.display(), e)),
+		Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Config::default()),
+		Err(e) => Err(format!("can't read config file {}: {}", path.display(), e)),
+	}
+}
+
+/// How results are printed.
+enum Output {
+	/// Aligned columns for humans.
+	Table,
+	/// Pretty-printed json.
+	Json,
+	/// One compact json value per line, for piping into jq.
+	Jsonl,
+	/// Only the values, strings without quotes.
+	Value,
+}
+
+impl FromStr for Output {
+	type Err = String;
+	
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"table" => Ok(Output::Table),
+			"json" => Ok(Output::Json),
+			"jsonl" => Ok(Output::Jsonl),
+			"value" => Ok(Output::Value),
+			_ => Err(format!("unknown output format: {}", s)),
+		}
+	}
+}
+
+/// The value at `field`, a path with dots between the keys.
+fn field_value<'a>(value: &'a Value, field: &str) -> Option<&'a Value> {
+	value.pointer(&format!("/{}", field.replace('.', "/")))
+}
+
+/// Keeps only the selected fields of a value: the field itself if one is
+/// selected, otherwise an object of the fields by path. Missing fields are null.
+fn select_fields(value: Value, fields: &[String]) -> Value {
+	let select = |field: &String| field_value(&value, field).cloned().unwrap_or(Value::Null);
+	
+	match fields {
+		[] => value,
+		[field] => select(field),
+		fields => Value::Object(fields.iter().map(|field| (field.clone(), select(field))).collect()),
+	}
+}
+
+impl Output {
+	fn print_value(&self, value: &Value) {
+		match (self, value) {
+			(Output::Json, value) => println!("{}", serde_json::to_string_pretty(value).unwrap()),
+			(Output::Value, Value::String(string)) => println!("{}", string),
+			(_, value) => println!("{}", value),
+		}
+	}
+	
+	/// Prints the objects with only the selected fields of their values, or
+	/// a column per field in tables.
+	fn print_objects(&self, objects: Vec<Object>, fields: &[String]) {
+		if let Output::Table = self {
+			return print_table(&objects, fields);
+		}
+		
+		let objects: Vec<Object> = objects.into_iter()
+			.map(|object| Object { value: select_fields(object.value, fields), ..object })
+			.collect();
+		
+		match self {
+			Output::Json => println!("{}", serde_json::to_string_pretty(&objects).unwrap()),
+			Output::Jsonl => for object in &objects {
+				println!("{}", serde_json::to_string(object).unwrap());
+			},
+			_ => for object in &objects {
+				self.print_value(&object.value);
+			},
+		}
+	}
+}
+
+fn print_table(objects: &[Object], fields: &[String]) {
+	let mut header = vec!["NAME".to_string(), "LAST MODIFIED".to_string()];
+	if fields.is_empty() {
+		header.push("VALUE".to_string());
+	} else {
+		header.extend(fields.iter().map(|field| field.to_uppercase()));
+	}
+	
+	let mut rows = vec![header];
+	for object in objects {
+		let mut row = vec![object.name.clone(), object.last_modified.to_rfc3339_opts(SecondsFormat::Secs, true)];
+		
+		if fields.is_empty() {
+			row.push(object.value.to_string());
+		} else {
+			for field in fields {
+				row.push(match field_value(&object.value, field) {
+					Some(Value::String(string)) => string.clone(),
+					Some(value) => value.to_string(),
+					None => "-".to_string(),
+				});
+			}
+		}
+		
+		rows.push(row);
+	}
+	
+	let widths: Vec<usize> = (0..rows[0].len())
+		.map(|column| rows.iter().map(|row| row[column].chars().count()).max().unwrap_or(0))
+		.collect();
+	
+	for row in rows {
+		let cells: Vec<String> = row.iter().zip(&widths).map(|(cell, width)| format!("{:width$}", cell, width = width)).collect();
+		println!("{}", cells.join("  ").trim_end());
+	}
+}
+
 #[derive(Clap)]
 enum Command {
 	Get {
-		pattern: String
+		pattern: String,
+		#[clap(long = "field", number_of_values = 1, about = "only print this field of the values, like battery.level, can be repeated")]
+		fields: Vec<String>,
 	},
 	Set {
 		name: String,
@@ -33,56 +213,976 @@ enum Command {
 	Remove {
 		name: String,
 	},
+	#[clap(about = "remove all objects matching a pattern, after asking for confirmation")]
+	Rm {
+		pattern: String,
+		#[clap(short, long, about = "don't ask for confirmation")]
+		yes: bool,
+	},
+	#[clap(about = "run the set, patch, remove and emit commands of a file with one json command per line")]
+	Apply {
+		#[clap(about = "filename or - to read from stdin")]
+		file: String,
+		#[clap(long, about = "send all commands in one request, needs a tcp:// or ws:// url")]
+		batch: bool,
+	},
+	#[clap(about = "compare two snapshots, like backups or the output of get, or a snapshot and the live objects")]
+	Diff {
+		old: String,
+		new: Option<String>,
+		#[clap(long, about = "compare the snapshot to the objects matching this pattern", conflicts_with = "new")]
+		live: Option<String>,
+	},
 	Emit {
 		object: String,
 		event: String,
 		data: String,
 	},
+	#[clap(about = "call a method on an object and print its result")]
 	Invoke {
 		object: String,
 		method: String,
 		args: String,
+		#[clap(long, parse(try_from_str = parse_duration), about = "give up after this long, like 500ms, 30s or 5m")]
+		timeout: Option<Duration>,
+	},
+	#[clap(about = "print the changes of the objects matching a pattern until interrupted")]
+	Watch {
+		pattern: String,
+		#[clap(long, about = "colorize the lines by type")]
+		color: bool,
+		#[clap(long, about = "print json lines like the bodies of webhooks", conflicts_with = "color")]
+		json: bool,
+		#[clap(long = "field", number_of_values = 1, about = "only print this field of the values, like battery.level, can be repeated")]
+		fields: Vec<String>,
+	},
+	#[clap(about = "print the events emitted on the objects matching a pattern until interrupted")]
+	Listen {
+		pattern: String,
+		#[clap(long, about = "only print events with this name")]
+		event: Option<String>,
+	},
+	#[clap(about = "wait until an object matching a pattern exists and meets a condition, print it and exit")]
+	Wait {
+		pattern: String,
+		#[clap(long, about = "like value.online == true or value.battery < 20, a bare field has to be neither null nor false")]
+		condition: Option<Condition>,
+		#[clap(long, parse(try_from_str = parse_duration), about = "give up after this long, like 500ms, 30s or 5m, and exit with 124")]
+		timeout: Option<Duration>,
 	},
+	#[cfg(feature = "repl")]
+	#[clap(about = "run commands at an interactive prompt")]
+	Repl,
+	#[clap(about = "measure throughput and latency of a workload, use a tcp:// or ws:// url for rpc transports")]
+	Bench {
+		#[clap(short, long, default_value = "set", possible_values = &["set", "get", "query", "invoke"])]
+		workload: Workload,
+		#[clap(short, long, default_value = "10", about = "concurrent connections, each making requests one after another")]
+		connections: usize,
+		#[clap(short = 'n', long, default_value = "10000", about = "requests of all connections together")]
+		requests: usize,
+	},
+	#[cfg(feature = "mdns")]
+	#[clap(about = "list servers advertised on the local network")]
+	Discover {
+		#[clap(long, default_value = "3", about = "seconds to wait for answers")]
+		timeout: u64,
+	},
+}
+
+/// Calls the same method on any kind of client.
+macro_rules! with_client {
+	($client:expr, $c:ident => $call:expr) => {
+		match $client {
+			ObjtalkClient::Http($c) => $call,
+			ObjtalkClient::Tcp($c) => $call,
+			ObjtalkClient::Ws($c) => $call,
+		}
+	};
+}
+
+fn print_line(kind: &str, subject: &str, value: Option<&Value>) {
+	let kind = match kind {
+		"add" => kind.green(),
+		"change" => kind.yellow(),
+		"remove" => kind.red(),
+		_ => kind.cyan(),
+	};
+	
+	match value {
+		Some(value) => println!("{} {} {}", kind, subject, value),
+		None => println!("{} {}", kind, subject),
+	}
+}
+
+fn print_object(kind: &str, object: &Object, json: bool, fields: &[String]) {
+	let object = &Object { value: select_fields(object.value.clone(), fields), ..object.clone() };
+	
+	if json {
+		println!("{}", json!({ "type": kind, "object": object }));
+	} else if kind == "remove" {
+		print_line(kind, &object.name, None);
+	} else {
+		print_line(kind, &object.name, Some(&object.value));
+	}
+}
+
+async fn watch(client: &ObjtalkClient, pattern: String, json: bool, fields: &[String]) -> Result<(), Error> {
+	let mut query = with_client!(client, c => c.query(pattern).await)?;
+	
+	// the current objects are printed like they were just added
+	for object in &query.objects {
+		print_object("add", object, json, fields);
+	}
+	
+	while let Some(event) = query.next().await {
+		match event {
+			QueryEvent::Add(object) => print_object("add", &object, json, fields),
+			QueryEvent::Change { object, .. } => print_object("change", &object, json, fields),
+			QueryEvent::Remove(object) => print_object("remove", &object, json, fields),
+			QueryEvent::Event { object, event, data } => if json {
+				println!("{}", json!({ "type": "event", "object": object, "event": event, "data": data }));
+			} else {
+				print_line("event", &format!("{} {}", object, event), Some(&data));
+			},
+			QueryEvent::Reconnected(objects) => for object in &objects {
+				print_object("add", object, json, fields);
+			},
+			QueryEvent::Invocation { .. } => {},
+		}
+	}
+	
+	Err(Error::ConnectionClosed)
+}
+
+/// Asks on the terminal, anything but y or yes is a no.
+fn confirm(question: &str) -> bool {
+	print!("{} [y/N] ", question);
+	let _ = std::io::stdout().flush();
+	
+	let mut answer = String::new();
+	if std::io::stdin().read_line(&mut answer).is_err() {
+		return false;
+	}
+	
+	matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
 }
 
-async fn do_main() -> Result<(), Error> {
+async fn rm(client: &ObjtalkClient, pattern: String, yes: bool) -> Result<(), Error> {
+	let objects = with_client!(client, c => c.get(&pattern).await)?;
+	
+	if objects.is_empty() {
+		eprintln!("no objects match {}", pattern);
+		return Ok(());
+	}
+	
+	for object in &objects {
+		println!("{}", object.name);
+	}
+	
+	if !yes && !confirm(&format!("remove {} objects?", objects.len())) {
+		eprintln!("aborted");
+		return Ok(());
+	}
+	
+	// objects removed by someone else meanwhile are skipped silently
+	let mut removed = 0;
+	for object in objects {
+		if with_client!(client, c => c.remove(object.name).await)? {
+			removed += 1;
+		}
+	}
+	
+	println!("removed {} objects", removed);
+	Ok(())
+}
+
+/// A line of a file for `apply`, in the format of the protocol's requests
+/// without an id.
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase", deny_unknown_fields)]
+enum ApplyCommand {
+	Set { name: String, value: Value },
+	Patch { name: String, value: Value },
+	Remove { name: String },
+	Emit { object: String, event: String, data: Value },
+}
+
+/// Reads the commands of a file with their line numbers, skipping empty
+/// lines. Nothing is applied if any line is invalid.
+fn read_commands(file: &str) -> Result<Vec<(usize, ApplyCommand)>, Failure> {
+	let content = if file == "-" {
+		std::io::read_to_string(std::io::stdin())
+	} else {
+		std::fs::read_to_string(file)
+	}.map_err(|e| Failure::Input(format!("can't read {}: {}", file, e)))?;
+	
+	let mut commands = vec![];
+	let mut invalid = 0;
+	
+	for (i, line) in content.lines().enumerate() {
+		if line.trim().is_empty() {
+			continue;
+		}
+		
+		match serde_json::from_str(line) {
+			Ok(command) => commands.push((i + 1, command)),
+			Err(e) => {
+				eprintln!("line {}: {}", i + 1, e);
+				invalid += 1;
+			},
+		}
+	}
+	
+	if invalid > 0 {
+		return Err(Failure::Input(format!("{} invalid lines, nothing applied", invalid)));
+	}
+	
+	Ok(commands)
+}
+
+/// Runs the commands in order, a failed one doesn't stop the ones after it.
+async fn apply(client: &ObjtalkClient, file: &str, batch: bool) -> Result<(), Failure> {
+	let commands = read_commands(file)?;
+	let total = commands.len();
+	
+	let results = if batch {
+		let client = rpc_client(client)
+			.ok_or_else(|| Failure::Input("--batch needs a tcp:// or ws:// url".to_string()))?;
+		
+		let lines: Vec<usize> = commands.iter().map(|(line, _)| *line).collect();
+		let batch = commands.into_iter().fold(client.batch(), |batch, (_, command)| match command {
+			ApplyCommand::Set { name, value } => batch.set(name, value),
+			ApplyCommand::Patch { name, value } => batch.patch(name, value),
+			ApplyCommand::Remove { name } => batch.remove(name),
+			ApplyCommand::Emit { object, event, data } => batch.emit(object, event, data),
+		});
+		
+		lines.into_iter().zip(batch.send().await?).map(|(line, result)| (line, result.map(|_| ()))).collect()
+	} else {
+		let mut results = vec![];
+		for (line, command) in commands {
+			let result = match command {
+				ApplyCommand::Set { name, value } => with_client!(client, c => c.set(name, value).await),
+				ApplyCommand::Patch { name, value } => with_client!(client, c => c.patch(name, value).await),
+				ApplyCommand::Remove { name } => with_client!(client, c => c.remove(name).await.map(|_| ())),
+				ApplyCommand::Emit { object, event, data } => with_client!(client, c => c.emit(object, event, data).await),
+			};
+			
+			// the ones after would fail the same way
+			if let Err(e @ (Error::ConnectionError(_) | Error::ConnectionClosed | Error::InternalHttpError(_) | Error::Unauthorized)) = result {
+				eprintln!("stopped at line {}", line);
+				return Err(e.into());
+			}
+			
+			results.push((line, result));
+		}
+		results
+	};
+	
+	let mut failed = 0;
+	for (line, result) in results {
+		if let Err(e) = result {
+			eprintln!("line {}: {}", line, e);
+			failed += 1;
+		}
+	}
+	
+	if failed > 0 {
+		return Err(Error::ServerError(format!("{} of {} commands failed", failed, total)).into());
+	}
+	
+	println!("applied {} commands", total);
+	Ok(())
+}
+
+/// Reads the objects of a backup, or of the output of `get` as json or jsonl.
+fn read_snapshot(file: &str) -> Result<Vec<Object>, Failure> {
+	let content = if file == "-" {
+		std::io::read_to_string(std::io::stdin())
+	} else {
+		std::fs::read_to_string(file)
+	}.map_err(|e| Failure::Input(format!("can't read {}: {}", file, e)))?;
+	
+	let invalid = |e: serde_json::Error| Failure::Input(format!("invalid snapshot {}: {}", file, e));
+	
+	let objects = match serde_json::from_str(&content) {
+		Ok(Value::Object(mut backup)) if backup.contains_key("objects") => backup.remove("objects").unwrap(),
+		Ok(Value::Array(objects)) => Value::Array(objects),
+		// jsonl, one object per line
+		_ => return content.lines()
+			.filter(|line| !line.trim().is_empty())
+			.map(|line| serde_json::from_str(line).map_err(invalid))
+			.collect(),
+	};
+	
+	serde_json::from_value(objects).map_err(invalid)
+}
+
+/// Collects the fields that differ, by their path within the value. Objects
+/// are compared key by key, anything else as a whole.
+fn diff_values<'a>(path: String, old: Option<&'a Value>, new: Option<&'a Value>, changes: &mut Vec<(String, Option<&'a Value>, Option<&'a Value>)>) {
+	match (old, new) {
+		(Some(Value::Object(old)), Some(Value::Object(new))) => {
+			let keys = old.keys().chain(new.keys().filter(|key| !old.contains_key(*key)));
+			for key in keys {
+				let path = if path.is_empty() { key.clone() } else { format!("{}.{}", path, key) };
+				diff_values(path, old.get(key), new.get(key), changes);
+			}
+		},
+		(old, new) if old != new => changes.push((path, old, new)),
+		_ => {},
+	}
+}
+
+/// Prints added objects with +, removed ones with - and changed ones with ~
+/// followed by their changed fields, ordered by name.
+async fn diff(client: Option<&ObjtalkClient>, old: String, new: Option<String>, live: Option<String>) -> Result<(), Failure> {
+	let (old, new) = match (new, live, client) {
+		(Some(new), None, _) => (read_snapshot(&old)?, read_snapshot(&new)?),
+		(None, Some(pattern), Some(client)) => (read_snapshot(&old)?, with_client!(client, c => c.get(pattern).await)?),
+		_ => return Err(Failure::Input("diff needs two snapshots, or --live and one snapshot".to_string())),
+	};
+	
+	let old: BTreeMap<String, Value> = old.into_iter().map(|object| (object.name, object.value)).collect();
+	let new: BTreeMap<String, Value> = new.into_iter().map(|object| (object.name, object.value)).collect();
+	
+	let missing = || "(missing)".to_string();
+	let names: BTreeSet<&String> = old.keys().chain(new.keys()).collect();
+	
+	for name in names {
+		match (old.get(name), new.get(name)) {
+			(None, Some(value)) => println!("+ {} {}", name, value),
+			(Some(value), None) => println!("- {} {}", name, value),
+			(Some(old), Some(new)) if old != new => {
+				println!("~ {}", name);
+				
+				let mut changes = vec![];
+				diff_values(String::new(), Some(old), Some(new), &mut changes);
+				
+				for (path, old, new) in changes {
+					let path = if path.is_empty() { "value".to_string() } else { path };
+					let old = old.map(|value| value.to_string()).unwrap_or_else(missing);
+					let new = new.map(|value| value.to_string()).unwrap_or_else(missing);
+					println!("    {}: {} -> {}", path, old, new);
+				}
+			},
+			_ => {},
+		}
+	}
+	
+	Ok(())
+}
+
+async fn listen(client: &ObjtalkClient, pattern: String, name: Option<String>) -> Result<(), Error> {
+	let mut query = with_client!(client, c => c.query(pattern).await)?;
+	
+	while let Some(event) = query.next().await {
+		if let QueryEvent::Event { object, event, data } = event {
+			if name.as_ref().is_none_or(|name| *name == event) {
+				// events don't carry a timestamp, they are printed as they arrive
+				let timestamp = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+				println!("{} {} {} {}", timestamp, object, event, data);
+			}
+		}
+	}
+	
+	Err(Error::ConnectionClosed)
+}
+
+/// Parses durations like 500ms, 30s, 5m or 1h, plain numbers are seconds.
+fn parse_duration(s: &str) -> Result<Duration, String> {
+	let split = s.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(s.len());
+	let (number, unit) = s.split_at(split);
+	let number: f64 = number.parse().map_err(|_| format!("invalid duration: {}", s))?;
+	
+	let seconds = match unit {
+		"ms" => number / 1000.0,
+		"" | "s" => number,
+		"m" => number * 60.0,
+		"h" => number * 3600.0,
+		_ => return Err(format!("invalid duration unit: {}", unit)),
+	};
+	
+	Ok(Duration::from_secs_f64(seconds))
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Comparison {
+	Equal,
+	NotEqual,
+	Less,
+	LessOrEqual,
+	Greater,
+	GreaterOrEqual,
+}
+
+/// A field of an object, like `value.online` or `name`, optionally compared
+/// to a json literal. Literals that aren't valid json are strings.
+struct Condition {
+	field: String,
+	comparison: Option<(Comparison, Value)>,
+}
+
+impl FromStr for Condition {
+	type Err = String;
+	
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		const OPERATORS: &[(&str, Comparison)] = &[
+			("==", Comparison::Equal), ("!=", Comparison::NotEqual),
+			("<=", Comparison::LessOrEqual), (">=", Comparison::GreaterOrEqual),
+			("<", Comparison::Less), (">", Comparison::Greater),
+		];
+		
+		let operator = s.char_indices()
+			.find_map(|(i, _)| OPERATORS.iter().find(|(op, _)| s[i..].starts_with(op)).map(|op| (i, op)));
+		
+		let (field, comparison) = match operator {
+			Some((i, (op, comparison))) => {
+				let literal = s[i + op.len()..].trim();
+				let value = serde_json::from_str(literal).unwrap_or_else(|_| Value::String(literal.to_string()));
+				(&s[..i], Some((*comparison, value)))
+			},
+			None => (s, None),
+		};
+		
+		let field = field.trim();
+		if field.is_empty() || field.contains(char::is_whitespace) {
+			return Err(format!("invalid condition: {}", s));
+		}
+		
+		Ok(Condition { field: field.to_string(), comparison })
+	}
+}
+
+impl Condition {
+	fn matches(&self, object: &Object) -> bool {
+		let object = serde_json::to_value(object).unwrap();
+		let value = field_value(&object, &self.field).unwrap_or(&Value::Null);
+		
+		let (comparison, literal) = match &self.comparison {
+			Some(comparison) => comparison,
+			None => return !matches!(value, Value::Null | Value::Bool(false)),
+		};
+		
+		// numbers are compared by value, so 1 == 1.0
+		let ordering = match (value, literal) {
+			(Value::Number(a), Value::Number(b)) => a.as_f64().partial_cmp(&b.as_f64()),
+			(Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+			(a, b) => if a == b { Some(std::cmp::Ordering::Equal) } else { None },
+		};
+		
+		match comparison {
+			Comparison::Equal => ordering == Some(std::cmp::Ordering::Equal),
+			Comparison::NotEqual => ordering != Some(std::cmp::Ordering::Equal),
+			Comparison::Less => ordering == Some(std::cmp::Ordering::Less),
+			Comparison::LessOrEqual => matches!(ordering, Some(std::cmp::Ordering::Less | std::cmp::Ordering::Equal)),
+			Comparison::Greater => ordering == Some(std::cmp::Ordering::Greater),
+			Comparison::GreaterOrEqual => matches!(ordering, Some(std::cmp::Ordering::Greater | std::cmp::Ordering::Equal)),
+		}
+	}
+}
+
+/// Exits with 124 like `timeout` if nothing matched in time, to tell that
+/// apart from errors.
+async fn wait(client: &ObjtalkClient, output: &Output, pattern: String, condition: Option<Condition>, timeout: Option<Duration>) -> Result<(), Error> {
+	let matches = |object: &Object| condition.as_ref().is_none_or(|condition| condition.matches(object));
+	
+	let wait = async {
+		let mut query = with_client!(client, c => c.query(pattern.clone()).await)?;
+		
+		if let Some(object) = query.objects.iter().find(|object| matches(object)) {
+			return Ok(object.clone());
+		}
+		
+		while let Some(event) = query.next().await {
+			match event {
+				QueryEvent::Add(object) | QueryEvent::Change { object, .. } if matches(&object) => return Ok(object),
+				QueryEvent::Reconnected(objects) => if let Some(object) = objects.into_iter().find(|object| matches(object)) {
+					return Ok(object);
+				},
+				_ => {},
+			}
+		}
+		
+		Err(Error::ConnectionClosed)
+	};
+	
+	let object = match timeout {
+		Some(timeout) => match tokio::time::timeout(timeout, wait).await {
+			Ok(result) => result?,
+			Err(_) => {
+				eprintln!("nothing matching {} within {:?}", pattern, timeout);
+				std::process::exit(EXIT_TIMEOUT);
+			},
+		},
+		None => wait.await?,
+	};
+	
+	output.print_objects(vec![object], &[]);
+	Ok(())
+}
+
+#[cfg(feature = "repl")]
+const REPL_COMMANDS: &[&str] = &["get", "set", "patch", "remove", "emit", "invoke", "watch", "listen", "help", "exit"];
+
+#[cfg(feature = "repl")]
+const REPL_HELP: &str = "get <pattern>
+set <name> <value>
+patch <name> <value>
+remove <name>
+emit <object> <event> <data>
+invoke <object> <method> <args>
+watch <pattern>
+listen <pattern> [<event>]
+exit
+
+watch and listen run until ctrl-c.";
+
+/// Completes command names, and object names as their first argument.
+#[cfg(feature = "repl")]
+#[derive(Helper, Highlighter, Hinter, Validator)]
+struct ReplHelper {
+	objects: CachedStore,
+}
+
+#[cfg(feature = "repl")]
+impl Completer for ReplHelper {
+	type Candidate = String;
+	
+	fn complete(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> rustyline::Result<(usize, Vec<String>)> {
+		let line = &line[..pos];
+		let start = line.rfind(char::is_whitespace).map_or(0, |i| i + 1);
+		let word = &line[start..];
+		
+		let candidates = match line[..start].split_whitespace().count() {
+			0 => REPL_COMMANDS.iter().filter(|command| command.starts_with(word)).map(|command| command.to_string()).collect(),
+			1 => self.objects.objects().into_iter().map(|object| object.name).filter(|name| name.starts_with(word)).collect(),
+			_ => vec![],
+		};
+		
+		Ok((start, candidates))
+	}
+}
+
+/// Splits `line` into `count` arguments after the command, the last one
+/// taking the rest of the line, so json values can contain spaces.
+#[cfg(feature = "repl")]
+fn repl_args(line: &str, count: usize) -> Option<Vec<String>> {
+	let mut args = vec![];
+	let mut rest = line.trim();
+	
+	for _ in 1..count {
+		let end = rest.find(char::is_whitespace)?;
+		args.push(rest[..end].to_string());
+		rest = rest[end..].trim_start();
+	}
+	
+	if rest.is_empty() {
+		return None;
+	}
+	
+	args.push(rest.to_string());
+	Some(args)
+}
+
+/// Parses a line of the repl, `None` for unknown commands and wrong arguments.
+#[cfg(feature = "repl")]
+fn parse_repl_command(line: &str) -> Option<Command> {
+	let line = line.trim();
+	let (verb, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+	
+	let command = match verb {
+		"get" => Command::Get { pattern: rest.trim().to_string(), fields: vec![] },
+		"remove" => Command::Remove { name: rest.trim().to_string() },
+		"watch" => Command::Watch { pattern: rest.trim().to_string(), color: true, json: false, fields: vec![] },
+		"set" | "patch" => {
+			let mut args = repl_args(rest, 2)?;
+			let (value, name) = (args.pop()?, args.pop()?);
+			if verb == "set" { Command::Set { name, value } } else { Command::Patch { name, value } }
+		},
+		"emit" => {
+			let mut args = repl_args(rest, 3)?;
+			let (data, event, object) = (args.pop()?, args.pop()?, args.pop()?);
+			Command::Emit { object, event, data }
+		},
+		"invoke" => {
+			let mut args = repl_args(rest, 3)?;
+			let (args, method, object) = (args.pop()?, args.pop()?, args.pop()?);
+			Command::Invoke { object, method, args, timeout: None }
+		},
+		"listen" => {
+			let mut args = rest.split_whitespace().map(|arg| arg.to_string());
+			Command::Listen { pattern: args.next()?, event: args.next() }
+		},
+		_ => return None,
+	};
+	
+	match &command {
+		Command::Get { pattern, .. } | Command::Watch { pattern, .. } if pattern.is_empty() => None,
+		Command::Remove { name } if name.is_empty() => None,
+		_ => Some(command),
+	}
+}
+
+#[cfg(feature = "repl")]
+async fn repl(client: ObjtalkClient, output: Output) -> Result<(), Error> {
+	let objects = CachedStore::new();
+	objects.subscribe(with_client!(&client, c => c.query("*").await)?);
+	
+	let mut editor = Editor::new().map_err(|e| Error::ConnectionError(e.to_string()))?;
+	editor.set_helper(Some(ReplHelper { objects }));
+	
+	let history = std::env::var_os("HOME").map(|home| std::path::Path::new(&home).join(".objtalk_history"));
+	if let Some(history) = &history {
+		let _ = editor.load_history(history);
+	}
+	
+	loop {
+		let line = match tokio::task::block_in_place(|| editor.readline("objtalk> ")) {
+			Ok(line) => line,
+			// ctrl-c discards the line
+			Err(ReadlineError::Interrupted) => continue,
+			Err(ReadlineError::Eof) => break,
+			Err(e) => {
+				eprintln!("{}", e);
+				break;
+			},
+		};
+		
+		if line.trim().is_empty() {
+			continue;
+		}
+		
+		let _ = editor.add_history_entry(line.as_str());
+		
+		match line.trim() {
+			"exit" | "quit" => break,
+			"help" => println!("{}", REPL_HELP),
+			line => match parse_repl_command(line) {
+				Some(command) => tokio::select! {
+					result = run(&client, &output, command) => if let Err(e) = result {
+						eprintln!("{}", e);
+					},
+					// stops watch and listen
+					_ = tokio::signal::ctrl_c() => {},
+				},
+				None => eprintln!("unknown command or wrong arguments, see help"),
+			},
+		}
+	}
+	
+	if let Some(history) = &history {
+		let _ = editor.save_history(history);
+	}
+	
+	Ok(())
+}
+
+/// What each request of the bench command does.
+#[derive(Clone, Copy)]
+enum Workload {
+	/// Sets the object of the connection.
+	Set,
+	/// Gets the object of the connection.
+	Get,
+	/// Makes a query for the object of the connection and ends it.
+	Query,
+	/// Invokes a method that answers with its arguments.
+	Invoke,
+}
+
+impl FromStr for Workload {
+	type Err = String;
+	
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"set" => Ok(Workload::Set),
+			"get" => Ok(Workload::Get),
+			"query" => Ok(Workload::Query),
+			"invoke" => Ok(Workload::Invoke),
+			_ => Err(format!("unknown workload: {}", s)),
+		}
+	}
+}
+
+const BENCH_RPC_OBJECT: &str = "bench/rpc";
+
+/// Calls the same method on clients of any transport.
+fn rpc_client(client: &ObjtalkClient) -> Option<&RpcClient> {
+	match client {
+		ObjtalkClient::Http(_) => None,
+		ObjtalkClient::Tcp(client) => Some(client),
+		ObjtalkClient::Ws(client) => Some(client),
+	}
+}
+
+async fn bench_request(client: &ObjtalkClient, workload: Workload, name: &str, i: usize) -> Result<(), Error> {
+	match workload {
+		Workload::Set => with_client!(client, c => c.set(name, json!(i)).await),
+		Workload::Get => with_client!(client, c => c.get(name).await.map(|_| ())),
+		Workload::Invoke => with_client!(client, c => c.invoke(BENCH_RPC_OBJECT, "echo", json!(i)).await.map(|_| ())),
+		// queries over http end when they are dropped
+		Workload::Query => match rpc_client(client) {
+			Some(client) => {
+				let query = client.query(name).await?;
+				client.unsubscribe(query.id.unwrap()).await
+			},
+			None => with_client!(client, c => c.query(name).await.map(|_| ())),
+		},
+	}
+}
+
+fn millis(duration: Duration) -> String {
+	format!("{:.2}ms", duration.as_secs_f64() * 1000.0)
+}
+
+async fn bench(builder: ClientBuilder, workload: Workload, connections: usize, requests: usize) -> Result<(), Error> {
+	let connections = connections.max(1);
+	
+	// answers the invocations of all connections
+	let provider = match workload {
+		Workload::Invoke => {
+			let client = builder.connect().await?;
+			let rpc = rpc_client(&client)
+				.ok_or_else(|| Error::ConnectionError("the invoke workload needs a tcp:// or ws:// url to provide its method".to_string()))?;
+			
+			rpc.set(BENCH_RPC_OBJECT, Value::Null).await?;
+			rpc.provide(BENCH_RPC_OBJECT, RpcProvider::new().method("echo", |_, args| async move { args })).await?;
+			Some(client)
+		},
+		_ => None,
+	};
+	
+	let mut clients = vec![];
+	for connection in 0..connections {
+		let client = builder.connect().await?;
+		let name = format!("bench/{}", connection);
+		with_client!(&client, c => c.set(name.as_str(), json!(0)).await)?;
+		clients.push((client, name));
+	}
+	
+	let started = Instant::now();
+	
+	let workers = clients.into_iter().enumerate().map(|(connection, (client, name))| {
+		// the first connections make one more for the remainder
+		let count = requests / connections + usize::from(connection < requests % connections);
+		
+		tokio::spawn(async move {
+			let mut latencies = Vec::with_capacity(count);
+			let mut errors = 0;
+			
+			for i in 0..count {
+				let started = Instant::now();
+				match bench_request(&client, workload, &name, i).await {
+					Ok(()) => latencies.push(started.elapsed()),
+					Err(_) => errors += 1,
+				}
+			}
+			
+			(client, name, latencies, errors)
+		})
+	});
+	let results = join_all(workers).await;
+	
+	let elapsed = started.elapsed();
+	let mut latencies = vec![];
+	let mut errors = 0;
+	
+	for result in results {
+		let (client, name, worker_latencies, worker_errors) = result.expect("bench worker panicked");
+		with_client!(&client, c => c.remove(name).await)?;
+		latencies.extend(worker_latencies);
+		errors += worker_errors;
+	}
+	
+	if let Some(client) = provider {
+		with_client!(&client, c => c.remove(BENCH_RPC_OBJECT).await)?;
+	}
+	
+	latencies.sort();
+	let percentile = |p: f64| latencies[((latencies.len() - 1) as f64 * p).round() as usize];
+	
+	println!("{} requests over {} connections in {:.2}s, {} failed", latencies.len() + errors, connections, elapsed.as_secs_f64(), errors);
+	println!("throughput: {:.0} requests/s", latencies.len() as f64 / elapsed.as_secs_f64());
+	
+	if !latencies.is_empty() {
+		println!("latency: min {}, p50 {}, p90 {}, p99 {}, max {}",
+			millis(latencies[0]), millis(percentile(0.5)), millis(percentile(0.9)), millis(percentile(0.99)), millis(latencies[latencies.len() - 1]));
+	}
+	
+	Ok(())
+}
+
+/// Why a command failed, which decides the exit code.
+enum Failure {
+	Client(Error),
+	/// The result of an invocation was `{"error": ...}`.
+	Provider(String),
+	/// A file or argument the command can't use.
+	Input(String),
+}
+
+impl From<Error> for Failure {
+	fn from(error: Error) -> Self {
+		Failure::Client(error)
+	}
+}
+
+impl From<serde_json::Error> for Failure {
+	fn from(error: serde_json::Error) -> Self {
+		Failure::Client(error.into())
+	}
+}
+
+impl std::fmt::Display for Failure {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		match self {
+			Failure::Client(error) => write!(f, "{}", error),
+			Failure::Provider(error) => write!(f, "provider error: {}", error),
+			Failure::Input(error) => write!(f, "{}", error),
+		}
+	}
+}
+
+// exit codes for scripts, clap exits with 2 on wrong arguments
+const EXIT_ERROR: i32 = 1;
+const EXIT_PROVIDER_ERROR: i32 = 3;
+const EXIT_SERVER_ERROR: i32 = 4;
+const EXIT_UNAUTHORIZED: i32 = 5;
+const EXIT_TIMEOUT: i32 = 124;
+
+impl Failure {
+	fn exit_code(&self) -> i32 {
+		match self {
+			Failure::Provider(_) => EXIT_PROVIDER_ERROR,
+			Failure::Client(Error::ServerError(_)) | Failure::Client(Error::HttpError(_)) => EXIT_SERVER_ERROR,
+			Failure::Client(Error::Unauthorized) => EXIT_UNAUTHORIZED,
+			Failure::Client(Error::Timeout) => EXIT_TIMEOUT,
+			Failure::Client(_) | Failure::Input(_) => EXIT_ERROR,
+		}
+	}
+}
+
+/// The message of an `{"error": ...}` result, strings without quotes.
+fn provider_error(result: &Value) -> Option<String> {
+	match result.as_object()?.get("error")? {
+		Value::String(error) => Some(error.clone()),
+		error => Some(error.to_string()),
+	}
+}
+
+async fn do_main() -> Result<(), Failure> {
 	let opts: Opts = Opts::parse();
 	
-	let client = HttpClient::new(opts.url);
+	let config = read_config().unwrap_or_else(|error| {
+		eprintln!("{}", error);
+		std::process::exit(1);
+	});
+	
+	let url = opts.url
+		.or_else(|| std::env::var("OBJTALK_URL").ok())
+		.or(config.url)
+		.unwrap_or_else(|| "http://127.0.0.1:3000".to_string());
+	
+	let mut builder = ClientBuilder::new().url(url);
+	
+	// the environment keeps the token out of the shell history and process list
+	if let Some(token) = opts.token.or_else(|| std::env::var("OBJTALK_TOKEN").ok()).or(config.token) {
+		builder = builder.token(token);
+	}
+	
+	match opts.command {
+		// bench opens its own connections
+		Command::Bench { workload, connections, requests } => {
+			return Ok(bench(builder, workload, connections, requests).await?);
+		},
+		Command::Diff { old, new, live: None } => return diff(None, old, new, None).await,
+		_ => {},
+	}
+	
+	let client = builder.connect().await?;
 	
 	match opts.command {
-		Command::Get { pattern } => {
-			let objects = client.get(pattern).await?;
-			println!("{}", serde_json::to_string_pretty(&objects).unwrap());
+		#[cfg(feature = "repl")]
+		Command::Repl => Ok(repl(client, opts.output).await?),
+		command => run(&client, &opts.output, command).await,
+	}
+}
+
+async fn run(client: &ObjtalkClient, output: &Output, command: Command) -> Result<(), Failure> {
+	match command {
+		Command::Get { pattern, fields } => {
+			let objects = with_client!(client, c => c.get(pattern).await)?;
+			output.print_objects(objects, &fields);
 			Ok(())
 		},
 		Command::Set { name, value } => {
 			let value = serde_json::from_str(&value)?;
-			client.set(name, value).await?;
+			with_client!(client, c => c.set(name, value).await)?;
 			Ok(())
 		},
 		Command::Patch { name, value } => {
 			let value = serde_json::from_str(&value)?;
-			client.patch(name, value).await?;
+			with_client!(client, c => c.patch(name, value).await)?;
 			Ok(())
 		},
 		Command::Remove { name } => {
-			let existed = client.remove(&name).await?;
+			let existed = with_client!(client, c => c.remove(&name).await)?;
 			if !existed {
 				eprintln!("{} doesn't exist", name);
 			}
 			
 			Ok(())
 		},
+		Command::Rm { pattern, yes } => Ok(rm(client, pattern, yes).await?),
+		Command::Apply { file, batch } => apply(client, &file, batch).await,
+		Command::Diff { old, new, live } => diff(Some(client), old, new, live).await,
 		Command::Emit { object, event, data } => {
 			let data = serde_json::from_str(&data)?;
-			client.emit(object, event, data).await?;
+			with_client!(client, c => c.emit(object, event, data).await)?;
 			Ok(())
 		},
-		Command::Invoke { object, method, args } => {
+		Command::Invoke { object, method, args, timeout } => {
 			let args = serde_json::from_str(&args)?;
-			let result = client.invoke(object, method, args).await?;
-			println!("{}", serde_json::to_string_pretty(&result).unwrap());
+			let invoke = async { with_client!(client, c => c.invoke(object, method, args).await) };
+			let result = match timeout {
+				Some(timeout) => tokio::time::timeout(timeout, invoke).await.map_err(|_| Error::Timeout)?,
+				None => invoke.await,
+			}?;
+			
+			// providers answer failures with an error, like RpcProvider does
+			// for unknown methods
+			if let Some(error) = provider_error(&result) {
+				return Err(Failure::Provider(error));
+			}
+			
+			output.print_value(&result);
+			Ok(())
+		},
+		Command::Watch { pattern, color, json, fields } => {
+			colored::control::set_override(color);
+			Ok(watch(client, pattern, json, &fields).await?)
+		},
+		Command::Listen { pattern, event } => Ok(listen(client, pattern, event).await?),
+		Command::Wait { pattern, condition, timeout } => Ok(wait(client, output, pattern, condition, timeout).await?),
+		// only started from the command line, not in a repl
+		#[cfg(feature = "repl")]
+		Command::Repl => Ok(()),
+		Command::Bench { .. } => Ok(()),
+		#[cfg(feature = "mdns")]
+		Command::Discover { timeout } => {
+			for transport in discovery::discover(Duration::from_secs(timeout)).map_err(Error::from)? {
+				let location = match (transport.url(), transport.addr()) {
+					(Some(url), _) => url,
+					(None, Some(addr)) => addr.to_string(),
+					(None, None) => continue,
+				};
+				
+				let tls = if transport.tls { " (tls)" } else { "" };
+				println!("{}\t{}{}\t{}", transport.transport, location, tls, transport.name);
+			}
+			
 			Ok(())
 		},
 	}
@@ -90,8 +1190,8 @@ async fn do_main() -> Result<(), Error> {
 
 #[tokio::main]
 async fn main() {
-	if let Err(error) = do_main().await {
-		eprintln!("{}", error);
-		std::process::exit(1);
+	if let Err(failure) = do_main().await {
+		eprintln!("{}", failure);
+		std::process::exit(failure.exit_code());
 	}
 }
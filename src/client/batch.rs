@@ -0,0 +1,63 @@
+use crate::client::{Error, RpcClient};
+use serde_json::{json, Value};
+
+/// Operations queued to be sent in one request, e.g. to initialize many
+/// objects without waiting for each of them. The server runs them in order.
+pub struct Batch {
+	client: RpcClient,
+	requests: Vec<Value>,
+}
+
+impl Batch {
+	pub(crate) fn new(client: RpcClient) -> Self {
+		Batch { client, requests: vec![] }
+	}
+	
+	pub fn set<S: Into<String>>(mut self, name: S, value: Value) -> Self {
+		self.requests.push(json!({ "type": "set", "name": name.into(), "value": value }));
+		self
+	}
+	
+	pub fn patch<S: Into<String>>(mut self, name: S, value: Value) -> Self {
+		self.requests.push(json!({ "type": "patch", "name": name.into(), "value": value }));
+		self
+	}
+	
+	pub fn remove<S: Into<String>>(mut self, name: S) -> Self {
+		self.requests.push(json!({ "type": "remove", "name": name.into() }));
+		self
+	}
+	
+	pub fn emit<S: Into<String>, S2: Into<String>>(mut self, object: S, event: S2, data: Value) -> Self {
+		self.requests.push(json!({ "type": "emit", "object": object.into(), "event": event.into(), "data": data }));
+		self
+	}
+	
+	pub fn len(&self) -> usize {
+		self.requests.len()
+	}
+	
+	pub fn is_empty(&self) -> bool {
+		self.requests.is_empty()
+	}
+	
+	/// Sends the operations and returns their results in the same order, like
+	/// `{"success":true}` or `{"existed":false}`. An operation that fails
+	/// doesn't stop the ones after it.
+	pub async fn send(self) -> Result<Vec<Result<Value, Error>>, Error> {
+		if self.requests.is_empty() {
+			return Ok(vec![]);
+		}
+		
+		let reply = self.client.request(Value::Array(self.requests)).await?;
+		let responses = match reply.result {
+			Value::Array(responses) => responses,
+			_ => return Err(Error::ConnectionError("invalid batch response".to_string())),
+		};
+		
+		Ok(responses.into_iter().map(|mut response| match response["error"].as_str() {
+			Some(error) => Err(Error::ServerError(error.to_string())),
+			None => Ok(response["result"].take()),
+		}).collect())
+	}
+}
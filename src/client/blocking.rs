@@ -0,0 +1,216 @@
+use crate::{Command, Object};
+use crate::client::{self, Error, QueryEvent, TypedObject};
+use futures::StreamExt;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+use std::future::Future;
+use std::sync::OnceLock;
+use tokio::net::ToSocketAddrs;
+use tokio::runtime::{Builder, Runtime};
+use uuid::Uuid;
+
+/// Shared by all clients. Its worker keeps the connections of `TcpClient`s
+/// alive between requests.
+static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+
+/// Panics when called from within an async runtime.
+fn block_on<F: Future>(future: F) -> F::Output {
+	RUNTIME.get_or_init(|| {
+		Builder::new_multi_thread()
+			.worker_threads(1)
+			.thread_name("objtalk-client")
+			.enable_all()
+			.build()
+			.expect("failed to start the client runtime")
+	}).block_on(future)
+}
+
+/// A live query, see `client::Query`. Iterating blocks until the next
+/// notification and ends when the connection is closed.
+pub struct Query {
+	pub id: Option<Uuid>,
+	pub objects: Vec<Object>,
+	query: client::Query,
+}
+
+impl Query {
+	fn new(mut query: client::Query) -> Self {
+		Query {
+			id: query.id,
+			objects: std::mem::take(&mut query.objects),
+			query,
+		}
+	}
+}
+
+impl Iterator for Query {
+	type Item = QueryEvent;
+	
+	fn next(&mut self) -> Option<QueryEvent> {
+		block_on(self.query.next())
+	}
+}
+
+/// A blocking `client::HttpClient`.
+pub struct HttpClient {
+	client: client::HttpClient,
+}
+
+impl HttpClient {
+	pub fn new<S: Into<String>>(url: S) -> Self {
+		HttpClient { client: client::HttpClient::new(url) }
+	}
+	
+	/// Sends `token` as bearer token, for servers that require authentication.
+	pub fn with_token<S: Into<String>>(self, token: S) -> Self {
+		HttpClient { client: self.client.with_token(token) }
+	}
+	
+	pub fn get<S: Into<String>>(&self, pattern: S) -> Result<Vec<Object>, Error> {
+		block_on(self.client.get(pattern))
+	}
+	
+	pub fn get_as<T: DeserializeOwned, S: Into<String>>(&self, pattern: S) -> Result<Vec<TypedObject<T>>, Error> {
+		block_on(self.client.get_as(pattern))
+	}
+	
+	/// Watches the objects matching `pattern` over an event stream. The query
+	/// ends when it is dropped.
+	pub fn query<S: Into<String>>(&self, pattern: S) -> Result<Query, Error> {
+		block_on(self.client.query(pattern)).map(Query::new)
+	}
+	
+	pub fn set<S: Into<String>>(&self, name: S, value: Value) -> Result<(), Error> {
+		block_on(self.client.set(name, value))
+	}
+	
+	pub fn set_from<T: Serialize, S: Into<String>>(&self, name: S, value: &T) -> Result<(), Error> {
+		block_on(self.client.set_from(name, value))
+	}
+	
+	pub fn patch<S: Into<String>>(&self, name: S, value: Value) -> Result<(), Error> {
+		block_on(self.client.patch(name, value))
+	}
+	
+	pub fn remove<S: Into<String>>(&self, name: S) -> Result<bool, Error> {
+		block_on(self.client.remove(name))
+	}
+	
+	pub fn emit<S: Into<String>, S2: Into<String>>(&self, object: S, event: S2, data: Value) -> Result<(), Error> {
+		block_on(self.client.emit(object, event, data))
+	}
+	
+	pub fn invoke<S: Into<String>, S2: Into<String>>(&self, object: S, method: S2, args: Value) -> Result<Value, Error> {
+		block_on(self.client.invoke(object, method, args))
+	}
+}
+
+/// A blocking `client::TcpClient`, which reconnects in the background like
+/// the async one.
+#[derive(Clone)]
+pub struct TcpClient {
+	client: client::TcpClient,
+}
+
+impl TcpClient {
+	pub fn connect<A: ToSocketAddrs + Clone + Send + Sync + 'static>(addr: A) -> Result<Self, Error> {
+		block_on(client::TcpClient::connect(addr)).map(|client| TcpClient { client })
+	}
+	
+	pub fn set<S: Into<String>>(&self, name: S, value: Value) -> Result<(), Error> {
+		block_on(self.client.set(name, value))
+	}
+	
+	pub fn set_from<T: Serialize, S: Into<String>>(&self, name: S, value: &T) -> Result<(), Error> {
+		block_on(self.client.set_from(name, value))
+	}
+	
+	pub fn patch<S: Into<String>>(&self, name: S, value: Value) -> Result<(), Error> {
+		block_on(self.client.patch(name, value))
+	}
+	
+	pub fn get<S: Into<String>>(&self, pattern: S) -> Result<Vec<Object>, Error> {
+		block_on(self.client.get(pattern))
+	}
+	
+	pub fn get_as<T: DeserializeOwned, S: Into<String>>(&self, pattern: S) -> Result<Vec<TypedObject<T>>, Error> {
+		block_on(self.client.get_as(pattern))
+	}
+	
+	pub fn query<S: Into<String>>(&self, pattern: S) -> Result<Query, Error> {
+		block_on(self.client.query(pattern)).map(Query::new)
+	}
+	
+	/// See `RpcClient::query_with`.
+	pub fn query_with<S: Into<String>>(&self, pattern: S, provide_rpc: bool, include_previous: bool) -> Result<Query, Error> {
+		block_on(self.client.query_with(pattern, provide_rpc, include_previous)).map(Query::new)
+	}
+	
+	pub fn unsubscribe(&self, query_id: Uuid) -> Result<(), Error> {
+		block_on(self.client.unsubscribe(query_id))
+	}
+	
+	pub fn remove<S: Into<String>>(&self, name: S) -> Result<bool, Error> {
+		block_on(self.client.remove(name))
+	}
+	
+	pub fn emit<S: Into<String>, S2: Into<String>>(&self, object: S, event: S2, data: Value) -> Result<(), Error> {
+		block_on(self.client.emit(object, event, data))
+	}
+	
+	pub fn invoke<S: Into<String>, S2: Into<String>>(&self, object: S, method: S2, args: Value) -> Result<Value, Error> {
+		block_on(self.client.invoke(object, method, args))
+	}
+	
+	pub fn invoke_result(&self, invocation_id: Uuid, result: Value) -> Result<(), Error> {
+		block_on(self.client.invoke_result(invocation_id, result))
+	}
+	
+	pub fn set_disconnect_commands<C: Into<Vec<Command>>>(&self, commands: C) -> Result<(), Error> {
+		block_on(self.client.set_disconnect_commands(commands))
+	}
+	
+	pub fn ping(&self) -> Result<(), Error> {
+		block_on(self.client.ping())
+	}
+}
+
+#[cfg(all(test, feature = "server"))]
+mod tests {
+	use super::*;
+	use crate::server::config::MessageFormat;
+	use crate::server::logger::NullLogger;
+	use crate::server::tcp_transport::serve_connection;
+	use crate::server::Server;
+	use futures::FutureExt;
+	use serde_json::json;
+	use tokio::net::TcpListener;
+	
+	#[test]
+	fn test_tcp_client() {
+		let server = Server::new(None, Box::new(NullLogger));
+		let listener = block_on(TcpListener::bind("127.0.0.1:0")).unwrap();
+		let addr = listener.local_addr().unwrap();
+		
+		RUNTIME.get().unwrap().spawn(async move {
+			loop {
+				let (stream, addr) = listener.accept().await.unwrap();
+				tokio::spawn(serve_connection(stream, addr, server.clone(), None, MessageFormat::Json, None).map(|_| ()));
+			}
+		});
+		
+		let client = TcpClient::connect(addr).unwrap();
+		client.set("sensor/1", json!(1)).unwrap();
+		
+		let mut query = client.query("sensor/+").unwrap();
+		assert_eq!(query.objects[0].value, json!(1));
+		
+		// notifications are received while no request of the client is running
+		let writer = TcpClient::connect(addr).unwrap();
+		std::thread::spawn(move || writer.set("sensor/1", json!(2)).unwrap()).join().unwrap();
+		
+		assert!(matches!(query.next(), Some(QueryEvent::Change { object, .. }) if object.value == json!(2)));
+		assert_eq!(client.get("sensor/+").unwrap()[0].value, json!(2));
+	}
+}
@@ -0,0 +1,176 @@
+use crate::client::{ClientHooks, Error, HttpClient, TcpClient, WsClient, WsOptions};
+use crate::client::rpc::RpcOptions;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// A client made by a `ClientBuilder`, depending on the scheme of its url.
+pub enum ObjtalkClient {
+	Http(HttpClient),
+	Tcp(TcpClient),
+	Ws(WsClient),
+}
+
+impl ObjtalkClient {
+	pub fn builder() -> ClientBuilder {
+		ClientBuilder::new()
+	}
+}
+
+/// Configures the clients once, e.g. in an application that reads its
+/// settings from a file, and makes clients of any transport from it.
+#[derive(Clone)]
+pub struct ClientBuilder {
+	url: String,
+	token: Option<String>,
+	msgpack: bool,
+	rpc: RpcOptions,
+}
+
+impl Default for ClientBuilder {
+	fn default() -> Self {
+		ClientBuilder {
+			url: "http://127.0.0.1:3000".to_string(),
+			token: None,
+			msgpack: false,
+			rpc: RpcOptions::default(),
+		}
+	}
+}
+
+impl ClientBuilder {
+	pub fn new() -> Self {
+		ClientBuilder::default()
+	}
+	
+	/// `http://` or `https://` for the http API, `tcp://host:port` for the tcp
+	/// transport and `ws://` or `wss://` for websockets.
+	pub fn url<S: Into<String>>(mut self, url: S) -> Self {
+		self.url = url.into();
+		self
+	}
+	
+	/// Sent as bearer token over http and websockets. The tcp transport has no
+	/// token authentication, so tcp clients don't send it.
+	pub fn token<S: Into<String>>(mut self, token: S) -> Self {
+		self.token = Some(token.into());
+		self
+	}
+	
+	/// Requests without a response in time fail with `Timeout`.
+	pub fn timeout(mut self, timeout: Duration) -> Self {
+		self.rpc.timeout = Some(timeout);
+		self
+	}
+	
+	/// Requests the `msgpack` subprotocol for websockets.
+	pub fn msgpack(mut self, msgpack: bool) -> Self {
+		self.msgpack = msgpack;
+		self
+	}
+	
+	/// Whether tcp and websocket clients open lost connections again, which
+	/// they do by default.
+	pub fn reconnect(mut self, reconnect: bool) -> Self {
+		self.rpc.reconnect = reconnect;
+		self
+	}
+	
+	/// The delay before the first attempt to reconnect, which doubles with
+	/// every failed attempt up to `max`. Defaults to 0.5 and 30 seconds.
+	pub fn reconnect_delay(mut self, min: Duration, max: Duration) -> Self {
+		self.rpc.reconnect_delay_min = min;
+		self.rpc.reconnect_delay_max = max.max(min);
+		self
+	}
+	
+	/// Reports the traffic of the clients to `hooks`.
+	pub fn hooks(mut self, hooks: Arc<dyn ClientHooks>) -> Self {
+		self.rpc.hooks = Some(hooks);
+		self
+	}
+	
+	/// Makes a client for the transport of the url's scheme.
+	pub async fn connect(&self) -> Result<ObjtalkClient, Error> {
+		match self.url.split_once("://").map(|(scheme, _)| scheme) {
+			Some("http") | Some("https") => Ok(ObjtalkClient::Http(self.http())),
+			Some("tcp") => Ok(ObjtalkClient::Tcp(self.tcp().await?)),
+			Some("ws") | Some("wss") => Ok(ObjtalkClient::Ws(self.ws().await?)),
+			_ => Err(Error::ConnectionError(format!("unsupported url {}", self.url))),
+		}
+	}
+	
+	pub fn http(&self) -> HttpClient {
+		let mut client = HttpClient::new(self.url.trim_end_matches('/'));
+		
+		if let Some(token) = &self.token {
+			client = client.with_token(token);
+		}
+		
+		if let Some(timeout) = self.rpc.timeout {
+			client = client.with_timeout(timeout);
+		}
+		
+		if let Some(hooks) = &self.rpc.hooks {
+			client = client.with_hooks(hooks.clone());
+		}
+		
+		client
+	}
+	
+	/// Connects to the tcp transport, the url may leave out `tcp://`.
+	pub async fn tcp(&self) -> Result<TcpClient, Error> {
+		let addr = self.url.strip_prefix("tcp://").unwrap_or(&self.url).to_string();
+		TcpClient::connect_with(addr, self.rpc.clone()).await
+	}
+	
+	pub async fn ws(&self) -> Result<WsClient, Error> {
+		let options = WsOptions { token: self.token.clone(), msgpack: self.msgpack };
+		WsClient::connect_with_rpc(&self.url, options, self.rpc.clone()).await
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use tokio::net::TcpListener;
+	
+	/// A server that accepts connections but never answers.
+	async fn silent_server() -> String {
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let addr = listener.local_addr().unwrap().to_string();
+		
+		tokio::spawn(async move {
+			let mut connections = vec![];
+			
+			while let Ok((stream, _)) = listener.accept().await {
+				connections.push(stream);
+			}
+		});
+		
+		addr
+	}
+	
+	#[tokio::test]
+	async fn test_timeout() {
+		let addr = silent_server().await;
+		let builder = ObjtalkClient::builder().timeout(Duration::from_millis(50));
+		
+		let client = builder.clone().url(format!("tcp://{}", addr)).connect().await.unwrap();
+		match client {
+			ObjtalkClient::Tcp(client) => assert!(matches!(client.ping().await, Err(Error::Timeout))),
+			_ => panic!("not a tcp client"),
+		}
+		
+		let client = builder.url(format!("http://{}/", addr)).connect().await.unwrap();
+		match client {
+			ObjtalkClient::Http(client) => assert!(matches!(client.get("sensor/+").await, Err(Error::Timeout))),
+			_ => panic!("not an http client"),
+		}
+	}
+	
+	#[tokio::test]
+	async fn test_unsupported_url() {
+		assert!(matches!(ClientBuilder::new().url("mqtt://127.0.0.1").connect().await, Err(Error::ConnectionError(_))));
+		assert!(matches!(ClientBuilder::new().url("127.0.0.1:3001").connect().await, Err(Error::ConnectionError(_))));
+	}
+}
@@ -0,0 +1,139 @@
+use crate::Object;
+use crate::client::{runtime, Query, QueryEvent};
+use futures::StreamExt;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+#[derive(Default)]
+struct Mirror {
+	next_id: usize,
+	/// The objects of each query by name, so overlapping queries don't
+	/// remove each other's objects when they end.
+	queries: HashMap<usize, HashMap<String, Object>>,
+}
+
+/// A local copy of the objects of queries, kept up to date from their
+/// notifications, so lookups don't wait for the server.
+#[derive(Clone, Default)]
+pub struct CachedStore {
+	mirror: Arc<Mutex<Mirror>>,
+}
+
+impl CachedStore {
+	pub fn new() -> Self {
+		CachedStore::default()
+	}
+	
+	/// Mirrors the objects of `query` until it ends, then forgets them. Queries
+	/// of any client can be added, e.g. `client.query("sensor/+").await?`.
+	/// Must be called within a tokio runtime.
+	pub fn subscribe(&self, mut query: Query) {
+		let id = {
+			let mut mirror = self.mirror.lock().unwrap();
+			let id = mirror.next_id;
+			mirror.next_id += 1;
+			
+			let objects = query.objects.drain(..).map(|object| (object.name.clone(), object)).collect();
+			mirror.queries.insert(id, objects);
+			id
+		};
+		
+		let mirror = self.mirror.clone();
+		
+		runtime::spawn(async move {
+			while let Some(event) = query.next().await {
+				let mut mirror = mirror.lock().unwrap();
+				let objects = mirror.queries.get_mut(&id).unwrap();
+				
+				match event {
+					QueryEvent::Add(object) | QueryEvent::Change { object, .. } => {
+						objects.insert(object.name.clone(), object);
+					},
+					QueryEvent::Remove(object) => {
+						objects.remove(&object.name);
+					},
+					QueryEvent::Reconnected(current) => {
+						*objects = current.into_iter().map(|object| (object.name.clone(), object)).collect();
+					},
+					QueryEvent::Event { .. } | QueryEvent::Invocation { .. } => {},
+				}
+			}
+			
+			mirror.lock().unwrap().queries.remove(&id);
+		});
+	}
+	
+	pub fn get(&self, name: &str) -> Option<Object> {
+		self.mirror.lock().unwrap().queries.values()
+			.find_map(|objects| objects.get(name))
+			.cloned()
+	}
+	
+	/// All mirrored objects, sorted by name.
+	pub fn objects(&self) -> Vec<Object> {
+		let mirror = self.mirror.lock().unwrap();
+		let mut objects: HashMap<&str, &Object> = HashMap::new();
+		
+		for object in mirror.queries.values().flat_map(|objects| objects.values()) {
+			objects.insert(&object.name, object);
+		}
+		
+		let mut objects: Vec<Object> = objects.into_values().cloned().collect();
+		objects.sort_by(|a, b| a.name.cmp(&b.name));
+		objects
+	}
+}
+
+#[cfg(all(test, feature = "server"))]
+mod tests {
+	use super::*;
+	use crate::client::TcpClient;
+	use crate::server::config::MessageFormat;
+	use crate::server::logger::NullLogger;
+	use crate::server::tcp_transport::serve_connection;
+	use crate::server::Server;
+	use serde_json::json;
+	use std::time::Duration;
+	use tokio::io::duplex;
+	use tokio::time::sleep;
+	
+	/// Polls until `f` holds, failing after a second.
+	async fn eventually<F: FnMut() -> bool>(mut f: F) {
+		for _ in 0..100 {
+			if f() {
+				return;
+			}
+			
+			sleep(Duration::from_millis(10)).await;
+		}
+		
+		panic!("condition not met");
+	}
+	
+	#[tokio::test]
+	async fn test_cached_store() {
+		let server = Server::new(None, Box::new(NullLogger));
+		let (stream, server_stream) = duplex(4096);
+		let connection = serve_connection(server_stream, "127.0.0.1:1234".parse().unwrap(), server.clone(), None, MessageFormat::Json, None);
+		let connection = tokio::spawn(async move { let _ = connection.await; });
+		let client = TcpClient::from_stream(stream);
+		
+		client.set("sensor/1", json!(1)).await.unwrap();
+		client.set("lamp", json!(true)).await.unwrap();
+		
+		let store = CachedStore::new();
+		store.subscribe(client.query("sensor/+").await.unwrap());
+		store.subscribe(client.query("sensor/1,lamp").await.unwrap());
+		assert_eq!(store.get("sensor/1").unwrap().value, json!(1));
+		assert_eq!(store.objects().iter().map(|object| object.name.as_str()).collect::<Vec<_>>(), vec!["lamp", "sensor/1"]);
+		
+		client.set("sensor/2", json!(2)).await.unwrap();
+		client.set("sensor/1", json!(3)).await.unwrap();
+		client.remove("lamp").await.unwrap();
+		eventually(|| store.get("sensor/2").is_some() && store.get("sensor/1").unwrap().value == json!(3) && store.get("lamp").is_none()).await;
+		
+		// objects of ended queries are forgotten
+		connection.abort();
+		eventually(|| store.objects().is_empty()).await;
+	}
+}
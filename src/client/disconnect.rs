@@ -0,0 +1,41 @@
+use crate::Command;
+use serde_json::Value;
+
+/// Builds the commands the server runs when a client disconnects, e.g. a
+/// "last will" marking a device offline.
+#[derive(Debug, Default)]
+pub struct DisconnectCommands {
+	commands: Vec<Command>,
+}
+
+impl DisconnectCommands {
+	pub fn new() -> Self {
+		DisconnectCommands::default()
+	}
+	
+	pub fn set<S: Into<String>>(mut self, name: S, value: Value) -> Self {
+		self.commands.push(Command::Set { name: name.into(), value });
+		self
+	}
+	
+	pub fn patch<S: Into<String>>(mut self, name: S, value: Value) -> Self {
+		self.commands.push(Command::Patch { name: name.into(), value });
+		self
+	}
+	
+	pub fn remove<S: Into<String>>(mut self, name: S) -> Self {
+		self.commands.push(Command::Remove { name: name.into() });
+		self
+	}
+	
+	pub fn emit<S: Into<String>, S2: Into<String>>(mut self, object: S, event: S2, data: Value) -> Self {
+		self.commands.push(Command::Emit { object: object.into(), event: event.into(), data });
+		self
+	}
+}
+
+impl From<DisconnectCommands> for Vec<Command> {
+	fn from(commands: DisconnectCommands) -> Self {
+		commands.commands
+	}
+}
@@ -0,0 +1,103 @@
+use crate::MDNS_SERVICE_TYPE;
+use mdns_sd::{ResolvedService, ServiceDaemon, ServiceEvent};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+/// A server transport advertised on the local network.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiscoveredTransport {
+	pub name: String,
+	/// `http` or `tcp`.
+	pub transport: String,
+	pub tls: bool,
+	/// IPv4 addresses first.
+	pub addresses: Vec<IpAddr>,
+	pub port: u16,
+}
+
+impl DiscoveredTransport {
+	fn from_service(service: &ResolvedService) -> Self {
+		let mut addresses: Vec<IpAddr> = service.get_addresses().iter()
+			.map(|addr| addr.to_ip_addr())
+			.collect();
+		addresses.sort_by_key(|addr| (addr.is_ipv6(), *addr));
+		
+		let suffix = format!(".{}", MDNS_SERVICE_TYPE);
+		
+		DiscoveredTransport {
+			name: service.get_fullname().trim_end_matches(&suffix).replace('\\', ""),
+			transport: service.get_property_val_str("transport").unwrap_or_default().to_string(),
+			tls: service.get_property_val_str("tls") == Some("true"),
+			addresses,
+			port: service.get_port(),
+		}
+	}
+	
+	pub fn addr(&self) -> Option<SocketAddr> {
+		self.addresses.first().map(|ip| SocketAddr::new(*ip, self.port))
+	}
+	
+	/// The url to pass to `HttpClient::new`, for http transports.
+	pub fn url(&self) -> Option<String> {
+		if self.transport != "http" {
+			return None;
+		}
+		
+		let scheme = if self.tls { "https" } else { "http" };
+		self.addr().map(|addr| format!("{}://{}", scheme, addr))
+	}
+}
+
+/// Browses the local network for `timeout` and returns all transports found,
+/// sorted by name. Blocks the calling thread.
+pub fn discover(timeout: Duration) -> Result<Vec<DiscoveredTransport>, mdns_sd::Error> {
+	let daemon = ServiceDaemon::new()?;
+	let receiver = daemon.browse(MDNS_SERVICE_TYPE)?;
+	
+	let deadline = Instant::now() + timeout;
+	let mut found = HashMap::new();
+	
+	while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+		match receiver.recv_timeout(remaining) {
+			Ok(ServiceEvent::ServiceResolved(service)) => {
+				found.insert(service.get_fullname().to_string(), DiscoveredTransport::from_service(&service));
+			},
+			Ok(_) => {},
+			Err(_) => break,
+		}
+	}
+	
+	let _ = daemon.shutdown();
+	
+	let mut transports: Vec<DiscoveredTransport> = found.into_values().collect();
+	transports.sort_by(|a, b| a.name.cmp(&b.name));
+	
+	Ok(transports)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	
+	#[test]
+	fn test_url() {
+		let mut transport = DiscoveredTransport {
+			name: "greenhouse http 3000".to_string(),
+			transport: "http".to_string(),
+			tls: false,
+			addresses: vec!["192.168.1.10".parse().unwrap(), "fe80::1".parse().unwrap()],
+			port: 3000,
+		};
+		
+		assert_eq!(transport.url(), Some("http://192.168.1.10:3000".to_string()));
+		
+		transport.tls = true;
+		transport.addresses.remove(0);
+		assert_eq!(transport.url(), Some("https://[fe80::1]:3000".to_string()));
+		
+		transport.transport = "tcp".to_string();
+		assert_eq!(transport.url(), None);
+		assert_eq!(transport.addr(), Some("[fe80::1]:3000".parse().unwrap()));
+	}
+}
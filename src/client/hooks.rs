@@ -0,0 +1,26 @@
+use crate::client::{Error, QueryEvent};
+use std::time::Duration;
+
+/// Callbacks about the traffic of a client, e.g. to feed an application's
+/// own metrics or tracing. All of them do nothing by default. They are called
+/// from the client's tasks, so they should return quickly.
+pub trait ClientHooks: Send + Sync {
+	/// `kind` is the type of the request, like `set` or `query`, and `batch`
+	/// for batches.
+	fn request_started(&self, _kind: &str) {}
+	
+	/// `error` is `None` for successful requests.
+	fn request_finished(&self, _kind: &str, _duration: Duration, _error: Option<&Error>) {}
+	
+	/// The connection was lost, the next attempt to open it is in `delay`.
+	fn reconnecting(&self, _error: &str, _delay: Duration) {}
+	
+	fn reconnected(&self) {}
+	
+	/// A notification of a query arrived.
+	fn notification(&self, _event: &QueryEvent) {}
+	
+	/// The number of requests sent and waiting for their response, whenever
+	/// it changes.
+	fn queue_depth(&self, _depth: usize) {}
+}
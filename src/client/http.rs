@@ -0,0 +1,335 @@
+use crate::Object;
+use crate::client::{ClientHooks, Error, Query, QueryEvent, TypedObject, TypedQuery};
+use crate::client::query::EventStreamParser;
+use crate::client::typed::typed_objects;
+use futures::{future, stream, StreamExt};
+use hyper::body::Buf;
+use hyper::Client;
+use hyper::{Request, Response, Method, Body, StatusCode};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::time::timeout;
+
+/// Everything but the characters `encodeURIComponent` leaves alone.
+const QUERY_VALUE: &AsciiSet = &NON_ALPHANUMERIC
+	.remove(b'-').remove(b'_').remove(b'.').remove(b'!')
+	.remove(b'~').remove(b'*').remove(b'\'').remove(b'(').remove(b')');
+/// Object names in paths keep their slashes readable.
+const OBJECT_NAME: &AsciiSet = &QUERY_VALUE.remove(b'/');
+
+fn object_path(prefix: &str, name: String) -> String {
+	prefix.to_owned() + &utf8_percent_encode(&name, OBJECT_NAME).to_string()
+}
+
+fn status_error(status: StatusCode) -> Error {
+	match status {
+		StatusCode::UNAUTHORIZED => Error::Unauthorized,
+		status => Error::HttpError(status),
+	}
+}
+
+fn status_ok(res: &Response<Body>) -> Result<(), Error> {
+	if res.status() != StatusCode::OK {
+		Err(status_error(res.status()))
+	} else {
+		Ok(())
+	}
+}
+
+#[derive(Serialize)]
+struct EmitRequest {
+	event: String,
+	data: Value,
+}
+
+#[derive(Serialize)]
+struct InvokeRequest {
+	method: String,
+	args: Value,
+}
+
+pub struct HttpClient {
+	url: String,
+	token: Option<String>,
+	timeout: Option<Duration>,
+	hooks: Option<Arc<dyn ClientHooks>>,
+}
+
+impl HttpClient {
+	pub fn new<S: Into<String>>(url: S) -> Self {
+		HttpClient {
+			url: url.into(),
+			token: None,
+			timeout: None,
+			hooks: None,
+		}
+	}
+	
+	/// Sends `token` as bearer token, for servers that require authentication.
+	pub fn with_token<S: Into<String>>(mut self, token: S) -> Self {
+		self.token = Some(token.into());
+		self
+	}
+	
+	/// Fails requests without a response in time with `Timeout`. For queries
+	/// it only covers the response, not the events after it.
+	pub fn with_timeout(mut self, timeout: Duration) -> Self {
+		self.timeout = Some(timeout);
+		self
+	}
+	
+	/// Reports the requests to `hooks`.
+	pub fn with_hooks(mut self, hooks: Arc<dyn ClientHooks>) -> Self {
+		self.hooks = Some(hooks);
+		self
+	}
+	
+	fn request(&self, method: Method, path: String) -> hyper::http::request::Builder {
+		let req = Request::builder()
+			.method(method)
+			.uri(self.url.to_owned() + &path);
+		
+		match &self.token {
+			Some(token) => req.header(hyper::header::AUTHORIZATION, format!("Bearer {}", token)),
+			None => req,
+		}
+	}
+	
+	/// `kind` names the request for the hooks, like the requests of the
+	/// JSON-RPC protocol.
+	async fn send(&self, kind: &str, req: Request<Body>) -> Result<Response<Body>, Error> {
+		let started = Instant::now();
+		
+		if let Some(hooks) = &self.hooks {
+			hooks.request_started(kind);
+		}
+		
+		let response = Client::new().request(req);
+		let result = match self.timeout {
+			Some(duration) => timeout(duration, response).await.map_err(|_| Error::Timeout)
+				.and_then(|result| result.map_err(Error::from)),
+			None => response.await.map_err(Error::from),
+		};
+		
+		if let Some(hooks) = &self.hooks {
+			let error = match &result {
+				Ok(response) if response.status().is_success() => None,
+				// removing an object that doesn't exist isn't an error
+				Ok(response) if kind == "remove" && response.status() == StatusCode::NOT_FOUND => None,
+				Ok(response) => Some(status_error(response.status())),
+				Err(_) => None,
+			};
+			hooks.request_finished(kind, started.elapsed(), result.as_ref().err().or(error.as_ref()));
+		}
+		
+		result
+	}
+	
+	pub async fn get<S: Into<String>>(&self, pattern: S) -> Result<Vec<Object>, Error> {
+		let pattern = utf8_percent_encode(&pattern.into(), QUERY_VALUE).to_string();
+		let req = self.request(Method::GET, "/query?pattern=".to_owned() + &pattern)
+			.body(Body::empty()).unwrap();
+		
+		let res = self.send("get", req).await?;
+		status_ok(&res)?;
+		
+		let body = hyper::body::aggregate(res).await?;
+		
+		let objects = serde_json::from_reader(body.reader())?;
+		
+		Ok(objects)
+	}
+	
+	/// Watches the objects matching `pattern` over an event stream. The query
+	/// ends when it is dropped.
+	pub async fn query<S: Into<String>>(&self, pattern: S) -> Result<Query, Error> {
+		let pattern = utf8_percent_encode(&pattern.into(), QUERY_VALUE).to_string();
+		let req = self.request(Method::GET, "/query?pattern=".to_owned() + &pattern)
+			.header(hyper::header::ACCEPT, "text/event-stream")
+			.body(Body::empty()).unwrap();
+		
+		let res = self.send("query", req).await?;
+		status_ok(&res)?;
+		
+		// a failing body ends the stream like a closed connection
+		let mut events = res.into_body()
+			.scan(EventStreamParser::default(), |parser, chunk| future::ready(chunk.ok().map(|chunk| stream::iter(parser.push(&chunk)))))
+			.flatten()
+			.boxed();
+		
+		let objects = match events.next().await {
+			Some((name, data)) if name == "initial" => {
+				let mut initial: Value = serde_json::from_str(&data)?;
+				serde_json::from_value(initial["objects"].take())?
+			},
+			_ => return Err(Error::ConnectionClosed),
+		};
+		
+		let events = events.filter_map(|(name, data)| future::ready(QueryEvent::from_event_stream(&name, &data)));
+		
+		Ok(Query::new(None, objects, events))
+	}
+	
+	/// Like `get`, with the values deserialized into `T`.
+	pub async fn get_as<T: DeserializeOwned, S: Into<String>>(&self, pattern: S) -> Result<Vec<TypedObject<T>>, Error> {
+		typed_objects(self.get(pattern).await?)
+	}
+	
+	/// Like `set`, with the value serialized from `T`.
+	pub async fn set_from<T: Serialize, S: Into<String>>(&self, name: S, value: &T) -> Result<(), Error> {
+		self.set(name, serde_json::to_value(value)?).await
+	}
+	
+	/// Like `query`, with the values deserialized into `T`.
+	pub async fn query_as<T: DeserializeOwned, S: Into<String>>(&self, pattern: S) -> Result<TypedQuery<T>, Error> {
+		TypedQuery::new(self.query(pattern).await?)
+	}
+	
+	pub async fn set<S: Into<String>>(&self, name: S, value: Value) -> Result<(), Error> {
+		let value_json = serde_json::to_string(&value)?;
+		
+		let req = self.request(Method::POST, object_path("/objects/", name.into()))
+			.body(Body::from(value_json)).unwrap();
+		
+		let res = self.send("set", req).await?;
+		status_ok(&res)?;
+		
+		Ok(())
+	}
+	
+	pub async fn patch<S: Into<String>>(&self, name: S, value: Value) -> Result<(), Error> {
+		let value_json = serde_json::to_string(&value)?;
+		
+		let req = self.request(Method::PATCH, object_path("/objects/", name.into()))
+			.body(Body::from(value_json)).unwrap();
+		
+		let res = self.send("patch", req).await?;
+		status_ok(&res)?;
+		
+		Ok(())
+	}
+	
+	pub async fn remove<S: Into<String>>(&self, name: S) -> Result<bool, Error> {
+		let req = self.request(Method::DELETE, object_path("/objects/", name.into()))
+			.body(Body::empty()).unwrap();
+		
+		let res = self.send("remove", req).await?;
+		
+		match res.status() {
+			StatusCode::OK => Ok(true),
+			StatusCode::NOT_FOUND => Ok(false),
+			status => Err(status_error(status))
+		}
+	}
+	
+	pub async fn emit<S: Into<String>, S2: Into<String>>(&self, object: S, event: S2, data: Value) -> Result<(), Error> {
+		let emit_req = EmitRequest { event: event.into(), data };
+		let json = serde_json::to_string(&emit_req)?;
+		
+		let req = self.request(Method::POST, object_path("/events/", object.into()))
+			.body(Body::from(json)).unwrap();
+		
+		let res = self.send("emit", req).await?;
+		status_ok(&res)?;
+		
+		Ok(())
+	}
+	
+	pub async fn invoke<S: Into<String>, S2: Into<String>>(&self, object: S, method: S2, args: Value) -> Result<Value, Error> {
+		let invoke_req = InvokeRequest { method: method.into(), args };
+		let json = serde_json::to_string(&invoke_req)?;
+		
+		let req = self.request(Method::POST, object_path("/invoke/", object.into()))
+			.body(Body::from(json)).unwrap();
+		
+		let res = self.send("invoke", req).await?;
+		status_ok(&res)?;
+		
+		let body = hyper::body::aggregate(res).await?;
+		
+		let result = serde_json::from_reader(body.reader())?;
+		
+		Ok(result)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use serde_json::json;
+	use tokio::io::{AsyncReadExt, AsyncWriteExt};
+	use tokio::net::TcpListener;
+	
+	#[tokio::test]
+	async fn test_unauthorized() {
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let client = HttpClient::new(format!("http://{}", listener.local_addr().unwrap())).with_token("wrong");
+		
+		tokio::spawn(async move {
+			let (mut stream, _) = listener.accept().await.unwrap();
+			let mut head = vec![0; 1024];
+			let len = stream.read(&mut head).await.unwrap();
+			assert!(String::from_utf8_lossy(&head[..len]).to_lowercase().contains("authorization: bearer wrong"));
+			
+			stream.write_all(b"HTTP/1.1 401 Unauthorized\r\ncontent-length: 0\r\n\r\n").await.unwrap();
+		});
+		
+		assert!(matches!(client.get("sensor/+").await, Err(Error::Unauthorized)));
+	}
+	
+	#[tokio::test]
+	async fn test_encoded_object_name() {
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let client = HttpClient::new(format!("http://{}", listener.local_addr().unwrap()));
+		
+		tokio::spawn(async move {
+			let (mut stream, _) = listener.accept().await.unwrap();
+			let mut head = vec![0; 1024];
+			let len = stream.read(&mut head).await.unwrap();
+			assert!(String::from_utf8_lossy(&head[..len]).starts_with("DELETE /objects/lamp%201/k%C3%BCche%23%2B HTTP/1.1"));
+			
+			stream.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n").await.unwrap();
+		});
+		
+		assert!(client.remove("lamp 1/küche#+").await.unwrap());
+	}
+	
+	#[tokio::test]
+	async fn test_query() {
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let client = HttpClient::new(format!("http://{}", listener.local_addr().unwrap()));
+		
+		tokio::spawn(async move {
+			let (mut stream, _) = listener.accept().await.unwrap();
+			let mut head = vec![0; 1024];
+			let len = stream.read(&mut head).await.unwrap();
+			let head = String::from_utf8_lossy(&head[..len]).to_lowercase();
+			assert!(head.starts_with("get /query?pattern=sensor%2f%2b http/1.1"));
+			assert!(head.contains("accept: text/event-stream"));
+			
+			stream.write_all(b"HTTP/1.1 200 OK\r\ncontent-type: text/event-stream\r\ntransfer-encoding: chunked\r\n\r\n").await.unwrap();
+			
+			// events split across chunks, with a keepalive in between
+			for chunk in &[
+				"event:initial\ndata:{\"objects\":[{\"name\":\"sensor/1\",\"value\":1,\"lastModified\":\"2021-01-01T00:00:00Z\"}]}\n\nevent:change\ndata:{\"object\":",
+				"{\"name\":\"sensor/1\",\"value\":2,\"lastModified\":\"2021-01-01T00:00:01Z\"}}\n\n:keepalive\n\n",
+				"event:event\ndata:{\"object\":\"sensor/1\",\"event\":\"calibrated\",\"data\":true}\n\n",
+			] {
+				stream.write_all(format!("{:x}\r\n{}\r\n", chunk.len(), chunk).as_bytes()).await.unwrap();
+			}
+			
+			stream.write_all(b"0\r\n\r\n").await.unwrap();
+		});
+		
+		let mut query = client.query("sensor/+").await.unwrap();
+		assert_eq!(query.id, None);
+		assert_eq!(query.objects[0].value, json!(1));
+		assert!(matches!(query.next().await, Some(QueryEvent::Change { object, .. }) if object.value == json!(2)));
+		assert!(matches!(query.next().await, Some(QueryEvent::Event { event, .. }) if event == "calibrated"));
+		assert!(query.next().await.is_none());
+	}
+}
@@ -0,0 +1,67 @@
+use crate::client::RpcClient;
+use crate::client::rpc::RpcOptions;
+use crate::client::tcp::transport;
+use crate::server::config::MessageFormat;
+use crate::server::tcp_transport::serve_connection;
+use crate::server::Server;
+use futures::FutureExt;
+use std::net::SocketAddr;
+use std::ops::Deref;
+use tokio::io::duplex;
+
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// A client of a server in the same process, connected without sockets, e.g.
+/// to unit-test automation logic against `Server::new(None, ...)`. It speaks
+/// the protocol of the tcp transport, so it behaves like a `TcpClient`.
+#[derive(Clone)]
+pub struct MemoryClient {
+	client: RpcClient,
+}
+
+impl MemoryClient {
+	/// Must be called within a tokio runtime. The connection is closed when
+	/// the client and all its clones are dropped.
+	pub fn connect(server: &Server) -> Self {
+		let (stream, server_stream) = duplex(BUFFER_SIZE);
+		let addr = SocketAddr::from(([127, 0, 0, 1], 0));
+		
+		tokio::spawn(serve_connection(server_stream, addr, server.clone(), None, MessageFormat::Json, None).map(|_| ()));
+		
+		MemoryClient { client: RpcClient::spawn(transport(stream), None, RpcOptions::default()) }
+	}
+}
+
+impl Deref for MemoryClient {
+	type Target = RpcClient;
+	
+	fn deref(&self) -> &RpcClient {
+		&self.client
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::client::QueryEvent;
+	use crate::server::logger::NullLogger;
+	use futures::StreamExt;
+	use serde_json::json;
+	
+	#[tokio::test]
+	async fn test_memory_client() {
+		let server = Server::new(None, Box::new(NullLogger));
+		let sensor = MemoryClient::connect(&server);
+		let automation = MemoryClient::connect(&server);
+		
+		let mut query = automation.query("sensor/+").await.unwrap();
+		sensor.set("sensor/1", json!({ "temperature": 21 })).await.unwrap();
+		
+		assert!(matches!(query.next().await, Some(QueryEvent::Add(object)) if object.value == json!({ "temperature": 21 })));
+		assert_eq!(automation.get("sensor/1").await.unwrap().len(), 1);
+		
+		// the connection closes with the client
+		drop(automation);
+		assert!(query.next().await.is_none());
+	}
+}
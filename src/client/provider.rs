@@ -0,0 +1,57 @@
+use crate::client::{runtime, Query, QueryEvent, RpcClient};
+use futures::future::{BoxFuture, Future, FutureExt};
+use futures::StreamExt;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+type Handler = Box<dyn Fn(String, Value) -> BoxFuture<'static, Value> + Send + Sync>;
+
+/// The methods a client provides for the objects matching a pattern, see
+/// `RpcClient::provide`.
+#[derive(Default)]
+pub struct RpcProvider {
+	methods: HashMap<String, Handler>,
+}
+
+impl RpcProvider {
+	pub fn new() -> Self {
+		RpcProvider::default()
+	}
+	
+	/// Registers `handler` for `name`. It is called with the name of the
+	/// object and the args of every invocation, and its output is sent back
+	/// to the caller.
+	pub fn method<S, F, Fut>(mut self, name: S, handler: F) -> Self
+	where
+		S: Into<String>,
+		F: Fn(String, Value) -> Fut + Send + Sync + 'static,
+		Fut: Future<Output = Value> + Send + 'static,
+	{
+		self.methods.insert(name.into(), Box::new(move |object, args| handler(object, args).boxed()));
+		self
+	}
+	
+	/// Answers the invocations of `query` until it ends, each on its own task
+	/// so slow methods don't hold up others.
+	pub(crate) async fn serve(self, client: RpcClient, mut query: Query) {
+		let methods = Arc::new(self.methods);
+		
+		while let Some(event) = query.next().await {
+			if let QueryEvent::Invocation { invocation_id, object, method, args } = event {
+				let client = client.clone();
+				let methods = methods.clone();
+				
+				runtime::spawn(async move {
+					let result = match methods.get(&method) {
+						Some(handler) => handler(object, args).await,
+						None => json!({ "error": format!("unknown method: {}", method) }),
+					};
+					
+					// the caller is gone if the connection was lost meanwhile
+					let _ = client.invoke_result(invocation_id, result).await;
+				});
+			}
+		}
+	}
+}
@@ -0,0 +1,159 @@
+use crate::Object;
+use crate::json_rpc::EventMessage;
+use futures::stream::Stream;
+use futures::StreamExt;
+use serde_json::Value;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use uuid::Uuid;
+
+/// A notification of a query.
+#[derive(Debug, Clone)]
+pub enum QueryEvent {
+	/// An object started matching the pattern.
+	Add(Object),
+	/// `previous` is only sent for queries made with `include_previous`.
+	Change { object: Object, previous: Option<Value> },
+	Remove(Object),
+	Event { object: String, event: String, data: Value },
+	/// Only sent for queries made with `provide_rpc`, answer it with
+	/// `invoke_result`.
+	Invocation { invocation_id: Uuid, object: String, method: String, args: Value },
+	/// The connection was lost and the query made again. Changes in between
+	/// were missed, these are the objects it matches now.
+	Reconnected(Vec<Object>),
+}
+
+impl QueryEvent {
+	/// Splits a message of the JSON-RPC protocol into its query id and event,
+	/// `None` for messages that don't belong to a query.
+	pub(crate) fn from_message(msg: EventMessage) -> Option<(Uuid, Self)> {
+		match msg {
+			EventMessage::QueryAdd { query_id, object } => Some((query_id, QueryEvent::Add(object))),
+			EventMessage::QueryChange { query_id, object, previous } => Some((query_id, QueryEvent::Change { object, previous })),
+			EventMessage::QueryRemove { query_id, object } => Some((query_id, QueryEvent::Remove(object))),
+			EventMessage::QueryEvent { query_id, object, event, data } => Some((query_id, QueryEvent::Event { object, event, data })),
+			EventMessage::QueryInvocation { invocation_id, query_id, object, method, args } =>
+				Some((query_id, QueryEvent::Invocation { invocation_id, object, method, args })),
+			_ => None,
+		}
+	}
+	
+	/// Parses an event of an http event stream, `None` for unknown events.
+	#[cfg(feature = "client")]
+	pub(crate) fn from_event_stream(name: &str, data: &str) -> Option<Self> {
+		let mut data: Value = serde_json::from_str(data).ok()?;
+		let object = || serde_json::from_value(data["object"].clone()).ok();
+		
+		match name {
+			"add" => Some(QueryEvent::Add(object()?)),
+			"change" => Some(QueryEvent::Change { object: object()?, previous: None }),
+			"remove" => Some(QueryEvent::Remove(object()?)),
+			"event" => Some(QueryEvent::Event {
+				object: data["object"].as_str()?.to_string(),
+				event: data["event"].as_str()?.to_string(),
+				data: data["data"].take(),
+			}),
+			_ => None,
+		}
+	}
+}
+
+/// A live query: the objects matching its pattern when it was made, and a
+/// stream of the changes since. The stream ends when the connection is closed.
+pub struct Query {
+	/// `None` for queries over an event stream, which end when they are dropped.
+	pub id: Option<Uuid>,
+	pub objects: Vec<Object>,
+	events: Pin<Box<dyn Stream<Item = QueryEvent> + Send>>,
+}
+
+impl Query {
+	pub(crate) fn new<S: Stream<Item = QueryEvent> + Send + 'static>(id: Option<Uuid>, objects: Vec<Object>, events: S) -> Self {
+		Query { id, objects, events: Box::pin(events) }
+	}
+}
+
+impl Stream for Query {
+	type Item = QueryEvent;
+	
+	fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<QueryEvent>> {
+		self.events.poll_next_unpin(cx)
+	}
+}
+
+/// Splits the body of an http event stream into the names and data of its
+/// events, in whatever chunks it arrives.
+#[cfg(feature = "client")]
+#[derive(Default)]
+pub(crate) struct EventStreamParser {
+	buffer: Vec<u8>,
+}
+
+#[cfg(feature = "client")]
+impl EventStreamParser {
+	pub(crate) fn push(&mut self, chunk: &[u8]) -> Vec<(String, String)> {
+		self.buffer.extend(chunk.iter().filter(|c| **c != b'\r'));
+		
+		let mut events = vec![];
+		
+		while let Some(end) = self.buffer.windows(2).position(|window| window == b"\n\n") {
+			let block: Vec<u8> = self.buffer.drain(..end + 2).collect();
+			let block = String::from_utf8_lossy(&block);
+			
+			let mut name = "message".to_string();
+			let mut data = vec![];
+			
+			// comments like keepalives start with a colon and have no field name
+			for line in block.lines() {
+				match line.split_once(':') {
+					Some(("event", value)) => name = value.strip_prefix(' ').unwrap_or(value).to_string(),
+					Some(("data", value)) => data.push(value.strip_prefix(' ').unwrap_or(value)),
+					_ => {},
+				}
+			}
+			
+			if !data.is_empty() {
+				events.push((name, data.join("\n")));
+			}
+		}
+		
+		events
+	}
+}
+
+#[cfg(all(test, feature = "client"))]
+mod tests {
+	use super::*;
+	use serde_json::json;
+	
+	#[test]
+	fn test_event_stream_parser() {
+		let mut parser = EventStreamParser::default();
+		
+		assert_eq!(parser.push(b"event:initial\ndata:{\"objects\":[]}\n\n:keepalive\n\nevent: add\r\ndata:"), vec![
+			("initial".to_string(), r#"{"objects":[]}"#.to_string()),
+		]);
+		assert_eq!(parser.push(b"{}\n\nevent:remove\n"), vec![
+			("add".to_string(), "{}".to_string()),
+		]);
+		assert_eq!(parser.push(b"data:1\n\n"), vec![
+			("remove".to_string(), "1".to_string()),
+		]);
+	}
+	
+	#[test]
+	fn test_from_event_stream() {
+		let object = r#"{"object":{"name":"sensor","value":1,"lastModified":"2021-01-01T00:00:00Z"}}"#;
+		
+		assert!(matches!(QueryEvent::from_event_stream("add", object), Some(QueryEvent::Add(object)) if object.name == "sensor"));
+		assert!(matches!(QueryEvent::from_event_stream("change", object), Some(QueryEvent::Change { object, previous: None }) if object.value == json!(1)));
+		assert!(matches!(QueryEvent::from_event_stream("remove", object), Some(QueryEvent::Remove(_))));
+		assert!(matches!(
+			QueryEvent::from_event_stream("event", r#"{"object":"sensor","event":"calibrated","data":true}"#),
+			Some(QueryEvent::Event { object, event, data }) if object == "sensor" && event == "calibrated" && data == json!(true)
+		));
+		assert!(QueryEvent::from_event_stream("add", "{}").is_none());
+		assert!(QueryEvent::from_event_stream("unknown", object).is_none());
+	}
+}
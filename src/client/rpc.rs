@@ -0,0 +1,594 @@
+use crate::{Object, Event, Revision, AuditEntry, AuditQuery, Backup, Stats, Command, LogLevel};
+use crate::client::{Batch, ClientHooks, Error, Query, QueryEvent, RpcProvider, TypedObject, TypedQuery};
+use crate::client::runtime::{self, sleep, timeout, Instant};
+use crate::client::typed::typed_objects;
+use crate::json_rpc::EventMessage;
+use chrono::{DateTime, Utc};
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures::channel::oneshot;
+use futures::future::BoxFuture;
+use futures::sink::Sink;
+use futures::stream::Stream;
+use futures::{SinkExt, StreamExt};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ResponseMessage {
+	request_id: Value,
+	#[serde(default)]
+	result: Value,
+	#[serde(default)]
+	error: Option<String>,
+}
+
+/// The result of a request, with the messages of the query it created.
+pub(crate) struct Reply {
+	pub(crate) result: Value,
+	query: Option<UnboundedReceiver<QueryEvent>>,
+}
+
+struct Outgoing {
+	request: Value,
+	reply: oneshot::Sender<Result<Reply, Error>>,
+}
+
+/// A request waiting for its response.
+enum Pending {
+	/// `query` is the request of queries, to make them again after reconnecting.
+	Caller { query: Option<Value>, reply: oneshot::Sender<Result<Reply, Error>> },
+	/// A query made again after reconnecting, by the id its caller knows.
+	Resubscribe(Uuid),
+	/// A request repeated after reconnecting, whose response doesn't matter.
+	Replay,
+}
+
+/// A query, kept to make it again after reconnecting.
+struct Subscription {
+	request: Value,
+	/// The id the server gave the query on the current connection.
+	server_id: Uuid,
+	events: UnboundedSender<QueryEvent>,
+}
+
+pub(crate) type MessageSink = Pin<Box<dyn Sink<Value, Error = String> + Send>>;
+pub(crate) type MessageStream = Pin<Box<dyn Stream<Item = Result<Value, String>> + Send>>;
+/// Opens a new connection after the current one was lost.
+pub(crate) type Connector = Box<dyn Fn() -> BoxFuture<'static, Result<(MessageSink, MessageStream), Error>> + Send + Sync>;
+
+const RECONNECT_DELAY_MIN: Duration = Duration::from_millis(500);
+const RECONNECT_DELAY_MAX: Duration = Duration::from_secs(30);
+
+/// How a client handles slow requests and lost connections.
+#[derive(Clone)]
+pub(crate) struct RpcOptions {
+	/// Requests without a response in time fail with `Timeout`.
+	pub(crate) timeout: Option<Duration>,
+	/// Whether lost connections are opened again, if the client can.
+	pub(crate) reconnect: bool,
+	pub(crate) reconnect_delay_min: Duration,
+	pub(crate) reconnect_delay_max: Duration,
+	pub(crate) hooks: Option<Arc<dyn ClientHooks>>,
+}
+
+impl Default for RpcOptions {
+	fn default() -> Self {
+		RpcOptions {
+			timeout: None,
+			reconnect: true,
+			reconnect_delay_min: RECONNECT_DELAY_MIN,
+			reconnect_delay_max: RECONNECT_DELAY_MAX,
+			hooks: None,
+		}
+	}
+}
+
+/// Owns the connection: numbers the requests, passes responses to the
+/// requests they belong to and query messages to their queries. With a
+/// connector, lost connections are opened again.
+struct Connection {
+	sink: MessageSink,
+	stream: MessageStream,
+	connector: Option<Connector>,
+	reconnect_delay_min: Duration,
+	reconnect_delay_max: Duration,
+	next_id: u64,
+	pending: HashMap<u64, Pending>,
+	/// By the id of the query on its first connection, which callers know.
+	subscriptions: HashMap<Uuid, Subscription>,
+	/// Ids of the queries on the current connection to the ids callers know.
+	queries: HashMap<Uuid, Uuid>,
+	disconnect_commands: Option<Value>,
+	hooks: Option<Arc<dyn ClientHooks>>,
+}
+
+impl Connection {
+	async fn run(mut self, mut requests: UnboundedReceiver<Outgoing>) {
+		loop {
+			let error = match self.serve(&mut requests).await {
+				Ok(()) => break,
+				Err(e) => e,
+			};
+			
+			// whether requests in flight were handled is unknown, so they fail
+			self.pending.clear();
+			self.report_queue_depth();
+			self.queries.clear();
+			
+			if self.connector.is_none() || !self.reconnect(error, &mut requests).await {
+				break;
+			}
+			
+			if let Err(e) = self.resume().await {
+				println!("objtalk client: {}", e);
+			}
+		}
+	}
+	
+	/// Handles requests and messages until the client is dropped or the
+	/// connection is lost.
+	async fn serve(&mut self, requests: &mut UnboundedReceiver<Outgoing>) -> Result<(), String> {
+		loop {
+			tokio::select! {
+				outgoing = requests.next() => match outgoing {
+					Some(Outgoing { request, reply }) => {
+						let query = if request["type"] == "query" { Some(request.clone()) } else { None };
+						self.send(request, Pending::Caller { query, reply }).await?;
+					},
+					// the client was dropped
+					None => return Ok(()),
+				},
+				message = self.stream.next() => match message {
+					Some(Ok(value)) => self.handle_message(value).await?,
+					Some(Err(e)) => return Err(e),
+					None => return Err("connection closed".to_string()),
+				},
+			}
+		}
+	}
+	
+	async fn send(&mut self, mut request: Value, pending: Pending) -> Result<(), String> {
+		let id = self.next_id;
+		
+		// the responses of a batch arrive together, they are matched by the
+		// id of its first request
+		match request.as_array_mut() {
+			Some(requests) => for request in requests {
+				request["id"] = json!(self.next_id);
+				self.next_id += 1;
+			},
+			None => {
+				request["id"] = json!(id);
+				self.next_id += 1;
+			},
+		}
+		
+		if request["type"] == "setDisconnectCommands" {
+			self.disconnect_commands = Some(request.clone());
+		}
+		
+		// queries keep the id of their first connection
+		if request["type"] == "unsubscribe" {
+			let subscription = Uuid::deserialize(&request["queryId"]).ok()
+				.and_then(|query_id| self.subscriptions.remove(&query_id));
+			
+			if let Some(subscription) = subscription {
+				self.queries.remove(&subscription.server_id);
+				request["queryId"] = json!(subscription.server_id);
+			}
+		}
+		
+		self.sink.send(request).await?;
+		self.pending.insert(id, pending);
+		self.report_queue_depth();
+		Ok(())
+	}
+	
+	fn report_queue_depth(&self) {
+		if let Some(hooks) = &self.hooks {
+			hooks.queue_depth(self.pending.len());
+		}
+	}
+	
+	/// Waits with growing delays until a new connection is open. Requests
+	/// made meanwhile fail right away. Returns false if the client was dropped.
+	async fn reconnect(&mut self, mut error: String, requests: &mut UnboundedReceiver<Outgoing>) -> bool {
+		let mut delay = self.reconnect_delay_min;
+		
+		loop {
+			println!("objtalk client: {}, reconnecting in {}s", error, delay.as_secs_f32());
+			
+			if let Some(hooks) = &self.hooks {
+				hooks.reconnecting(&error, delay);
+			}
+			
+			let timeout = sleep(delay);
+			tokio::pin!(timeout);
+			
+			loop {
+				tokio::select! {
+					_ = &mut timeout => break,
+					outgoing = requests.next() => match outgoing {
+						Some(Outgoing { reply, .. }) => { let _ = reply.send(Err(Error::ConnectionClosed)); },
+						None => return false,
+					},
+				}
+			}
+			
+			match (self.connector.as_ref().unwrap())().await {
+				Ok((sink, stream)) => {
+					self.sink = sink;
+					self.stream = stream;
+					return true;
+				},
+				Err(e) => {
+					error = e.to_string();
+					delay = (delay * 2).min(self.reconnect_delay_max);
+				},
+			}
+		}
+	}
+	
+	/// Makes the queries again, which also registers the rpc providers, and
+	/// sets the disconnect commands again.
+	async fn resume(&mut self) -> Result<(), String> {
+		println!("objtalk client: reconnected");
+		
+		if let Some(hooks) = &self.hooks {
+			hooks.reconnected();
+		}
+		
+		if let Some(request) = self.disconnect_commands.clone() {
+			self.send(request, Pending::Replay).await?;
+		}
+		
+		let subscriptions: Vec<(Uuid, Value)> = self.subscriptions.iter()
+			.map(|(id, subscription)| (*id, subscription.request.clone()))
+			.collect();
+		
+		for (id, request) in subscriptions {
+			self.send(request, Pending::Resubscribe(id)).await?;
+		}
+		
+		Ok(())
+	}
+	
+	async fn handle_message(&mut self, value: Value) -> Result<(), String> {
+		if let Some(responses) = value.as_array() {
+			let request_id = responses.first().map(|response| response["requestId"].clone()).unwrap_or_default();
+			self.reply(request_id, Ok(value));
+			return Ok(());
+		}
+		
+		if value.get("type").is_none() {
+			let response: ResponseMessage = serde_json::from_value(value).map_err(|_| "invalid response".to_string())?;
+			let result = match response.error {
+				Some(error) => Err(Error::ServerError(error)),
+				None => Ok(response.result),
+			};
+			self.reply(response.request_id, result);
+			return Ok(());
+		}
+		
+		match serde_json::from_value(value) {
+			Ok(EventMessage::InvocationResult { request_id, result, error }) => {
+				let result = match error {
+					Some(error) => Err(Error::ServerError(error)),
+					None => Ok(result.unwrap_or(Value::Null)),
+				};
+				self.reply(request_id, result);
+			},
+			Ok(EventMessage::Ping) => {
+				// a notification, so there is no response to wait for
+				self.sink.send(json!({ "type": "ping" })).await?;
+			},
+			Ok(EventMessage::Shutdown) => return Err("server is shutting down".to_string()),
+			Ok(msg) => {
+				let (server_id, event) = match QueryEvent::from_message(msg) {
+					Some(query_event) => query_event,
+					None => return Ok(()),
+				};
+				
+				if let Some(id) = self.queries.get(&server_id).copied() {
+					self.notify(id, event);
+				}
+			},
+			// message types of newer servers
+			Err(_) => {},
+		}
+		
+		Ok(())
+	}
+	
+	/// Passes an event to a query. Queries that were dropped are forgotten.
+	fn notify(&mut self, id: Uuid, event: QueryEvent) {
+		if let Some(hooks) = &self.hooks {
+			hooks.notification(&event);
+		}
+		
+		let dropped = match self.subscriptions.get(&id) {
+			Some(subscription) => subscription.events.unbounded_send(event).is_err(),
+			None => false,
+		};
+		
+		if dropped {
+			if let Some(subscription) = self.subscriptions.remove(&id) {
+				self.queries.remove(&subscription.server_id);
+			}
+		}
+	}
+	
+	/// Answers a pending request. Query responses get a channel for the
+	/// query's messages right away, so none of them are missed.
+	fn reply(&mut self, request_id: Value, result: Result<Value, Error>) {
+		let pending = match request_id.as_u64().and_then(|id| self.pending.remove(&id)) {
+			Some(pending) => pending,
+			None => return,
+		};
+		self.report_queue_depth();
+		
+		match pending {
+			Pending::Caller { query, reply } => {
+				let result = result.map(|result| {
+					let query = match (query, result.get("queryId").map(Uuid::deserialize)) {
+						(Some(request), Some(Ok(server_id))) => {
+							let (tx, rx) = unbounded();
+							self.subscriptions.insert(server_id, Subscription { request, server_id, events: tx });
+							self.queries.insert(server_id, server_id);
+							Some(rx)
+						},
+						_ => None,
+					};
+					
+					Reply { result, query }
+				});
+				
+				let _ = reply.send(result);
+			},
+			Pending::Resubscribe(id) => {
+				let resubscribed = result.map_err(|e| e.to_string()).and_then(|mut result| {
+					let server_id: Uuid = serde_json::from_value(result["queryId"].take()).map_err(|e| e.to_string())?;
+					let objects: Vec<Object> = serde_json::from_value(result["objects"].take()).map_err(|e| e.to_string())?;
+					Ok((server_id, objects))
+				});
+				
+				match resubscribed {
+					Ok((server_id, objects)) => {
+						if let Some(subscription) = self.subscriptions.get_mut(&id) {
+							subscription.server_id = server_id;
+							self.queries.insert(server_id, id);
+							self.notify(id, QueryEvent::Reconnected(objects));
+						}
+					},
+					// ends the query's stream
+					Err(e) => {
+						println!("objtalk client: can't make query again: {}", e);
+						self.subscriptions.remove(&id);
+					},
+				}
+			},
+			Pending::Replay => {},
+		}
+	}
+}
+
+/// The requests of the JSON-RPC protocol, shared by the tcp and websocket
+/// clients. Unlike the http client it keeps one connection open, so it can
+/// make queries and answer invocations. Requests can be made concurrently,
+/// responses are matched by their request id.
+///
+/// Clients that opened the connection themselves open it again when it is
+/// lost, waiting longer after every failed attempt. Requests in flight or
+/// made while disconnected fail with `ConnectionClosed`. Queries are made
+/// again and get a `Reconnected` event with the objects they match then,
+/// since changes in between were missed.
+#[derive(Clone)]
+pub struct RpcClient {
+	requests: UnboundedSender<Outgoing>,
+	timeout: Option<Duration>,
+	hooks: Option<Arc<dyn ClientHooks>>,
+}
+
+impl RpcClient {
+	/// Runs the connection on its own task, so it must be called within a
+	/// tokio runtime, or in a browser.
+	pub(crate) fn spawn((sink, stream): (MessageSink, MessageStream), connector: Option<Connector>, options: RpcOptions) -> Self {
+		let (tx, rx) = unbounded();
+		
+		let connection = Connection {
+			sink,
+			stream,
+			connector: connector.filter(|_| options.reconnect),
+			reconnect_delay_min: options.reconnect_delay_min,
+			reconnect_delay_max: options.reconnect_delay_max,
+			next_id: 1,
+			pending: HashMap::new(),
+			subscriptions: HashMap::new(),
+			queries: HashMap::new(),
+			disconnect_commands: None,
+			hooks: options.hooks.clone(),
+		};
+		runtime::spawn(connection.run(rx));
+		
+		RpcClient { requests: tx, timeout: options.timeout, hooks: options.hooks }
+	}
+	
+	pub(crate) async fn request(&self, request: Value) -> Result<Reply, Error> {
+		let hooks = match &self.hooks {
+			Some(hooks) => hooks,
+			None => return self.send(request).await,
+		};
+		
+		let kind = request["type"].as_str().unwrap_or("batch").to_string();
+		hooks.request_started(&kind);
+		let started = Instant::now();
+		
+		let result = self.send(request).await;
+		hooks.request_finished(&kind, started.elapsed(), result.as_ref().err());
+		result
+	}
+	
+	async fn send(&self, request: Value) -> Result<Reply, Error> {
+		let (tx, rx) = oneshot::channel();
+		
+		self.requests.unbounded_send(Outgoing { request, reply: tx })
+			.map_err(|_| Error::ConnectionClosed)?;
+		
+		match self.timeout {
+			Some(duration) => timeout(duration, rx).await.map_err(|_| Error::Timeout)?,
+			None => rx.await,
+		}.map_err(|_| Error::ConnectionClosed)?
+	}
+	
+	async fn request_field<T: for<'de> Deserialize<'de>>(&self, request: Value, field: &str) -> Result<T, Error> {
+		let mut reply = self.request(request).await?;
+		Ok(serde_json::from_value(reply.result[field].take())?)
+	}
+	
+	pub async fn set<S: Into<String>>(&self, name: S, value: Value) -> Result<(), Error> {
+		self.request(json!({ "type": "set", "name": name.into(), "value": value })).await?;
+		Ok(())
+	}
+	
+	pub async fn patch<S: Into<String>>(&self, name: S, value: Value) -> Result<(), Error> {
+		self.request(json!({ "type": "patch", "name": name.into(), "value": value })).await?;
+		Ok(())
+	}
+	
+	pub async fn get<S: Into<String>>(&self, pattern: S) -> Result<Vec<Object>, Error> {
+		self.request_field(json!({ "type": "get", "pattern": pattern.into() }), "objects").await
+	}
+	
+	pub async fn query<S: Into<String>>(&self, pattern: S) -> Result<Query, Error> {
+		self.query_with(pattern, false, false).await
+	}
+	
+	/// Like `query`, with `provide_rpc` to receive the invocations of the
+	/// matching objects and `include_previous` to get the previous value with
+	/// every change.
+	pub async fn query_with<S: Into<String>>(&self, pattern: S, provide_rpc: bool, include_previous: bool) -> Result<Query, Error> {
+		let mut reply = self.request(json!({
+			"type": "query",
+			"pattern": pattern.into(),
+			"provideRpc": provide_rpc,
+			"includePrevious": include_previous,
+		})).await?;
+		
+		Ok(Query::new(
+			Some(serde_json::from_value(reply.result["queryId"].take())?),
+			serde_json::from_value(reply.result["objects"].take())?,
+			reply.query.unwrap(),
+		))
+	}
+	
+	/// Like `get`, with the values deserialized into `T`.
+	pub async fn get_as<T: DeserializeOwned, S: Into<String>>(&self, pattern: S) -> Result<Vec<TypedObject<T>>, Error> {
+		typed_objects(self.get(pattern).await?)
+	}
+	
+	/// Like `set`, with the value serialized from `T`.
+	pub async fn set_from<T: Serialize, S: Into<String>>(&self, name: S, value: &T) -> Result<(), Error> {
+		self.set(name, serde_json::to_value(value)?).await
+	}
+	
+	/// Like `query`, with the values deserialized into `T`.
+	pub async fn query_as<T: DeserializeOwned, S: Into<String>>(&self, pattern: S) -> Result<TypedQuery<T>, Error> {
+		TypedQuery::new(self.query(pattern).await?)
+	}
+	
+	pub async fn unsubscribe(&self, query_id: Uuid) -> Result<(), Error> {
+		self.request(json!({ "type": "unsubscribe", "queryId": query_id })).await?;
+		Ok(())
+	}
+	
+	pub async fn remove<S: Into<String>>(&self, name: S) -> Result<bool, Error> {
+		self.request_field(json!({ "type": "remove", "name": name.into() }), "existed").await
+	}
+	
+	pub async fn emit<S: Into<String>, S2: Into<String>>(&self, object: S, event: S2, data: Value) -> Result<(), Error> {
+		self.request(json!({ "type": "emit", "object": object.into(), "event": event.into(), "data": data })).await?;
+		Ok(())
+	}
+	
+	pub async fn invoke<S: Into<String>, S2: Into<String>>(&self, object: S, method: S2, args: Value) -> Result<Value, Error> {
+		let reply = self.request(json!({ "type": "invoke", "object": object.into(), "method": method.into(), "args": args })).await?;
+		Ok(reply.result)
+	}
+	
+	/// Queues operations to send them in one request.
+	pub fn batch(&self) -> Batch {
+		Batch::new(self.clone())
+	}
+	
+	/// Answers an invocation received on a query made with `provide_rpc`.
+	pub async fn invoke_result(&self, invocation_id: Uuid, result: Value) -> Result<(), Error> {
+		self.request(json!({ "type": "invokeResult", "invocationId": invocation_id, "result": result })).await?;
+		Ok(())
+	}
+	
+	/// Provides the methods of `provider` for the objects matching `pattern`:
+	/// invocations are dispatched to their handler and answered with its
+	/// output, methods without a handler with an error. Returns the id of the
+	/// query, unsubscribe from it to stop providing.
+	pub async fn provide<S: Into<String>>(&self, pattern: S, provider: RpcProvider) -> Result<Uuid, Error> {
+		let query = self.query_with(pattern, true, false).await?;
+		let id = query.id.unwrap();
+		
+		runtime::spawn(provider.serve(self.clone(), query));
+		Ok(id)
+	}
+	
+	pub async fn get_events<S: Into<String>>(&self, pattern: S, since: Option<DateTime<Utc>>, limit: Option<usize>) -> Result<Vec<Event>, Error> {
+		self.request_field(json!({ "type": "getEvents", "pattern": pattern.into(), "since": since, "limit": limit }), "events").await
+	}
+	
+	pub async fn get_history<S: Into<String>>(&self, name: S, since: Option<DateTime<Utc>>, until: Option<DateTime<Utc>>, limit: Option<usize>) -> Result<Vec<Revision>, Error> {
+		self.request_field(json!({ "type": "getHistory", "name": name.into(), "since": since, "until": until, "limit": limit }), "revisions").await
+	}
+	
+	/// Returns the entries of the server's audit log selected by `query`, newest first.
+	pub async fn get_audit(&self, query: AuditQuery) -> Result<Vec<AuditEntry>, Error> {
+		let mut request = serde_json::to_value(query).unwrap();
+		request["type"] = json!("getAudit");
+		self.request_field(request, "entries").await
+	}
+	
+	pub async fn get_stats(&self) -> Result<Stats, Error> {
+		self.request_field(json!({ "type": "getStats" }), "stats").await
+	}
+	
+	/// Overrides the server's log level, for `duration` if given. `None`
+	/// restores the configured level. Returns the level in effect.
+	pub async fn set_log_level(&self, level: Option<LogLevel>, duration: Option<Duration>) -> Result<LogLevel, Error> {
+		self.request_field(json!({ "type": "setLogLevel", "level": level, "duration": duration.map(|duration| duration.as_secs()) }), "level").await
+	}
+	
+	pub async fn backup(&self) -> Result<Backup, Error> {
+		self.request_field(json!({ "type": "backup" }), "backup").await
+	}
+	
+	pub async fn restore(&self, backup: Backup) -> Result<(), Error> {
+		self.request(json!({ "type": "restore", "backup": backup })).await?;
+		Ok(())
+	}
+	
+	/// Replaces the commands the server runs when this client disconnects,
+	/// given as a list or a `DisconnectCommands` builder. They are set again
+	/// on every new connection after a reconnect.
+	pub async fn set_disconnect_commands<C: Into<Vec<Command>>>(&self, commands: C) -> Result<(), Error> {
+		self.request(json!({ "type": "setDisconnectCommands", "commands": commands.into() })).await?;
+		Ok(())
+	}
+	
+	pub async fn ping(&self) -> Result<(), Error> {
+		self.request(json!({ "type": "ping" })).await?;
+		Ok(())
+	}
+}
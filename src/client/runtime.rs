@@ -0,0 +1,70 @@
+use std::future::Future;
+#[cfg(target_arch = "wasm32")]
+use std::time::Duration;
+
+#[cfg(all(target_arch = "wasm32", not(feature = "wasm")))]
+compile_error!("the client needs the wasm feature in browsers");
+
+/// Runs `future` in the background, on the tokio runtime or the browser's
+/// event loop.
+pub(crate) fn spawn<F: Future<Output = ()> + Send + 'static>(future: F) {
+	#[cfg(not(target_arch = "wasm32"))]
+	tokio::spawn(future);
+	#[cfg(target_arch = "wasm32")]
+	wasm_bindgen_futures::spawn_local(future);
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) use std::time::Instant;
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) use tokio::time::{sleep, timeout};
+
+/// `std::time::Instant` panics in browsers.
+#[cfg(target_arch = "wasm32")]
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Instant(f64);
+
+#[cfg(target_arch = "wasm32")]
+impl Instant {
+	pub(crate) fn now() -> Self {
+		Instant(js_sys::Date::now())
+	}
+	
+	pub(crate) fn elapsed(&self) -> Duration {
+		Duration::from_secs_f64((js_sys::Date::now() - self.0).max(0.0) / 1000.0)
+	}
+}
+
+#[cfg(target_arch = "wasm32")]
+#[wasm_bindgen::prelude::wasm_bindgen]
+extern "C" {
+	#[wasm_bindgen(js_name = setTimeout)]
+	fn set_timeout(handler: &js_sys::Function, millis: i32);
+}
+
+/// Completes after `duration`. The timer isn't cancelled when the future is
+/// dropped, it then fires without effect.
+#[cfg(target_arch = "wasm32")]
+pub(crate) async fn sleep(duration: Duration) {
+	use wasm_bindgen::closure::Closure;
+	use wasm_bindgen::JsCast;
+	
+	let (tx, rx) = futures::channel::oneshot::channel();
+	let handler = Closure::once_into_js(move || { let _ = tx.send(()); });
+	set_timeout(handler.unchecked_ref(), duration.as_millis().min(i32::MAX as u128) as i32);
+	let _ = rx.await;
+}
+
+#[cfg(target_arch = "wasm32")]
+pub(crate) async fn timeout<F: Future>(duration: Duration, future: F) -> Result<F::Output, ()> {
+	use futures::future::{select, Either};
+	
+	futures::pin_mut!(future);
+	let sleep = sleep(duration);
+	futures::pin_mut!(sleep);
+	
+	match select(future, sleep).await {
+		Either::Left((output, _)) => Ok(output),
+		Either::Right(_) => Err(()),
+	}
+}
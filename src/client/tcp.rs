@@ -0,0 +1,395 @@
+use crate::client::{Error, RpcClient};
+use crate::client::rpc::{Connector, MessageSink, MessageStream, RpcOptions};
+use futures::future;
+use futures::{FutureExt, SinkExt, StreamExt};
+use serde_json::Value;
+use std::ops::Deref;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio_util::codec::{Framed, LinesCodec};
+
+pub(crate) fn transport<S: AsyncRead + AsyncWrite + Unpin + Send + 'static>(stream: S) -> (MessageSink, MessageStream) {
+	let (sink, stream) = Framed::new(stream, LinesCodec::new()).split();
+	
+	let sink = sink
+		.sink_map_err(|e| e.to_string())
+		.with(|value: Value| future::ok(value.to_string()));
+	let stream = stream.map(|line| {
+		line.map_err(|e| e.to_string())
+			.and_then(|line| serde_json::from_str(&line).map_err(|_| "invalid message".to_string()))
+	});
+	
+	(Box::pin(sink), Box::pin(stream))
+}
+
+/// A client for the tcp transport, sending messages as json lines.
+#[derive(Clone)]
+pub struct TcpClient {
+	client: RpcClient,
+}
+
+impl TcpClient {
+	/// Connects to `addr`, and again whenever the connection is lost.
+	pub async fn connect<A: ToSocketAddrs + Clone + Send + Sync + 'static>(addr: A) -> Result<Self, Error> {
+		Self::connect_with(addr, RpcOptions::default()).await
+	}
+	
+	pub(crate) async fn connect_with<A: ToSocketAddrs + Clone + Send + Sync + 'static>(addr: A, options: RpcOptions) -> Result<Self, Error> {
+		let connector: Connector = Box::new(move || {
+			let addr = addr.clone();
+			
+			async move {
+				let stream = TcpStream::connect(addr).await.map_err(|e| Error::ConnectionError(e.to_string()))?;
+				Ok(transport(stream))
+			}.boxed()
+		});
+		
+		let connection = connector().await?;
+		Ok(TcpClient { client: RpcClient::spawn(connection, Some(connector), options) })
+	}
+	
+	/// Uses an established connection, e.g. one wrapped in tls, which isn't
+	/// opened again when it is lost. Must be called within a tokio runtime.
+	pub fn from_stream<S: AsyncRead + AsyncWrite + Unpin + Send + 'static>(stream: S) -> Self {
+		TcpClient { client: RpcClient::spawn(transport(stream), None, RpcOptions::default()) }
+	}
+}
+
+impl Deref for TcpClient {
+	type Target = RpcClient;
+	
+	fn deref(&self) -> &RpcClient {
+		&self.client
+	}
+}
+
+#[cfg(all(test, feature = "server"))]
+mod tests {
+	use super::*;
+	use crate::client::QueryEvent;
+	use crate::server::config::MessageFormat;
+	use crate::server::logger::NullLogger;
+	use crate::server::tcp_transport::serve_connection;
+	use crate::patterns::Pattern;
+	use crate::server::Server;
+	use serde_json::json;
+	use tokio::io::duplex;
+	
+	fn connect(server: &Server) -> TcpClient {
+		let (stream, server_stream) = duplex(4096);
+		let server = server.clone();
+		
+		tokio::spawn(async move {
+			let _ = serve_connection(server_stream, "127.0.0.1:1234".parse().unwrap(), server, None, MessageFormat::Json, None).await;
+		});
+		
+		TcpClient::from_stream(stream)
+	}
+	
+	#[tokio::test]
+	async fn test_requests() {
+		let server = Server::new(None, Box::new(NullLogger));
+		let client = connect(&server);
+		
+		client.set("sensor/1", json!({ "temperature": 21 })).await.unwrap();
+		client.patch("sensor/1", json!({ "humidity": 40 })).await.unwrap();
+		
+		let (objects, stats) = tokio::join!(client.get("sensor/+"), client.get_stats());
+		let objects = objects.unwrap();
+		assert_eq!(objects.len(), 1);
+		assert_eq!(objects[0].value, json!({ "temperature": 21, "humidity": 40 }));
+		let stats = stats.unwrap();
+		assert_eq!(stats.storage, None);
+		assert_eq!(stats.operations["set"].count, 1);
+		assert_eq!(stats.operations["patch"].count, 1);
+		
+		assert!(client.remove("sensor/1").await.unwrap());
+		assert!(!client.remove("sensor/1").await.unwrap());
+		client.ping().await.unwrap();
+		
+		server.set_read_only(true);
+		assert!(matches!(client.set("sensor/1", json!(1)).await, Err(Error::ServerError(e)) if e == "read-only follower"));
+	}
+	
+	#[tokio::test]
+	async fn test_batch() {
+		let server = Server::new(None, Box::new(NullLogger));
+		let client = connect(&server);
+		
+		client.set("sensor/2", json!(2)).await.unwrap();
+		let mut query = client.query("sensor/+").await.unwrap();
+		
+		let batch = client.batch()
+			.set("sensor/1", json!({ "temperature": 21 }))
+			.patch("sensor/1", json!({ "humidity": 40 }))
+			.remove("sensor/2")
+			.remove("sensor/3");
+		assert_eq!(batch.len(), 4);
+		
+		// requests sent meanwhile are still matched to their responses
+		let (results, ping) = tokio::join!(batch.send(), client.ping());
+		ping.unwrap();
+		
+		let results: Vec<Value> = results.unwrap().into_iter().map(Result::unwrap).collect();
+		assert_eq!(results, vec![json!({ "success": true }), json!({ "success": true }), json!({ "existed": true }), json!({ "existed": false })]);
+		assert_eq!(client.get("sensor/+").await.unwrap()[0].value, json!({ "temperature": 21, "humidity": 40 }));
+		assert!(matches!(query.next().await, Some(QueryEvent::Add(_))));
+		
+		// failed operations don't stop the ones after them
+		let results = client.batch().patch("sensor/1", json!(1)).remove("sensor/1").send().await.unwrap();
+		assert!(matches!(&results[0], Err(Error::ServerError(_))));
+		assert_eq!(results[1].as_ref().unwrap(), &json!({ "existed": true }));
+		assert!(client.batch().send().await.unwrap().is_empty());
+	}
+	
+	#[tokio::test]
+	async fn test_query() {
+		let server = Server::new(None, Box::new(NullLogger));
+		let client = connect(&server);
+		
+		client.set("sensor/1", json!(1)).await.unwrap();
+		
+		let mut query = client.query("sensor/+").await.unwrap();
+		assert_eq!(query.objects.len(), 1);
+		
+		client.set("sensor/1", json!(2)).await.unwrap();
+		client.emit("sensor/1", "calibrated", json!(true)).await.unwrap();
+		client.remove("sensor/1").await.unwrap();
+		
+		assert!(matches!(query.next().await, Some(QueryEvent::Change { object, .. }) if object.value == json!(2)));
+		assert!(matches!(query.next().await, Some(QueryEvent::Event { event, .. }) if event == "calibrated"));
+		assert!(matches!(query.next().await, Some(QueryEvent::Remove(object)) if object.name == "sensor/1"));
+	}
+	
+	#[tokio::test]
+	async fn test_typed() {
+		use crate::client::TypedQueryEvent;
+		use serde::{Deserialize, Serialize};
+		
+		#[derive(Serialize, Deserialize, Debug, PartialEq)]
+		struct Sensor {
+			temperature: f64,
+		}
+		
+		let server = Server::new(None, Box::new(NullLogger));
+		let client = connect(&server);
+		
+		client.set_from("sensor/1", &Sensor { temperature: 21.5 }).await.unwrap();
+		
+		let objects = client.get_as::<Sensor, _>("sensor/+").await.unwrap();
+		assert_eq!(objects.len(), 1);
+		assert_eq!(objects[0].value, Sensor { temperature: 21.5 });
+		
+		let mut query = client.query_as::<Sensor, _>("sensor/+").await.unwrap();
+		assert_eq!(query.objects[0].name, "sensor/1");
+		
+		client.set_from("sensor/1", &Sensor { temperature: 22.0 }).await.unwrap();
+		client.set("sensor/1", json!("broken")).await.unwrap();
+		client.remove("sensor/1").await.unwrap();
+		
+		assert!(matches!(query.next().await, Some(Ok(TypedQueryEvent::Change(object))) if object.value.temperature == 22.0));
+		assert!(matches!(query.next().await, Some(Err(Error::InternalJsonError(_)))));
+		assert!(matches!(query.next().await, Some(Ok(TypedQueryEvent::Remove(name))) if name == "sensor/1"));
+		
+		client.set("sensor/2", json!("broken")).await.unwrap();
+		assert!(client.get_as::<Sensor, _>("sensor/+").await.is_err());
+	}
+	
+	#[tokio::test]
+	async fn test_invoke() {
+		let server = Server::new(None, Box::new(NullLogger));
+		let provider = connect(&server);
+		let caller = connect(&server);
+		
+		provider.set("lamp", json!({ "on": false })).await.unwrap();
+		let mut query = provider.query_with("lamp", true, false).await.unwrap();
+		
+		tokio::spawn(async move {
+			while let Some(event) = query.next().await {
+				if let QueryEvent::Invocation { invocation_id, args, .. } = event {
+					provider.invoke_result(invocation_id, json!({ "on": args })).await.unwrap();
+				}
+			}
+		});
+		
+		assert_eq!(caller.invoke("lamp", "switch", json!(true)).await.unwrap(), json!({ "on": true }));
+		assert!(matches!(caller.invoke("sensor/1", "switch", json!(true)).await, Err(Error::ServerError(_))));
+	}
+	
+	#[tokio::test]
+	async fn test_disconnect_commands() {
+		use crate::client::DisconnectCommands;
+		
+		let server = Server::new(None, Box::new(NullLogger));
+		let admin = server.client_connect();
+		let (stream, server_stream) = duplex(4096);
+		let connection = tokio::spawn(serve_connection(server_stream, "127.0.0.1:1234".parse().unwrap(), server.clone(), None, MessageFormat::Json, None).map(|_| ()));
+		let client = TcpClient::from_stream(stream);
+		
+		client.set("device/lamp", json!({ "online": true, "on": true })).await.unwrap();
+		client.set("device/lamp/session", json!(1)).await.unwrap();
+		client.set_disconnect_commands(DisconnectCommands::new()
+			.patch("device/lamp", json!({ "online": false }))
+			.remove("device/lamp/session")).await.unwrap();
+		
+		drop(client);
+		let _ = connection.await;
+		
+		let objects = server.get(&Pattern::compile("device/lamp,device/lamp/session").unwrap(), &admin);
+		assert_eq!(objects.len(), 1);
+		assert_eq!(objects[0].value, json!({ "online": false, "on": true }));
+	}
+	
+	#[tokio::test]
+	async fn test_provide() {
+		use crate::client::RpcProvider;
+		
+		let server = Server::new(None, Box::new(NullLogger));
+		let provider = connect(&server);
+		let caller = connect(&server);
+		
+		provider.set("lamp/1", json!({ "on": false })).await.unwrap();
+		
+		let methods = RpcProvider::new()
+			.method("switch", |object, args| async move { json!({ "object": object, "on": args }) });
+		let query_id = provider.provide("lamp/+", methods).await.unwrap();
+		
+		assert_eq!(caller.invoke("lamp/1", "switch", json!(true)).await.unwrap(), json!({ "object": "lamp/1", "on": true }));
+		assert_eq!(caller.invoke("lamp/1", "dim", json!(50)).await.unwrap(), json!({ "error": "unknown method: dim" }));
+		
+		provider.unsubscribe(query_id).await.unwrap();
+		assert!(matches!(caller.invoke("lamp/1", "switch", json!(true)).await, Err(Error::ServerError(_))));
+	}
+	
+	#[tokio::test]
+	async fn test_reconnect() {
+		use crate::Command;
+		use futures::channel::mpsc::unbounded;
+		use tokio::net::TcpListener;
+		
+		let server = Server::new(None, Box::new(NullLogger));
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let addr = listener.local_addr().unwrap();
+		
+		// hands out the connections, so the test can close them
+		let (connections_tx, mut connections) = unbounded();
+		let accept_server = server.clone();
+		tokio::spawn(async move {
+			loop {
+				let (stream, addr) = listener.accept().await.unwrap();
+				let connection = tokio::spawn(serve_connection(stream, addr, accept_server.clone(), None, MessageFormat::Json, None).map(|_| ()));
+				connections_tx.unbounded_send(connection).unwrap();
+			}
+		});
+		
+		let admin = server.client_connect();
+		let client = TcpClient::connect(addr).await.unwrap();
+		let mut query = client.query("sensor/+").await.unwrap();
+		client.set("client/online", json!(true)).await.unwrap();
+		client.set_disconnect_commands(vec![Command::Remove { name: "client/online".to_string() }]).await.unwrap();
+		
+		connections.next().await.unwrap().abort();
+		server.set("sensor/1", json!(1), &admin).unwrap();
+		
+		assert!(matches!(query.next().await, Some(QueryEvent::Reconnected(objects)) if objects.len() == 1 && objects[0].value == json!(1)));
+		assert!(server.get(&Pattern::compile("client/online").unwrap(), &admin).is_empty());
+		
+		server.set("sensor/1", json!(2), &admin).unwrap();
+		assert!(matches!(query.next().await, Some(QueryEvent::Change { object, .. }) if object.value == json!(2)));
+		
+		// the disconnect commands were set on the new connection, too
+		server.set("client/online", json!(true), &admin).unwrap();
+		connections.next().await.unwrap().abort();
+		assert!(matches!(query.next().await, Some(QueryEvent::Reconnected(_))));
+		assert!(server.get(&Pattern::compile("client/online").unwrap(), &admin).is_empty());
+		
+		client.unsubscribe(query.id.unwrap()).await.unwrap();
+		server.set("sensor/1", json!(3), &admin).unwrap();
+		client.ping().await.unwrap();
+		assert!(query.next().await.is_none());
+	}
+	
+	#[tokio::test]
+	async fn test_hooks() {
+		use crate::client::ClientHooks;
+		use std::sync::{Arc, Mutex};
+		use std::time::Duration;
+		use tokio::net::TcpListener;
+		
+		#[derive(Default)]
+		struct Recorder {
+			records: Mutex<Vec<String>>,
+		}
+		
+		impl Recorder {
+			fn record(&self, record: String) {
+				self.records.lock().unwrap().push(record);
+			}
+			
+			fn take(&self) -> Vec<String> {
+				std::mem::take(&mut self.records.lock().unwrap())
+			}
+		}
+		
+		impl ClientHooks for Recorder {
+			fn request_started(&self, kind: &str) {
+				self.record(format!("started {}", kind));
+			}
+			
+			fn request_finished(&self, kind: &str, _duration: Duration, error: Option<&Error>) {
+				self.record(format!("finished {} {}", kind, if error.is_some() { "error" } else { "ok" }));
+			}
+			
+			fn reconnecting(&self, _error: &str, _delay: Duration) {
+				self.record("reconnecting".to_string());
+			}
+			
+			fn reconnected(&self) {
+				self.record("reconnected".to_string());
+			}
+			
+			fn notification(&self, _event: &QueryEvent) {
+				self.record("notification".to_string());
+			}
+			
+			fn queue_depth(&self, depth: usize) {
+				self.record(format!("queue {}", depth));
+			}
+		}
+		
+		let server = Server::new(None, Box::new(NullLogger));
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let addr = listener.local_addr().unwrap();
+		
+		let accept_server = server.clone();
+		let (connection_tx, connection) = futures::channel::oneshot::channel();
+		tokio::spawn(async move {
+			let (stream, addr) = listener.accept().await.unwrap();
+			let _ = connection_tx.send(tokio::spawn(serve_connection(stream, addr, accept_server.clone(), None, MessageFormat::Json, None).map(|_| ())));
+			
+			let (stream, addr) = listener.accept().await.unwrap();
+			let _ = serve_connection(stream, addr, accept_server, None, MessageFormat::Json, None).await;
+		});
+		
+		let recorder = Arc::new(Recorder::default());
+		let options = RpcOptions { hooks: Some(recorder.clone()), reconnect_delay_min: Duration::from_millis(10), ..RpcOptions::default() };
+		let client = TcpClient::connect_with(addr, options).await.unwrap();
+		
+		client.set("sensor/1", json!(1)).await.unwrap();
+		assert_eq!(recorder.take(), vec!["started set", "queue 1", "queue 0", "finished set ok"]);
+		
+		let mut query = client.query("sensor/+").await.unwrap();
+		client.set("sensor/1", json!(2)).await.unwrap();
+		query.next().await.unwrap();
+		assert!(recorder.take().contains(&"notification".to_string()));
+		
+		connection.await.unwrap().abort();
+		assert!(matches!(query.next().await, Some(QueryEvent::Reconnected(_))));
+		let records = recorder.take();
+		assert!(records.contains(&"reconnecting".to_string()) && records.contains(&"reconnected".to_string()));
+		
+		server.set_read_only(true);
+		assert!(client.set("sensor/1", json!(3)).await.is_err());
+		assert_eq!(recorder.take().last().unwrap(), "finished set error");
+	}
+}
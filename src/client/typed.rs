@@ -0,0 +1,93 @@
+use crate::Object;
+use crate::client::{Error, Query, QueryEvent};
+use chrono::{DateTime, Utc};
+use futures::stream::Stream;
+use futures::StreamExt;
+use serde::de::DeserializeOwned;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use uuid::Uuid;
+
+/// An object with its value deserialized into an application type.
+#[derive(Debug, Clone)]
+pub struct TypedObject<T> {
+	pub name: String,
+	pub value: T,
+	pub last_modified: DateTime<Utc>,
+}
+
+impl<T: DeserializeOwned> TypedObject<T> {
+	pub fn from_object(object: Object) -> Result<Self, Error> {
+		Ok(TypedObject {
+			value: serde_json::from_value(object.value)?,
+			name: object.name,
+			last_modified: object.last_modified,
+		})
+	}
+}
+
+pub(crate) fn typed_objects<T: DeserializeOwned>(objects: Vec<Object>) -> Result<Vec<TypedObject<T>>, Error> {
+	objects.into_iter().map(TypedObject::from_object).collect()
+}
+
+/// A notification of a typed query.
+#[derive(Debug, Clone)]
+pub enum TypedQueryEvent<T> {
+	Add(TypedObject<T>),
+	Change(TypedObject<T>),
+	/// The name of the removed object.
+	Remove(String),
+	Event { object: String, event: String, data: serde_json::Value },
+	/// See `QueryEvent::Reconnected`.
+	Reconnected(Vec<TypedObject<T>>),
+}
+
+/// A query whose objects are deserialized into `T`. Objects whose value
+/// doesn't fit `T` make the stream yield an error, but don't end it.
+pub struct TypedQuery<T> {
+	pub id: Option<Uuid>,
+	pub objects: Vec<TypedObject<T>>,
+	query: Query,
+	value: PhantomData<fn() -> T>,
+}
+
+impl<T: DeserializeOwned> TypedQuery<T> {
+	pub(crate) fn new(mut query: Query) -> Result<Self, Error> {
+		Ok(TypedQuery {
+			id: query.id,
+			objects: typed_objects(std::mem::take(&mut query.objects))?,
+			query,
+			value: PhantomData,
+		})
+	}
+}
+
+// the objects are never pinned, only the inner query is polled
+impl<T> Unpin for TypedQuery<T> {}
+
+impl<T: DeserializeOwned> Stream for TypedQuery<T> {
+	type Item = Result<TypedQueryEvent<T>, Error>;
+	
+	fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+		loop {
+			let event = match self.query.poll_next_unpin(cx) {
+				Poll::Ready(Some(event)) => event,
+				Poll::Ready(None) => return Poll::Ready(None),
+				Poll::Pending => return Poll::Pending,
+			};
+			
+			let event = match event {
+				QueryEvent::Add(object) => TypedObject::from_object(object).map(TypedQueryEvent::Add),
+				QueryEvent::Change { object, .. } => TypedObject::from_object(object).map(TypedQueryEvent::Change),
+				QueryEvent::Remove(object) => Ok(TypedQueryEvent::Remove(object.name)),
+				QueryEvent::Event { object, event, data } => Ok(TypedQueryEvent::Event { object, event, data }),
+				QueryEvent::Reconnected(objects) => typed_objects(objects).map(TypedQueryEvent::Reconnected),
+				// typed queries don't provide rpc
+				QueryEvent::Invocation { .. } => continue,
+			};
+			
+			return Poll::Ready(Some(event));
+		}
+	}
+}
@@ -0,0 +1,156 @@
+use crate::client::{Error, RpcClient};
+use crate::client::rpc::{Connector, MessageSink, MessageStream, RpcOptions};
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures::channel::oneshot;
+use futures::{FutureExt, SinkExt, StreamExt};
+use serde_json::Value;
+use std::ops::Deref;
+use wasm_bindgen::closure::Closure;
+use wasm_bindgen::JsCast;
+use web_sys::{CloseEvent, MessageEvent, WebSocket};
+
+enum SocketEvent {
+	Open,
+	Message(Result<Value, String>),
+	Error,
+	Close(String),
+}
+
+/// Opens a websocket, which is served on the browser's event loop. Messages
+/// are passed over channels, so the connection can be used like the others.
+async fn open(url: String) -> Result<(MessageSink, MessageStream), Error> {
+	let (opened_tx, opened_rx) = oneshot::channel();
+	let (incoming_tx, incoming_rx) = unbounded();
+	let (outgoing_tx, outgoing_rx) = unbounded::<Value>();
+	
+	wasm_bindgen_futures::spawn_local(serve(url, opened_tx, incoming_tx, outgoing_rx));
+	
+	opened_rx.await.map_err(|_| Error::ConnectionClosed)??;
+	
+	let sink = outgoing_tx.sink_map_err(|e| e.to_string());
+	Ok((Box::pin(sink), Box::pin(incoming_rx)))
+}
+
+async fn serve(
+	url: String,
+	opened: oneshot::Sender<Result<(), Error>>,
+	incoming: UnboundedSender<Result<Value, String>>,
+	mut outgoing: UnboundedReceiver<Value>,
+) {
+	let socket = match WebSocket::new(&url) {
+		Ok(socket) => socket,
+		Err(e) => {
+			let _ = opened.send(Err(Error::ConnectionError(format!("{:?}", e))));
+			return;
+		},
+	};
+	
+	let (events_tx, mut events) = unbounded();
+	
+	let tx = events_tx.clone();
+	let on_open = Closure::<dyn FnMut()>::new(move || { let _ = tx.unbounded_send(SocketEvent::Open); });
+	let tx = events_tx.clone();
+	let on_message = Closure::<dyn FnMut(MessageEvent)>::new(move |event: MessageEvent| {
+		// the server answers json in text frames
+		if let Some(text) = event.data().as_string() {
+			let message = serde_json::from_str(&text).map_err(|_| "invalid message".to_string());
+			let _ = tx.unbounded_send(SocketEvent::Message(message));
+		}
+	});
+	let tx = events_tx.clone();
+	let on_error = Closure::<dyn FnMut()>::new(move || { let _ = tx.unbounded_send(SocketEvent::Error); });
+	let tx = events_tx;
+	let on_close = Closure::<dyn FnMut(CloseEvent)>::new(move |event: CloseEvent| {
+		let _ = tx.unbounded_send(SocketEvent::Close(format!("connection closed with code {}", event.code())));
+	});
+	
+	socket.set_onopen(Some(on_open.as_ref().unchecked_ref()));
+	socket.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+	socket.set_onerror(Some(on_error.as_ref().unchecked_ref()));
+	socket.set_onclose(Some(on_close.as_ref().unchecked_ref()));
+	
+	// browsers don't tell why a websocket couldn't be opened, not even an
+	// http status, so a wrong token is just a connection error
+	let result = loop {
+		match events.next().await {
+			Some(SocketEvent::Open) => break Ok(()),
+			Some(SocketEvent::Message(_)) => {},
+			Some(SocketEvent::Error) => {},
+			Some(SocketEvent::Close(reason)) => break Err(Error::ConnectionError(reason)),
+			None => break Err(Error::ConnectionClosed),
+		}
+	};
+	let is_open = result.is_ok();
+	let _ = opened.send(result);
+	
+	if is_open {
+		loop {
+			tokio::select! {
+				value = outgoing.next() => match value {
+					Some(value) => if let Err(e) = socket.send_with_str(&value.to_string()) {
+						let _ = incoming.unbounded_send(Err(format!("{:?}", e)));
+						break;
+					},
+					// the client was dropped
+					None => break,
+				},
+				event = events.next() => match event {
+					Some(SocketEvent::Message(message)) => { let _ = incoming.unbounded_send(message); },
+					Some(SocketEvent::Close(reason)) => {
+						let _ = incoming.unbounded_send(Err(reason));
+						break;
+					},
+					Some(SocketEvent::Open) | Some(SocketEvent::Error) => {},
+					None => break,
+				},
+			}
+		}
+	}
+	
+	socket.set_onopen(None);
+	socket.set_onmessage(None);
+	socket.set_onerror(None);
+	socket.set_onclose(None);
+	let _ = socket.close();
+}
+
+/// A client for websockets of the http transport that runs in browsers,
+/// e.g. for dashboards built with Yew. Needs the `wasm` feature and the
+/// `wasm32-unknown-unknown` target.
+#[derive(Clone)]
+pub struct WebClient {
+	client: RpcClient,
+}
+
+impl WebClient {
+	/// Connects to a `ws://` or `wss://` url, like `wss://example.com/`, and
+	/// again whenever the connection is lost.
+	pub async fn connect<S: AsRef<str>>(url: S) -> Result<Self, Error> {
+		Self::connect_with_rpc(url.as_ref().to_string(), RpcOptions::default()).await
+	}
+	
+	/// Sends `token` as `access_token` query parameter, since browsers can't
+	/// set headers on websockets.
+	pub async fn connect_with_token<S: AsRef<str>, T: AsRef<str>>(url: S, token: T) -> Result<Self, Error> {
+		let url = url.as_ref();
+		let separator = if url.contains('?') { '&' } else { '?' };
+		let token = String::from(js_sys::encode_uri_component(token.as_ref()));
+		
+		Self::connect_with_rpc(format!("{}{}access_token={}", url, separator, token), RpcOptions::default()).await
+	}
+	
+	pub(crate) async fn connect_with_rpc(url: String, rpc_options: RpcOptions) -> Result<Self, Error> {
+		let connector: Connector = Box::new(move || open(url.clone()).boxed());
+		
+		let connection = connector().await?;
+		Ok(WebClient { client: RpcClient::spawn(connection, Some(connector), rpc_options) })
+	}
+}
+
+impl Deref for WebClient {
+	type Target = RpcClient;
+	
+	fn deref(&self) -> &RpcClient {
+		&self.client
+	}
+}
@@ -0,0 +1,196 @@
+use crate::client::{Error, RpcClient};
+use crate::client::rpc::{Connector, MessageSink, MessageStream, RpcOptions};
+use futures::future;
+use futures::{FutureExt, SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::ops::Deref;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::{HeaderValue, StatusCode};
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+use tokio_tungstenite::{connect_async, WebSocketStream};
+
+/// Websocket subprotocol for MessagePack binary frames.
+const MSGPACK_PROTOCOL: &str = "msgpack";
+
+#[derive(Debug, Clone, Default)]
+pub struct WsOptions {
+	/// Sent as bearer token, for servers that require authentication.
+	pub token: Option<String>,
+	/// Exchanges messages MessagePack-encoded in binary frames instead of json
+	/// text frames.
+	pub msgpack: bool,
+}
+
+fn encode(value: Value, msgpack: bool) -> Message {
+	if msgpack {
+		// human readable keeps uuids and timestamps as strings like in json
+		let mut data = vec![];
+		value.serialize(&mut rmp_serde::Serializer::new(&mut data).with_struct_map().with_human_readable()).unwrap();
+		Message::binary(data)
+	} else {
+		Message::text(value.to_string())
+	}
+}
+
+/// Decodes a message, returns `None` for control frames.
+fn decode(message: Message) -> Option<Result<Value, String>> {
+	match message {
+		Message::Text(text) => Some(serde_json::from_str(&text).map_err(|_| "invalid message".to_string())),
+		Message::Binary(data) => {
+			let mut deserializer = rmp_serde::Deserializer::new(&data[..]).with_human_readable();
+			Some(Value::deserialize(&mut deserializer).map_err(|_| "invalid message".to_string()))
+		},
+		_ => None,
+	}
+}
+
+fn transport<S: AsyncRead + AsyncWrite + Unpin + Send + 'static>(websocket: WebSocketStream<S>, msgpack: bool) -> (MessageSink, MessageStream) {
+	let (sink, stream) = websocket.split();
+	
+	let sink = sink
+		.sink_map_err(|e| e.to_string())
+		.with(move |value: Value| future::ok(encode(value, msgpack)));
+	let stream = stream.filter_map(|message| future::ready(match message {
+		Ok(message) => decode(message),
+		Err(e) => Some(Err(e.to_string())),
+	}));
+	
+	(Box::pin(sink), Box::pin(stream))
+}
+
+async fn open(url: &str, options: &WsOptions) -> Result<(MessageSink, MessageStream), Error> {
+	let mut request = url.into_client_request().map_err(|e| Error::ConnectionError(e.to_string()))?;
+	
+	if let Some(token) = &options.token {
+		let value = HeaderValue::from_str(&format!("Bearer {}", token)).map_err(|_| Error::ConnectionError("invalid token".to_string()))?;
+		request.headers_mut().insert("authorization", value);
+	}
+	
+	if options.msgpack {
+		request.headers_mut().insert("sec-websocket-protocol", HeaderValue::from_static(MSGPACK_PROTOCOL));
+	}
+	
+	let (websocket, response) = connect_async(request).await.map_err(|e| match e {
+		WsError::Http(response) if response.status() == StatusCode::UNAUTHORIZED => Error::Unauthorized,
+		e => Error::ConnectionError(e.to_string()),
+	})?;
+	
+	// servers without msgpack support keep using json
+	let msgpack = response.headers().get("sec-websocket-protocol")
+		.is_some_and(|protocol| protocol == MSGPACK_PROTOCOL);
+	
+	Ok(transport(websocket, msgpack))
+}
+
+/// A client for websockets of the http transport, e.g. for servers behind a
+/// reverse proxy that only exposes the https port.
+#[derive(Clone)]
+pub struct WsClient {
+	client: RpcClient,
+}
+
+impl WsClient {
+	/// Connects to a `ws://` or `wss://` url, like `wss://example.com/`.
+	pub async fn connect<S: AsRef<str>>(url: S) -> Result<Self, Error> {
+		Self::connect_with(url, WsOptions::default()).await
+	}
+	
+	/// Connects to `url`, and again whenever the connection is lost.
+	pub async fn connect_with<S: AsRef<str>>(url: S, options: WsOptions) -> Result<Self, Error> {
+		Self::connect_with_rpc(url, options, RpcOptions::default()).await
+	}
+	
+	pub(crate) async fn connect_with_rpc<S: AsRef<str>>(url: S, options: WsOptions, rpc_options: RpcOptions) -> Result<Self, Error> {
+		let url = url.as_ref().to_string();
+		
+		let connector: Connector = Box::new(move || {
+			let url = url.clone();
+			let options = options.clone();
+			
+			async move { open(&url, &options).await }.boxed()
+		});
+		
+		let connection = connector().await?;
+		Ok(WsClient { client: RpcClient::spawn(connection, Some(connector), rpc_options) })
+	}
+	
+	/// Uses an established websocket, which isn't opened again when it is
+	/// lost. Must be called within a tokio runtime.
+	pub fn from_websocket<S: AsyncRead + AsyncWrite + Unpin + Send + 'static>(websocket: WebSocketStream<S>, msgpack: bool) -> Self {
+		WsClient { client: RpcClient::spawn(transport(websocket, msgpack), None, RpcOptions::default()) }
+	}
+}
+
+impl Deref for WsClient {
+	type Target = RpcClient;
+	
+	fn deref(&self) -> &RpcClient {
+		&self.client
+	}
+}
+
+#[cfg(all(test, feature = "server"))]
+mod tests {
+	use super::*;
+	use crate::client::QueryEvent;
+	use crate::server::http_transport::{serve_websocket, Encoding};
+	use crate::server::logger::NullLogger;
+	use crate::server::Server;
+	use serde_json::json;
+	use tokio::io::duplex;
+	use tokio_tungstenite::tungstenite::protocol::Role;
+	
+	async fn connect(server: &Server, msgpack: bool) -> WsClient {
+		let (stream, server_stream) = duplex(4096);
+		let server = server.clone();
+		let encoding = if msgpack { Encoding::MessagePack } else { Encoding::Json };
+		
+		tokio::spawn(async move {
+			let websocket = WebSocketStream::from_raw_socket(server_stream, Role::Server, None).await;
+			let _ = serve_websocket(websocket, server.clone(), server.client_connect(), encoding, None).await;
+		});
+		
+		WsClient::from_websocket(WebSocketStream::from_raw_socket(stream, Role::Client, None).await, msgpack)
+	}
+	
+	#[tokio::test]
+	async fn test_unauthorized() {
+		use tokio::io::{AsyncReadExt, AsyncWriteExt};
+		use tokio::net::TcpListener;
+		
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let url = format!("ws://{}/", listener.local_addr().unwrap());
+		
+		tokio::spawn(async move {
+			let (mut stream, _) = listener.accept().await.unwrap();
+			let mut head = vec![0; 1024];
+			let len = stream.read(&mut head).await.unwrap();
+			assert!(String::from_utf8_lossy(&head[..len]).to_lowercase().contains("authorization: bearer wrong"));
+			
+			stream.write_all(b"HTTP/1.1 401 Unauthorized\r\ncontent-length: 0\r\n\r\n").await.unwrap();
+		});
+		
+		let options = WsOptions { token: Some("wrong".to_string()), ..WsOptions::default() };
+		assert!(matches!(WsClient::connect_with(url, options).await, Err(Error::Unauthorized)));
+	}
+	
+	#[tokio::test]
+	async fn test_requests() {
+		for msgpack in [false, true] {
+			let server = Server::new(None, Box::new(NullLogger));
+			let client = connect(&server, msgpack).await;
+			
+			client.set("sensor/1", json!({ "temperature": 21.5 })).await.unwrap();
+			let mut query = client.query("sensor/+").await.unwrap();
+			assert_eq!(query.objects[0].value, json!({ "temperature": 21.5 }));
+			
+			client.patch("sensor/1", json!({ "humidity": 40 })).await.unwrap();
+			assert!(matches!(query.next().await, Some(QueryEvent::Change { object, .. }) if object.value == json!({ "temperature": 21.5, "humidity": 40 })));
+			
+			assert!(client.remove("sensor/1").await.unwrap());
+			assert!(client.get("sensor/+").await.unwrap().is_empty());
+		}
+	}
+}
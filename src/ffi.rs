@@ -0,0 +1,275 @@
+use crate::client::blocking::TcpClient;
+use crate::client::QueryEvent;
+use serde_json::{json, Value};
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int, c_void};
+use std::ptr;
+
+thread_local! {
+	static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error<E: ToString>(error: E) {
+	let error = CString::new(error.to_string()).unwrap_or_default();
+	LAST_ERROR.with(|last_error| *last_error.borrow_mut() = Some(error));
+}
+
+/// Borrows a C string, `None` for null pointers and invalid utf-8.
+unsafe fn str_arg<'a>(s: *const c_char) -> Option<&'a str> {
+	if s.is_null() {
+		set_last_error("null argument");
+		return None;
+	}
+	
+	CStr::from_ptr(s).to_str().map_err(|_| set_last_error("invalid utf-8")).ok()
+}
+
+unsafe fn json_arg(s: *const c_char) -> Option<Value> {
+	serde_json::from_str(str_arg(s)?).map_err(set_last_error).ok()
+}
+
+fn string_result(value: Value) -> *mut c_char {
+	CString::new(value.to_string()).map(CString::into_raw).unwrap_or(ptr::null_mut())
+}
+
+fn event_json(event: QueryEvent) -> Value {
+	match event {
+		QueryEvent::Add(object) => json!({ "type": "add", "object": object }),
+		QueryEvent::Change { object, .. } => json!({ "type": "change", "object": object }),
+		QueryEvent::Remove(object) => json!({ "type": "remove", "object": object }),
+		QueryEvent::Event { object, event, data } => json!({ "type": "event", "object": object, "event": event, "data": data }),
+		QueryEvent::Invocation { invocation_id, object, method, args } =>
+			json!({ "type": "invocation", "invocationId": invocation_id, "object": object, "method": method, "args": args }),
+		QueryEvent::Reconnected(objects) => json!({ "type": "reconnected", "objects": objects }),
+	}
+}
+
+/// The last error of a failed call on this thread, valid until the next
+/// failing call. Null if there was none.
+#[no_mangle]
+pub extern "C" fn objtalk_last_error() -> *const c_char {
+	LAST_ERROR.with(|last_error| last_error.borrow().as_ref().map(|error| error.as_ptr()).unwrap_or(ptr::null()))
+}
+
+/// Connects to the tcp transport at `addr`, like `127.0.0.1:3001`. Returns
+/// null on errors.
+///
+/// # Safety
+///
+/// `addr` must be a valid C string.
+#[no_mangle]
+pub unsafe extern "C" fn objtalk_connect(addr: *const c_char) -> *mut TcpClient {
+	let addr = match str_arg(addr) {
+		Some(addr) => addr.to_string(),
+		None => return ptr::null_mut(),
+	};
+	
+	match TcpClient::connect(addr) {
+		Ok(client) => Box::into_raw(Box::new(client)),
+		Err(e) => {
+			set_last_error(e);
+			ptr::null_mut()
+		},
+	}
+}
+
+/// Closes the connection, which also ends the subscriptions of the client.
+///
+/// # Safety
+///
+/// `client` must be returned by `objtalk_connect` and not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn objtalk_disconnect(client: *mut TcpClient) {
+	if !client.is_null() {
+		drop(Box::from_raw(client));
+	}
+}
+
+/// Sets `name` to the JSON `value`. Returns 0 on success and -1 on errors.
+///
+/// # Safety
+///
+/// `client` must be returned by `objtalk_connect`, the others valid C strings.
+#[no_mangle]
+pub unsafe extern "C" fn objtalk_set(client: *const TcpClient, name: *const c_char, value: *const c_char) -> c_int {
+	let (name, value) = match (str_arg(name), json_arg(value)) {
+		(Some(name), Some(value)) => (name, value),
+		_ => return -1,
+	};
+	
+	match (*client).set(name, value) {
+		Ok(()) => 0,
+		Err(e) => {
+			set_last_error(e);
+			-1
+		},
+	}
+}
+
+/// Returns the objects matching `pattern` as a JSON array, to be freed with
+/// `objtalk_string_free`, or null on errors.
+///
+/// # Safety
+///
+/// `client` must be returned by `objtalk_connect`, `pattern` a valid C string.
+#[no_mangle]
+pub unsafe extern "C" fn objtalk_get(client: *const TcpClient, pattern: *const c_char) -> *mut c_char {
+	let pattern = match str_arg(pattern) {
+		Some(pattern) => pattern,
+		None => return ptr::null_mut(),
+	};
+	
+	match (*client).get(pattern) {
+		Ok(objects) => string_result(json!(objects)),
+		Err(e) => {
+			set_last_error(e);
+			ptr::null_mut()
+		},
+	}
+}
+
+/// Calls `method` of `object` with the JSON `args` and returns the JSON
+/// result, to be freed with `objtalk_string_free`, or null on errors.
+///
+/// # Safety
+///
+/// `client` must be returned by `objtalk_connect`, the others valid C strings.
+#[no_mangle]
+pub unsafe extern "C" fn objtalk_invoke(client: *const TcpClient, object: *const c_char, method: *const c_char, args: *const c_char) -> *mut c_char {
+	let (object, method, args) = match (str_arg(object), str_arg(method), json_arg(args)) {
+		(Some(object), Some(method), Some(args)) => (object, method, args),
+		_ => return ptr::null_mut(),
+	};
+	
+	match (*client).invoke(object, method, args) {
+		Ok(result) => string_result(result),
+		Err(e) => {
+			set_last_error(e);
+			ptr::null_mut()
+		},
+	}
+}
+
+/// The opaque pointer passed back to callbacks.
+struct UserData(*mut c_void);
+
+// the caller is responsible for what it shares with the callback's thread
+unsafe impl Send for UserData {}
+
+/// Called with an event as JSON, like `{"type":"change","object":{...}}`. The
+/// string is only valid during the call.
+pub type ObjtalkCallback = extern "C" fn(event: *const c_char, user_data: *mut c_void);
+
+/// Queries `pattern` and calls `callback` for every notification on a thread
+/// of its own, until the connection is closed. The current objects are passed
+/// as `add` events first. Returns 0 on success and -1 on errors.
+///
+/// # Safety
+///
+/// `client` must be returned by `objtalk_connect`, `pattern` a valid C string
+/// and `callback` safe to call from another thread with `user_data`.
+#[no_mangle]
+pub unsafe extern "C" fn objtalk_subscribe(client: *const TcpClient, pattern: *const c_char, callback: ObjtalkCallback, user_data: *mut c_void) -> c_int {
+	let pattern = match str_arg(pattern) {
+		Some(pattern) => pattern,
+		None => return -1,
+	};
+	
+	let mut query = match (*client).query(pattern) {
+		Ok(query) => query,
+		Err(e) => {
+			set_last_error(e);
+			return -1;
+		},
+	};
+	
+	let user_data = UserData(user_data);
+	
+	std::thread::spawn(move || {
+		let initial = std::mem::take(&mut query.objects).into_iter().map(QueryEvent::Add);
+		
+		for event in initial.chain(&mut query) {
+			if let Ok(event) = CString::new(event_json(event).to_string()) {
+				callback(event.as_ptr(), user_data.0);
+			}
+		}
+	});
+	
+	0
+}
+
+/// Frees a string returned by the API.
+///
+/// # Safety
+///
+/// `s` must be returned by this API and not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn objtalk_string_free(s: *mut c_char) {
+	if !s.is_null() {
+		drop(CString::from_raw(s));
+	}
+}
+
+#[cfg(all(test, feature = "server"))]
+mod tests {
+	use super::*;
+	use crate::server::config::MessageFormat;
+	use crate::server::logger::NullLogger;
+	use crate::server::tcp_transport::serve_connection;
+	use crate::server::Server;
+	use futures::FutureExt;
+	use std::sync::mpsc::{channel, Sender};
+	use std::time::Duration;
+	use tokio::net::TcpListener;
+	
+	extern "C" fn forward(event: *const c_char, user_data: *mut c_void) {
+		let events = unsafe { &*(user_data as *const Sender<String>) };
+		let _ = events.send(unsafe { CStr::from_ptr(event) }.to_str().unwrap().to_string());
+	}
+	
+	#[test]
+	fn test_ffi() {
+		let runtime = tokio::runtime::Runtime::new().unwrap();
+		let server = Server::new(None, Box::new(NullLogger));
+		let listener = runtime.block_on(TcpListener::bind("127.0.0.1:0")).unwrap();
+		let addr = CString::new(listener.local_addr().unwrap().to_string()).unwrap();
+		
+		runtime.spawn(async move {
+			loop {
+				let (stream, addr) = listener.accept().await.unwrap();
+				tokio::spawn(serve_connection(stream, addr, server.clone(), None, MessageFormat::Json, None).map(|_| ()));
+			}
+		});
+		
+		let name = CString::new("sensor/1").unwrap();
+		let pattern = CString::new("sensor/+").unwrap();
+		
+		unsafe {
+			let client = objtalk_connect(addr.as_ptr());
+			assert!(!client.is_null());
+			
+			assert_eq!(objtalk_set(client, name.as_ptr(), CString::new("21").unwrap().as_ptr()), 0);
+			assert_eq!(objtalk_set(client, name.as_ptr(), CString::new("{invalid").unwrap().as_ptr()), -1);
+			assert!(!objtalk_last_error().is_null());
+			
+			let objects = objtalk_get(client, pattern.as_ptr());
+			let value: Value = serde_json::from_str(CStr::from_ptr(objects).to_str().unwrap()).unwrap();
+			assert_eq!(value[0]["value"], json!(21));
+			objtalk_string_free(objects);
+			
+			let (events_tx, events) = channel::<String>();
+			let events_tx = Box::into_raw(Box::new(events_tx));
+			assert_eq!(objtalk_subscribe(client, pattern.as_ptr(), forward, events_tx as *mut c_void), 0);
+			assert_eq!(objtalk_set(client, name.as_ptr(), CString::new("22").unwrap().as_ptr()), 0);
+			
+			let add: Value = serde_json::from_str(&events.recv_timeout(Duration::from_secs(1)).unwrap()).unwrap();
+			assert_eq!(add["type"], "add");
+			let change: Value = serde_json::from_str(&events.recv_timeout(Duration::from_secs(1)).unwrap()).unwrap();
+			assert_eq!((&change["type"], &change["object"]["value"]), (&json!("change"), &json!(22)));
+			
+			objtalk_disconnect(client);
+			drop(Box::from_raw(events_tx));
+		}
+	}
+}
@@ -1,4 +1,5 @@
-use crate::{Object, Command};
+use crate::{Object, Event, Revision, AuditEntry, AuditQuery, Backup, Stats, Command, LogLevel};
+use chrono::{DateTime, Utc};
 use serde::{Serialize,Deserialize};
 use serde_json::Value;
 use uuid::Uuid;
@@ -26,6 +27,8 @@ pub enum Request {
 		pattern: String,
 		#[serde(default = "bool::default")]
 		provide_rpc: bool,
+		#[serde(default = "bool::default")]
+		include_previous: bool,
 	},
 	#[serde(rename_all = "camelCase")]
 	Unsubscribe {
@@ -44,6 +47,43 @@ pub enum Request {
 		method: String,
 		args: Value,
 	},
+	#[serde(rename = "getEvents")]
+	GetEvents {
+		pattern: String,
+		#[serde(default)]
+		since: Option<DateTime<Utc>>,
+		#[serde(default)]
+		limit: Option<usize>,
+	},
+	#[serde(rename = "getHistory")]
+	GetHistory {
+		name: String,
+		#[serde(default)]
+		since: Option<DateTime<Utc>>,
+		#[serde(default)]
+		until: Option<DateTime<Utc>>,
+		#[serde(default)]
+		limit: Option<usize>,
+	},
+	#[serde(rename = "getAudit")]
+	GetAudit {
+		#[serde(flatten)]
+		query: AuditQuery,
+	},
+	#[serde(rename = "getStats")]
+	GetStats {},
+	#[serde(rename = "setLogLevel")]
+	SetLogLevel {
+		/// Null restores the configured level.
+		level: Option<LogLevel>,
+		/// Seconds until the configured level is restored.
+		#[serde(default)]
+		duration: Option<u64>,
+	},
+	Backup {},
+	Restore {
+		backup: Backup,
+	},
 	#[serde(rename = "invokeResult")]
 	#[serde(rename_all = "camelCase")]
 	InvokeResult {
@@ -54,6 +94,7 @@ pub enum Request {
 	SetDisconnectCommands {
 		commands: Vec<Command>,
 	},
+	Ping {},
 }
 
 #[derive(Serialize, Debug)]
@@ -72,16 +113,55 @@ pub enum Response {
 	},
 	Remove {
 		existed: bool,
-	}
+	},
+	Events {
+		events: Vec<Event>,
+	},
+	History {
+		revisions: Vec<Revision>,
+	},
+	Audit {
+		entries: Vec<AuditEntry>,
+	},
+	Backup {
+		backup: Backup,
+	},
+	Stats {
+		stats: Stats,
+	},
+	LogLevel {
+		level: LogLevel,
+	},
 }
 
 #[derive(Deserialize, Debug)]
 pub struct RequestMessage {
+	/// Null or absent for notifications, which aren't answered.
+	#[serde(default)]
 	pub id: Value,
 	#[serde(flatten)]
 	pub request: Request,
 }
 
+/// A single request, or several sent as an array.
+#[derive(Debug)]
+pub enum Requests {
+	Single(RequestMessage),
+	/// Requests that can't be parsed are `Err`, so the others still run.
+	Batch(Vec<Result<RequestMessage, serde_json::Error>>),
+}
+
+impl Requests {
+	pub fn from_value(value: Value) -> Result<Self, serde_json::Error> {
+		match value {
+			Value::Array(values) => Ok(Requests::Batch(values.into_iter()
+				.map(serde_json::from_value)
+				.collect())),
+			value => serde_json::from_value(value).map(Requests::Single),
+		}
+	}
+}
+
 #[derive(Serialize, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct ResponseMessage {
@@ -92,7 +172,7 @@ pub struct ResponseMessage {
 	pub error: Option<String>,
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 #[serde(tag = "type")]
 #[serde(rename_all = "camelCase")]
 pub enum EventMessage {
@@ -105,6 +185,8 @@ pub enum EventMessage {
 	QueryChange {
 		query_id: Uuid,
 		object: Object,
+		#[serde(skip_serializing_if = "Option::is_none")]
+		previous: Option<Value>,
 	},
 	#[serde(rename_all = "camelCase")]
 	QueryRemove {
@@ -134,5 +216,9 @@ pub enum EventMessage {
 		result: Option<Value>,
 		#[serde(skip_serializing_if = "Option::is_none")]
 		error: Option<String>,
-	}
+	},
+	/// Asks the client to send any request, e.g. a ping, to show it's alive.
+	Ping,
+	/// Sent before the server closes the connection because it is shutting down.
+	Shutdown,
 }
@@ -2,15 +2,26 @@
 pub mod patterns;
 #[cfg(feature = "server")]
 pub mod server;
-#[cfg(feature = "client")]
+#[cfg(feature = "client-min")]
 pub mod client;
+#[cfg(feature = "ffi")]
+pub mod ffi;
 pub mod json_rpc;
 
 use serde::{Serialize, Deserialize};
 use serde_json::Value;
 use chrono::prelude::*;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use uuid::Uuid;
 
-pub const VERSION_STRING: &'static str = env!("VERSION_STRING");
+pub const VERSION_STRING: &str = env!("VERSION_STRING");
+
+/// DNS-SD service type the server's transports are advertised as.
+#[cfg(feature = "mdns")]
+pub const MDNS_SERVICE_TYPE: &str = "_objtalk._tcp.local.";
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
@@ -20,7 +31,243 @@ pub struct Object {
 	pub last_modified: DateTime<Utc>,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Event {
+	pub object: String,
+	pub event: String,
+	pub data: Value,
+	pub timestamp: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Revision {
+	pub name: String,
+	pub value: Value,
+	#[serde(default)]
+	pub removed: bool,
+	pub timestamp: DateTime<Utc>,
+	pub client: Uuid,
+}
+
+/// Severity of a message of the server log. Messages below the server's
+/// level aren't logged, so `trace` logs everything.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+	Error,
+	Warn,
+	Info,
+	Debug,
+	#[default]
+	Trace,
+}
+
+impl fmt::Display for LogLevel {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str(match self {
+			LogLevel::Error => "error",
+			LogLevel::Warn => "warn",
+			LogLevel::Info => "info",
+			LogLevel::Debug => "debug",
+			LogLevel::Trace => "trace",
+		})
+	}
+}
+
+/// Operations of clients that can be recorded in the audit log.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditOperation {
+	Set,
+	Patch,
+	Remove,
+	Emit,
+	Invoke,
+}
+
+impl AuditOperation {
+	pub const ALL: [AuditOperation; 5] = [
+		AuditOperation::Set,
+		AuditOperation::Patch,
+		AuditOperation::Remove,
+		AuditOperation::Emit,
+		AuditOperation::Invoke,
+	];
+	
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			AuditOperation::Set => "set",
+			AuditOperation::Patch => "patch",
+			AuditOperation::Remove => "remove",
+			AuditOperation::Emit => "emit",
+			AuditOperation::Invoke => "invoke",
+		}
+	}
+}
+
+impl fmt::Display for AuditOperation {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str(self.as_str())
+	}
+}
+
+impl FromStr for AuditOperation {
+	type Err = String;
+	
+	fn from_str(string: &str) -> Result<Self, Self::Err> {
+		AuditOperation::ALL.iter()
+			.find(|operation| operation.as_str() == string)
+			.copied()
+			.ok_or_else(|| format!("unknown operation {}", string))
+	}
+}
+
+/// An operation of a client recorded in the audit log. `value` is the value
+/// that was set or patched, the data of an emitted event or the arguments of
+/// an invocation, and null for removals.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct AuditEntry {
+	pub timestamp: DateTime<Utc>,
+	pub operation: AuditOperation,
+	pub object: String,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub event: Option<String>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub method: Option<String>,
+	#[serde(default)]
+	pub value: Value,
+	pub client: Uuid,
+	#[serde(default)]
+	pub addr: Option<IpAddr>,
+	#[serde(default)]
+	pub identity: Option<String>,
+}
+
+/// Selects entries of the audit log, which are returned newest first. Unset
+/// fields match all entries. Pages are fetched with `offset`, keep `until`
+/// fixed while paging so new entries don't shift them.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct AuditQuery {
+	/// Pattern the names of the objects have to match.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub pattern: Option<String>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub operation: Option<AuditOperation>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub identity: Option<String>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub since: Option<DateTime<Utc>>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub until: Option<DateTime<Utc>>,
+	#[serde(default)]
+	pub offset: usize,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub limit: Option<usize>,
+}
+
+/// Serialized export of a persisted store.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Backup {
+	pub created: DateTime<Utc>,
+	pub objects: Vec<Object>,
+	#[serde(default)]
+	pub events: Vec<Event>,
+	#[serde(default)]
+	pub revisions: Vec<Revision>,
+}
+
+impl Backup {
+	/// Like `objtalk-backup-20210507T175329Z.json`.
+	pub fn filename(&self) -> String {
+		format!("objtalk-backup-{}.json", self.created.format("%Y%m%dT%H%M%SZ"))
+	}
+}
+
+/// Counters of a storage writer. Latencies are in seconds.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct StorageStats {
+	pub writes: u64,
+	pub errors: u64,
+	pub queue_depth: u64,
+	pub write_latency_sum: f64,
+	pub write_latency_max: f64,
+	pub database_size: Option<u64>,
+	pub degraded: bool,
+	/// When a write last succeeded.
+	#[serde(default)]
+	pub last_write: Option<DateTime<Utc>>,
+}
+
+/// A write or other storage operation that failed even after retrying.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StorageErrorEntry {
+	pub timestamp: DateTime<Utc>,
+	pub operation: String,
+	pub object: Option<String>,
+	pub error: String,
+	pub degraded: bool,
+}
+
+/// Health of the persistence of a server, for operators.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct StorageStatus {
+	/// The configured backend, e.g. `sqlite`, if the server was built from a config.
+	pub backend: Option<String>,
+	#[serde(flatten)]
+	pub stats: StorageStats,
+	/// The latest errors, newest first.
+	pub recent_errors: Vec<StorageErrorEntry>,
+}
+
+/// Upper bounds in seconds of the buckets of the latency histograms.
+pub const LATENCY_BUCKETS: [f64; 10] = [0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.1, 1.0, 10.0];
+
+/// Count and latencies in seconds of an operation type of the server.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct OperationStats {
+	pub count: u64,
+	pub latency_sum: f64,
+	pub latency_max: f64,
+	/// Operations that took at most the bound of `LATENCY_BUCKETS` at the
+	/// same index. Cumulative as in prometheus, so the last one counts all
+	/// operations up to 10 seconds.
+	pub buckets: Vec<u64>,
+}
+
+impl OperationStats {
+	pub fn record(&mut self, latency: f64) {
+		self.count += 1;
+		self.latency_sum += latency;
+		self.latency_max = self.latency_max.max(latency);
+		
+		self.buckets.resize(LATENCY_BUCKETS.len(), 0);
+		for (bucket, bound) in self.buckets.iter_mut().zip(LATENCY_BUCKETS.iter()) {
+			if latency <= *bound {
+				*bucket += 1;
+			}
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Stats {
+	pub storage: Option<StorageStats>,
+	/// By operation type: `set`, `patch`, `get`, `remove`, `emit`, `query`,
+	/// `dispatch` (notifying the queries of a change or event) and `invoke`
+	/// (from the invocation until the provider's result).
+	#[serde(default)]
+	pub operations: BTreeMap<String, OperationStats>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
 #[serde(tag = "type")]
 #[serde(rename_all = "lowercase")]
 pub enum Command {
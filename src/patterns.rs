@@ -44,7 +44,7 @@ impl Pattern {
 		if string == "$system" {
 			self.includes_system
 		} else {
-			self.regex.is_match(&string)
+			self.regex.is_match(string)
 		}
 	}
 	
@@ -0,0 +1,164 @@
+use crate::server::config::{AdminConfig, AuthConfig};
+use std::env;
+use std::fs::read_to_string;
+use std::sync::Arc;
+
+/// Compares in constant time, so response times don't reveal how much of a
+/// token was guessed correctly.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+	a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// The bearer tokens a transport accepts.
+#[derive(Clone, Debug)]
+pub struct Tokens {
+	tokens: Arc<Vec<String>>,
+}
+
+impl Tokens {
+	/// Collects the tokens of the config and its token file, which contains one
+	/// token per line. Empty lines and lines starting with `#` are ignored.
+	pub fn from_config(config: &AuthConfig) -> Result<Self, String> {
+		let mut tokens = config.tokens.clone();
+		
+		if let Some(filename) = &config.token_file {
+			let contents = read_to_string(filename)
+				.map_err(|e| format!("can't read token file {}: {}", filename.display(), e))?;
+			
+			tokens.extend(contents.lines()
+				.map(|line| line.trim())
+				.filter(|line| !line.is_empty() && !line.starts_with('#'))
+				.map(|line| line.to_string()));
+		}
+		
+		if tokens.is_empty() {
+			return Err("auth is configured without any tokens".to_string());
+		}
+		
+		Ok(Tokens { tokens: Arc::new(tokens) })
+	}
+	
+	pub fn verify(&self, token: &str) -> bool {
+		self.tokens.iter().any(|valid| constant_time_eq(valid.as_bytes(), token.as_bytes()))
+	}
+	
+	/// Verifies the token of an `Authorization: Bearer <token>` header value.
+	pub fn verify_header(&self, authorization: &str) -> bool {
+		match authorization.split_once(' ') {
+			Some((scheme, token)) if scheme.eq_ignore_ascii_case("bearer") => self.verify(token.trim()),
+			_ => false,
+		}
+	}
+}
+
+/// The username and password protecting the admin panel with basic auth.
+#[derive(Clone, Debug)]
+pub struct AdminCredentials {
+	username: String,
+	password: String,
+}
+
+impl AdminCredentials {
+	/// `None` if the config has no password.
+	pub fn from_config(config: &AdminConfig) -> Result<Option<Self>, String> {
+		let password = match (&config.password, &config.password_env) {
+			(Some(password), None) => password.clone(),
+			(None, Some(var)) => env::var(var).map_err(|_| format!("environment variable {} not set", var))?,
+			(None, None) => return Ok(None),
+			_ => return Err("only one of admin.password and admin.password-env can be set".to_string()),
+		};
+		
+		if password.is_empty() {
+			return Err("admin password is empty".to_string());
+		}
+		
+		Ok(Some(AdminCredentials {
+			username: config.username.clone().unwrap_or_else(|| "admin".to_string()),
+			password,
+		}))
+	}
+	
+	/// Verifies an `Authorization: Basic <base64 of username:password>` header value.
+	pub fn verify_header(&self, authorization: &str) -> bool {
+		let encoded = match authorization.split_once(' ') {
+			Some((scheme, encoded)) if scheme.eq_ignore_ascii_case("basic") => encoded.trim(),
+			_ => return false,
+		};
+		
+		let decoded = match base64::decode(encoded).ok().and_then(|bytes| String::from_utf8(bytes).ok()) {
+			Some(decoded) => decoded,
+			None => return false,
+		};
+		
+		match decoded.split_once(':') {
+			// both are compared, so the response time doesn't tell which one was wrong
+			Some((username, password)) => {
+				let username_ok = constant_time_eq(username.as_bytes(), self.username.as_bytes());
+				let password_ok = constant_time_eq(password.as_bytes(), self.password.as_bytes());
+				username_ok & password_ok
+			},
+			None => false,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::fs;
+	
+	#[test]
+	fn test_tokens() {
+		let filename = env::temp_dir().join(format!("objtalk-tokens-{}.txt", std::process::id()));
+		fs::write(&filename, "# sensors\nsensor-token\n\n  dashboard-token  \n").unwrap();
+		
+		let tokens = Tokens::from_config(&AuthConfig {
+			tokens: vec!["admin-token".to_string()],
+			token_file: Some(filename.clone()),
+		});
+		fs::remove_file(&filename).unwrap();
+		let tokens = tokens.unwrap();
+		
+		assert!(tokens.verify("admin-token"));
+		assert!(tokens.verify("sensor-token"));
+		assert!(tokens.verify("dashboard-token"));
+		assert!(!tokens.verify("# sensors"));
+		assert!(!tokens.verify("admin"));
+		assert!(!tokens.verify(""));
+		
+		assert!(tokens.verify_header("Bearer admin-token"));
+		assert!(tokens.verify_header("bearer sensor-token"));
+		assert!(!tokens.verify_header("Basic admin-token"));
+		assert!(!tokens.verify_header("admin-token"));
+	}
+	
+	#[test]
+	fn test_no_tokens() {
+		let result = Tokens::from_config(&AuthConfig { tokens: vec![], token_file: None });
+		assert_eq!(result.err(), Some("auth is configured without any tokens".to_string()));
+	}
+	
+	#[test]
+	fn test_admin_credentials() {
+		assert!(AdminCredentials::from_config(&AdminConfig::default()).unwrap().is_none());
+		
+		let credentials = AdminCredentials::from_config(&AdminConfig {
+			password: Some("secret".to_string()),
+			..AdminConfig::default()
+		}).unwrap().unwrap();
+		
+		assert!(credentials.verify_header("Basic YWRtaW46c2VjcmV0"));
+		assert!(credentials.verify_header("basic YWRtaW46c2VjcmV0"));
+		assert!(!credentials.verify_header("Basic YWRtaW46d3Jvbmc="));
+		assert!(!credentials.verify_header("Basic b3BzOnNlY3JldA=="));
+		assert!(!credentials.verify_header("Bearer secret"));
+		assert!(!credentials.verify_header("Basic not-base64"));
+		
+		let result = AdminCredentials::from_config(&AdminConfig {
+			password: Some("secret".to_string()),
+			password_env: Some("OBJTALK_ADMIN_PASSWORD".to_string()),
+			..AdminConfig::default()
+		});
+		assert!(result.is_err());
+	}
+}
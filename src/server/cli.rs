@@ -0,0 +1,447 @@
+use clap::Clap;
+use crate::VERSION_STRING;
+use crate::server::auth::{AdminCredentials, Tokens};
+use crate::server::config::*;
+use crate::server::connection::{ConnectionLimits, Ping};
+#[cfg(unix)]
+use crate::server::daemon::{self, Pidfile};
+use crate::server::http_transport::HttpTransport;
+use crate::server::logger::{FanoutLogger, JsonLogger, Logger, SampleRule, StdoutLogger};
+#[cfg(feature = "mdns")]
+use crate::server::mdns;
+#[cfg(feature = "mqtt")]
+use crate::server::mqtt_bridge::MqttBridge;
+#[cfg(feature = "influxdb")]
+use crate::server::influxdb::InfluxdbExporter;
+#[cfg(feature = "webhooks")]
+use crate::server::webhook::Webhook;
+use crate::server::nats_bridge::NatsBridge;
+use crate::server::replication::Follower;
+use crate::server::{Server, ServerBuilder};
+use crate::server::storage::{self, Backends};
+use crate::server::tcp_transport::TcpTransport;
+use crate::server::udp_transport::UdpTransport;
+use crate::server::upstream::Upstream;
+use crate::server::tls;
+use futures::future::join_all;
+use futures::FutureExt;
+use std::fs::read_to_string;
+use std::io::{self, Read};
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::time::sleep;
+
+/// How long connections get to close after a shutdown was requested.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clap)]
+#[clap(version = VERSION_STRING)]
+struct Opts {
+	#[clap(short, long, default_value = "objtalk.toml", about = "filename or - to read from stdin")]
+	config: String,
+	#[clap(long, conflicts_with = "config", about = "run without a config file: http with the admin panel on port 3000, tcp on port 3001, objects only in memory")]
+	dev: bool,
+	#[clap(long, about = "run in the background once the server is up, output to a terminal is discarded")]
+	daemon: bool,
+	#[clap(long, about = "write the process id to this file")]
+	pidfile: Option<String>,
+	#[clap(long, about = "switch to this user after binding the ports")]
+	user: Option<String>,
+	#[clap(long, about = "switch to this group after binding the ports, defaults to the group of --user")]
+	group: Option<String>,
+	#[clap(subcommand)]
+	command: Option<Command>,
+}
+
+#[derive(Clap)]
+enum Command {
+	#[clap(about = "copy all objects, events and history between the storages of two config files")]
+	Migrate {
+		#[clap(long)]
+		from: String,
+		#[clap(long)]
+		to: String,
+	},
+	#[clap(about = "print a commented config file with all options, to start a new one from")]
+	PrintConfig,
+}
+
+fn read_config(filename: &str) -> Result<Config, String> {
+	let config_contents = if filename == "-" {
+		let mut buffer = String::new();
+		io::stdin().read_to_string(&mut buffer).map_err(|e| format!("can't read config from stdin: {}", e))?;
+		buffer
+	} else {
+		read_to_string(filename).map_err(|e| format!("can't read config file: {}", e))?
+	};
+	
+	toml::from_str(&config_contents)
+		.map_err(|e| format!("invalid config: {}", e))
+}
+
+async fn migrate(from: &str, to: &str, backends: &Backends) -> Result<(), String> {
+	let from_config = read_config(from)?.storage.ok_or(format!("{} has no storage config", from))?;
+	let to_config = read_config(to)?.storage.ok_or(format!("{} has no storage config", to))?;
+	
+	let from_storage = storage::from_config(&from_config, backends)?;
+	let to_storage = storage::from_config(&to_config, backends)?;
+	
+	let stats = storage::migrate(from_storage.as_ref(), to_storage.as_ref()).await
+		.map_err(|e| format!("migration failed: {}", e))?;
+	println!("migrated {} objects, {} events and {} revisions", stats.objects, stats.events, stats.revisions);
+	
+	Ok(())
+}
+
+/// Completes on ctrl-c or, on unix, SIGTERM.
+async fn shutdown_signal() {
+	#[cfg(unix)]
+	{
+		use tokio::signal::unix::{signal, SignalKind};
+		
+		let mut terminate = signal(SignalKind::terminate()).unwrap();
+		
+		tokio::select! {
+			_ = tokio::signal::ctrl_c() => {},
+			_ = terminate.recv() => {},
+		}
+	}
+	
+	#[cfg(not(unix))]
+	let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Waits for the connections to close, then runs the disconnect commands of
+/// the clients that are left, writes the pending changes to the storage and
+/// closes it.
+async fn drain(server: &Server) {
+	let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+	
+	while server.connected_clients() > 0 && Instant::now() < deadline {
+		sleep(Duration::from_millis(50)).await;
+	}
+	
+	server.run_disconnect_commands();
+	
+	if let Err(e) = server.close_storage() {
+		println!("can't close the storage: {}", e);
+	}
+}
+
+async fn bind(addr: std::net::SocketAddr) -> Result<TcpListener, String> {
+	TcpListener::bind(addr).await.map_err(|e| format!("can't listen on {}: {}", addr, e))
+}
+
+/// Parses the command line and runs the server or a subcommand, creating
+/// storages with the backends registered on `builder`. `--daemon` is only
+/// supported by `main`, since forking within a runtime isn't safe.
+pub async fn run(builder: ServerBuilder) -> Result<(), String> {
+	let opts: Opts = Opts::parse();
+	
+	if opts.daemon {
+		return Err("--daemon needs cli::main".to_string());
+	}
+	
+	serve(opts, builder, || {}).await
+}
+
+/// Runs the server or a subcommand, calls `ready` once the server is up.
+async fn serve<F: FnOnce()>(opts: Opts, mut builder: ServerBuilder, ready: F) -> Result<(), String> {
+	match opts.command {
+		Some(Command::Migrate { from, to }) => return migrate(&from, &to, builder.backends()).await,
+		Some(Command::PrintConfig) => {
+			print!("{}", DEFAULT_CONFIG);
+			return Ok(());
+		},
+		None => {},
+	}
+	
+	let config = if opts.dev {
+		println!("dev mode, objects are kept in memory only");
+		toml::from_str(DEV_CONFIG).unwrap()
+	} else {
+		read_config(&opts.config)?
+	};
+	
+	// bound before dropping privileges, so low ports can be used
+	let mut http_listeners = vec![];
+	for conf in &config.http {
+		http_listeners.push(bind(conf.addr).await?);
+	}
+	
+	let mut tcp_listeners = vec![];
+	for conf in &config.tcp {
+		tcp_listeners.push(bind(conf.addr).await?);
+	}
+	
+	let mut udp_sockets = vec![];
+	for conf in &config.udp {
+		udp_sockets.push(UdpSocket::bind(conf.addr).await.map_err(|e| format!("can't listen on {}: {}", conf.addr, e))?);
+	}
+	
+	#[cfg(not(unix))]
+	if opts.pidfile.is_some() || opts.user.is_some() || opts.group.is_some() {
+		return Err("--pidfile, --user and --group are only supported on unix".to_string());
+	}
+	
+	// written while still privileged, e.g. to /var/run
+	#[cfg(unix)]
+	let _pidfile = opts.pidfile.as_deref().map(Pidfile::create).transpose()?;
+	
+	#[cfg(unix)]
+	daemon::drop_privileges(opts.user.as_deref(), opts.group.as_deref())?;
+	
+	let maintenance_interval = config.storage.as_ref().and_then(|storage_config| storage_config.maintenance.interval);
+	
+	if let Some(storage_config) = config.storage {
+		builder = builder.storage(storage_config);
+	}
+	
+	builder = builder.log_level(config.log_level);
+	
+	let sample_rules = config.log_sample.iter()
+		.map(|sample| SampleRule::new(&sample.message_type, sample.pattern.as_deref(), sample.every))
+		.collect::<Result<Vec<_>, _>>()
+		.map_err(|e| format!("invalid log-sample: {}", e))?;
+	builder = builder.log_sampling(sample_rules);
+	builder = builder.system_log(config.system_log);
+	builder = builder.admin_identities(config.admin_identities);
+	
+	if let Some(threshold) = config.stats.slow_operation_threshold {
+		builder = builder.slow_operation_threshold(Duration::from_millis(threshold));
+	}
+	
+	if !config.log.is_empty() {
+		let mut loggers: Vec<Box<dyn Logger + Send>> = vec![];
+		
+		for conf in &config.log {
+			let logger: Box<dyn Logger + Send> = match (conf.format, &conf.file) {
+				(LogFormat::Text, None) => Box::new(StdoutLogger::new()),
+				(LogFormat::Json, None) => Box::new(JsonLogger::new()),
+				(LogFormat::Text, Some(file)) => Box::new(StdoutLogger::to_file(file)
+					.map_err(|e| format!("can't open log file {}: {}", file.display(), e))?),
+				(LogFormat::Json, Some(file)) => Box::new(JsonLogger::to_file(file)
+					.map_err(|e| format!("can't open log file {}: {}", file.display(), e))?),
+			};
+			loggers.push(logger);
+		}
+		
+		let logger: Box<dyn Logger + Send> = match loggers.len() {
+			1 => loggers.pop().unwrap(),
+			_ => Box::new(FanoutLogger::new(loggers)),
+		};
+		builder = builder.logger(logger);
+	}
+	
+	let server = builder.build()?;
+	
+	if let Some(interval) = maintenance_interval {
+		server.start_maintenance(Duration::from_secs(interval));
+	}
+	
+	if let Some(interval) = config.stats.interval.filter(|interval| *interval > 0) {
+		server.start_live_stats(Duration::from_secs(interval));
+	}
+	
+	#[cfg(not(feature = "mdns"))]
+	if config.mdns.is_some() {
+		return Err("build without mdns support".to_string());
+	}
+	
+	// kept alive until the transports stop
+	#[cfg(feature = "mdns")]
+	let _advertisement = match &config.mdns {
+		Some(mdns_config) => Some(mdns::advertise(mdns_config, &config.http, &config.tcp)?),
+		None => None,
+	};
+	
+	let mut transports = vec![];
+	
+	let shutdown_server = server.clone();
+	tokio::spawn(async move {
+		shutdown_signal().await;
+		println!("shutting down");
+		shutdown_server.shutdown();
+	});
+	
+	for (conf, listener) in config.http.into_iter().zip(http_listeners) {
+		let tls = match &conf.tls {
+			Some(tls_config) => Some(tls::http_acceptor(tls_config)?),
+			None => None,
+		};
+		
+		let limits = ConnectionLimits {
+			max_connections: conf.max_connections,
+			idle_timeout: conf.idle_timeout.map(Duration::from_secs),
+		};
+		
+		let websocket_ping = match conf.websocket_ping_interval {
+			0 => None,
+			seconds => Some(Ping {
+				interval: Duration::from_secs(seconds),
+				timeout: Duration::from_secs(conf.websocket_pong_timeout),
+			}),
+		};
+		
+		let tokens = match &conf.auth {
+			Some(auth_config) => Some(Tokens::from_config(auth_config)?),
+			None => None,
+		};
+		
+		let admin_credentials = AdminCredentials::from_config(&conf.admin)?;
+		
+		if admin_credentials.is_some() && !conf.admin.enabled {
+			return Err("admin.password is set, but the admin panel isn't enabled".to_string());
+		}
+		
+		// without a login, the panel's websocket can't be told apart from other clients
+		if conf.admin.read_only && admin_credentials.is_none() {
+			return Err("admin.read-only is set, but admin.password isn't".to_string());
+		}
+		
+		let keepalive_interval = match conf.keepalive_interval {
+			0 => None,
+			seconds => Some(Duration::from_secs(seconds)),
+		};
+		
+		let transport = HttpTransport::new(conf.addr, server.clone(), conf.allow_origin, keepalive_interval, websocket_ping, conf.max_body_size, conf.admin.enabled, conf.admin.asset_overrides, admin_credentials, conf.admin.read_only, conf.admin.backup_dir, conf.admin.max_restore_size, tokens, limits, conf.trusted_proxies, conf.proxy_protocol, conf.h2c, tls);
+		transports.push(async move {
+			transport.serve_on(listener).await;
+		}.boxed());
+	}
+	
+	for (conf, listener) in config.tcp.iter().zip(tcp_listeners) {
+		let tls = match &conf.tls {
+			Some(tls_config) => Some(tls::acceptor(tls_config)?),
+			None => None,
+		};
+		
+		let transport = TcpTransport::new(conf, server.clone(), tls);
+		transports.push(async move {
+			transport.serve_on(listener).await;
+		}.boxed());
+	}
+	
+	for (conf, socket) in config.udp.into_iter().zip(udp_sockets) {
+		let transport = UdpTransport::new(conf.addr, server.clone(), conf.format);
+		transports.push(async move {
+			transport.serve_on(socket).await;
+		}.boxed());
+	}
+	
+	for conf in config.upstream {
+		let upstream = Upstream::new(conf, server.clone())?;
+		transports.push(async move {
+			upstream.serve().await;
+		}.boxed());
+	}
+	
+	if let Some(conf) = config.replication {
+		server.set_read_only(true);
+		
+		let follower = Follower::new(conf, server.clone())?;
+		transports.push(async move {
+			follower.serve().await;
+		}.boxed());
+	}
+	
+	#[cfg(not(feature = "mqtt"))]
+	if !config.mqtt.is_empty() {
+		return Err("build without mqtt support".to_string());
+	}
+	
+	#[cfg(feature = "mqtt")]
+	for conf in config.mqtt {
+		let bridge = MqttBridge::new(conf, server.clone())?;
+		transports.push(async move {
+			bridge.serve().await;
+		}.boxed());
+	}
+	
+	for conf in config.nats {
+		let bridge = NatsBridge::new(conf, server.clone())?;
+		transports.push(async move {
+			bridge.serve().await;
+		}.boxed());
+	}
+	
+	#[cfg(not(feature = "webhooks"))]
+	if !config.webhook.is_empty() {
+		return Err("build without webhook support".to_string());
+	}
+	
+	#[cfg(feature = "webhooks")]
+	for conf in config.webhook {
+		let webhook = Webhook::new(conf, server.clone())?;
+		transports.push(async move {
+			webhook.serve().await;
+		}.boxed());
+	}
+	
+	#[cfg(not(feature = "influxdb"))]
+	if !config.influxdb.is_empty() {
+		return Err("build without influxdb support".to_string());
+	}
+	
+	#[cfg(feature = "influxdb")]
+	for conf in config.influxdb {
+		let exporter = InfluxdbExporter::new(conf, server.clone())?;
+		transports.push(async move {
+			exporter.serve().await;
+		}.boxed());
+	}
+	
+	ready();
+	
+	// the transports stop accepting connections, their connections close on their own
+	tokio::select! {
+		_ = join_all(transports) => {},
+		_ = server.shutting_down() => {},
+	}
+	
+	drain(&server).await;
+	
+	Ok(())
+}
+
+/// Entry point of `objtalk-server`. Embedders with custom storage backends
+/// can call it from their own binary to get the same command line interface.
+pub fn main(builder: ServerBuilder) {
+	let opts: Opts = Opts::parse();
+	
+	#[cfg(not(unix))]
+	if opts.daemon {
+		eprintln!("--daemon is only supported on unix");
+		std::process::exit(1);
+	}
+	
+	// before the runtime starts its threads
+	#[cfg(unix)]
+	let daemon = match opts.daemon {
+		true => match daemon::daemonize() {
+			Ok(ready) => Some(ready),
+			Err(error) => {
+				eprintln!("{}", error);
+				std::process::exit(1);
+			},
+		},
+		false => None,
+	};
+	
+	let runtime = tokio::runtime::Builder::new_current_thread()
+		.enable_all()
+		.build()
+		.unwrap();
+	
+	let ready = move || {
+		#[cfg(unix)]
+		if let Some(ready) = daemon {
+			ready.notify();
+		}
+	};
+	
+	if let Err(error) = runtime.block_on(serve(opts, builder, ready)) {
+		eprintln!("{}", error);
+		std::process::exit(1);
+	}
+}
@@ -1,18 +1,331 @@
-use std::net::SocketAddr;
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
 use std::path::PathBuf;
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer};
+use serde::de::Error;
+use crate::{AuditOperation, LogLevel};
+
+#[derive(Deserialize, Debug, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+#[serde(deny_unknown_fields)]
+pub struct HistoryConfig {
+	#[serde(default)]
+	pub enabled: bool,
+	#[serde(default)]
+	pub retention: Option<u64>,
+	#[serde(default)]
+	pub max_revisions: Option<u32>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SqliteSynchronous {
+	Off,
+	Normal,
+	Full,
+	Extra,
+}
 
 #[derive(Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
 #[serde(deny_unknown_fields)]
 pub struct SqliteConfig {
 	pub filename: String,
+	#[serde(default)]
+	pub history: HistoryConfig,
+	#[serde(default)]
+	pub wal: bool,
+	#[serde(default)]
+	pub synchronous: Option<SqliteSynchronous>,
+	/// Milliseconds to wait for a locked database before failing.
+	#[serde(default)]
+	pub busy_timeout: Option<u64>,
+	/// Positive values are pages, negative values KiB, as in `PRAGMA cache_size`.
+	#[serde(default)]
+	pub cache_size: Option<i64>,
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct SledConfig {
+	pub path: String,
+}
+
+fn default_snapshot_interval() -> u64 {
+	60
+}
+
+fn default_s3_key() -> String {
+	"objtalk.json".to_string()
+}
+
+fn default_s3_region() -> String {
+	"us-east-1".to_string()
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+#[serde(deny_unknown_fields)]
+pub struct S3Config {
+	pub endpoint: String,
+	pub bucket: String,
+	#[serde(default = "default_s3_key")]
+	pub key: String,
+	#[serde(default = "default_s3_region")]
+	pub region: String,
+	/// Falls back to the `AWS_ACCESS_KEY_ID` environment variable.
+	#[serde(default)]
+	pub access_key_id: Option<String>,
+	/// Falls back to the `AWS_SECRET_ACCESS_KEY` environment variable.
+	#[serde(default)]
+	pub secret_access_key: Option<String>,
 }
 
 #[derive(Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+#[serde(deny_unknown_fields)]
+pub struct SnapshotConfig {
+	#[serde(default)]
+	pub filename: Option<String>,
+	#[serde(default)]
+	pub s3: Option<S3Config>,
+	#[serde(default = "default_snapshot_interval")]
+	pub interval: u64,
+	#[serde(default)]
+	pub max_changes: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+#[serde(deny_unknown_fields)]
+pub struct EncryptionConfig {
+	#[serde(default)]
+	pub key: Option<String>,
+	#[serde(default)]
+	pub key_env: Option<String>,
+	/// Accepts values stored before encryption was enabled.
+	#[serde(default)]
+	pub allow_plaintext: bool,
+}
+
+#[derive(Deserialize)]
 #[serde(tag = "backend")]
 #[serde(rename_all = "kebab-case")]
-pub enum StorageConfig {
-	Sqlite { sqlite: SqliteConfig }
+enum BuiltinBackendConfig {
+	Sqlite { sqlite: SqliteConfig },
+	Sled { sled: SledConfig },
+	Snapshot { snapshot: SnapshotConfig },
+}
+
+#[derive(Debug, PartialEq)]
+pub enum StorageBackendConfig {
+	Sqlite { sqlite: SqliteConfig },
+	Sled { sled: SledConfig },
+	Snapshot { snapshot: SnapshotConfig },
+	/// A backend registered by the embedder, see `storage::Backends`. Its
+	/// options are the table named like the backend, e.g. `[storage.redis]`.
+	Custom { backend: String, options: toml::Value },
+}
+
+impl StorageBackendConfig {
+	pub fn name(&self) -> &str {
+		match self {
+			StorageBackendConfig::Sqlite { .. } => "sqlite",
+			StorageBackendConfig::Sled { .. } => "sled",
+			StorageBackendConfig::Snapshot { .. } => "snapshot",
+			StorageBackendConfig::Custom { backend, .. } => backend,
+		}
+	}
+}
+
+impl<'de> Deserialize<'de> for StorageBackendConfig {
+	fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+		let mut table = toml::value::Table::deserialize(deserializer)?;
+		
+		let backend = match table.get("backend") {
+			Some(toml::Value::String(backend)) => backend.clone(),
+			Some(_) => return Err(D::Error::custom("backend must be a string")),
+			None => return Err(D::Error::missing_field("backend")),
+		};
+		
+		match backend.as_str() {
+			"sqlite" | "sled" | "snapshot" => {
+				let config = BuiltinBackendConfig::deserialize(toml::Value::Table(table))
+					.map_err(D::Error::custom)?;
+				
+				Ok(match config {
+					BuiltinBackendConfig::Sqlite { sqlite } => StorageBackendConfig::Sqlite { sqlite },
+					BuiltinBackendConfig::Sled { sled } => StorageBackendConfig::Sled { sled },
+					BuiltinBackendConfig::Snapshot { snapshot } => StorageBackendConfig::Snapshot { snapshot },
+				})
+			},
+			_ => Ok(StorageBackendConfig::Custom {
+				options: table.remove(&backend).unwrap_or_else(|| toml::Value::Table(toml::value::Table::new())),
+				backend,
+			}),
+		}
+	}
+}
+
+fn default_event_retention() -> u64 {
+	7 * 24 * 60 * 60
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+#[serde(deny_unknown_fields)]
+pub struct EventsConfig {
+	#[serde(default)]
+	pub enabled: bool,
+	#[serde(default = "default_event_retention")]
+	pub retention: u64,
+}
+
+impl Default for EventsConfig {
+	fn default() -> Self {
+		EventsConfig {
+			enabled: false,
+			retention: default_event_retention(),
+		}
+	}
+}
+
+fn default_audit_operations() -> Vec<AuditOperation> {
+	vec![AuditOperation::Set, AuditOperation::Patch, AuditOperation::Remove, AuditOperation::Invoke]
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+#[serde(deny_unknown_fields)]
+pub struct AuditConfig {
+	#[serde(default)]
+	pub enabled: bool,
+	/// Seconds entries are kept, forever if unset.
+	#[serde(default)]
+	pub retention: Option<u64>,
+	#[serde(default = "default_audit_operations")]
+	pub operations: Vec<AuditOperation>,
+}
+
+impl Default for AuditConfig {
+	fn default() -> Self {
+		AuditConfig {
+			enabled: false,
+			retention: None,
+			operations: default_audit_operations(),
+		}
+	}
+}
+
+#[derive(Deserialize, Debug, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct MaintenanceConfig {
+	/// Seconds between maintenance runs, disabled if unset.
+	#[serde(default)]
+	pub interval: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+	/// Colored lines for humans.
+	#[default]
+	Text,
+	/// A json object per line, for log collectors.
+	Json,
+}
+
+#[derive(Deserialize, Debug, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct LogConfig {
+	#[serde(default)]
+	pub format: LogFormat,
+	/// Appended to instead of writing to stdout.
+	#[serde(default)]
+	pub file: Option<PathBuf>,
+}
+
+/// Logs one in `every` messages of a type, e.g. `set`, optionally only
+/// those about objects matching `pattern`.
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct LogSampleConfig {
+	#[serde(rename = "type")]
+	pub message_type: String,
+	#[serde(default)]
+	pub pattern: Option<String>,
+	pub every: u64,
+}
+
+fn default_system_log_enabled() -> bool {
+	true
+}
+
+/// Which log messages are emitted as `log` events on the `$system` object.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+#[serde(deny_unknown_fields)]
+pub struct SystemLogConfig {
+	#[serde(default = "default_system_log_enabled")]
+	pub enabled: bool,
+	/// Only clients connected with one of these identities receive the
+	/// events, all clients if unset.
+	#[serde(default)]
+	pub identities: Option<Vec<String>>,
+	/// Only messages of these types, e.g. `set`, all if unset.
+	#[serde(default)]
+	pub types: Option<Vec<String>>,
+}
+
+impl Default for SystemLogConfig {
+	fn default() -> Self {
+		SystemLogConfig {
+			enabled: default_system_log_enabled(),
+			identities: None,
+			types: None,
+		}
+	}
+}
+
+fn one_or_list<'de, D: Deserializer<'de>, T: Deserialize<'de>>(deserializer: D) -> Result<Vec<T>, D::Error> {
+	#[derive(Deserialize)]
+	#[serde(untagged)]
+	enum OneOrList<T> {
+		One(T),
+		List(Vec<T>),
+	}
+	
+	Ok(match OneOrList::deserialize(deserializer)? {
+		OneOrList::One(one) => vec![one],
+		OneOrList::List(list) => list,
+	})
+}
+
+#[derive(Deserialize, Debug, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+#[serde(deny_unknown_fields)]
+pub struct StatsConfig {
+	/// Seconds between `stats` events on `$system`, disabled if unset.
+	#[serde(default)]
+	pub interval: Option<u64>,
+	/// Milliseconds after which an operation or storage write is reported
+	/// as slow, disabled if unset.
+	#[serde(default)]
+	pub slow_operation_threshold: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+pub struct StorageConfig {
+	#[serde(flatten)]
+	pub backend: StorageBackendConfig,
+	#[serde(default)]
+	pub encryption: Option<EncryptionConfig>,
+	#[serde(default)]
+	pub events: EventsConfig,
+	#[serde(default)]
+	pub audit: AuditConfig,
+	#[serde(default)]
+	pub maintenance: MaintenanceConfig,
 }
 
 #[derive(Deserialize, Debug, Default, PartialEq)]
@@ -23,6 +336,79 @@ pub struct AdminConfig {
 	pub enabled: bool,
 	#[serde(default)]
 	pub asset_overrides: Option<PathBuf>,
+	/// For basic auth on the admin panel and api, `admin` if unset.
+	#[serde(default)]
+	pub username: Option<String>,
+	/// Protects the admin panel and api with basic auth if set.
+	#[serde(default)]
+	pub password: Option<String>,
+	/// Environment variable with the password, instead of `password`.
+	#[serde(default)]
+	pub password_env: Option<String>,
+	/// Rejects changes through the admin api and with the admin login.
+	#[serde(default)]
+	pub read_only: bool,
+	/// Directory `POST /backup` writes backups to.
+	#[serde(default)]
+	pub backup_dir: Option<PathBuf>,
+	/// Bytes a backup posted to `/restore` may have, 64 MiB if unset.
+	#[serde(default)]
+	pub max_restore_size: Option<usize>,
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+	/// PEM file with the certificate chain.
+	pub cert: PathBuf,
+	/// PEM file with the PKCS#8 or RSA private key.
+	pub key: PathBuf,
+	/// PEM file with the CA certificates client certificates must be signed
+	/// by. Client certificates are required if set.
+	#[serde(default)]
+	pub client_ca: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Debug, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+#[serde(deny_unknown_fields)]
+pub struct AuthConfig {
+	#[serde(default)]
+	pub tokens: Vec<String>,
+	/// File with one token per line.
+	#[serde(default)]
+	pub token_file: Option<PathBuf>,
+}
+
+fn string_or_list<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+	#[derive(Deserialize)]
+	#[serde(untagged)]
+	enum StringOrList {
+		String(String),
+		List(Vec<String>),
+	}
+	
+	Ok(match StringOrList::deserialize(deserializer)? {
+		StringOrList::String(string) => vec![string],
+		StringOrList::List(list) => list,
+	})
+}
+
+fn default_keepalive_interval() -> u64 {
+	30
+}
+
+fn default_websocket_ping_interval() -> u64 {
+	30
+}
+
+fn default_websocket_pong_timeout() -> u64 {
+	10
+}
+
+fn default_max_body_size() -> usize {
+	1024 * 1024
 }
 
 #[derive(Deserialize, Debug, PartialEq)]
@@ -30,26 +416,386 @@ pub struct AdminConfig {
 #[serde(deny_unknown_fields)]
 pub struct HttpConfig {
 	pub addr: SocketAddr,
+	/// Origins allowed to make cross-origin requests, `*` allows all.
+	#[serde(default, deserialize_with = "string_or_list")]
+	pub allow_origin: Vec<String>,
+	/// Seconds of inactivity after which a comment is sent on event streams,
+	/// 0 disables keepalives.
+	#[serde(default = "default_keepalive_interval")]
+	pub keepalive_interval: u64,
+	/// Seconds between pings on websockets, 0 disables pings.
+	#[serde(default = "default_websocket_ping_interval")]
+	pub websocket_ping_interval: u64,
+	/// Seconds to wait for a pong before closing the websocket.
+	#[serde(default = "default_websocket_pong_timeout")]
+	pub websocket_pong_timeout: u64,
+	/// Bytes a request body may have, larger ones are rejected with 413.
+	#[serde(default = "default_max_body_size")]
+	pub max_body_size: usize,
+	/// Connections above this are closed right away.
 	#[serde(default)]
-	pub allow_origin: Option<String>,
+	pub max_connections: Option<usize>,
+	/// Seconds without traffic after which a connection is closed.
+	#[serde(default)]
+	pub idle_timeout: Option<u64>,
+	/// Proxies whose `X-Forwarded-For` headers are trusted.
+	#[serde(default)]
+	pub trusted_proxies: Vec<IpAddr>,
+	/// Expects a PROXY protocol header at the start of every connection.
+	#[serde(default)]
+	pub proxy_protocol: bool,
 	#[serde(default)]
 	pub admin: AdminConfig,
+	/// Requires a bearer token on all requests if set.
+	#[serde(default)]
+	pub auth: Option<AuthConfig>,
+	/// Accepts HTTP/2 without tls from clients that know the server supports
+	/// it. With tls, HTTP/2 is always negotiated via ALPN.
+	#[serde(default)]
+	pub h2c: bool,
+	#[serde(default)]
+	pub tls: Option<TlsConfig>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageFormat {
+	/// JSON messages, delimited by newlines on tcp.
+	#[default]
+	Json,
+	/// CBOR messages, prefixed with their length as a 32 bit big-endian integer on tcp.
+	Cbor,
+}
+
+fn default_keepalive_time() -> u64 {
+	60
+}
+
+fn default_keepalive_interval_tcp() -> u64 {
+	10
+}
+
+fn default_keepalive_retries() -> u32 {
+	3
+}
+
+/// TCP keepalive probes, so connections to devices that vanished without
+/// closing them are detected by the operating system.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+#[serde(deny_unknown_fields)]
+pub struct TcpKeepaliveConfig {
+	/// Seconds without traffic before the first probe.
+	#[serde(default = "default_keepalive_time")]
+	pub time: u64,
+	/// Seconds between probes.
+	#[serde(default = "default_keepalive_interval_tcp")]
+	pub interval: u64,
+	/// Unanswered probes after which the connection is closed.
+	#[serde(default = "default_keepalive_retries")]
+	pub retries: u32,
+}
+
+fn default_ping_timeout() -> u64 {
+	10
 }
 
 #[derive(Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
 #[serde(deny_unknown_fields)]
 pub struct TcpConfig {
 	pub addr: SocketAddr,
+	#[serde(default)]
+	pub tls: Option<TlsConfig>,
+	#[serde(default)]
+	pub format: MessageFormat,
+	/// Connections above this are closed right away.
+	#[serde(default)]
+	pub max_connections: Option<usize>,
+	/// Seconds without traffic after which a connection is closed.
+	#[serde(default)]
+	pub idle_timeout: Option<u64>,
+	#[serde(default)]
+	pub keepalive: Option<TcpKeepaliveConfig>,
+	/// Seconds without a message from the client after which it's sent a
+	/// ping it has to answer within `ping_timeout` seconds.
+	#[serde(default)]
+	pub ping_interval: Option<u64>,
+	#[serde(default = "default_ping_timeout")]
+	pub ping_timeout: u64,
+	/// Expects a PROXY protocol header at the start of every connection.
+	#[serde(default)]
+	pub proxy_protocol: bool,
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct UdpConfig {
+	pub addr: SocketAddr,
+	#[serde(default)]
+	pub format: MessageFormat,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum MqttDirection {
+	/// Publish objtalk objects and events and apply messages from the broker.
+	#[default]
+	Both,
+	/// Only publish objtalk objects and events to the broker.
+	Out,
+	/// Only apply messages from the broker to objtalk.
+	In,
+}
+
+fn default_mqtt_port() -> u16 {
+	1883
+}
+
+fn default_mqtt_client_id() -> String {
+	"objtalk".to_string()
+}
+
+fn default_mqtt_prefix() -> String {
+	"objtalk/".to_string()
+}
+
+fn default_mqtt_pattern() -> String {
+	"*".to_string()
 }
 
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+#[serde(deny_unknown_fields)]
+pub struct MqttConfig {
+	pub host: String,
+	#[serde(default = "default_mqtt_port")]
+	pub port: u16,
+	#[serde(default = "default_mqtt_client_id")]
+	pub client_id: String,
+	#[serde(default)]
+	pub username: Option<String>,
+	#[serde(default)]
+	pub password: Option<String>,
+	/// Prepended to object names to get the topic names.
+	#[serde(default = "default_mqtt_prefix")]
+	pub prefix: String,
+	/// Objects that are bridged, in both directions.
+	#[serde(default = "default_mqtt_pattern")]
+	pub pattern: String,
+	#[serde(default)]
+	pub direction: MqttDirection,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum NatsDirection {
+	/// Publish changes and events and accept commands.
+	#[default]
+	Both,
+	/// Only publish changes and events.
+	Out,
+	/// Only accept commands.
+	In,
+}
+
+fn default_nats_addr() -> String {
+	"127.0.0.1:4222".to_string()
+}
+
+fn default_nats_prefix() -> String {
+	"objtalk.".to_string()
+}
+
+fn default_nats_pattern() -> String {
+	"*".to_string()
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+#[serde(deny_unknown_fields)]
+pub struct NatsConfig {
+	/// Host and port of the NATS server.
+	#[serde(default = "default_nats_addr")]
+	pub addr: String,
+	#[serde(default)]
+	pub username: Option<String>,
+	#[serde(default)]
+	pub password: Option<String>,
+	#[serde(default)]
+	pub token: Option<String>,
+	/// Prepended to all subjects.
+	#[serde(default = "default_nats_prefix")]
+	pub prefix: String,
+	/// Objects that are bridged.
+	#[serde(default = "default_nats_pattern")]
+	pub pattern: String,
+	#[serde(default)]
+	pub direction: NatsDirection,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum UpstreamDirection {
+	/// Mirror objects both ways.
+	#[default]
+	Both,
+	/// Only copy local objects to the upstream server.
+	Out,
+	/// Only copy objects of the upstream server to this one.
+	In,
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+#[serde(deny_unknown_fields)]
+pub struct UpstreamConfig {
+	/// Host and port of the upstream server's tcp transport.
+	pub addr: String,
+	/// Objects that are mirrored, relative to the prefixes.
+	pub pattern: String,
+	/// Prepended to the pattern and object names on this server.
+	#[serde(default)]
+	pub local_prefix: String,
+	/// Prepended to the pattern and object names on the upstream server.
+	#[serde(default)]
+	pub remote_prefix: String,
+	#[serde(default)]
+	pub direction: UpstreamDirection,
+}
+
+fn default_replication_pattern() -> String {
+	"*".to_string()
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+#[serde(deny_unknown_fields)]
+pub struct ReplicationConfig {
+	/// Host and port of the leader's tcp transport.
+	pub leader: String,
+	/// Objects that are replicated, all of them by default.
+	#[serde(default = "default_replication_pattern")]
+	pub pattern: String,
+}
+
+fn default_webhook_events() -> bool {
+	true
+}
+
+fn default_webhook_retries() -> u32 {
+	5
+}
+
+fn default_webhook_timeout() -> u64 {
+	10
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+#[serde(deny_unknown_fields)]
+pub struct WebhookConfig {
+	pub url: String,
+	/// Objects whose changes are posted.
+	pub pattern: String,
+	/// Also posts the events emitted on the objects.
+	#[serde(default = "default_webhook_events")]
+	pub events: bool,
+	/// Sent with every request, e.g. for authentication.
+	#[serde(default)]
+	pub headers: BTreeMap<String, String>,
+	/// Failed deliveries are retried this often with increasing delays.
+	#[serde(default = "default_webhook_retries")]
+	pub retries: u32,
+	/// Seconds a request may take.
+	#[serde(default = "default_webhook_timeout")]
+	pub timeout: u64,
+}
+
+fn default_influxdb_measurement() -> String {
+	"objtalk".to_string()
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+#[serde(deny_unknown_fields)]
+pub struct InfluxdbConfig {
+	/// The write endpoint of the HTTP API, or `udp://host:port` for line
+	/// protocol datagrams.
+	pub url: String,
+	/// Sent as `Authorization: Token <token>` with HTTP writes.
+	#[serde(default)]
+	pub token: Option<String>,
+	/// Objects whose numeric fields are written on every change.
+	pub pattern: String,
+	#[serde(default = "default_influxdb_measurement")]
+	pub measurement: String,
+}
+
+fn default_mdns_name() -> String {
+	"objtalk".to_string()
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct MdnsConfig {
+	/// Instance name the transports are advertised under, also used as host name.
+	#[serde(default = "default_mdns_name")]
+	pub name: String,
+}
+
+/// A commented configuration with all sections, printed by `print-config`.
+pub const DEFAULT_CONFIG: &str = include_str!("default-config.toml");
+
+/// Used with `--dev`, for local experiments without a config file.
+pub const DEV_CONFIG: &str = r#"
+[[http]]
+addr = "127.0.0.1:3000"
+admin.enabled = true
+allow-origin = "*"
+
+[[tcp]]
+addr = "127.0.0.1:3001"
+"#;
+
 #[derive(Deserialize, Debug, PartialEq)]
 #[serde(deny_unknown_fields)]
 pub struct Config {
 	pub storage: Option<StorageConfig>,
 	#[serde(default)]
+	pub stats: StatsConfig,
+	/// Colored lines on stdout if empty. `[log]` is taken as a single entry.
+	#[serde(default, deserialize_with = "one_or_list")]
+	pub log: Vec<LogConfig>,
+	#[serde(default, rename = "log-level")]
+	pub log_level: LogLevel,
+	#[serde(default, rename = "log-sample")]
+	pub log_sample: Vec<LogSampleConfig>,
+	#[serde(default, rename = "system-log")]
+	pub system_log: SystemLogConfig,
+	/// Clients with one of these identities, e.g. the common name of their
+	/// tls client certificate, may use the admin requests over tcp and
+	/// websockets.
+	#[serde(default, rename = "admin-identities")]
+	pub admin_identities: Vec<String>,
+	#[serde(default)]
 	pub http: Vec<HttpConfig>,
 	#[serde(default)]
 	pub tcp: Vec<TcpConfig>,
+	#[serde(default)]
+	pub udp: Vec<UdpConfig>,
+	#[serde(default)]
+	pub mqtt: Vec<MqttConfig>,
+	#[serde(default)]
+	pub nats: Vec<NatsConfig>,
+	#[serde(default)]
+	pub webhook: Vec<WebhookConfig>,
+	#[serde(default)]
+	pub influxdb: Vec<InfluxdbConfig>,
+	#[serde(default)]
+	pub upstream: Vec<UpstreamConfig>,
+	pub replication: Option<ReplicationConfig>,
+	pub mdns: Option<MdnsConfig>,
 }
 
 #[cfg(test)]
@@ -57,30 +803,321 @@ mod tests {
 	use super::*;
 	
 	#[test]
-	fn test_default() {
+	fn test_default() {
+		let config: Config = toml::from_str(r#"
+		"#).unwrap();
+		
+		assert_eq!(config.storage, None);
+		assert_eq!(config.stats, StatsConfig::default());
+		assert_eq!(config.log, vec![]);
+		assert_eq!(config.log_level, LogLevel::Trace);
+		assert_eq!(config.log_sample, vec![]);
+		assert_eq!(config.system_log, SystemLogConfig::default());
+		assert_eq!(config.admin_identities, Vec::<String>::new());
+		assert_eq!(config.http, vec![]);
+		assert_eq!(config.tcp, vec![]);
+		assert_eq!(config.udp, vec![]);
+		assert_eq!(config.mqtt, vec![]);
+		assert_eq!(config.nats, vec![]);
+		assert_eq!(config.webhook, vec![]);
+		assert_eq!(config.influxdb, vec![]);
+		assert_eq!(config.upstream, vec![]);
+		assert_eq!(config.replication, None);
+		assert_eq!(config.mdns, None);
+	}
+	
+	#[test]
+	fn test_default_config() {
+		let config: Config = toml::from_str(DEFAULT_CONFIG).unwrap();
+		
+		assert!(matches!(config.storage.unwrap().backend, StorageBackendConfig::Sqlite { .. }));
+		assert_eq!(config.http.len(), 1);
+		assert!(config.http[0].admin.enabled);
+		assert_eq!(config.tcp.len(), 1);
+		
+		// every commented option is valid, except the other storage backends
+		let uncommented: Vec<&str> = DEFAULT_CONFIG.lines()
+			.map(|line| match line.strip_prefix('#') {
+				Some(option) if !option.is_empty() && !option.starts_with(' ')
+					&& !["backend", "sled.", "snapshot."].iter().any(|prefix| option.starts_with(prefix)) => option,
+				_ => line,
+			})
+			.collect();
+		
+		let config: Config = toml::from_str(&uncommented.join("\n")).unwrap();
+		assert_eq!(config.storage.unwrap().audit.retention, Some(7776000));
+		assert_eq!(config.stats.interval, Some(2));
+		assert_eq!(config.stats.slow_operation_threshold, Some(100));
+		assert_eq!(config.log[0].format, LogFormat::Json);
+		assert_eq!(config.log[0].file, Some(PathBuf::from("objtalk.log")));
+		assert_eq!(config.log_level, LogLevel::Info);
+		assert_eq!(config.log_sample[0].every, 100);
+		assert_eq!(config.system_log.identities, Some(vec!["admin".to_string()]));
+		assert_eq!(config.http[0].auth.as_ref().unwrap().tokens, vec!["secret".to_string()]);
+		assert_eq!(config.tcp[0].keepalive.unwrap().retries, 3);
+		assert_eq!(config.udp.len(), 1);
+		assert_eq!(config.mqtt.len(), 1);
+		assert_eq!(config.nats.len(), 1);
+		assert_eq!(config.upstream.len(), 1);
+		assert_eq!(config.webhook.len(), 1);
+		assert_eq!(config.influxdb.len(), 1);
+		assert!(config.replication.is_some());
+		assert!(config.mdns.is_some());
+	}
+	
+	#[test]
+	fn test_dev_config() {
+		let config: Config = toml::from_str(DEV_CONFIG).unwrap();
+		
+		assert_eq!(config.storage, None);
+		assert_eq!(config.http[0].addr, "127.0.0.1:3000".parse().unwrap());
+		assert!(config.http[0].admin.enabled);
+		assert_eq!(config.tcp[0].addr, "127.0.0.1:3001".parse().unwrap());
+	}
+	
+	#[test]
+	fn test_storage_sqlite() {
+		let config: Config = toml::from_str(r#"
+			[storage]
+			backend = "sqlite"
+			sqlite.filename = "objtalk.db"
+		"#).unwrap();
+		
+		assert_eq!(config.storage, Some(StorageConfig {
+			backend: StorageBackendConfig::Sqlite {
+				sqlite: SqliteConfig {
+					filename: "objtalk.db".to_string(),
+					history: HistoryConfig::default(),
+					wal: false,
+					synchronous: None,
+					busy_timeout: None,
+					cache_size: None,
+				}
+			},
+			encryption: None,
+			events: EventsConfig::default(),
+			audit: AuditConfig::default(),
+			maintenance: MaintenanceConfig::default(),
+		}));
+	}
+	
+	#[test]
+	fn test_storage_sqlite_history() {
+		let config: Config = toml::from_str(r#"
+			[storage]
+			backend = "sqlite"
+			sqlite.filename = "objtalk.db"
+			sqlite.history.enabled = true
+			sqlite.history.retention = 2592000
+			sqlite.history.max-revisions = 1000
+		"#).unwrap();
+		
+		if let StorageBackendConfig::Sqlite { sqlite } = config.storage.unwrap().backend {
+			assert_eq!(sqlite.history, HistoryConfig {
+				enabled: true,
+				retention: Some(2592000),
+				max_revisions: Some(1000),
+			});
+		} else {
+			panic!("unexpected backend");
+		}
+	}
+	
+	#[test]
+	fn test_storage_sqlite_tuning() {
+		let config: Config = toml::from_str(r#"
+			[storage]
+			backend = "sqlite"
+			sqlite.filename = "objtalk.db"
+			sqlite.wal = true
+			sqlite.synchronous = "normal"
+			sqlite.busy-timeout = 5000
+			sqlite.cache-size = -8000
+		"#).unwrap();
+		
+		if let StorageBackendConfig::Sqlite { sqlite } = config.storage.unwrap().backend {
+			assert!(sqlite.wal);
+			assert_eq!(sqlite.synchronous, Some(SqliteSynchronous::Normal));
+			assert_eq!(sqlite.busy_timeout, Some(5000));
+			assert_eq!(sqlite.cache_size, Some(-8000));
+		} else {
+			panic!("unexpected backend");
+		}
+	}
+	
+	#[test]
+	fn test_storage_sled() {
+		let config: Config = toml::from_str(r#"
+			[storage]
+			backend = "sled"
+			sled.path = "objtalk.sled"
+		"#).unwrap();
+		
+		assert_eq!(config.storage, Some(StorageConfig {
+			backend: StorageBackendConfig::Sled {
+				sled: SledConfig {
+					path: "objtalk.sled".to_string(),
+				}
+			},
+			encryption: None,
+			events: EventsConfig::default(),
+			audit: AuditConfig::default(),
+			maintenance: MaintenanceConfig::default(),
+		}));
+	}
+	
+	#[test]
+	fn test_storage_snapshot() {
 		let config: Config = toml::from_str(r#"
+			[storage]
+			backend = "snapshot"
+			snapshot.filename = "objtalk.json"
+			snapshot.max-changes = 100
 		"#).unwrap();
 		
-		assert_eq!(config.storage, None);
-		assert_eq!(config.http, vec![]);
-		assert_eq!(config.tcp, vec![]);
+		assert_eq!(config.storage, Some(StorageConfig {
+			backend: StorageBackendConfig::Snapshot {
+				snapshot: SnapshotConfig {
+					filename: Some("objtalk.json".to_string()),
+					s3: None,
+					interval: 60,
+					max_changes: Some(100),
+				}
+			},
+			encryption: None,
+			events: EventsConfig::default(),
+			audit: AuditConfig::default(),
+			maintenance: MaintenanceConfig::default(),
+		}));
 	}
 	
 	#[test]
-	fn test_storage_sqlite() {
+	fn test_storage_snapshot_s3() {
+		let config: Config = toml::from_str(r#"
+			[storage]
+			backend = "snapshot"
+			snapshot.interval = 300
+			snapshot.s3.endpoint = "https://s3.eu-central-1.amazonaws.com"
+			snapshot.s3.bucket = "objtalk"
+			snapshot.s3.region = "eu-central-1"
+		"#).unwrap();
+		
+		if let StorageBackendConfig::Snapshot { snapshot } = config.storage.unwrap().backend {
+			assert_eq!(snapshot.filename, None);
+			assert_eq!(snapshot.s3, Some(S3Config {
+				endpoint: "https://s3.eu-central-1.amazonaws.com".to_string(),
+				bucket: "objtalk".to_string(),
+				key: "objtalk.json".to_string(),
+				region: "eu-central-1".to_string(),
+				access_key_id: None,
+				secret_access_key: None,
+			}));
+		} else {
+			panic!("unexpected backend");
+		}
+	}
+	
+	#[test]
+	fn test_storage_custom() {
+		let config: Config = toml::from_str(r#"
+			[storage]
+			backend = "redis"
+			redis.url = "redis://127.0.0.1"
+			events.enabled = true
+		"#).unwrap();
+		
+		let storage = config.storage.unwrap();
+		assert!(storage.events.enabled);
+		assert_eq!(storage.backend, StorageBackendConfig::Custom {
+			backend: "redis".to_string(),
+			options: toml::from_str(r#"url = "redis://127.0.0.1""#).unwrap(),
+		});
+	}
+	
+	#[test]
+	fn test_storage_invalid() {
+		assert!(toml::from_str::<Config>(r#"
+			[storage]
+			sqlite.filename = "objtalk.db"
+		"#).is_err());
+		
+		assert!(toml::from_str::<Config>(r#"
+			[storage]
+			backend = "sqlite"
+			sqlite.filename = "objtalk.db"
+			sqlite.foo = true
+		"#).is_err());
+	}
+	
+	#[test]
+	fn test_storage_encryption() {
 		let config: Config = toml::from_str(r#"
 			[storage]
 			backend = "sqlite"
 			sqlite.filename = "objtalk.db"
+			encryption.key-env = "OBJTALK_STORAGE_KEY"
 		"#).unwrap();
 		
-		assert_eq!(config.storage, Some(StorageConfig::Sqlite {
-			sqlite: SqliteConfig {
-				filename: "objtalk.db".to_string(),
-			}
+		assert_eq!(config.storage.unwrap().encryption, Some(EncryptionConfig {
+			key: None,
+			key_env: Some("OBJTALK_STORAGE_KEY".to_string()),
+			allow_plaintext: false,
 		}));
 	}
 	
+	#[test]
+	fn test_storage_events() {
+		let config: Config = toml::from_str(r#"
+			[storage]
+			backend = "sqlite"
+			sqlite.filename = "objtalk.db"
+			events.enabled = true
+			events.retention = 3600
+		"#).unwrap();
+		
+		assert_eq!(config.storage.unwrap().events, EventsConfig {
+			enabled: true,
+			retention: 3600,
+		});
+	}
+	
+	#[test]
+	fn test_storage_audit() {
+		let config: Config = toml::from_str(r#"
+			[storage]
+			backend = "sqlite"
+			sqlite.filename = "objtalk.db"
+			audit.enabled = true
+			audit.retention = 7776000
+			audit.operations = ["set", "emit"]
+		"#).unwrap();
+		
+		assert_eq!(config.storage.unwrap().audit, AuditConfig {
+			enabled: true,
+			retention: Some(7776000),
+			operations: vec![AuditOperation::Set, AuditOperation::Emit],
+		});
+		
+		assert!(toml::from_str::<Config>(r#"
+			[storage]
+			backend = "sqlite"
+			sqlite.filename = "objtalk.db"
+			audit.operations = ["get"]
+		"#).is_err());
+	}
+	
+	#[test]
+	fn test_storage_maintenance() {
+		let config: Config = toml::from_str(r#"
+			[storage]
+			backend = "sqlite"
+			sqlite.filename = "objtalk.db"
+			maintenance.interval = 86400
+		"#).unwrap();
+		
+		assert_eq!(config.storage.unwrap().maintenance, MaintenanceConfig { interval: Some(86400) });
+	}
+	
 	#[test]
 	fn test_http_addr() {
 		let config: Config = toml::from_str(r#"
@@ -91,11 +1128,28 @@ mod tests {
 		assert_eq!(config.http, vec![
 			HttpConfig {
 				addr: "127.0.0.1:4000".parse().unwrap(),
-				allow_origin: None,
+				allow_origin: vec![],
+				keepalive_interval: 30,
+				websocket_ping_interval: 30,
+				websocket_pong_timeout: 10,
+				max_body_size: 1048576,
+				max_connections: None,
+				idle_timeout: None,
+				trusted_proxies: vec![],
+				proxy_protocol: false,
 				admin: AdminConfig {
 					enabled: false,
 					asset_overrides: None,
+					username: None,
+					password: None,
+					password_env: None,
+					read_only: false,
+					backup_dir: None,
+					max_restore_size: None,
 				},
+				auth: None,
+				h2c: false,
+				tls: None,
 			}
 		]);
 	}
@@ -111,11 +1165,28 @@ mod tests {
 		assert_eq!(config.http, vec![
 			HttpConfig {
 				addr: "127.0.0.1:4000".parse().unwrap(),
-				allow_origin: None,
+				allow_origin: vec![],
+				keepalive_interval: 30,
+				websocket_ping_interval: 30,
+				websocket_pong_timeout: 10,
+				max_body_size: 1048576,
+				max_connections: None,
+				idle_timeout: None,
+				trusted_proxies: vec![],
+				proxy_protocol: false,
 				admin: AdminConfig {
 					enabled: true,
 					asset_overrides: None,
-				}
+					username: None,
+					password: None,
+					password_env: None,
+					read_only: false,
+					backup_dir: None,
+					max_restore_size: None,
+				},
+				auth: None,
+				h2c: false,
+				tls: None,
 			}
 		]);
 	}
@@ -132,15 +1203,63 @@ mod tests {
 		assert_eq!(config.http, vec![
 			HttpConfig {
 				addr: "127.0.0.1:4000".parse().unwrap(),
-				allow_origin: None,
+				allow_origin: vec![],
+				keepalive_interval: 30,
+				websocket_ping_interval: 30,
+				websocket_pong_timeout: 10,
+				max_body_size: 1048576,
+				max_connections: None,
+				idle_timeout: None,
+				trusted_proxies: vec![],
+				proxy_protocol: false,
 				admin: AdminConfig {
 					enabled: true,
 					asset_overrides: Some(PathBuf::from("assets")),
-				}
+					username: None,
+					password: None,
+					password_env: None,
+					read_only: false,
+					backup_dir: None,
+					max_restore_size: None,
+				},
+				auth: None,
+				h2c: false,
+				tls: None,
 			}
 		]);
 	}
 	
+	#[test]
+	fn test_http_tls() {
+		let config: Config = toml::from_str(r#"
+			[[http]]
+			addr = "0.0.0.0:443"
+			tls.cert = "cert.pem"
+			tls.key = "key.pem"
+		"#).unwrap();
+		
+		assert_eq!(config.http[0].tls, Some(TlsConfig {
+			cert: PathBuf::from("cert.pem"),
+			key: PathBuf::from("key.pem"),
+			client_ca: None,
+		}));
+	}
+	
+	#[test]
+	fn test_http_auth() {
+		let config: Config = toml::from_str(r#"
+			[[http]]
+			addr = "0.0.0.0:3000"
+			auth.tokens = ["secret"]
+			auth.token-file = "tokens.txt"
+		"#).unwrap();
+		
+		assert_eq!(config.http[0].auth, Some(AuthConfig {
+			tokens: vec!["secret".to_string()],
+			token_file: Some(PathBuf::from("tokens.txt")),
+		}));
+	}
+	
 	#[test]
 	fn test_http_websocket_allow_origin() {
 		let config: Config = toml::from_str(r#"
@@ -149,7 +1268,64 @@ mod tests {
 			allow-origin = "localhost"
 		"#).unwrap();
 		
-		assert_eq!(config.http[0].allow_origin, Some("localhost".to_string()));
+		assert_eq!(config.http[0].allow_origin, vec!["localhost".to_string()]);
+	}
+	
+	#[test]
+	fn test_http_allow_origin_list() {
+		let config: Config = toml::from_str(r#"
+			[[http]]
+			addr = "127.0.0.1:4000"
+			allow-origin = ["https://app.example.com", "http://localhost:8080"]
+		"#).unwrap();
+		
+		assert_eq!(config.http[0].allow_origin, vec!["https://app.example.com".to_string(), "http://localhost:8080".to_string()]);
+	}
+	
+	#[test]
+	fn test_http_keepalive_interval() {
+		let config: Config = toml::from_str(r#"
+			[[http]]
+			addr = "127.0.0.1:4000"
+			keepalive-interval = 15
+		"#).unwrap();
+		
+		assert_eq!(config.http[0].keepalive_interval, 15);
+	}
+	
+	#[test]
+	fn test_http_websocket_ping() {
+		let config: Config = toml::from_str(r#"
+			[[http]]
+			addr = "127.0.0.1:4000"
+			websocket-ping-interval = 15
+			websocket-pong-timeout = 5
+		"#).unwrap();
+		
+		assert_eq!(config.http[0].websocket_ping_interval, 15);
+		assert_eq!(config.http[0].websocket_pong_timeout, 5);
+	}
+	
+	#[test]
+	fn test_http_h2c() {
+		let config: Config = toml::from_str(r#"
+			[[http]]
+			addr = "127.0.0.1:4000"
+			h2c = true
+		"#).unwrap();
+		
+		assert!(config.http[0].h2c);
+	}
+	
+	#[test]
+	fn test_http_max_body_size() {
+		let config: Config = toml::from_str(r#"
+			[[http]]
+			addr = "127.0.0.1:4000"
+			max-body-size = 4096
+		"#).unwrap();
+		
+		assert_eq!(config.http[0].max_body_size, 4096);
 	}
 	
 	#[test]
@@ -162,10 +1338,368 @@ mod tests {
 		assert_eq!(config.tcp, vec![
 			TcpConfig {
 				addr: "127.0.0.1:4000".parse().unwrap(),
+				tls: None,
+				format: MessageFormat::Json,
+				max_connections: None,
+				idle_timeout: None,
+				keepalive: None,
+				ping_interval: None,
+				ping_timeout: 10,
+				proxy_protocol: false,
 			}
 		]);
 	}
 	
+	#[test]
+	fn test_tcp_tls() {
+		let config: Config = toml::from_str(r#"
+			[[tcp]]
+			addr = "0.0.0.0:3001"
+			tls.cert = "cert.pem"
+			tls.key = "key.pem"
+			tls.client-ca = "ca.pem"
+		"#).unwrap();
+		
+		assert_eq!(config.tcp[0].tls, Some(TlsConfig {
+			cert: PathBuf::from("cert.pem"),
+			key: PathBuf::from("key.pem"),
+			client_ca: Some(PathBuf::from("ca.pem")),
+		}));
+	}
+	
+	#[test]
+	fn test_tcp_cbor() {
+		let config: Config = toml::from_str(r#"
+			[[tcp]]
+			addr = "0.0.0.0:3001"
+			format = "cbor"
+		"#).unwrap();
+		
+		assert_eq!(config.tcp[0].format, MessageFormat::Cbor);
+	}
+	
+	#[test]
+	fn test_connection_limits() {
+		let config: Config = toml::from_str(r#"
+			[[http]]
+			addr = "0.0.0.0:3000"
+			max-connections = 500
+			idle-timeout = 300
+			
+			[[tcp]]
+			addr = "0.0.0.0:3001"
+			max-connections = 100
+			idle-timeout = 60
+		"#).unwrap();
+		
+		assert_eq!(config.http[0].max_connections, Some(500));
+		assert_eq!(config.http[0].idle_timeout, Some(300));
+		assert_eq!(config.tcp[0].max_connections, Some(100));
+		assert_eq!(config.tcp[0].idle_timeout, Some(60));
+	}
+	
+	#[test]
+	fn test_proxies() {
+		let config: Config = toml::from_str(r#"
+			[[http]]
+			addr = "127.0.0.1:4000"
+			trusted-proxies = ["127.0.0.1", "::1"]
+			
+			[[tcp]]
+			addr = "127.0.0.1:4001"
+			proxy-protocol = true
+		"#).unwrap();
+		
+		assert_eq!(config.http[0].trusted_proxies, vec!["127.0.0.1".parse::<IpAddr>().unwrap(), "::1".parse().unwrap()]);
+		assert!(!config.http[0].proxy_protocol);
+		assert!(config.tcp[0].proxy_protocol);
+	}
+	
+	#[test]
+	fn test_tcp_keepalive() {
+		let config: Config = toml::from_str(r#"
+			[[tcp]]
+			addr = "0.0.0.0:3001"
+			keepalive.time = 30
+			ping-interval = 60
+			
+			[[tcp]]
+			addr = "0.0.0.0:3002"
+			keepalive = { time = 20, interval = 5, retries = 2 }
+			ping-interval = 60
+			ping-timeout = 5
+		"#).unwrap();
+		
+		assert_eq!(config.tcp[0].keepalive, Some(TcpKeepaliveConfig { time: 30, interval: 10, retries: 3 }));
+		assert_eq!(config.tcp[0].ping_interval, Some(60));
+		assert_eq!(config.tcp[0].ping_timeout, 10);
+		assert_eq!(config.tcp[1].keepalive, Some(TcpKeepaliveConfig { time: 20, interval: 5, retries: 2 }));
+		assert_eq!(config.tcp[1].ping_timeout, 5);
+	}
+	
+	#[test]
+	fn test_udp() {
+		let config: Config = toml::from_str(r#"
+			[[udp]]
+			addr = "0.0.0.0:3002"
+			
+			[[udp]]
+			addr = "0.0.0.0:3003"
+			format = "cbor"
+		"#).unwrap();
+		
+		assert_eq!(config.udp, vec![
+			UdpConfig {
+				addr: "0.0.0.0:3002".parse().unwrap(),
+				format: MessageFormat::Json,
+			},
+			UdpConfig {
+				addr: "0.0.0.0:3003".parse().unwrap(),
+				format: MessageFormat::Cbor,
+			},
+		]);
+	}
+	
+	#[test]
+	fn test_log() {
+		let config: Config = toml::from_str(r#"
+			[log]
+			format = "json"
+		"#).unwrap();
+		
+		assert_eq!(config.log, vec![LogConfig { format: LogFormat::Json, file: None }]);
+		
+		let config: Config = toml::from_str(r#"
+			[[log]]
+			
+			[[log]]
+			format = "json"
+			file = "objtalk.log"
+		"#).unwrap();
+		
+		assert_eq!(config.log, vec![
+			LogConfig { format: LogFormat::Text, file: None },
+			LogConfig { format: LogFormat::Json, file: Some(PathBuf::from("objtalk.log")) },
+		]);
+		
+		assert!(toml::from_str::<Config>(r#"
+			log.format = "xml"
+		"#).is_err());
+		
+		let config: Config = toml::from_str(r#"
+			log-level = "warn"
+		"#).unwrap();
+		assert_eq!(config.log_level, LogLevel::Warn);
+		
+		assert!(toml::from_str::<Config>(r#"
+			log-level = "verbose"
+		"#).is_err());
+		
+		let config: Config = toml::from_str(r#"
+			[[log-sample]]
+			type = "set"
+			pattern = "sensor/*"
+			every = 100
+		"#).unwrap();
+		
+		assert_eq!(config.log_sample, vec![LogSampleConfig {
+			message_type: "set".to_string(),
+			pattern: Some("sensor/*".to_string()),
+			every: 100,
+		}]);
+	}
+	
+	#[test]
+	fn test_mdns() {
+		let config: Config = toml::from_str(r#"
+			[mdns]
+		"#).unwrap();
+		
+		assert_eq!(config.mdns, Some(MdnsConfig { name: "objtalk".to_string() }));
+		
+		let config: Config = toml::from_str(r#"
+			mdns.name = "greenhouse"
+		"#).unwrap();
+		
+		assert_eq!(config.mdns, Some(MdnsConfig { name: "greenhouse".to_string() }));
+	}
+	
+	#[test]
+	fn test_mqtt() {
+		let config: Config = toml::from_str(r#"
+			[[mqtt]]
+			host = "broker.local"
+			
+			[[mqtt]]
+			host = "broker.local"
+			port = 8883
+			client-id = "objtalk-sensors"
+			username = "objtalk"
+			password = "secret"
+			prefix = "home/"
+			pattern = "sensor/*"
+			direction = "in"
+		"#).unwrap();
+		
+		assert_eq!(config.mqtt, vec![
+			MqttConfig {
+				host: "broker.local".to_string(),
+				port: 1883,
+				client_id: "objtalk".to_string(),
+				username: None,
+				password: None,
+				prefix: "objtalk/".to_string(),
+				pattern: "*".to_string(),
+				direction: MqttDirection::Both,
+			},
+			MqttConfig {
+				host: "broker.local".to_string(),
+				port: 8883,
+				client_id: "objtalk-sensors".to_string(),
+				username: Some("objtalk".to_string()),
+				password: Some("secret".to_string()),
+				prefix: "home/".to_string(),
+				pattern: "sensor/*".to_string(),
+				direction: MqttDirection::In,
+			},
+		]);
+	}
+	
+	#[test]
+	fn test_nats() {
+		let config: Config = toml::from_str(r#"
+			[[nats]]
+			
+			[[nats]]
+			addr = "nats.local:4222"
+			token = "secret"
+			prefix = "site-1.objtalk."
+			pattern = "lamp/*"
+			direction = "out"
+		"#).unwrap();
+		
+		assert_eq!(config.nats, vec![
+			NatsConfig {
+				addr: "127.0.0.1:4222".to_string(),
+				username: None,
+				password: None,
+				token: None,
+				prefix: "objtalk.".to_string(),
+				pattern: "*".to_string(),
+				direction: NatsDirection::Both,
+			},
+			NatsConfig {
+				addr: "nats.local:4222".to_string(),
+				username: None,
+				password: None,
+				token: Some("secret".to_string()),
+				prefix: "site-1.objtalk.".to_string(),
+				pattern: "lamp/*".to_string(),
+				direction: NatsDirection::Out,
+			},
+		]);
+	}
+	
+	#[test]
+	fn test_influxdb() {
+		let config: Config = toml::from_str(r#"
+			[[influxdb]]
+			url = "http://influxdb.local:8086/api/v2/write?org=home&bucket=objtalk"
+			token = "secret"
+			pattern = "sensor/*"
+			
+			[[influxdb]]
+			url = "udp://127.0.0.1:8089"
+			pattern = "power/+"
+			measurement = "power"
+		"#).unwrap();
+		
+		assert_eq!(config.influxdb, vec![
+			InfluxdbConfig {
+				url: "http://influxdb.local:8086/api/v2/write?org=home&bucket=objtalk".to_string(),
+				token: Some("secret".to_string()),
+				pattern: "sensor/*".to_string(),
+				measurement: "objtalk".to_string(),
+			},
+			InfluxdbConfig {
+				url: "udp://127.0.0.1:8089".to_string(),
+				token: None,
+				pattern: "power/+".to_string(),
+				measurement: "power".to_string(),
+			},
+		]);
+	}
+	
+	#[test]
+	fn test_webhook() {
+		let config: Config = toml::from_str(r#"
+			[[webhook]]
+			url = "https://example.com/hooks/objtalk"
+			pattern = "sensor/+"
+			
+			[[webhook]]
+			url = "http://127.0.0.1:8080/"
+			pattern = "door"
+			events = false
+			headers.authorization = "Bearer secret"
+			retries = 0
+			timeout = 2
+		"#).unwrap();
+		
+		assert_eq!(config.webhook, vec![
+			WebhookConfig {
+				url: "https://example.com/hooks/objtalk".to_string(),
+				pattern: "sensor/+".to_string(),
+				events: true,
+				headers: BTreeMap::new(),
+				retries: 5,
+				timeout: 10,
+			},
+			WebhookConfig {
+				url: "http://127.0.0.1:8080/".to_string(),
+				pattern: "door".to_string(),
+				events: false,
+				headers: vec![("authorization".to_string(), "Bearer secret".to_string())].into_iter().collect(),
+				retries: 0,
+				timeout: 2,
+			},
+		]);
+	}
+	
+	#[test]
+	fn test_upstream() {
+		let config: Config = toml::from_str(r#"
+			[[upstream]]
+			addr = "central.local:3001"
+			pattern = "sensor/+,lamp/+"
+			remote-prefix = "building-1/"
+			direction = "out"
+		"#).unwrap();
+		
+		assert_eq!(config.upstream, vec![
+			UpstreamConfig {
+				addr: "central.local:3001".to_string(),
+				pattern: "sensor/+,lamp/+".to_string(),
+				local_prefix: "".to_string(),
+				remote_prefix: "building-1/".to_string(),
+				direction: UpstreamDirection::Out,
+			},
+		]);
+	}
+	
+	#[test]
+	fn test_replication() {
+		let config: Config = toml::from_str(r#"
+			[replication]
+			leader = "leader.local:3001"
+		"#).unwrap();
+		
+		assert_eq!(config.replication, Some(ReplicationConfig {
+			leader: "leader.local:3001".to_string(),
+			pattern: "*".to_string(),
+		}));
+	}
+	
 	#[test]
 	fn test_multiple_transports() {
 		let config: Config = toml::from_str(r#"
@@ -178,9 +1712,25 @@ mod tests {
 		assert_eq!(config.tcp, vec![
 			TcpConfig {
 				addr: "127.0.0.1:4000".parse().unwrap(),
+				tls: None,
+				format: MessageFormat::Json,
+				max_connections: None,
+				idle_timeout: None,
+				keepalive: None,
+				ping_interval: None,
+				ping_timeout: 10,
+				proxy_protocol: false,
 			},
 			TcpConfig {
 				addr: "127.0.0.1:4001".parse().unwrap(),
+				tls: None,
+				format: MessageFormat::Json,
+				max_connections: None,
+				idle_timeout: None,
+				keepalive: None,
+				ping_interval: None,
+				ping_timeout: 10,
+				proxy_protocol: false,
 			},
 		]);
 	}
@@ -0,0 +1,373 @@
+use crate::server::config::TcpKeepaliveConfig;
+use socket2::{SockRef, TcpKeepalive};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{sleep, timeout, Instant, Sleep};
+
+/// Pause after a failed accept, e.g. when out of file descriptors.
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+/// Time a proxy has to send the PROXY protocol header.
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+/// Starts version 2 PROXY protocol headers.
+const PROXY_V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+/// Longest version 1 PROXY protocol header, including the line break.
+const PROXY_V1_MAX_LENGTH: usize = 107;
+
+/// Accepts the next connection, logging and retrying on errors.
+pub async fn accept(listener: &TcpListener, transport: &str) -> (TcpStream, SocketAddr) {
+	loop {
+		match listener.accept().await {
+			Ok(connection) => return connection,
+			Err(e) => {
+				println!("{} transport: can't accept connection: {}", transport, e);
+				sleep(ACCEPT_ERROR_DELAY).await;
+			},
+		}
+	}
+}
+
+/// Enables TCP keepalive probes on an accepted connection. The interval and
+/// retries are left to the system on platforms that can't set them.
+pub fn set_keepalive(stream: &TcpStream, config: &TcpKeepaliveConfig) -> io::Result<()> {
+	let keepalive = TcpKeepalive::new().with_time(Duration::from_secs(config.time));
+	
+	#[cfg(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "freebsd"))]
+	let keepalive = keepalive
+		.with_interval(Duration::from_secs(config.interval))
+		.with_retries(config.retries);
+	
+	SockRef::from(stream).set_tcp_keepalive(&keepalive)
+}
+
+fn invalid_proxy_header() -> io::Error {
+	io::Error::new(io::ErrorKind::InvalidData, "invalid proxy header")
+}
+
+fn parse_proxy_v1(line: &[u8]) -> io::Result<Option<SocketAddr>> {
+	let line = str::from_utf8(line).map_err(|_| invalid_proxy_header())?;
+	let fields: Vec<&str> = line.trim_end().split(' ').collect();
+	
+	match fields[..] {
+		["PROXY", "UNKNOWN", ..] => Ok(None),
+		["PROXY", "TCP4", source, _, port, _] | ["PROXY", "TCP6", source, _, port, _] => {
+			let ip: IpAddr = source.parse().map_err(|_| invalid_proxy_header())?;
+			let port: u16 = port.parse().map_err(|_| invalid_proxy_header())?;
+			Ok(Some(SocketAddr::new(ip, port)))
+		},
+		_ => Err(invalid_proxy_header()),
+	}
+}
+
+/// Reads the PROXY protocol header (version 1 or 2) a proxy sends before the
+/// data of a connection, and returns the address of the client. Connections
+/// the proxy makes on its own, e.g. health checks, have no client address.
+pub async fn read_proxy_header<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<SocketAddr>> {
+	let mut start = [0; 5];
+	stream.read_exact(&mut start).await?;
+	
+	if &start == b"PROXY" {
+		let mut line = start.to_vec();
+		
+		while !line.ends_with(b"\r\n") {
+			if line.len() >= PROXY_V1_MAX_LENGTH {
+				return Err(invalid_proxy_header());
+			}
+			
+			line.push(stream.read_u8().await?);
+		}
+		
+		return parse_proxy_v1(&line);
+	}
+	
+	let mut header = [0; 11];
+	
+	if start[..] != PROXY_V2_SIGNATURE[..5] {
+		return Err(invalid_proxy_header());
+	}
+	
+	stream.read_exact(&mut header).await?;
+	
+	if header[..7] != PROXY_V2_SIGNATURE[5..] || header[7] >> 4 != 2 {
+		return Err(invalid_proxy_header());
+	}
+	
+	let mut addresses = vec![0; u16::from_be_bytes([header[9], header[10]]) as usize];
+	stream.read_exact(&mut addresses).await?;
+	
+	// the LOCAL command, sent for the proxy's own connections
+	if header[7] & 0x0f == 0 {
+		return Ok(None);
+	}
+	
+	let port = |offset: usize| u16::from_be_bytes([addresses[offset], addresses[offset + 1]]);
+	
+	match header[8] >> 4 {
+		1 if addresses.len() >= 12 => {
+			let ip = Ipv4Addr::new(addresses[0], addresses[1], addresses[2], addresses[3]);
+			Ok(Some(SocketAddr::new(ip.into(), port(8))))
+		},
+		2 if addresses.len() >= 36 => {
+			let mut octets = [0; 16];
+			octets.copy_from_slice(&addresses[..16]);
+			Ok(Some(SocketAddr::new(Ipv6Addr::from(octets).into(), port(32))))
+		},
+		// unix sockets and unspecified families
+		_ => Ok(None),
+	}
+}
+
+/// Returns the client address from the PROXY protocol header of a connection,
+/// or the address of its peer if the proxy didn't send one. Logs and returns
+/// `None` if the header is missing or invalid.
+pub async fn proxied_addr(stream: &mut TcpStream, addr: SocketAddr, transport: &str) -> Option<SocketAddr> {
+	match timeout(PROXY_HEADER_TIMEOUT, read_proxy_header(stream)).await {
+		Ok(Ok(client)) => Some(client.unwrap_or(addr)),
+		Ok(Err(e)) => {
+			println!("{} transport: closing connection from {}: {}", transport, addr, e);
+			None
+		},
+		Err(_) => {
+			println!("{} transport: closing connection from {}: no proxy header received", transport, addr);
+			None
+		},
+	}
+}
+
+/// Limits on the connections of a transport.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct ConnectionLimits {
+	pub max_connections: Option<usize>,
+	pub idle_timeout: Option<Duration>,
+}
+
+/// Pings that have to be answered within `timeout`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ping {
+	pub interval: Duration,
+	pub timeout: Duration,
+}
+
+/// Counts the open connections of a transport.
+#[derive(Debug, Clone)]
+pub struct Connections {
+	max: Option<usize>,
+	active: Arc<AtomicUsize>,
+}
+
+impl Connections {
+	pub fn new(max: Option<usize>) -> Self {
+		Connections { max, active: Arc::new(AtomicUsize::new(0)) }
+	}
+	
+	/// Reserves a connection, or returns `None` if the maximum is reached.
+	pub fn acquire(&self) -> Option<ConnectionSlot> {
+		let max = self.max.unwrap_or(usize::MAX);
+		
+		self.active.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |active| {
+			if active < max { Some(active + 1) } else { None }
+		}).ok()?;
+		
+		Some(ConnectionSlot { active: self.active.clone() })
+	}
+}
+
+/// Frees its connection when dropped.
+#[derive(Debug)]
+pub struct ConnectionSlot {
+	active: Arc<AtomicUsize>,
+}
+
+impl Drop for ConnectionSlot {
+	fn drop(&mut self) {
+		self.active.fetch_sub(1, Ordering::SeqCst);
+	}
+}
+
+/// A stream that ends once nothing was read or written for the timeout, so
+/// whatever serves the connection closes it like a disconnect.
+pub struct IdleTimeout<S> {
+	stream: S,
+	/// `transport` and `addr` are only used for logging.
+	transport: &'static str,
+	addr: SocketAddr,
+	timeout: Option<Duration>,
+	deadline: Option<Pin<Box<Sleep>>>,
+	expired: bool,
+}
+
+impl<S> IdleTimeout<S> {
+	pub fn new(stream: S, transport: &'static str, addr: SocketAddr, timeout: Option<Duration>) -> Self {
+		IdleTimeout {
+			stream,
+			transport,
+			addr,
+			timeout,
+			deadline: timeout.map(|timeout| Box::pin(sleep(timeout))),
+			expired: false,
+		}
+	}
+	
+	fn reset(&mut self) {
+		if let (Some(deadline), Some(timeout)) = (&mut self.deadline, self.timeout) {
+			deadline.as_mut().reset(Instant::now() + timeout);
+		}
+	}
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for IdleTimeout<S> {
+	fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+		if self.expired {
+			return Poll::Ready(Ok(()));
+		}
+		
+		let filled = buf.filled().len();
+		
+		match Pin::new(&mut self.stream).poll_read(cx, buf) {
+			Poll::Ready(result) => {
+				if buf.filled().len() > filled {
+					self.reset();
+				}
+				
+				Poll::Ready(result)
+			},
+			Poll::Pending => {
+				let expired = match &mut self.deadline {
+					Some(deadline) => deadline.as_mut().poll(cx).is_ready(),
+					None => false,
+				};
+				
+				if !expired {
+					return Poll::Pending;
+				}
+				
+				println!("{} transport: closing idle connection from {}", self.transport, self.addr);
+				self.expired = true;
+				Poll::Ready(Ok(()))
+			},
+		}
+	}
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for IdleTimeout<S> {
+	fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+		let result = Pin::new(&mut self.stream).poll_write(cx, buf);
+		
+		if let Poll::Ready(Ok(written)) = result {
+			if written > 0 {
+				self.reset();
+			}
+		}
+		
+		result
+	}
+	
+	fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Pin::new(&mut self.stream).poll_flush(cx)
+	}
+	
+	fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Pin::new(&mut self.stream).poll_shutdown(cx)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+	
+	#[tokio::test]
+	async fn test_set_keepalive() {
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+		
+		set_keepalive(&client, &TcpKeepaliveConfig { time: 30, interval: 5, retries: 2 }).unwrap();
+		
+		let socket = SockRef::from(&client);
+		assert!(socket.keepalive().unwrap());
+		assert_eq!(socket.keepalive_time().unwrap(), Duration::from_secs(30));
+		
+		#[cfg(target_os = "linux")]
+		{
+			assert_eq!(socket.keepalive_interval().unwrap(), Duration::from_secs(5));
+			assert_eq!(socket.keepalive_retries().unwrap(), 2);
+		}
+	}
+	
+	#[tokio::test]
+	async fn test_read_proxy_header() {
+		let mut stream = &b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nhello"[..];
+		assert_eq!(read_proxy_header(&mut stream).await.unwrap(), Some("192.0.2.1:56324".parse().unwrap()));
+		assert_eq!(stream, b"hello");
+		
+		let mut stream = &b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n"[..];
+		assert_eq!(read_proxy_header(&mut stream).await.unwrap(), Some("[2001:db8::1]:56324".parse().unwrap()));
+		
+		let mut stream = &b"PROXY UNKNOWN\r\n"[..];
+		assert_eq!(read_proxy_header(&mut stream).await.unwrap(), None);
+		
+		let mut v2 = PROXY_V2_SIGNATURE.to_vec();
+		v2.extend_from_slice(&[0x21, 0x11, 0, 12, 192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb]);
+		v2.extend_from_slice(b"hello");
+		let mut stream = &v2[..];
+		assert_eq!(read_proxy_header(&mut stream).await.unwrap(), Some("192.0.2.1:56324".parse().unwrap()));
+		assert_eq!(stream, b"hello");
+		
+		let mut local = PROXY_V2_SIGNATURE.to_vec();
+		local.extend_from_slice(&[0x20, 0x00, 0, 0]);
+		assert_eq!(read_proxy_header(&mut &local[..]).await.unwrap(), None);
+		
+		assert!(read_proxy_header(&mut &b"GET / HTTP/1.1\r\n"[..]).await.is_err());
+		assert!(read_proxy_header(&mut &b"PROXY TCP4 nonsense\r\n"[..]).await.is_err());
+		assert!(read_proxy_header(&mut &[b'P', b'R', b'O', b'X', b'Y', b' '][..]).await.is_err());
+		assert!(read_proxy_header(&mut &[b"PROXY ".to_vec(), vec![b'x'; 200]].concat()[..]).await.is_err());
+	}
+	
+	#[test]
+	fn test_connections() {
+		let connections = Connections::new(Some(2));
+		
+		let first = connections.acquire().unwrap();
+		let _second = connections.acquire().unwrap();
+		assert!(connections.acquire().is_none());
+		
+		drop(first);
+		assert!(connections.acquire().is_some());
+		
+		let unlimited = Connections::new(None);
+		let slots: Vec<_> = (0..100).map(|_| unlimited.acquire().unwrap()).collect();
+		assert_eq!(slots.len(), 100);
+	}
+	
+	#[tokio::test]
+	async fn test_idle_timeout() {
+		let (client, server) = duplex(64);
+		let (mut client_read, mut client_write) = tokio::io::split(client);
+		let mut server = IdleTimeout::new(server, "tcp", "127.0.0.1:1234".parse().unwrap(), Some(Duration::from_millis(200)));
+		
+		sleep(Duration::from_millis(150)).await;
+		client_write.write_all(b"ping").await.unwrap();
+		
+		let mut buffer = [0; 4];
+		server.read_exact(&mut buffer).await.unwrap();
+		
+		// reading pushed the deadline back
+		sleep(Duration::from_millis(150)).await;
+		server.write_all(b"pong").await.unwrap();
+		client_read.read_exact(&mut buffer).await.unwrap();
+		
+		let started = Instant::now();
+		assert_eq!(server.read(&mut buffer).await.unwrap(), 0);
+		assert!(started.elapsed() >= Duration::from_millis(150));
+		assert_eq!(server.read(&mut buffer).await.unwrap(), 0);
+	}
+}
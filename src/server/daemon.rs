@@ -0,0 +1,152 @@
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::os::unix::io::RawFd;
+use std::path::PathBuf;
+
+fn os_error(what: &str) -> String {
+	format!("{}: {}", what, io::Error::last_os_error())
+}
+
+/// The end of the pipe the parent waits on, held by the daemon until it is up.
+pub struct Ready {
+	fd: RawFd,
+}
+
+impl Ready {
+	/// Lets the parent exit successfully. Output to a terminal is discarded from
+	/// now on, since the terminal may go away; redirected output is kept.
+	pub fn notify(self) {
+		unsafe {
+			let null = libc::open(b"/dev/null\0".as_ptr() as *const libc::c_char, libc::O_RDWR);
+			if null >= 0 {
+				for fd in [libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+					if libc::isatty(fd) == 1 {
+						libc::dup2(null, fd);
+					}
+				}
+				libc::close(null);
+			}
+			
+			libc::write(self.fd, [1u8].as_ptr() as *const libc::c_void, 1);
+			libc::close(self.fd);
+		}
+	}
+}
+
+/// Forks into the background, in a new session and with stdin from
+/// /dev/null. The parent exits once the child calls `Ready::notify`, or with
+/// an error if the child exits before, so startup errors still reach the
+/// terminal. Must be called before any threads are started.
+pub fn daemonize() -> Result<Ready, String> {
+	let mut fds = [0; 2];
+	if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+		return Err(os_error("can't create pipe"));
+	}
+	let [read_fd, write_fd] = fds;
+	
+	match unsafe { libc::fork() } {
+		-1 => Err(os_error("can't fork")),
+		0 => unsafe {
+			libc::close(read_fd);
+			
+			if libc::setsid() == -1 {
+				return Err(os_error("can't start a new session"));
+			}
+			
+			let null = libc::open(b"/dev/null\0".as_ptr() as *const libc::c_char, libc::O_RDONLY);
+			if null >= 0 {
+				libc::dup2(null, libc::STDIN_FILENO);
+				libc::close(null);
+			}
+			
+			Ok(Ready { fd: write_fd })
+		},
+		_ => unsafe {
+			libc::close(write_fd);
+			
+			let mut byte = 0u8;
+			let read = libc::read(read_fd, &mut byte as *mut u8 as *mut libc::c_void, 1);
+			std::process::exit(if read == 1 { 0 } else { 1 });
+		},
+	}
+}
+
+/// Removes the pidfile when the server stops. That fails if the privileges
+/// to do so were dropped, which is ignored.
+pub struct Pidfile {
+	path: PathBuf,
+}
+
+impl Pidfile {
+	pub fn create(path: &str) -> Result<Self, String> {
+		fs::write(path, format!("{}\n", std::process::id()))
+			.map_err(|e| format!("can't write pidfile {}: {}", path, e))?;
+		
+		Ok(Pidfile { path: PathBuf::from(path) })
+	}
+}
+
+impl Drop for Pidfile {
+	fn drop(&mut self) {
+		let _ = fs::remove_file(&self.path);
+	}
+}
+
+fn c_string(name: &str) -> Result<CString, String> {
+	CString::new(name).map_err(|_| format!("invalid name {}", name))
+}
+
+/// Returns the uid and primary gid of a user name or numeric id.
+fn lookup_user(user: &str) -> Result<(libc::uid_t, Option<libc::gid_t>), String> {
+	let name = c_string(user)?;
+	let passwd = unsafe { libc::getpwnam(name.as_ptr()) };
+	
+	if !passwd.is_null() {
+		return Ok(unsafe { ((*passwd).pw_uid, Some((*passwd).pw_gid)) });
+	}
+	
+	user.parse().map(|uid| (uid, None)).map_err(|_| format!("unknown user {}", user))
+}
+
+fn lookup_group(group: &str) -> Result<libc::gid_t, String> {
+	let name = c_string(group)?;
+	let entry = unsafe { libc::getgrnam(name.as_ptr()) };
+	
+	if !entry.is_null() {
+		return Ok(unsafe { (*entry).gr_gid });
+	}
+	
+	group.parse().map_err(|_| format!("unknown group {}", group))
+}
+
+/// Switches to `group` (by default the primary group of `user`) and then to
+/// `user`, dropping the supplementary groups of root.
+pub fn drop_privileges(user: Option<&str>, group: Option<&str>) -> Result<(), String> {
+	let user = user.map(lookup_user).transpose()?;
+	
+	let gid = match group {
+		Some(group) => Some(lookup_group(group)?),
+		None => user.and_then(|(_, gid)| gid),
+	};
+	
+	if let Some(gid) = gid {
+		unsafe {
+			if libc::setgroups(1, &gid) != 0 {
+				return Err(os_error("can't drop supplementary groups"));
+			}
+			
+			if libc::setgid(gid) != 0 {
+				return Err(os_error("can't switch group"));
+			}
+		}
+	}
+	
+	if let Some((uid, _)) = user {
+		if unsafe { libc::setuid(uid) } != 0 {
+			return Err(os_error("can't switch user"));
+		}
+	}
+	
+	Ok(())
+}
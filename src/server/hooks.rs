@@ -0,0 +1,66 @@
+use crate::server::{Client, Error};
+use crate::Command;
+use serde_json::Value;
+
+/// Gets the client, the object name and the value, which it may change.
+pub type SetHook = Box<dyn Fn(&Client, &str, &mut Value) -> Result<(), String> + Send + Sync>;
+/// Gets the client and the object name.
+pub type RemoveHook = Box<dyn Fn(&Client, &str) -> Result<(), String> + Send + Sync>;
+/// Gets the client, the object name, the event name and the data, which it may change.
+pub type EmitHook = Box<dyn Fn(&Client, &str, &str, &mut Value) -> Result<(), String> + Send + Sync>;
+
+/// Hooks registered with the `ServerBuilder`, which see the operations of
+/// clients before they are applied. They run in the order they were
+/// registered and outside of the state lock, and the first one returning an
+/// error rejects the operation with `Error::Rejected`.
+///
+/// Disconnect commands pass the hooks when they are registered, as the
+/// registering client. Changes made by the server itself, by replication and
+/// by restoring a backup don't pass the hooks.
+#[derive(Default)]
+pub struct Hooks {
+	pub(crate) set: Vec<SetHook>,
+	pub(crate) patch: Vec<SetHook>,
+	pub(crate) remove: Vec<RemoveHook>,
+	pub(crate) emit: Vec<EmitHook>,
+	/// Gets the method name instead of the event name and the arguments
+	/// instead of the data.
+	pub(crate) invoke: Vec<EmitHook>,
+}
+
+impl Hooks {
+	pub(crate) fn run_set(&self, client: &Client, name: &str, value: &mut Value) -> Result<(), Error> {
+		run(&self.set, |hook| hook(client, name, value))
+	}
+	
+	pub(crate) fn run_patch(&self, client: &Client, name: &str, value: &mut Value) -> Result<(), Error> {
+		run(&self.patch, |hook| hook(client, name, value))
+	}
+	
+	pub(crate) fn run_remove(&self, client: &Client, name: &str) -> Result<(), Error> {
+		run(&self.remove, |hook| hook(client, name))
+	}
+	
+	pub(crate) fn run_emit(&self, client: &Client, object: &str, event: &str, data: &mut Value) -> Result<(), Error> {
+		run(&self.emit, |hook| hook(client, object, event, data))
+	}
+	
+	pub(crate) fn run_invoke(&self, client: &Client, object: &str, method: &str, args: &mut Value) -> Result<(), Error> {
+		run(&self.invoke, |hook| hook(client, object, method, args))
+	}
+	
+	/// Runs the hooks of the operation the command makes once the client
+	/// disconnects.
+	pub(crate) fn run_command(&self, client: &Client, command: &mut Command) -> Result<(), Error> {
+		match command {
+			Command::Set { name, value } => self.run_set(client, name, value),
+			Command::Patch { name, value } => self.run_patch(client, name, value),
+			Command::Remove { name } => self.run_remove(client, name),
+			Command::Emit { object, event, data } => self.run_emit(client, object, event, data),
+		}
+	}
+}
+
+fn run<H>(hooks: &[H], mut call: impl FnMut(&H) -> Result<(), String>) -> Result<(), Error> {
+	hooks.iter().try_for_each(|hook| call(hook).map_err(Error::Rejected))
+}
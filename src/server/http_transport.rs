@@ -1,20 +1,40 @@
-use crate::json_rpc::RequestMessage;
+use crate::{AuditQuery, Backup, LogLevel};
+use crate::json_rpc::{Requests, Response as RpcResponse};
 use crate::patterns::Pattern;
 use crate::server::admin::get_admin_asset;
-use crate::server::json_rpc::{handle_message, handle_inbox_message};
-use crate::server::{Server, Message};
+use crate::server::auth::{AdminCredentials, Tokens};
+use crate::server::connection::{self, ConnectionLimits, Connections, IdleTimeout, Ping};
+use crate::server::metrics;
+use crate::server::tls::{self, TlsAcceptor};
+use crate::server::json_rpc::{handle_batch, handle_message, handle_inbox_message, DEFAULT_AUDIT_LIMIT};
+use crate::server::storage::AuditFilter;
+use crate::server::{Server, Client, Message};
+use chrono::{DateTime, Utc};
 use futures::sink::SinkExt;
 use futures::stream::StreamExt;
-use hyper::service::{make_service_fn, service_fn};
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
 use hyper::{Request, Response, Body, StatusCode, Method, HeaderMap, header};
-use hyper_tungstenite::{tungstenite, HyperWebsocket, is_upgrade_request};
+use hyper::body::HttpBody;
+use hyper::header::HeaderValue;
+use hyper_tungstenite::{tungstenite, is_upgrade_request, WebSocketStream};
+use percent_encoding::percent_decode_str;
 use serde::{Serialize, Deserialize};
 use serde_json::{json, Value};
 use std::convert::Infallible;
-use std::net::SocketAddr;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
 use std::path::Path;
 use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio::time::sleep_until;
 use tungstenite::Message as WebsocketMessage;
+use tungstenite::protocol::CloseFrame;
+use tungstenite::protocol::frame::coding::CloseCode;
+use uuid::Uuid;
 
 fn remove_first_slash(string: &str) -> &str {
 	let mut chars = string.chars();
@@ -36,6 +56,78 @@ fn error_response(status: StatusCode, string: String) -> Response<Body> {
 		.body(Body::from(string)).unwrap()
 }
 
+/// Reads the body, failing with 413 as soon as it's known to be larger than
+/// `limit` instead of buffering it.
+async fn read_body(req: Request<Body>, limit: usize) -> Result<Vec<u8>, (StatusCode, String)> {
+	let too_large = || (StatusCode::PAYLOAD_TOO_LARGE, format!("body larger than {} bytes", limit));
+	
+	let content_length = req.headers().get(header::CONTENT_LENGTH)
+		.and_then(|value| value.to_str().ok())
+		.and_then(|value| value.parse::<usize>().ok());
+	
+	if content_length.map(|length| length > limit).unwrap_or(false) {
+		return Err(too_large());
+	}
+	
+	let mut body = req.into_body();
+	let mut bytes = Vec::with_capacity(content_length.unwrap_or(0));
+	
+	while let Some(chunk) = body.data().await {
+		let chunk = chunk.map_err(|_| (StatusCode::BAD_REQUEST, "invalid body".to_string()))?;
+		
+		if bytes.len() + chunk.len() > limit {
+			return Err(too_large());
+		}
+		
+		bytes.extend_from_slice(&chunk);
+	}
+	
+	Ok(bytes)
+}
+
+/// Follows the `X-Forwarded-For` chain from the peer back through trusted
+/// proxies, and returns the first address that isn't one of them.
+fn forwarded_addr(peer: IpAddr, headers: &HeaderMap, trusted_proxies: &[IpAddr]) -> IpAddr {
+	let forwarded: Vec<&str> = headers.get_all("x-forwarded-for").iter()
+		.filter_map(|value| value.to_str().ok())
+		.flat_map(|value| value.split(','))
+		.collect();
+	
+	let mut addr = peer;
+	
+	for entry in forwarded.iter().rev() {
+		if !trusted_proxies.contains(&addr) {
+			break;
+		}
+		
+		let entry = entry.trim();
+		
+		match entry.parse::<IpAddr>().or_else(|_| entry.parse::<SocketAddr>().map(|addr| addr.ip())) {
+			Ok(forwarded) => addr = forwarded,
+			Err(_) => break,
+		}
+	}
+	
+	addr
+}
+
+/// Returns the percent-decoded value of a query parameter. `+` is kept as is
+/// instead of being read as a space, so patterns like `sensor/+` work unencoded.
+fn query_param(req: &Request<Body>, name: &str) -> Option<String> {
+	req.uri().query()?.split('&')
+		.map(|pair| pair.split_once('=').unwrap_or((pair, "")))
+		.find(|(key, _)| percent_decode_str(key).decode_utf8().map(|key| key == name).unwrap_or(false))
+		.and_then(|(_, value)| percent_decode_str(value).decode_utf8().ok())
+		.map(|value| value.into_owned())
+}
+
+/// Parses an optional query parameter, e.g. `?limit=10`.
+fn parse_param<T: std::str::FromStr>(req: &Request<Body>, name: &str) -> Result<Option<T>, (StatusCode, String)> {
+	query_param(req, name)
+		.map(|value| value.parse().map_err(|_| (StatusCode::BAD_REQUEST, format!("invalid {}", name))))
+		.transpose()
+}
+
 fn is_event_stream(headers: &HeaderMap) -> bool {
 	if let Some(value) = headers.get(header::ACCEPT) {
 		if let Ok(str_value) = value.to_str() {
@@ -46,6 +138,8 @@ fn is_event_stream(headers: &HeaderMap) -> bool {
 	false
 }
 
+const KEEPALIVE: &str = ":keepalive\n\n";
+
 fn event(name: &str, data: Value) -> String {
 	let json_string = serde_json::to_string(&data).unwrap();
 	format!("event:{}\ndata:{}\n\n", name, json_string)
@@ -63,34 +157,124 @@ struct InvokeRequest {
 	args: Value,
 }
 
-async fn serve_websocket(websocket: HyperWebsocket, server: Server) -> Result<(), Box<dyn std::error::Error>> {
-	let mut websocket = websocket.await?;
+/// Websocket subprotocol clients request to use MessagePack binary frames
+/// instead of JSON text frames.
+const MSGPACK_PROTOCOL: &str = "msgpack";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Encoding {
+	Json,
+	MessagePack,
+}
+
+impl Encoding {
+	fn negotiate(headers: &HeaderMap) -> Self {
+		let protocols = headers.get_all(header::SEC_WEBSOCKET_PROTOCOL).iter()
+			.filter_map(|value| value.to_str().ok())
+			.flat_map(|value| value.split(','))
+			.map(|protocol| protocol.trim());
+		
+		for protocol in protocols {
+			if protocol == MSGPACK_PROTOCOL {
+				return Encoding::MessagePack;
+			}
+		}
+		
+		Encoding::Json
+	}
 	
-	let mut client = server.client_connect();
+	fn encode<T: Serialize>(self, value: &T) -> WebsocketMessage {
+		match self {
+			Encoding::Json => WebsocketMessage::text(serde_json::to_string(value).unwrap()),
+			Encoding::MessagePack => {
+				// human readable keeps uuids and timestamps as strings like in json
+				let mut data = vec![];
+				value.serialize(&mut rmp_serde::Serializer::new(&mut data).with_struct_map().with_human_readable()).unwrap();
+				WebsocketMessage::binary(data)
+			},
+		}
+	}
+	
+	/// Decodes a request, returns `None` for frames of the other encoding.
+	fn decode(self, message: &WebsocketMessage) -> Option<Result<Requests, ()>> {
+		let value = match (self, message) {
+			(Encoding::Json, WebsocketMessage::Text(line)) => serde_json::from_str(line).map_err(|_| ()),
+			(Encoding::MessagePack, WebsocketMessage::Binary(data)) => {
+				let mut deserializer = rmp_serde::Deserializer::new(&data[..]).with_human_readable();
+				Value::deserialize(&mut deserializer).map_err(|_| ())
+			},
+			_ => return None,
+		};
+		
+		Some(value.and_then(|value| Requests::from_value(value).map_err(|_| ())))
+	}
+}
+
+pub(crate) async fn serve_websocket<S>(mut websocket: WebSocketStream<S>, server: Server, mut client: Client, encoding: Encoding, ping: Option<Ping>) -> Result<(), Box<dyn std::error::Error>>
+where
+	S: AsyncRead + AsyncWrite + Unpin,
+{
+	let mut next_ping = ping.map(|ping| Instant::now() + ping.interval);
+	let mut ping_sent: Option<Instant> = None;
+	
+	let shutting_down = server.shutting_down();
+	tokio::pin!(shutting_down);
 	
 	loop {
+		let ping_deadline = match (ping, ping_sent, next_ping) {
+			(Some(ping), Some(sent), _) => sent + ping.timeout,
+			(_, _, Some(next_ping)) => next_ping,
+			_ => Instant::now(),
+		};
+		
 		tokio::select! {
 			Some(msg) = client.inbox_next() => {
-				let response = handle_inbox_message(msg);
-				let json_string = serde_json::to_string(&response).unwrap();
-				websocket.send(WebsocketMessage::text(json_string)).await?;
+				if let Some(response) = handle_inbox_message(msg) {
+					websocket.send(encoding.encode(&response)).await?;
+				}
+			},
+			_ = sleep_until(ping_deadline.into()), if ping.is_some() => {
+				if ping_sent.is_some() {
+					match client.addr() {
+						Some(addr) => println!("http transport: closing websocket from {}, pong not received", addr),
+						None => println!("http transport: closing websocket, pong not received"),
+					}
+					break;
+				}
+				
+				websocket.send(WebsocketMessage::Ping(vec![])).await?;
+				ping_sent = Some(Instant::now());
+				next_ping = ping.map(|ping| Instant::now() + ping.interval);
+			},
+			_ = &mut shutting_down => {
+				websocket.close(Some(CloseFrame { code: CloseCode::Away, reason: "server shutting down".into() })).await?;
+				break;
 			},
 			result = websocket.next() => match result {
 				Some(message) => {
 					let message = message?;
 					
-					if let WebsocketMessage::Text(line) = message {
-						match serde_json::from_str::<RequestMessage>(&line) {
-							Ok(request) => {
-								if let Some(response) = handle_message(request, &client, server.clone()) {
-									let json_string = serde_json::to_string(&response).unwrap();
-									websocket.send(WebsocketMessage::text(json_string)).await?;
-								}
-							},
-							Err(_) => {
-								websocket.send(WebsocketMessage::text("{\"type\":\"error\",\"error\":\"invalid message\"}")).await?;
+					if let WebsocketMessage::Pong(_) = message {
+						ping_sent = None;
+					}
+					
+					match encoding.decode(&message) {
+						Some(Ok(Requests::Single(request))) => {
+							if let Some(response) = handle_message(request, &client, server.clone()).await {
+								websocket.send(encoding.encode(&response)).await?;
 							}
-						}
+						},
+						Some(Ok(Requests::Batch(requests))) => {
+							let responses = handle_batch(requests, &client, server.clone()).await;
+							
+							if !responses.is_empty() {
+								websocket.send(encoding.encode(&responses)).await?;
+							}
+						},
+						Some(Err(())) => {
+							websocket.send(encoding.encode(&json!({ "type": "error", "error": "invalid message" }))).await?;
+						},
+						None => {},
 					}
 				},
 				None => break,
@@ -101,20 +285,236 @@ async fn serve_websocket(websocket: HyperWebsocket, server: Server) -> Result<()
 	Ok(())
 }
 
+const ALLOWED_METHODS: &str = "GET, POST, PATCH, DELETE, OPTIONS";
+
+const DEFAULT_POLL_TIMEOUT: u64 = 30;
+const MAX_POLL_TIMEOUT: u64 = 120;
+/// How long a long-polling query is kept after its last poll ended.
+const POLL_SESSION_TIMEOUT: Duration = Duration::from_secs(60);
+/// First path segments of the admin api, which is only served with the admin
+/// panel enabled.
+const ADMIN_APIS: &[&str] = &["audit", "storage", "log-level", "backup", "restore"];
+/// Backups are larger than other bodies, so restores have a limit of their own.
+const DEFAULT_MAX_RESTORE_SIZE: usize = 64 * 1024 * 1024;
+
+/// A query of a long-polling client, kept between its requests.
+struct PollSession {
+	/// Taken out while a request is waiting for notifications.
+	client: Option<Client>,
+	expires: Instant,
+}
+
+type PollSessions = Arc<Mutex<HashMap<Uuid, PollSession>>>;
+
+/// Returns the client of a poll session when the request is done, even if
+/// it was cancelled because the http client went away.
+struct PollGuard {
+	sessions: PollSessions,
+	query_id: Uuid,
+	client: Option<Client>,
+}
+
+impl Drop for PollGuard {
+	fn drop(&mut self) {
+		if let Some(session) = self.sessions.lock().unwrap().get_mut(&self.query_id) {
+			session.client = self.client.take();
+			session.expires = Instant::now() + POLL_SESSION_TIMEOUT;
+		}
+	}
+}
+
 #[derive(Clone)]
 struct RequestHandler {
 	server: Server,
-	allow_origin: Option<String>,
+	/// Origins that get cors headers, `*` allows all.
+	allow_origin: Vec<String>,
+	/// Idle time after which a comment is sent on event streams.
+	keepalive_interval: Option<Duration>,
+	/// Bytes a request body may have.
+	max_body_size: usize,
 	admin_enabled: bool,
 	admin_asset_overrides: Option<PathBuf>,
+	/// Required for the admin panel and api if set, and allow all other
+	/// requests, too.
+	admin_credentials: Option<AdminCredentials>,
+	/// Rejects changes through the admin api or with the admin login.
+	admin_read_only: bool,
+	/// Where backups triggered through the admin api are written.
+	admin_backup_dir: Option<PathBuf>,
+	/// Bytes a posted backup may have, `DEFAULT_MAX_RESTORE_SIZE` if unset.
+	admin_max_restore_size: Option<usize>,
+	/// Tokens of which one is required on all requests if set.
+	tokens: Option<Tokens>,
+	/// Pings sent on websockets.
+	websocket_ping: Option<Ping>,
+	/// `X-Forwarded-For` headers of these peers are trusted.
+	trusted_proxies: Vec<IpAddr>,
+	/// Address of the connection's peer.
+	remote_addr: Option<SocketAddr>,
+	/// Address of the client that made the request, which differs from the
+	/// peer's if that is a trusted proxy.
+	client_addr: Option<IpAddr>,
+	/// Identity of the tls client certificate of the connection.
+	identity: Option<String>,
+	poll_sessions: PollSessions,
 }
 
 impl RequestHandler {
+	fn client_connect(&self) -> Client {
+		self.server.client_connect_from(self.client_addr, self.identity.clone())
+	}
+	
+	/// The value for `Access-Control-Allow-Origin` if the request's origin is allowed.
+	fn cors_origin(&self, headers: &HeaderMap) -> Option<HeaderValue> {
+		if self.allow_origin.iter().any(|allowed| allowed == "*") {
+			return Some(HeaderValue::from_static("*"));
+		}
+		
+		let origin = headers.get(header::ORIGIN)?;
+		
+		if self.allow_origin.iter().any(|allowed| allowed.as_bytes() == origin.as_bytes()) {
+			Some(origin.clone())
+		} else {
+			None
+		}
+	}
+	
+	/// Whether the request is for the admin panel or api.
+	fn is_admin_request(&self, req: &Request<Body>) -> bool {
+		let path = req.uri().path();
+		let first = path.trim_start_matches('/').split('/').next().unwrap_or("");
+		
+		self.admin_enabled && !is_upgrade_request(req)
+			&& (path == "/" || first == "_assets" || ADMIN_APIS.contains(&first))
+	}
+	
+	fn is_admin_login(&self, req: &Request<Body>) -> bool {
+		let authorization = req.headers().get(header::AUTHORIZATION).and_then(|value| value.to_str().ok());
+		
+		match (&self.admin_credentials, authorization) {
+			(Some(credentials), Some(authorization)) => credentials.verify_header(authorization),
+			_ => false,
+		}
+	}
+	
+	/// Whether the request exposes or replaces the data of all clients or
+	/// changes the server, so only the admin login may make it.
+	fn is_privileged_request(&self, req: &Request<Body>) -> bool {
+		self.admin_enabled && matches!((req.method(), req.uri().path()),
+			(&Method::GET, "/audit") | (&Method::POST, "/log-level")
+			| (&Method::GET, "/backup") | (&Method::POST, "/backup") | (&Method::POST, "/restore"))
+	}
+	
+	/// Whether the request opens the websocket of the admin panel. Browsers
+	/// send the origin of the page, which is this server for the panel.
+	fn is_admin_websocket(&self, req: &Request<Body>) -> bool {
+		if !self.admin_enabled || !is_upgrade_request(req) {
+			return false;
+		}
+		
+		let host = req.headers().get(header::HOST).and_then(|value| value.to_str().ok())
+			.or_else(|| req.uri().authority().map(|authority| authority.as_str()));
+		let origin = req.headers().get(header::ORIGIN).and_then(|value| value.to_str().ok())
+			.and_then(|origin| origin.split_once("://"))
+			.map(|(_, origin)| origin);
+		
+		matches!((host, origin), (Some(host), Some(origin)) if host.eq_ignore_ascii_case(origin))
+	}
+	
+	/// Whether the request may only read because the admin is read-only.
+	fn is_read_only(&self, req: &Request<Body>) -> bool {
+		self.admin_read_only
+			&& (self.is_admin_request(req) || self.is_admin_websocket(req) || self.is_admin_login(req))
+	}
+	
+	/// Checks the `Authorization` header, or the `access_token` query parameter
+	/// for browser websockets and event sources which can't set headers.
+	fn is_authorized(&self, req: &Request<Body>) -> bool {
+		if self.is_admin_login(req) {
+			return true;
+		}
+		
+		if self.admin_credentials.is_some() && self.is_admin_request(req) {
+			return false;
+		}
+		
+		let tokens = match &self.tokens {
+			Some(tokens) => tokens,
+			None => return true,
+		};
+		
+		// the admin ui itself contains no data, it authenticates its websocket
+		if self.admin_enabled && !is_upgrade_request(req) && req.method() == Method::GET
+			&& (req.uri().path() == "/" || req.uri().path().starts_with("/_assets/")) {
+			return true;
+		}
+		
+		if let Some(authorization) = req.headers().get(header::AUTHORIZATION) {
+			return authorization.to_str().map(|value| tokens.verify_header(value)).unwrap_or(false);
+		}
+		
+		query_param(req, "access_token").map(|token| tokens.verify(&token)).unwrap_or(false)
+	}
+	
 	async fn handle_request(&self, req: Request<Body>) -> Response<Body> {
+		let cors_origin = self.cors_origin(req.headers());
+		
+		let mut response = if req.method() == Method::OPTIONS {
+			self.handle_preflight(req.headers())
+		} else if !self.is_authorized(&req) {
+			// browsers ask for the admin password on a basic challenge
+			let challenge = if self.admin_credentials.is_some() && self.is_admin_request(&req) {
+				HeaderValue::from_static("Basic realm=\"objtalk admin\", charset=\"UTF-8\"")
+			} else {
+				HeaderValue::from_static("Bearer")
+			};
+			
+			let mut response = error_response(StatusCode::UNAUTHORIZED, "unauthorized".to_string());
+			response.headers_mut().insert(header::WWW_AUTHENTICATE, challenge);
+			response
+		} else if self.is_privileged_request(&req) && !self.is_admin_login(&req) {
+			error_response(StatusCode::FORBIDDEN, "admin only".to_string())
+		} else if req.method() != Method::GET && req.method() != Method::HEAD && self.is_read_only(&req) {
+			error_response(StatusCode::FORBIDDEN, "admin is read-only".to_string())
+		} else {
+			self.route(req).await
+		};
+		
+		let headers = response.headers_mut();
+		
+		if let Some(origin) = cors_origin {
+			headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+		}
+		
+		// the allowed origin depends on the request unless all are allowed
+		if !self.allow_origin.is_empty() && !self.allow_origin.iter().any(|allowed| allowed == "*") {
+			headers.append(header::VARY, HeaderValue::from_static("Origin"));
+		}
+		
+		response
+	}
+	
+	fn handle_preflight(&self, request_headers: &HeaderMap) -> Response<Body> {
+		let mut res = Response::builder()
+			.status(StatusCode::NO_CONTENT)
+			.header(header::ALLOW, ALLOWED_METHODS)
+			.header(header::ACCESS_CONTROL_ALLOW_METHODS, ALLOWED_METHODS)
+			.header(header::ACCESS_CONTROL_MAX_AGE, "86400");
+		
+		if let Some(headers) = request_headers.get(header::ACCESS_CONTROL_REQUEST_HEADERS) {
+			res = res.header(header::ACCESS_CONTROL_ALLOW_HEADERS, headers);
+		}
+		
+		res.body(Body::empty()).unwrap()
+	}
+	
+	async fn route(&self, req: Request<Body>) -> Response<Body> {
 		let path = req.uri().path().to_string();
 		let parts: Vec<&str> = path.splitn(3, "/").collect();
+		// object names are percent-encoded, e.g. `/objects/lamp%201` for `lamp 1`
+		let name = parts.get(2).and_then(|name| percent_decode_str(name).decode_utf8().ok());
 		
-		match (req.method(), parts[1], parts.get(2)) {
+		match (req.method(), parts[1], name.as_deref()) {
 			(&Method::GET, "", None) if is_upgrade_request(&req) => self.handle_websocket(req),
 			
 			(&Method::GET, "", None) if self.admin_enabled => self.handle_admin_index(req).await,
@@ -130,12 +530,24 @@ impl RequestHandler {
 			
 			(&Method::GET, "query", None) if is_event_stream(req.headers()) => self.handle_query(req),
 			(&Method::GET, "query", None) => self.handle_get_all(req),
+			
+			(&Method::GET, "poll", None) => self.handle_poll(req).await,
+			
+			(&Method::GET, "metrics", None) => self.handle_metrics(),
+			
+			(&Method::GET, "audit", None) if self.admin_enabled => self.handle_audit(req).await,
+			(&Method::GET, "storage", None) if self.admin_enabled => Ok(json_response(&self.server.storage_status())),
+			(&Method::GET, "log-level", None) if self.admin_enabled => Ok(json_response(&json!({ "level": self.server.log_level() }))),
+			(&Method::POST, "log-level", None) if self.admin_enabled => self.handle_set_log_level(req).await,
+			(&Method::GET, "backup", None) if self.admin_enabled => self.handle_backup().await,
+			(&Method::POST, "backup", None) if self.admin_enabled => self.handle_backup_to_dir().await,
+			(&Method::POST, "restore", None) if self.admin_enabled => self.handle_restore(req).await,
 			_ => Err((StatusCode::BAD_REQUEST, "bad request".to_string())),
 		}.unwrap_or_else(|(status, string)| error_response(status, string))
 	}
 	
 	fn handle_get(&self, name: &str) -> Result<Response<Body>, (StatusCode, String)> {
-		let client = self.server.client_connect();
+		let client = self.client_connect();
 		
 		let pattern = Pattern::compile(name)
 			.map_err(|_| (StatusCode::BAD_REQUEST, "invalid pattern".to_string()))?;
@@ -149,10 +561,9 @@ impl RequestHandler {
 	}
 	
 	fn handle_get_all(&self, req: Request<Body>) -> Result<Response<Body>, (StatusCode, String)> {
-		let client = self.server.client_connect();
+		let client = self.client_connect();
 		
-		let query = req.uri().query().ok_or((StatusCode::BAD_REQUEST, "pattern missing".to_string()))?;
-		let pattern_str = query.replace("pattern=", "");
+		let pattern_str = query_param(&req, "pattern").ok_or((StatusCode::BAD_REQUEST, "pattern missing".to_string()))?;
 		
 		let pattern = Pattern::compile(&pattern_str)
 			.map_err(|_| (StatusCode::BAD_REQUEST, "invalid pattern".to_string()))?;
@@ -163,10 +574,9 @@ impl RequestHandler {
 	}
 
 	async fn handle_set(&self, name: &str, req: Request<Body>) -> Result<Response<Body>, (StatusCode, String)> {
-		let client = self.server.client_connect();
+		let client = self.client_connect();
 		
-		let bytes = hyper::body::to_bytes(req).await
-			.map_err(|_| (StatusCode::BAD_REQUEST, "invalid body".to_string()))?;
+		let bytes = read_body(req, self.max_body_size).await?;
 		
 		let value = serde_json::from_slice::<Value>(&bytes)
 			.map_err(|_| (StatusCode::BAD_REQUEST, "invalid json".to_string()))?;
@@ -179,10 +589,9 @@ impl RequestHandler {
 	}
 	
 	async fn handle_patch(&self, name: &str, req: Request<Body>) -> Result<Response<Body>, (StatusCode, String)> {
-		let client = self.server.client_connect();
+		let client = self.client_connect();
 		
-		let bytes = hyper::body::to_bytes(req).await
-			.map_err(|_| (StatusCode::BAD_REQUEST, "invalid body".to_string()))?;
+		let bytes = read_body(req, self.max_body_size).await?;
 		
 		let value = serde_json::from_slice::<Value>(&bytes)
 			.map_err(|_| (StatusCode::BAD_REQUEST, "invalid json".to_string()))?;
@@ -195,10 +604,9 @@ impl RequestHandler {
 	}
 	
 	async fn handle_emit(&self, name: &str, req: Request<Body>) -> Result<Response<Body>, (StatusCode, String)> {
-		let client = self.server.client_connect();
+		let client = self.client_connect();
 		
-		let bytes = hyper::body::to_bytes(req).await
-			.map_err(|_| (StatusCode::BAD_REQUEST, "invalid body".to_string()))?;
+		let bytes = read_body(req, self.max_body_size).await?;
 		
 		let emit_req = serde_json::from_slice::<EmitRequest>(&bytes)
 			.map_err(|_| (StatusCode::BAD_REQUEST, "invalid json".to_string()))?;
@@ -211,10 +619,9 @@ impl RequestHandler {
 	}
 	
 	async fn handle_invoke(&self, name: &str, req: Request<Body>) -> Result<Response<Body>, (StatusCode, String)> {
-		let mut client = self.server.client_connect();
+		let mut client = self.client_connect();
 		
-		let bytes = hyper::body::to_bytes(req).await
-			.map_err(|_| (StatusCode::BAD_REQUEST, "invalid body".to_string()))?;
+		let bytes = read_body(req, self.max_body_size).await?;
 		
 		let invoke_req = serde_json::from_slice::<InvokeRequest>(&bytes)
 			.map_err(|_| (StatusCode::BAD_REQUEST, "invalid json".to_string()))?;
@@ -233,7 +640,7 @@ impl RequestHandler {
 	}
 
 	fn handle_remove(&self, name: &str) -> Result<Response<Body>, (StatusCode, String)> {
-		let client = self.server.client_connect();
+		let client = self.client_connect();
 		
 		let existed = self.server.remove(name, &client)
 			.map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
@@ -247,62 +654,265 @@ impl RequestHandler {
 	}
 	
 	fn handle_query(&self, req: Request<Body>) -> Result<Response<Body>, (StatusCode, String)> {
-		let mut client = self.server.client_connect();
+		let mut client = self.client_connect();
 		
-		let query = req.uri().query().ok_or((StatusCode::BAD_REQUEST, "pattern missing".to_string()))?;
-		let pattern_str = query.replace("pattern=", "");
+		let pattern_str = query_param(&req, "pattern").ok_or((StatusCode::BAD_REQUEST, "pattern missing".to_string()))?;
 		let pattern = Pattern::compile(&pattern_str)
 			.map_err(|_| (StatusCode::BAD_REQUEST, "invalid pattern".to_string()))?;
 		
-		let (query_id, objects) = self.server.query(&pattern, false, &client)
+		let (query_id, objects) = self.server.query(&pattern, false, false, &client)
 			.map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
 		
 		let (mut sender, body) = Body::channel();
+		let keepalive_interval = self.keepalive_interval;
+		
+		let server = self.server.clone();
 		
 		tokio::spawn(async move {
-			let msg = event("initial", json!({ "objects": objects }));
-			if sender.send_data(msg.into()).await.is_err() {
-				return;
-			}
-			
-			while let Some(msg) = client.inbox_next().await {
-				let out = match msg {
-					Message::QueryAdd { query_id: msg_query_id, object } =>
-						if query_id == msg_query_id { Some(event("add", json!({ "object": object }))) } else { None },
-					Message::QueryChange { query_id: msg_query_id, object } =>
-						if query_id == msg_query_id { Some(event("change", json!({ "object": object }))) } else { None },
-					Message::QueryRemove { query_id: msg_query_id, object } =>
-						if query_id == msg_query_id { Some(event("remove", json!({ "object": object }))) } else { None },
-					Message::QueryEvent { query_id: msg_query_id, object, event: event_name, data } =>
-						if query_id == msg_query_id { Some(event("event", json!({ "object": object, "event": event_name, "data": data }))) } else { None },
-					Message::QueryInvocation { .. } => unreachable!(),
-					Message::InvocationResult { .. } => unreachable!(),
-				};
+			let stream = async move {
+				let msg = event("initial", json!({ "objects": objects }));
+				if sender.send_data(msg.into()).await.is_err() {
+					return;
+				}
 				
-				if let Some(msg) = out {
-					if sender.send_data(msg.into()).await.is_err() {
-						return;
+				loop {
+					let next = match keepalive_interval {
+						Some(interval) => match tokio::time::timeout(interval, client.inbox_next()).await {
+							Ok(next) => next,
+							Err(_) => {
+								// comments keep proxies from closing idle connections
+								if sender.send_data(KEEPALIVE.into()).await.is_err() {
+									return;
+								}
+								
+								continue;
+							},
+						},
+						None => client.inbox_next().await,
+					};
+					
+					let msg = match next {
+						Some(msg) => msg,
+						None => return,
+					};
+					
+					let out = match msg {
+						Message::QueryAdd { query_id: msg_query_id, object } =>
+							if query_id == msg_query_id { Some(event("add", json!({ "object": object }))) } else { None },
+						Message::QueryChange { query_id: msg_query_id, object, .. } =>
+							if query_id == msg_query_id { Some(event("change", json!({ "object": object }))) } else { None },
+						Message::QueryRemove { query_id: msg_query_id, object } =>
+							if query_id == msg_query_id { Some(event("remove", json!({ "object": object }))) } else { None },
+						Message::QueryEvent { query_id: msg_query_id, object, event: event_name, data } =>
+							if query_id == msg_query_id { Some(event("event", json!({ "object": object, "event": event_name, "data": data }))) } else { None },
+						Message::QueryInvocation { .. } => unreachable!(),
+						Message::InvocationResult { .. } => unreachable!(),
+					};
+					
+					if let Some(msg) = out {
+						if sender.send_data(msg.into()).await.is_err() {
+							return;
+						}
 					}
 				}
+			};
+			
+			// ending the stream lets hyper close the connection
+			tokio::select! {
+				_ = stream => {},
+				_ = server.shutting_down() => {},
 			}
 		});
 		
-		let mut res = Response::builder()
-			.header(header::CONTENT_TYPE, "text/event-stream");
+		Ok(Response::builder()
+			.header(header::CONTENT_TYPE, "text/event-stream")
+			.body(body).unwrap())
+	}
+	
+	async fn handle_poll(&self, req: Request<Body>) -> Result<Response<Body>, (StatusCode, String)> {
+		let now = Instant::now();
+		self.poll_sessions.lock().unwrap()
+			.retain(|_, session| session.client.is_none() || session.expires > now);
+		
+		if let Some(query_id) = query_param(&req, "queryId") {
+			return self.handle_poll_wait(&query_id, req).await;
+		}
+		
+		let client = self.client_connect();
+		
+		let pattern_str = query_param(&req, "pattern").ok_or((StatusCode::BAD_REQUEST, "pattern missing".to_string()))?;
+		let pattern = Pattern::compile(&pattern_str)
+			.map_err(|_| (StatusCode::BAD_REQUEST, "invalid pattern".to_string()))?;
+		
+		let (query_id, objects) = self.server.query(&pattern, false, false, &client)
+			.map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+		
+		self.poll_sessions.lock().unwrap().insert(query_id, PollSession {
+			client: Some(client),
+			expires: now + POLL_SESSION_TIMEOUT,
+		});
+		
+		Ok(json_response(&RpcResponse::Query { query_id, objects }))
+	}
+	
+	async fn handle_poll_wait(&self, query_id: &str, req: Request<Body>) -> Result<Response<Body>, (StatusCode, String)> {
+		let query_id = Uuid::parse_str(query_id)
+			.map_err(|_| (StatusCode::BAD_REQUEST, "invalid query id".to_string()))?;
+		
+		let timeout = match query_param(&req, "timeout") {
+			Some(timeout) => timeout.parse::<u64>()
+				.map_err(|_| (StatusCode::BAD_REQUEST, "invalid timeout".to_string()))?
+				.min(MAX_POLL_TIMEOUT),
+			None => DEFAULT_POLL_TIMEOUT,
+		};
+		
+		let client = {
+			let mut sessions = self.poll_sessions.lock().unwrap();
+			let session = sessions.get_mut(&query_id)
+				.ok_or((StatusCode::NOT_FOUND, "query not found".to_string()))?;
+			session.client.take()
+				.ok_or((StatusCode::CONFLICT, "query is already being polled".to_string()))?
+		};
+		
+		let mut guard = PollGuard { sessions: self.poll_sessions.clone(), query_id, client: Some(client) };
+		let client = guard.client.as_mut().unwrap();
+		
+		let mut notifications = vec![];
+		
+		let next = tokio::select! {
+			next = tokio::time::timeout(Duration::from_secs(timeout), client.inbox_next()) => next,
+			_ = self.server.shutting_down() => Ok(None),
+		};
 		
-		if let Some(allow_origin) = &self.allow_origin {
-			res = res.header(header::ACCESS_CONTROL_ALLOW_ORIGIN, allow_origin)
+		if let Ok(Some(msg)) = next {
+			notifications.extend(handle_inbox_message(msg));
+			
+			while let Ok(Some(msg)) = client.inbox_try_next() {
+				notifications.extend(handle_inbox_message(msg));
+			}
 		}
 		
-		Ok(res.body(body).unwrap())
+		Ok(json_response(&json!({ "notifications": notifications })))
+	}
+	
+	fn handle_metrics(&self) -> Result<Response<Body>, (StatusCode, String)> {
+		let body = metrics::render(&self.server.stats());
+		
+		Ok(Response::builder()
+			.header(header::CONTENT_TYPE, "text/plain; version=0.0.4")
+			.body(Body::from(body)).unwrap())
+	}
+	
+	async fn handle_audit(&self, req: Request<Body>) -> Result<Response<Body>, (StatusCode, String)> {
+		let client = self.client_connect();
+		
+		let query = AuditQuery {
+			pattern: query_param(&req, "pattern"),
+			operation: parse_param(&req, "operation")?,
+			identity: query_param(&req, "identity"),
+			since: parse_param::<DateTime<Utc>>(&req, "since")?,
+			until: parse_param::<DateTime<Utc>>(&req, "until")?,
+			offset: parse_param(&req, "offset")?.unwrap_or(0),
+			limit: parse_param(&req, "limit")?,
+		};
+		
+		let filter = AuditFilter::new(query, DEFAULT_AUDIT_LIMIT)
+			.map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+		
+		let entries = self.server.get_audit(filter, &client).await
+			.map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+		
+		Ok(json_response(&json!({ "entries": entries })))
+	}
+	
+	async fn handle_set_log_level(&self, req: Request<Body>) -> Result<Response<Body>, (StatusCode, String)> {
+		#[derive(Deserialize)]
+		struct SetLogLevel {
+			level: Option<LogLevel>,
+			#[serde(default)]
+			duration: Option<u64>,
+		}
+		
+		let client = self.client_connect();
+		
+		let bytes = read_body(req, self.max_body_size).await?;
+		
+		let body = serde_json::from_slice::<SetLogLevel>(&bytes)
+			.map_err(|_| (StatusCode::BAD_REQUEST, "invalid log level".to_string()))?;
+		
+		let level = self.server.set_log_level(body.level, body.duration.map(Duration::from_secs), &client);
+		Ok(json_response(&json!({ "level": level })))
+	}
+	
+	async fn handle_backup(&self) -> Result<Response<Body>, (StatusCode, String)> {
+		let client = self.client_connect();
+		
+		// waits until the storage thread has written all pending changes
+		let backup = self.server.backup(&client).await
+			.map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+		
+		let mut res = json_response(&backup);
+		res.headers_mut().insert(header::CONTENT_DISPOSITION,
+			format!("attachment; filename=\"{}\"", backup.filename()).parse().unwrap());
+		
+		Ok(res)
+	}
+	
+	async fn handle_backup_to_dir(&self) -> Result<Response<Body>, (StatusCode, String)> {
+		let dir = self.admin_backup_dir.clone()
+			.ok_or((StatusCode::BAD_REQUEST, "no backup dir configured".to_string()))?;
+		
+		let client = self.client_connect();
+		let server = self.server.clone();
+		
+		let path = tokio::task::spawn_blocking(move || server.backup_to_dir(&dir, &client)).await
+			.map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "backup failed".to_string()))?
+			.map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+		
+		Ok(json_response(&json!({ "file": path })))
+	}
+	
+	async fn handle_restore(&self, req: Request<Body>) -> Result<Response<Body>, (StatusCode, String)> {
+		let client = self.client_connect();
+		
+		let bytes = read_body(req, self.admin_max_restore_size.unwrap_or(DEFAULT_MAX_RESTORE_SIZE)).await?;
+		
+		let backup = serde_json::from_slice::<Backup>(&bytes)
+			.map_err(|_| (StatusCode::BAD_REQUEST, "invalid backup".to_string()))?;
+		
+		self.server.restore(backup, &client)
+			.map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+		
+		let success: Value = json!({ "success": true });
+		Ok(json_response(&success))
+	}
+	
+	fn websocket_client(&self, req: &Request<Body>) -> Client {
+		let mut client = self.client_connect();
+		client.set_read_only(self.is_read_only(req));
+		client.set_admin(self.is_admin_login(req));
+		client
 	}
 	
 	fn handle_websocket(&self, req: Request<Body>) -> Result<Response<Body>, (StatusCode, String)> {
-		let (response, websocket) = hyper_tungstenite::upgrade(req, None).unwrap();
+		let encoding = Encoding::negotiate(req.headers());
+		let client = self.websocket_client(&req);
+		
+		let (mut response, websocket) = hyper_tungstenite::upgrade(req, None).unwrap();
+		
+		if encoding == Encoding::MessagePack {
+			response.headers_mut().insert(header::SEC_WEBSOCKET_PROTOCOL, MSGPACK_PROTOCOL.parse().unwrap());
+		}
 		
 		let server = self.server.clone();
+		let ping = self.websocket_ping;
 		tokio::spawn(async move {
-			if let Err(e) = serve_websocket(websocket, server).await {
+			let result = match websocket.await {
+				Ok(websocket) => serve_websocket(websocket, server, client, encoding, ping).await,
+				Err(e) => Err(e.into()),
+			};
+			
+			if let Err(e) = result {
 				dbg!(e);
 			}
 		});
@@ -323,42 +933,670 @@ impl RequestHandler {
 
 pub struct HttpTransport {
 	addr: SocketAddr,
+	h2c: bool,
+	proxy_protocol: bool,
+	tls: Option<TlsAcceptor>,
+	connections: Connections,
+	idle_timeout: Option<Duration>,
 	request_handler: RequestHandler,
 }
 
+async fn serve_connection<S>(stream: S, http: Http, addr: SocketAddr, mut request_handler: RequestHandler, identity: Option<String>)
+where
+	S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+	request_handler.remote_addr = Some(addr);
+	request_handler.identity = identity;
+	let server = request_handler.server.clone();
+	
+	let service = service_fn(move |req: Request<Body>| {
+		let mut request_handler = request_handler.clone();
+		request_handler.client_addr = Some(forwarded_addr(addr.ip(), req.headers(), &request_handler.trusted_proxies));
+		
+		async move { Ok::<_, Infallible>(request_handler.handle_request(req).await) }
+	});
+	
+	let connection = http.serve_connection(stream, service).with_upgrades();
+	tokio::pin!(connection);
+	
+	tokio::select! {
+		_ = &mut connection => return,
+		_ = server.shutting_down() => {},
+	}
+	
+	// finishes the current request, then closes the connection
+	connection.as_mut().graceful_shutdown();
+	let _ = connection.await;
+}
+
 impl HttpTransport {
+	#[allow(clippy::too_many_arguments)]
 	pub fn new(addr: SocketAddr, server: Server,
-		allow_origin: Option<String>,
-		admin_enabled: bool, admin_asset_overrides: Option<PathBuf>
+		allow_origin: Vec<String>,
+		keepalive_interval: Option<Duration>,
+		websocket_ping: Option<Ping>,
+		max_body_size: usize,
+		admin_enabled: bool, admin_asset_overrides: Option<PathBuf>,
+		admin_credentials: Option<AdminCredentials>,
+		admin_read_only: bool,
+		admin_backup_dir: Option<PathBuf>,
+		admin_max_restore_size: Option<usize>,
+		tokens: Option<Tokens>,
+		limits: ConnectionLimits,
+		trusted_proxies: Vec<IpAddr>,
+		proxy_protocol: bool,
+		h2c: bool,
+		tls: Option<TlsAcceptor>
 	) -> Self {
 		HttpTransport {
 			addr, 
+			h2c,
+			proxy_protocol,
+			tls,
+			connections: Connections::new(limits.max_connections),
+			idle_timeout: limits.idle_timeout,
 			request_handler: RequestHandler {
 				server,
 				allow_origin,
+				keepalive_interval,
+				max_body_size,
 				admin_enabled,
 				admin_asset_overrides,
+				admin_credentials,
+				admin_read_only,
+				admin_backup_dir,
+				admin_max_restore_size,
+				tokens,
+				websocket_ping,
+				trusted_proxies,
+				remote_addr: None,
+				client_addr: None,
+				identity: None,
+				poll_sessions: Arc::new(Mutex::new(HashMap::new())),
 			},
 		}
 	}
 	
 	pub async fn serve(&self) {
-		println!("http transport listening on http://{}", self.addr);
+		let listener = TcpListener::bind(self.addr).await.unwrap();
+		self.serve_on(listener).await;
+	}
+	
+	/// Accepts connections on a listener bound before, e.g. before dropping
+	/// privileges.
+	pub async fn serve_on(&self, listener: TcpListener) {
+		if self.tls.is_some() {
+			println!("http transport listening on https://{}", self.addr);
+		} else {
+			println!("http transport listening on http://{}", self.addr);
+		}
 		
-		let request_handler = self.request_handler.clone();
-		let make_svc = make_service_fn(move |_conn| {
-			let request_handler = request_handler.clone();
+		loop {
+			let (mut stream, addr) = connection::accept(&listener, "http").await;
 			
-			async move {
-				Ok::<_, Infallible>(service_fn(move |req| {
-					let request_handler = request_handler.clone();
-					
-					async move { Ok::<_, Infallible>(request_handler.handle_request(req).await) }
-				}))
+			let slot = match self.connections.acquire() {
+				Some(slot) => slot,
+				None => {
+					println!("http transport: too many connections, closing connection from {}", addr);
+					continue;
+				},
+			};
+			
+			let tls = self.tls.clone();
+			let h2c = self.h2c;
+			let idle_timeout = self.idle_timeout;
+			let proxy_protocol = self.proxy_protocol;
+			let request_handler = self.request_handler.clone();
+			
+			tokio::spawn(async move {
+				let addr = if proxy_protocol {
+					match connection::proxied_addr(&mut stream, addr, "http").await {
+						Some(addr) => addr,
+						None => return,
+					}
+				} else {
+					addr
+				};
+				
+				let stream = IdleTimeout::new(stream, "http", addr, idle_timeout);
+				let mut http = Http::new();
+				
+				match tls {
+					Some(tls) => {
+						// failed handshakes, e.g. plain http requests, just drop the connection
+						if let Ok(stream) = tls.accept(stream).await {
+							if tls::negotiated_http2(&stream) {
+								http.http2_only(true);
+							} else {
+								http.http1_only(true);
+							}
+							
+							let identity = tls::client_identity(&stream);
+							serve_connection(stream, http, addr, request_handler, identity).await;
+						}
+					},
+					None => {
+						// without h2c, a connection preface is not taken as HTTP/2
+						http.http1_only(!h2c);
+						serve_connection(stream, http, addr, request_handler, None).await;
+					},
+				}
+				
+				drop(slot);
+			});
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::json_rpc::{Request, Response, ResponseMessage};
+	use crate::server::config::{AdminConfig, AuthConfig};
+	use crate::server::logger::NullLogger;
+	
+	fn request_handler() -> RequestHandler {
+		RequestHandler {
+			server: Server::new(None, Box::new(NullLogger)),
+			allow_origin: vec![],
+			keepalive_interval: None,
+			max_body_size: 1024,
+			admin_enabled: false,
+			admin_asset_overrides: None,
+			admin_credentials: None,
+			admin_read_only: false,
+			admin_backup_dir: None,
+			admin_max_restore_size: None,
+			tokens: None,
+			websocket_ping: None,
+			trusted_proxies: vec![],
+			remote_addr: None,
+			client_addr: None,
+			identity: None,
+			poll_sessions: Arc::default(),
+		}
+	}
+	
+	async fn get(handler: &RequestHandler, uri: &str) -> (StatusCode, Value) {
+		let response = handler.handle_request(hyper::Request::get(uri).body(Body::empty()).unwrap()).await;
+		let status = response.status();
+		let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+		
+		(status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+	}
+	
+	#[test]
+	fn test_negotiate_encoding() {
+		let mut headers = HeaderMap::new();
+		assert_eq!(Encoding::negotiate(&headers), Encoding::Json);
+		
+		headers.insert(header::SEC_WEBSOCKET_PROTOCOL, "json, msgpack".parse().unwrap());
+		assert_eq!(Encoding::negotiate(&headers), Encoding::MessagePack);
+	}
+	
+	#[test]
+	fn test_msgpack_request() {
+		let mut data = vec![];
+		json!({ "id": 1, "type": "set", "name": "foo", "value": { "temp": 20.5 } })
+			.serialize(&mut rmp_serde::Serializer::new(&mut data)).unwrap();
+		
+		let message = match Encoding::MessagePack.decode(&WebsocketMessage::binary(data.clone())).unwrap().unwrap() {
+			Requests::Single(message) => message,
+			Requests::Batch(_) => panic!("unexpected batch"),
+		};
+		assert_eq!(message.id, json!(1));
+		
+		match message.request {
+			Request::Set { name, value } => {
+				assert_eq!(name, "foo");
+				assert_eq!(value, json!({ "temp": 20.5 }));
+			},
+			request => panic!("unexpected request {:?}", request),
+		}
+		
+		assert!(Encoding::MessagePack.decode(&WebsocketMessage::binary(vec![0xc1])).unwrap().is_err());
+		assert!(Encoding::Json.decode(&WebsocketMessage::binary(data)).is_none());
+		
+		let batch = WebsocketMessage::text(r#"[{ "id": 1, "type": "getStats" }, { "id": 2 }]"#);
+		match Encoding::Json.decode(&batch).unwrap().unwrap() {
+			Requests::Batch(requests) => {
+				assert!(requests[0].is_ok());
+				assert!(requests[1].is_err());
+			},
+			Requests::Single(_) => panic!("expected batch"),
+		}
+	}
+	
+	#[test]
+	fn test_msgpack_response() {
+		let message = Encoding::MessagePack.encode(&ResponseMessage {
+			request_id: json!(1),
+			result: Some(Response::Success { success: true }),
+			error: None,
+		});
+		
+		assert!(message.is_binary());
+		
+		let value: Value = rmp_serde::from_slice(&message.into_data()).unwrap();
+		assert_eq!(value, json!({ "requestId": 1, "result": { "success": true } }));
+	}
+	
+	#[tokio::test]
+	async fn test_long_polling() {
+		let handler = request_handler();
+		let client = handler.server.client_connect();
+		handler.server.set("sensor", json!(20), &client).unwrap();
+		
+		let (_, result) = get(&handler, "/poll?pattern=sensor").await;
+		assert_eq!(result["objects"][0]["value"], json!(20));
+		let query_id = result["queryId"].as_str().unwrap().to_string();
+		
+		let (_, result) = get(&handler, &format!("/poll?queryId={}&timeout=0", query_id)).await;
+		assert_eq!(result, json!({ "notifications": [] }));
+		
+		handler.server.set("sensor", json!(21), &client).unwrap();
+		handler.server.emit("sensor", "calibrated", Value::Null, &client).unwrap();
+		
+		let (_, result) = get(&handler, &format!("/poll?queryId={}&timeout=10", query_id)).await;
+		assert_eq!(result["notifications"][0]["type"], "queryChange");
+		assert_eq!(result["notifications"][0]["object"]["value"], json!(21));
+		assert_eq!(result["notifications"][1]["type"], "queryEvent");
+		
+		let (status, _) = get(&handler, &format!("/poll?queryId={}", Uuid::new_v4())).await;
+		assert_eq!(status, StatusCode::NOT_FOUND);
+		
+		let (status, _) = get(&handler, "/poll?queryId=invalid").await;
+		assert_eq!(status, StatusCode::BAD_REQUEST);
+	}
+	
+	#[tokio::test]
+	async fn test_cors() {
+		let mut handler = request_handler();
+		handler.allow_origin = vec!["https://app.example.com".to_string()];
+		
+		let preflight = hyper::Request::options("/objects/sensor")
+			.header(header::ORIGIN, "https://app.example.com")
+			.header(header::ACCESS_CONTROL_REQUEST_METHOD, "POST")
+			.header(header::ACCESS_CONTROL_REQUEST_HEADERS, "content-type")
+			.body(Body::empty()).unwrap();
+		let response = handler.handle_request(preflight).await;
+		
+		assert_eq!(response.status(), StatusCode::NO_CONTENT);
+		assert_eq!(response.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN], "https://app.example.com");
+		assert_eq!(response.headers()[header::ACCESS_CONTROL_ALLOW_METHODS], ALLOWED_METHODS);
+		assert_eq!(response.headers()[header::ACCESS_CONTROL_ALLOW_HEADERS], "content-type");
+		
+		let set = hyper::Request::post("/objects/sensor")
+			.header(header::ORIGIN, "https://app.example.com")
+			.body(Body::from("42")).unwrap();
+		let response = handler.handle_request(set).await;
+		
+		assert_eq!(response.status(), StatusCode::OK);
+		assert_eq!(response.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN], "https://app.example.com");
+		assert_eq!(response.headers()[header::VARY], "Origin");
+		
+		let other = hyper::Request::get("/objects/sensor")
+			.header(header::ORIGIN, "https://evil.example.com")
+			.body(Body::empty()).unwrap();
+		let response = handler.handle_request(other).await;
+		
+		assert!(response.headers().get(header::ACCESS_CONTROL_ALLOW_ORIGIN).is_none());
+		
+		handler.allow_origin = vec!["*".to_string()];
+		let response = handler.handle_request(hyper::Request::get("/objects/sensor").body(Body::empty()).unwrap()).await;
+		assert_eq!(response.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+		assert!(response.headers().get(header::VARY).is_none());
+	}
+	
+	#[tokio::test]
+	async fn test_auth() {
+		let mut handler = request_handler();
+		handler.tokens = Some(Tokens::from_config(&AuthConfig { tokens: vec!["secret".to_string()], token_file: None }).unwrap());
+		handler.admin_enabled = true;
+		
+		let response = handler.handle_request(hyper::Request::get("/query?pattern=*").body(Body::empty()).unwrap()).await;
+		assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+		assert_eq!(response.headers()[header::WWW_AUTHENTICATE], "Bearer");
+		
+		let wrong = hyper::Request::get("/query?pattern=*")
+			.header(header::AUTHORIZATION, "Bearer wrong")
+			.body(Body::empty()).unwrap();
+		assert_eq!(handler.handle_request(wrong).await.status(), StatusCode::UNAUTHORIZED);
+		
+		let authorized = hyper::Request::get("/query?pattern=*")
+			.header(header::AUTHORIZATION, "Bearer secret")
+			.body(Body::empty()).unwrap();
+		assert_eq!(handler.handle_request(authorized).await.status(), StatusCode::OK);
+		
+		let (status, _) = get(&handler, "/query?pattern=*&access_token=secret").await;
+		assert_eq!(status, StatusCode::OK);
+		
+		let upgrade = hyper::Request::get("/")
+			.header(header::CONNECTION, "upgrade")
+			.header(header::UPGRADE, "websocket")
+			.body(Body::empty()).unwrap();
+		assert_eq!(handler.handle_request(upgrade).await.status(), StatusCode::UNAUTHORIZED);
+		
+		let preflight = hyper::Request::options("/objects/sensor").body(Body::empty()).unwrap();
+		assert_eq!(handler.handle_request(preflight).await.status(), StatusCode::NO_CONTENT);
+		
+		let (status, _) = get(&handler, "/").await;
+		assert_eq!(status, StatusCode::OK);
+	}
+	
+	#[tokio::test]
+	async fn test_admin_auth() {
+		let mut handler = request_handler();
+		handler.tokens = Some(Tokens::from_config(&AuthConfig { tokens: vec!["secret".to_string()], token_file: None }).unwrap());
+		handler.admin_enabled = true;
+		handler.admin_credentials = AdminCredentials::from_config(&AdminConfig {
+			password: Some("hunter2".to_string()),
+			..AdminConfig::default()
+		}).unwrap();
+		
+		// admin:hunter2
+		let login = "Basic YWRtaW46aHVudGVyMg==";
+		
+		for path in ["/", "/_assets/index.js", "/log-level"] {
+			let response = handler.handle_request(hyper::Request::get(path).body(Body::empty()).unwrap()).await;
+			assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+			assert!(response.headers()[header::WWW_AUTHENTICATE].to_str().unwrap().starts_with("Basic"));
+		}
+		
+		// api tokens don't open the admin panel
+		let (status, _) = get(&handler, "/log-level?access_token=secret").await;
+		assert_eq!(status, StatusCode::UNAUTHORIZED);
+		
+		let admin = hyper::Request::get("/log-level")
+			.header(header::AUTHORIZATION, login)
+			.body(Body::empty()).unwrap();
+		assert_eq!(handler.handle_request(admin).await.status(), StatusCode::OK);
+		
+		// the admin may use the rest of the api, too
+		let query = hyper::Request::get("/query?pattern=*")
+			.header(header::AUTHORIZATION, login)
+			.body(Body::empty()).unwrap();
+		assert_eq!(handler.handle_request(query).await.status(), StatusCode::OK);
+		
+		let (status, _) = get(&handler, "/query?pattern=*&access_token=secret").await;
+		assert_eq!(status, StatusCode::OK);
+	}
+	
+	#[tokio::test]
+	async fn test_admin_read_only() {
+		let mut handler = request_handler();
+		handler.admin_enabled = true;
+		handler.admin_read_only = true;
+		handler.admin_credentials = AdminCredentials::from_config(&AdminConfig {
+			password: Some("hunter2".to_string()),
+			..AdminConfig::default()
+		}).unwrap();
+		
+		let login = "Basic YWRtaW46aHVudGVyMg==";
+		
+		let (status, _) = get(&handler, "/log-level").await;
+		assert_eq!(status, StatusCode::UNAUTHORIZED);
+		
+		let view = hyper::Request::get("/log-level")
+			.header(header::AUTHORIZATION, login)
+			.body(Body::empty()).unwrap();
+		assert_eq!(handler.handle_request(view).await.status(), StatusCode::OK);
+		
+		let change = hyper::Request::post("/log-level")
+			.header(header::AUTHORIZATION, login)
+			.body(Body::from(r#"{"level":"info"}"#)).unwrap();
+		assert_eq!(handler.handle_request(change).await.status(), StatusCode::FORBIDDEN);
+		
+		let set = hyper::Request::post("/objects/lamp")
+			.header(header::AUTHORIZATION, login)
+			.body(Body::from("true")).unwrap();
+		assert_eq!(handler.handle_request(set).await.status(), StatusCode::FORBIDDEN);
+		
+		// other clients aren't affected
+		let set = hyper::Request::post("/objects/lamp").body(Body::from("true")).unwrap();
+		assert_eq!(handler.handle_request(set).await.status(), StatusCode::OK);
+	}
+	
+	#[tokio::test]
+	async fn test_admin_only() {
+		let backup = r#"{"created":"2024-01-01T00:00:00Z","objects":[]}"#;
+		// requests with the status they get with the admin login
+		let requests = [
+			(Method::POST, "/restore", backup, StatusCode::OK),
+			// the handler has neither storage nor a backup dir
+			(Method::GET, "/backup", "", StatusCode::INTERNAL_SERVER_ERROR),
+			(Method::POST, "/backup", "", StatusCode::BAD_REQUEST),
+			(Method::POST, "/log-level", r#"{"level":"info"}"#, StatusCode::OK),
+			(Method::GET, "/audit", "", StatusCode::OK),
+		];
+		
+		let request = |method: &Method, path: &str, body: &'static str, authorization: Option<&str>| {
+			let mut request = hyper::Request::builder().method(method).uri(path);
+			if let Some(authorization) = authorization {
+				request = request.header(header::AUTHORIZATION, authorization);
 			}
+			request.body(Body::from(body)).unwrap()
+		};
+		
+		// without an admin password, nobody may make them
+		let mut handler = request_handler();
+		handler.admin_enabled = true;
+		for (method, path, body, _) in &requests {
+			assert_eq!(handler.handle_request(request(method, path, body, None)).await.status(), StatusCode::FORBIDDEN, "{}", path);
+		}
+		
+		handler.tokens = Some(Tokens::from_config(&AuthConfig { tokens: vec!["secret".to_string()], token_file: None }).unwrap());
+		for (method, path, body, _) in &requests {
+			assert_eq!(handler.handle_request(request(method, path, body, Some("Bearer secret"))).await.status(), StatusCode::FORBIDDEN, "{}", path);
+		}
+		
+		handler.admin_credentials = AdminCredentials::from_config(&AdminConfig {
+			password: Some("hunter2".to_string()),
+			..AdminConfig::default()
+		}).unwrap();
+		for (method, path, body, admin_status) in &requests {
+			assert_eq!(handler.handle_request(request(method, path, body, None)).await.status(), StatusCode::UNAUTHORIZED, "{}", path);
+			assert_eq!(handler.handle_request(request(method, path, body, Some("Bearer secret"))).await.status(), StatusCode::UNAUTHORIZED, "{}", path);
+			assert_eq!(handler.handle_request(request(method, path, body, Some("Basic YWRtaW46aHVudGVyMg=="))).await.status(), *admin_status, "{}", path);
+		}
+	}
+	
+	#[tokio::test]
+	async fn test_admin_read_only_websocket() {
+		use tungstenite::protocol::Role;
+		
+		let mut handler = request_handler();
+		handler.admin_enabled = true;
+		handler.admin_read_only = true;
+		
+		let upgrade = |origin: &str| hyper::Request::get("/")
+			.header(header::HOST, "127.0.0.1:3000")
+			.header(header::ORIGIN, origin)
+			.header(header::CONNECTION, "upgrade")
+			.header(header::UPGRADE, "websocket")
+			.body(Body::empty()).unwrap();
+		
+		// opened by another page, e.g. a dashboard
+		assert!(!handler.websocket_client(&upgrade("http://dashboard.local")).is_read_only());
+		
+		// opened by the admin panel, without admin credentials
+		let client = handler.websocket_client(&upgrade("http://127.0.0.1:3000"));
+		assert!(client.is_read_only());
+		
+		let (stream, websocket) = tokio::io::duplex(1024);
+		let websocket = WebSocketStream::from_raw_socket(websocket, Role::Server, None).await;
+		let mut stream = WebSocketStream::from_raw_socket(stream, Role::Client, None).await;
+		let server = handler.server.clone();
+		tokio::spawn(async move {
+			let _ = serve_websocket(websocket, server, client, Encoding::Json, None).await;
 		});
 		
-		let http_server = hyper::Server::bind(&self.addr).serve(make_svc);
-		http_server.await.unwrap();
+		stream.send(WebsocketMessage::Text(r#"{"id":1,"type":"set","name":"lamp","value":true}"#.to_string())).await.unwrap();
+		let response = stream.next().await.unwrap().unwrap();
+		assert_eq!(response.into_text().unwrap(), r#"{"requestId":1,"error":"read-only client"}"#);
+		assert!(handler.server.get(&Pattern::compile("lamp").unwrap(), &handler.server.client_connect()).is_empty());
+	}
+	
+	#[tokio::test]
+	async fn test_max_body_size() {
+		let handler = request_handler();
+		
+		let set = hyper::Request::post("/objects/sensor").body(Body::from(format!("\"{}\"", "a".repeat(1022)))).unwrap();
+		assert_eq!(handler.handle_request(set).await.status(), StatusCode::OK);
+		
+		let set = hyper::Request::post("/objects/sensor").body(Body::from(format!("\"{}\"", "a".repeat(1023)))).unwrap();
+		assert_eq!(handler.handle_request(set).await.status(), StatusCode::PAYLOAD_TOO_LARGE);
+		
+		// without a content length the limit is enforced while reading
+		let (mut sender, body) = Body::channel();
+		tokio::spawn(async move {
+			for _ in 0..4 {
+				let _ = sender.send_data(vec![b' '; 512].into()).await;
+			}
+		});
+		
+		let patch = hyper::Request::patch("/objects/sensor").body(body).unwrap();
+		assert_eq!(handler.handle_request(patch).await.status(), StatusCode::PAYLOAD_TOO_LARGE);
+		
+		let mut handler = request_handler();
+		handler.admin_enabled = true;
+		handler.admin_credentials = AdminCredentials::from_config(&AdminConfig {
+			password: Some("hunter2".to_string()),
+			..AdminConfig::default()
+		}).unwrap();
+		
+		let login = "Basic YWRtaW46aHVudGVyMg==";
+		
+		let change = hyper::Request::post("/log-level")
+			.header(header::AUTHORIZATION, login)
+			.body(Body::from(format!("{{\"level\":\"info\"{}}}", " ".repeat(1024)))).unwrap();
+		assert_eq!(handler.handle_request(change).await.status(), StatusCode::PAYLOAD_TOO_LARGE);
+		assert_eq!(handler.server.log_level(), LogLevel::Trace);
+		
+		// restores have their own limit, backups are usually larger
+		let backup = format!(r#"{{"created":"2024-01-01T00:00:00Z","objects":[{{"name":"sensor","value":"{}","lastModified":"2024-01-01T00:00:00Z"}}]}}"#, "a".repeat(2048));
+		
+		let restore = hyper::Request::post("/restore")
+			.header(header::AUTHORIZATION, login)
+			.body(Body::from(backup.clone())).unwrap();
+		assert_eq!(handler.handle_request(restore).await.status(), StatusCode::OK);
+		
+		handler.admin_max_restore_size = Some(2048);
+		let restore = hyper::Request::post("/restore")
+			.header(header::AUTHORIZATION, login)
+			.body(Body::from(backup)).unwrap();
+		assert_eq!(handler.handle_request(restore).await.status(), StatusCode::PAYLOAD_TOO_LARGE);
+	}
+	
+	#[tokio::test]
+	async fn test_websocket_ping() {
+		use tungstenite::protocol::Role;
+		
+		let (client, stream) = tokio::io::duplex(1024);
+		let websocket = WebSocketStream::from_raw_socket(stream, Role::Server, None).await;
+		let mut client = WebSocketStream::from_raw_socket(client, Role::Client, None).await;
+		
+		let server = Server::new(None, Box::new(NullLogger));
+		let ping = Ping { interval: Duration::from_millis(100), timeout: Duration::from_millis(50) };
+		let started = Instant::now();
+		
+		tokio::select! {
+			result = serve_websocket(websocket, server.clone(), server.client_connect(), Encoding::Json, Some(ping)) => {
+				assert!(result.is_ok());
+				assert!(started.elapsed() >= Duration::from_millis(250));
+			},
+			_ = async {
+				// reading answers pings
+				for _ in 0..2 {
+					assert!(matches!(client.next().await, Some(Ok(WebsocketMessage::Ping(_)))));
+				}
+				
+				tokio::time::sleep(Duration::from_secs(5)).await;
+			} => panic!("websocket without pongs wasn't closed"),
+		}
+	}
+	
+	#[tokio::test]
+	async fn test_h2c() {
+		use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+		
+		async fn first_frame(h2c: bool) -> Vec<u8> {
+			let (mut client, stream) = duplex(4096);
+			
+			let mut http = Http::new();
+			http.http1_only(!h2c);
+			tokio::spawn(serve_connection(stream, http, "127.0.0.1:1234".parse().unwrap(), request_handler(), None));
+			
+			client.write_all(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n").await.unwrap();
+			
+			// the frame header, or nothing if the connection was closed
+			let mut buffer = vec![];
+			(&mut client).take(9).read_to_end(&mut buffer).await.unwrap();
+			buffer
+		}
+		
+		// a SETTINGS frame
+		assert_eq!(first_frame(true).await[3], 0x04);
+		// HTTP/1 doesn't understand the preface
+		assert!(first_frame(false).await.is_empty());
+	}
+	
+	#[test]
+	fn test_forwarded_addr() {
+		let proxy: IpAddr = "10.0.0.1".parse().unwrap();
+		let trusted = [proxy, "10.0.0.2".parse().unwrap()];
+		
+		let mut headers = HeaderMap::new();
+		assert_eq!(forwarded_addr(proxy, &headers, &trusted), proxy);
+		
+		headers.insert("x-forwarded-for", "203.0.113.7, 198.51.100.3, 10.0.0.2".parse().unwrap());
+		assert_eq!(forwarded_addr(proxy, &headers, &trusted), "198.51.100.3".parse::<IpAddr>().unwrap());
+		
+		// spoofed headers of untrusted peers are ignored
+		let client: IpAddr = "192.0.2.1".parse().unwrap();
+		assert_eq!(forwarded_addr(client, &headers, &trusted), client);
+		
+		headers.insert("x-forwarded-for", "[2001:db8::1]:4711".parse().unwrap());
+		assert_eq!(forwarded_addr(proxy, &headers, &trusted), "2001:db8::1".parse::<IpAddr>().unwrap());
+		
+		headers.insert("x-forwarded-for", "unknown, 10.0.0.2".parse().unwrap());
+		assert_eq!(forwarded_addr(proxy, &headers, &trusted), "10.0.0.2".parse::<IpAddr>().unwrap());
+	}
+	
+	#[test]
+	fn test_query_param() {
+		let req = hyper::Request::get("/query?limit=10&pattern=sensor%2F%2B%2C%C3%BC&flag").body(Body::empty()).unwrap();
+		assert_eq!(query_param(&req, "pattern"), Some("sensor/+,ü".to_string()));
+		assert_eq!(query_param(&req, "limit"), Some("10".to_string()));
+		assert_eq!(query_param(&req, "flag"), Some("".to_string()));
+		assert_eq!(query_param(&req, "missing"), None);
+		
+		let req = hyper::Request::get("/query?pattern=sensor/+").body(Body::empty()).unwrap();
+		assert_eq!(query_param(&req, "pattern"), Some("sensor/+".to_string()));
+	}
+	
+	#[tokio::test]
+	async fn test_get_all_pattern() {
+		let handler = request_handler();
+		let client = handler.client_connect();
+		handler.server.set("température/salon", json!(21), &client).unwrap();
+		handler.server.set("other", json!(0), &client).unwrap();
+		
+		let (status, objects) = get(&handler, "/query?pattern=temp%C3%A9rature%2F%2B&unused=1").await;
+		assert_eq!(status, StatusCode::OK);
+		assert_eq!(objects.as_array().unwrap().len(), 1);
+		assert_eq!(objects[0]["name"], "température/salon");
+	}
+	
+	#[tokio::test]
+	async fn test_encoded_object_name() {
+		let handler = request_handler();
+		
+		let set = hyper::Request::post("/objects/lamp%201/k%C3%BCche%23").body(Body::from("true")).unwrap();
+		assert_eq!(handler.handle_request(set).await.status(), StatusCode::OK);
+		
+		let (status, object) = get(&handler, "/objects/lamp%201/k%C3%BCche%23").await;
+		assert_eq!(status, StatusCode::OK);
+		assert_eq!(object["name"], "lamp 1/küche#");
 	}
 }
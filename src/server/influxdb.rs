@@ -0,0 +1,256 @@
+use crate::Object;
+use crate::patterns::Pattern;
+use crate::server::config::InfluxdbConfig;
+use crate::server::{Message, Server};
+use serde_json::Value;
+use std::net::UdpSocket;
+use std::sync::mpsc::{channel, Receiver};
+use std::thread;
+use std::time::Duration;
+use ureq::Agent;
+
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Escapes the characters that end a measurement, tag or field key in line
+/// protocol.
+fn escape(name: &str, special: &[char]) -> String {
+	let mut escaped = String::with_capacity(name.len());
+	
+	for c in name.chars() {
+		if c == '\\' || special.contains(&c) {
+			escaped.push('\\');
+		}
+		
+		escaped.push(c);
+	}
+	
+	escaped
+}
+
+/// Collects the numbers in `value`, naming nested fields by their path
+/// joined with `.` and a plain number `value`.
+fn numeric_fields(path: &str, value: &Value, fields: &mut Vec<(String, f64)>) {
+	match value {
+		Value::Number(number) => {
+			if let Some(number) = number.as_f64() {
+				fields.push((if path.is_empty() { "value".to_string() } else { path.to_string() }, number));
+			}
+		},
+		Value::Object(map) => {
+			for (key, value) in map {
+				let path = if path.is_empty() { key.clone() } else { format!("{}.{}", path, key) };
+				numeric_fields(&path, value, fields);
+			}
+		},
+		_ => {},
+	}
+}
+
+/// Formats an object as a line of line protocol, with its name as the
+/// `object` tag. All numbers are written as floats, so integer and float
+/// values of the same field don't conflict. Objects without numbers give no
+/// line.
+fn line(measurement: &str, object: &Object) -> Option<String> {
+	let mut fields = vec![];
+	numeric_fields("", &object.value, &mut fields);
+	
+	if fields.is_empty() {
+		return None;
+	}
+	
+	let fields: Vec<String> = fields.into_iter()
+		.map(|(key, value)| format!("{}={:?}", escape(&key, &[',', '=', ' ']), value))
+		.collect();
+	
+	Some(format!("{},object={} {} {}",
+		escape(measurement, &[',', ' ']),
+		escape(&object.name, &[',', '=', ' ']),
+		fields.join(","),
+		object.last_modified.timestamp_nanos()))
+}
+
+enum Output {
+	Http { agent: Agent, url: String, token: Option<String> },
+	Udp { socket: UdpSocket, addr: String },
+}
+
+impl Output {
+	fn write(&self, lines: &[String]) -> Result<(), String> {
+		match self {
+			Output::Http { agent, url, token } => {
+				let mut request = agent.post(url).set("content-type", "text/plain; charset=utf-8");
+				
+				if let Some(token) = token {
+					request = request.set("authorization", &format!("Token {}", token));
+				}
+				
+				request.send_string(&lines.join("\n"))
+					.map(|_| ())
+					.map_err(|e| e.to_string())
+			},
+			// one datagram per line keeps them below common size limits
+			Output::Udp { socket, addr } => {
+				for line in lines {
+					socket.send_to(line.as_bytes(), addr.as_str()).map_err(|e| e.to_string())?;
+				}
+				
+				Ok(())
+			},
+		}
+	}
+	
+	/// Writes lines on its own thread, together with the ones that were
+	/// queued meanwhile, so a slow database doesn't block the server.
+	fn run(self, lines: Receiver<String>) {
+		while let Ok(line) = lines.recv() {
+			let mut batch = vec![line];
+			batch.extend(lines.try_iter());
+			
+			if let Err(e) = self.write(&batch) {
+				println!("influxdb export error: dropping {} lines: {}", batch.len(), e);
+			}
+		}
+	}
+}
+
+/// Writes the numeric fields of objects matching a pattern to InfluxDB on
+/// every change.
+pub struct InfluxdbExporter {
+	server: Server,
+	pattern: Pattern,
+	measurement: String,
+	url: String,
+	output: Output,
+}
+
+impl InfluxdbExporter {
+	pub fn new(config: InfluxdbConfig, server: Server) -> Result<Self, String> {
+		let pattern = Pattern::compile(&config.pattern)
+			.map_err(|_| format!("invalid influxdb pattern {}", config.pattern))?;
+		
+		let output = if let Some(addr) = config.url.strip_prefix("udp://") {
+			let socket = UdpSocket::bind("0.0.0.0:0").map_err(|e| format!("can't create udp socket: {}", e))?;
+			Output::Udp { socket, addr: addr.to_string() }
+		} else if config.url.starts_with("http://") || config.url.starts_with("https://") {
+			let agent = ureq::AgentBuilder::new().timeout(HTTP_TIMEOUT).build();
+			Output::Http { agent, url: config.url.clone(), token: config.token }
+		} else {
+			return Err(format!("invalid influxdb url {}", config.url));
+		};
+		
+		Ok(InfluxdbExporter { server, pattern, measurement: config.measurement, url: config.url, output })
+	}
+	
+	pub async fn serve(self) {
+		let mut client = self.server.client_connect();
+		
+		if let Err(e) = self.server.query(&self.pattern, false, false, &client) {
+			println!("influxdb export error: {}", e);
+			return;
+		}
+		
+		println!("influxdb export writing to {}", self.url);
+		
+		let (lines_tx, lines_rx) = channel();
+		let output = self.output;
+		
+		thread::Builder::new()
+			.name("objtalk-influxdb".to_string())
+			.spawn(move || output.run(lines_rx))
+			.unwrap();
+		
+		while let Some(msg) = client.inbox_next().await {
+			let object = match msg {
+				Message::QueryAdd { object, .. } | Message::QueryChange { object, .. } => object,
+				_ => continue,
+			};
+			
+			if let Some(line) = line(&self.measurement, &object) {
+				if lines_tx.send(line).is_err() {
+					break;
+				}
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use chrono::{TimeZone, Utc};
+	use serde_json::json;
+	use std::io::{BufRead, BufReader, Read, Write};
+	use std::net::TcpListener;
+	
+	fn object(name: &str, value: Value) -> Object {
+		Object { name: name.to_string(), value, last_modified: Utc.timestamp(1600000000, 5) }
+	}
+	
+	#[test]
+	fn test_line() {
+		assert_eq!(line("objtalk", &object("sensor/1", json!(21))), Some("objtalk,object=sensor/1 value=21.0 1600000000000000005".to_string()));
+		assert_eq!(
+			line("objtalk", &object("sensor/1", json!({ "temperature": 21.5, "battery": { "level": 80 }, "name": "garden", "online": true }))),
+			Some("objtalk,object=sensor/1 temperature=21.5,battery.level=80.0 1600000000000000005".to_string())
+		);
+		assert_eq!(
+			line("room data", &object("living room,1", json!({ "a=b": 1 }))),
+			Some(r"room\ data,object=living\ room\,1 a\=b=1.0 1600000000000000005".to_string())
+		);
+		assert_eq!(line("objtalk", &object("lamp", json!({ "on": true }))), None);
+		assert_eq!(line("objtalk", &object("lamp", json!([1, 2]))), None);
+	}
+	
+	#[test]
+	fn test_write_http() {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let url = format!("http://{}/api/v2/write?bucket=objtalk", listener.local_addr().unwrap());
+		
+		let server = thread::spawn(move || {
+			let (stream, _) = listener.accept().unwrap();
+			let mut reader = BufReader::new(stream);
+			let mut head = String::new();
+			
+			loop {
+				let len = head.len();
+				reader.read_line(&mut head).unwrap();
+				
+				if head[len..].trim().is_empty() {
+					break;
+				}
+			}
+			
+			let length: usize = head.lines()
+				.find_map(|line| line.to_lowercase().strip_prefix("content-length:").map(|len| len.trim().parse().unwrap()))
+				.unwrap();
+			let mut body = vec![0; length];
+			reader.read_exact(&mut body).unwrap();
+			
+			write!(reader.get_mut(), "HTTP/1.1 204 No Content\r\ncontent-length: 0\r\nconnection: close\r\n\r\n").unwrap();
+			(head, String::from_utf8(body).unwrap())
+		});
+		
+		let output = Output::Http { agent: Agent::new(), url, token: Some("secret".to_string()) };
+		output.write(&["a value=1.0 1".to_string(), "b value=2.0 2".to_string()]).unwrap();
+		let (head, body) = server.join().unwrap();
+		
+		assert!(head.starts_with("POST /api/v2/write?bucket=objtalk HTTP/1.1"));
+		assert!(head.to_lowercase().contains("authorization: token secret"));
+		assert_eq!(body, "a value=1.0 1\nb value=2.0 2");
+	}
+	
+	#[test]
+	fn test_write_udp() {
+		let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+		receiver.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+		
+		let output = Output::Udp { socket: UdpSocket::bind("127.0.0.1:0").unwrap(), addr: receiver.local_addr().unwrap().to_string() };
+		output.write(&["a value=1.0 1".to_string(), "b value=2.0 2".to_string()]).unwrap();
+		
+		let mut buf = [0; 64];
+		let len = receiver.recv(&mut buf).unwrap();
+		assert_eq!(&buf[..len], b"a value=1.0 1");
+		let len = receiver.recv(&mut buf).unwrap();
+		assert_eq!(&buf[..len], b"b value=2.0 2");
+	}
+}
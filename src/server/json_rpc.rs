@@ -1,9 +1,47 @@
 use crate::json_rpc::*;
 use crate::patterns::Pattern;
 use crate::server::{Server, Client, Message};
-use serde_json::Value;
+use crate::server::storage::AuditFilter;
+use serde_json::{json, Value};
+use std::time::Duration;
 
-fn handle_request(request: Request, request_id: Value, client: &Client, server: Server) -> Result<Option<Response>, String> {
+const DEFAULT_EVENT_LIMIT: usize = 100;
+const DEFAULT_HISTORY_LIMIT: usize = 100;
+pub const DEFAULT_AUDIT_LIMIT: usize = 100;
+
+/// Whether a request changes objects or the server, which read-only clients
+/// may not do.
+fn changes_state(request: &Request) -> bool {
+	matches!(request,
+		Request::Set { .. }
+		| Request::Patch { .. }
+		| Request::Remove { .. }
+		| Request::Emit { .. }
+		| Request::Invoke { .. }
+		| Request::SetLogLevel { .. }
+		| Request::Restore { .. }
+		| Request::SetDisconnectCommands { .. })
+}
+
+/// Whether a request is reserved for admin clients, as it exposes or replaces
+/// the data of all clients or changes the server.
+fn is_privileged(request: &Request) -> bool {
+	matches!(request,
+		Request::GetAudit { .. }
+		| Request::SetLogLevel { .. }
+		| Request::Backup { .. }
+		| Request::Restore { .. })
+}
+
+async fn handle_request(request: Request, request_id: Value, client: &Client, server: Server) -> Result<Option<Response>, String> {
+	if client.is_read_only() && changes_state(&request) {
+		return Err("read-only client".to_string());
+	}
+	
+	if !client.is_admin() && is_privileged(&request) {
+		return Err("admin only".to_string());
+	}
+	
 	match request {
 		Request::Set { name, value } => {
 			server.set(&name, value, client)
@@ -23,10 +61,10 @@ fn handle_request(request: Request, request_id: Value, client: &Client, server:
 			let objects = server.get(&pattern, client);
 			Ok(Some(Response::Get { objects }))
 		},
-		Request::Query { pattern, provide_rpc } => {
+		Request::Query { pattern, provide_rpc, include_previous } => {
 			let pattern = Pattern::compile(&pattern).map_err(|_| "invalid pattern".to_string())?;
 			
-			let (query_id, objects) = server.query(&pattern, provide_rpc, client)
+			let (query_id, objects) = server.query(&pattern, provide_rpc, include_previous, client)
 				.map_err(|e| e.to_string())?;
 			
 			Ok(Some(Response::Query { query_id, objects }))
@@ -55,6 +93,46 @@ fn handle_request(request: Request, request_id: Value, client: &Client, server:
 			
 			Ok(None)
 		},
+		Request::GetEvents { pattern, since, limit } => {
+			let pattern = Pattern::compile(&pattern).map_err(|_| "invalid pattern".to_string())?;
+			
+			let events = server.get_events(&pattern, since, limit.unwrap_or(DEFAULT_EVENT_LIMIT), client).await
+				.map_err(|e| e.to_string())?;
+			Ok(Some(Response::Events { events }))
+		},
+		Request::GetHistory { name, since, until, limit } => {
+			let revisions = server.get_history(&name, since, until, limit.unwrap_or(DEFAULT_HISTORY_LIMIT), client).await
+				.map_err(|e| e.to_string())?;
+			
+			Ok(Some(Response::History { revisions }))
+		},
+		Request::GetAudit { query } => {
+			let filter = AuditFilter::new(query, DEFAULT_AUDIT_LIMIT)?;
+			let entries = server.get_audit(filter, client).await
+				.map_err(|e| e.to_string())?;
+			
+			Ok(Some(Response::Audit { entries }))
+		},
+		Request::GetStats {} => {
+			Ok(Some(Response::Stats { stats: server.stats() }))
+		},
+		Request::SetLogLevel { level, duration } => {
+			let level = server.set_log_level(level, duration.map(Duration::from_secs), client);
+			
+			Ok(Some(Response::LogLevel { level }))
+		},
+		Request::Backup {} => {
+			let backup = server.backup(client).await
+				.map_err(|e| e.to_string())?;
+			
+			Ok(Some(Response::Backup { backup }))
+		},
+		Request::Restore { backup } => {
+			server.restore(backup, client)
+				.map_err(|e| e.to_string())?;
+			
+			Ok(Some(Response::Success { success: true }))
+		},
 		Request::InvokeResult { invocation_id, result } => {
 			server.invoke_result(invocation_id, result, client)
 				.map_err(|e| e.to_string())?;
@@ -67,11 +145,21 @@ fn handle_request(request: Request, request_id: Value, client: &Client, server:
 			
 			Ok(Some(Response::Success { success: true }))
 		},
+		Request::Ping {} => {
+			Ok(Some(Response::Success { success: true }))
+		},
 	}
 }
 
-pub fn handle_message(req: RequestMessage, client: &Client, server: Server) -> Option<ResponseMessage> {
-	match handle_request(req.request, req.id.clone(), client, server) {
+/// Requests without an id are notifications, which aren't answered.
+pub async fn handle_message(req: RequestMessage, client: &Client, server: Server) -> Option<ResponseMessage> {
+	let result = handle_request(req.request, req.id.clone(), client, server).await;
+	
+	if req.id.is_null() {
+		return None;
+	}
+	
+	match result {
 		Ok(None) => None,
 		Ok(Some(result)) => {
 			Some(ResponseMessage {
@@ -90,14 +178,137 @@ pub fn handle_message(req: RequestMessage, client: &Client, server: Server) -> O
 	}
 }
 
-pub fn handle_inbox_message(msg: Message) -> EventMessage {
-	match msg {
+/// Handles the requests of a batch in order. Invalid requests get an error in
+/// their place, requests without a response, like `invoke`, are left out.
+pub async fn handle_batch(requests: Vec<Result<RequestMessage, serde_json::Error>>, client: &Client, server: Server) -> Vec<Value> {
+	let mut responses = vec![];
+	
+	for request in requests {
+		let response = match request {
+			Ok(request) => handle_message(request, client, server.clone()).await
+				.map(|response| serde_json::to_value(response).unwrap()),
+			Err(_) => Some(json!({ "type": "error", "error": "invalid message" })),
+		};
+		
+		responses.extend(response);
+	}
+	
+	responses
+}
+
+/// Returns `None` for results of invocations that were sent as notifications.
+pub fn handle_inbox_message(msg: Message) -> Option<EventMessage> {
+	Some(match msg {
 		Message::QueryAdd { query_id, object } => EventMessage::QueryAdd { query_id, object },
-		Message::QueryChange { query_id, object } => EventMessage::QueryChange { query_id, object },
+		Message::QueryChange { query_id, object, previous } => EventMessage::QueryChange { query_id, object, previous },
 		Message::QueryRemove { query_id, object } => EventMessage::QueryRemove { query_id, object },
 		Message::QueryEvent { query_id, object, event, data } => EventMessage::QueryEvent { query_id, object, event, data },
 		Message::QueryInvocation { query_id, invocation_id, object, method, args } => EventMessage::QueryInvocation { query_id, invocation_id, object, method, args },
+		Message::InvocationResult { request_id, .. } if request_id.is_null() => return None,
 		Message::InvocationResult { request_id, result: Ok(result) } => EventMessage::InvocationResult { request_id, result: Some(result), error: None },
 		Message::InvocationResult { request_id, result: Err(error) } => EventMessage::InvocationResult { request_id, result: None, error: Some(error.to_string()) },
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::LogLevel;
+	use crate::server::logger::NullLogger;
+	
+	#[tokio::test]
+	async fn test_handle_batch() {
+		let server = Server::new(None, Box::new(NullLogger));
+		let client = server.client_connect();
+		
+		let requests = Requests::from_value(json!([
+			{ "id": 1, "type": "set", "name": "sensor", "value": 21 },
+			{ "id": 2, "type": "unknown" },
+			{ "id": 3, "type": "get", "pattern": "sensor" },
+		])).unwrap();
+		
+		let requests = match requests {
+			Requests::Batch(requests) => requests,
+			Requests::Single(_) => panic!("not a batch"),
+		};
+		
+		let responses = handle_batch(requests, &client, server).await;
+		
+		assert_eq!(responses.len(), 3);
+		assert_eq!(responses[0], json!({ "requestId": 1, "result": { "success": true } }));
+		assert_eq!(responses[1], json!({ "type": "error", "error": "invalid message" }));
+		assert_eq!(responses[2]["requestId"], json!(3));
+		assert_eq!(responses[2]["result"]["objects"][0]["value"], json!(21));
+		
+		assert!(matches!(Requests::from_value(json!({ "id": 1, "type": "getStats" })), Ok(Requests::Single(_))));
+		assert!(Requests::from_value(json!({ "id": 1, "type": "unknown" })).is_err());
+	}
+	
+	#[tokio::test]
+	async fn test_notifications() {
+		let server = Server::new(None, Box::new(NullLogger));
+		let mut provider = server.client_connect();
+		let mut consumer = server.client_connect();
+		
+		let set = serde_json::from_value(json!({ "type": "set", "name": "sensor", "value": 21 })).unwrap();
+		assert!(handle_message(set, &consumer, server.clone()).await.is_none());
+		assert_eq!(server.get(&Pattern::compile("sensor").unwrap(), &consumer)[0].value, json!(21));
+		
+		let invalid = serde_json::from_value(json!({ "id": null, "type": "remove", "name": "missing" })).unwrap();
+		assert!(handle_message(invalid, &consumer, server.clone()).await.is_none());
+		
+		server.query(&Pattern::compile("sensor").unwrap(), true, false, &provider).unwrap();
+		
+		let invoke = serde_json::from_value(json!({ "type": "invoke", "object": "sensor", "method": "reset", "args": null })).unwrap();
+		assert!(handle_message(invoke, &consumer, server.clone()).await.is_none());
+		
+		let invocation_id = match provider.inbox_try_next().unwrap().unwrap() {
+			Message::QueryInvocation { invocation_id, .. } => invocation_id,
+			_ => panic!("unexpected message"),
+		};
+		server.invoke_result(invocation_id, json!(true), &provider).unwrap();
+		
+		let result = consumer.inbox_try_next().unwrap().unwrap();
+		assert!(matches!(result, Message::InvocationResult { .. }));
+		assert!(handle_inbox_message(result).is_none());
+	}
+	
+	#[tokio::test]
+	async fn test_read_only_client() {
+		let server = Server::new(None, Box::new(NullLogger));
+		server.set("sensor", json!(20), &server.client_connect()).unwrap();
+		
+		let mut client = server.client_connect();
+		client.set_read_only(true);
+		
+		let set = serde_json::from_value(json!({ "id": 1, "type": "set", "name": "sensor", "value": 21 })).unwrap();
+		let response = serde_json::to_value(handle_message(set, &client, server.clone()).await).unwrap();
+		assert_eq!(response, json!({ "requestId": 1, "error": "read-only client" }));
+		
+		let get = serde_json::from_value(json!({ "id": 2, "type": "get", "pattern": "sensor" })).unwrap();
+		let response = serde_json::to_value(handle_message(get, &client, server.clone()).await).unwrap();
+		assert_eq!(response["result"]["objects"][0]["value"], json!(20));
+	}
+	
+	#[tokio::test]
+	async fn test_admin_only() {
+		let server = Server::new(None, Box::new(NullLogger));
+		let mut client = server.client_connect();
+		
+		let request = json!({ "id": 1, "type": "setLogLevel", "level": "error" });
+		
+		let response = serde_json::to_value(handle_message(serde_json::from_value(request.clone()).unwrap(), &client, server.clone()).await).unwrap();
+		assert_eq!(response, json!({ "requestId": 1, "error": "admin only" }));
+		assert_eq!(server.log_level(), LogLevel::Trace);
+		
+		let audit = serde_json::from_value(json!({ "id": 2, "type": "getAudit" })).unwrap();
+		let response = serde_json::to_value(handle_message(audit, &client, server.clone()).await).unwrap();
+		assert_eq!(response, json!({ "requestId": 2, "error": "admin only" }));
+		
+		client.set_admin(true);
+		
+		let response = serde_json::to_value(handle_message(serde_json::from_value(request).unwrap(), &client, server.clone()).await).unwrap();
+		assert_eq!(response, json!({ "requestId": 1, "result": { "level": "error" } }));
+		assert_eq!(server.log_level(), LogLevel::Error);
 	}
 }
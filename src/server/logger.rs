@@ -1,16 +1,28 @@
-use chrono::Local;
+use crate::LogLevel;
+use crate::patterns::Pattern;
+use chrono::{Local, SecondsFormat, Utc};
 use colored::*;
 use serde::Serialize;
-use serde_json::Value;
+use serde_json::{Map, Value};
 use std::cell::RefCell;
 use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::path::Path;
 use uuid::Uuid;
 
 #[derive(Serialize)]
 #[serde(tag = "type")]
 #[serde(rename_all = "camelCase")]
 pub enum LogMessage {
-	ClientConnect { client: Uuid },
+	ClientConnect {
+		client: Uuid,
+		#[serde(skip_serializing_if = "Option::is_none")]
+		addr: Option<IpAddr>,
+		#[serde(skip_serializing_if = "Option::is_none")]
+		identity: Option<String>,
+	},
 	ClientDisconnect { client: Uuid },
 	Set { object: String, value: Value, client: Uuid },
 	Patch { object: String, value: Value, client: Uuid },
@@ -20,10 +32,128 @@ pub enum LogMessage {
 	Unsubscribe { query: Uuid, client: Uuid },
 	Remove { object: String, client: Uuid },
 	Emit { object: String, event: String, data: Value, client: Uuid },
+	GetEvents { pattern: String, client: Uuid },
+	GetHistory { object: String, client: Uuid },
+	GetAudit { client: Uuid },
+	Backup { client: Uuid },
+	Restore { objects: usize, client: Uuid },
 	#[serde(rename_all = "camelCase")]
 	Invoke { object: String, method: String, args: Value, invocation_id: Uuid, client: Uuid },
 	#[serde(rename_all = "camelCase")]
 	InvokeResult { invocation_id: Uuid, result: Value, client: Uuid },
+	StorageError { operation: String, object: Option<String>, error: String, degraded: bool },
+	StorageMaintenance { duration: f64, reclaimed: Option<u64> },
+	WebhookDelivery { url: String, object: String, attempts: u32, status: Option<u16>, error: Option<String> },
+	SetLogLevel { level: LogLevel, duration: Option<u64>, client: Uuid },
+	/// An operation that held the state lock, or with `storage` a storage
+	/// write, for longer than the slow operation threshold.
+	SlowOperation { operation: String, object: Option<String>, duration: f64, storage: bool },
+}
+
+/// The values of `LogMessage::kind`.
+pub const MESSAGE_KINDS: &[&str] = &[
+	"clientConnect", "clientDisconnect", "set", "patch", "get", "query", "unsubscribe", "remove", "emit",
+	"getEvents", "getHistory", "getAudit", "backup", "restore", "invoke", "invokeResult",
+	"storageError", "storageMaintenance", "webhookDelivery", "setLogLevel", "slowOperation",
+];
+
+impl LogMessage {
+	/// The client whose operation is logged, `None` for messages of the server itself.
+	pub fn client(&self) -> Option<Uuid> {
+		match self {
+			LogMessage::ClientConnect { client, .. }
+			| LogMessage::ClientDisconnect { client }
+			| LogMessage::Set { client, .. }
+			| LogMessage::Patch { client, .. }
+			| LogMessage::Get { client, .. }
+			| LogMessage::Query { client, .. }
+			| LogMessage::Unsubscribe { client, .. }
+			| LogMessage::Remove { client, .. }
+			| LogMessage::Emit { client, .. }
+			| LogMessage::GetEvents { client, .. }
+			| LogMessage::GetHistory { client, .. }
+			| LogMessage::GetAudit { client }
+			| LogMessage::Backup { client }
+			| LogMessage::Restore { client, .. }
+			| LogMessage::Invoke { client, .. }
+			| LogMessage::InvokeResult { client, .. }
+			| LogMessage::SetLogLevel { client, .. } => Some(*client),
+			LogMessage::StorageError { .. }
+			| LogMessage::StorageMaintenance { .. }
+			| LogMessage::WebhookDelivery { .. }
+			| LogMessage::SlowOperation { .. } => None,
+		}
+	}
+	
+	/// The `type` of the message in json lines.
+	pub fn kind(&self) -> &'static str {
+		match self {
+			LogMessage::ClientConnect { .. } => "clientConnect",
+			LogMessage::ClientDisconnect { .. } => "clientDisconnect",
+			LogMessage::Set { .. } => "set",
+			LogMessage::Patch { .. } => "patch",
+			LogMessage::Get { .. } => "get",
+			LogMessage::Query { .. } => "query",
+			LogMessage::Unsubscribe { .. } => "unsubscribe",
+			LogMessage::Remove { .. } => "remove",
+			LogMessage::Emit { .. } => "emit",
+			LogMessage::GetEvents { .. } => "getEvents",
+			LogMessage::GetHistory { .. } => "getHistory",
+			LogMessage::GetAudit { .. } => "getAudit",
+			LogMessage::Backup { .. } => "backup",
+			LogMessage::Restore { .. } => "restore",
+			LogMessage::Invoke { .. } => "invoke",
+			LogMessage::InvokeResult { .. } => "invokeResult",
+			LogMessage::StorageError { .. } => "storageError",
+			LogMessage::StorageMaintenance { .. } => "storageMaintenance",
+			LogMessage::WebhookDelivery { .. } => "webhookDelivery",
+			LogMessage::SetLogLevel { .. } => "setLogLevel",
+			LogMessage::SlowOperation { .. } => "slowOperation",
+		}
+	}
+	
+	/// The object the message is about, if it is about a single one.
+	pub fn object(&self) -> Option<&str> {
+		match self {
+			LogMessage::Set { object, .. }
+			| LogMessage::Patch { object, .. }
+			| LogMessage::Remove { object, .. }
+			| LogMessage::Emit { object, .. }
+			| LogMessage::GetHistory { object, .. }
+			| LogMessage::Invoke { object, .. }
+			| LogMessage::WebhookDelivery { object, .. } => Some(object),
+			LogMessage::StorageError { object, .. } | LogMessage::SlowOperation { object, .. } => object.as_deref(),
+			_ => None,
+		}
+	}
+	
+	/// Changes and connections are `info`, subscriptions `debug` and reads `trace`.
+	pub fn level(&self) -> LogLevel {
+		match self {
+			LogMessage::StorageError { .. } => LogLevel::Error,
+			LogMessage::WebhookDelivery { error: Some(_), .. }
+			| LogMessage::SetLogLevel { .. }
+			| LogMessage::SlowOperation { .. } => LogLevel::Warn,
+			LogMessage::ClientConnect { .. }
+			| LogMessage::ClientDisconnect { .. }
+			| LogMessage::Set { .. }
+			| LogMessage::Patch { .. }
+			| LogMessage::Remove { .. }
+			| LogMessage::Emit { .. }
+			| LogMessage::Invoke { .. }
+			| LogMessage::Backup { .. }
+			| LogMessage::Restore { .. }
+			| LogMessage::StorageMaintenance { .. } => LogLevel::Info,
+			LogMessage::Query { .. }
+			| LogMessage::Unsubscribe { .. }
+			| LogMessage::InvokeResult { .. }
+			| LogMessage::WebhookDelivery { .. } => LogLevel::Debug,
+			LogMessage::Get { .. }
+			| LogMessage::GetEvents { .. }
+			| LogMessage::GetHistory { .. }
+			| LogMessage::GetAudit { .. } => LogLevel::Trace,
+		}
+	}
 }
 
 pub trait Logger {
@@ -92,34 +222,99 @@ impl UuidColorer {
 	}
 }
 
+/// Where a logger writes its lines.
+enum LogOutput {
+	Stdout,
+	File(RefCell<File>),
+}
+
+impl LogOutput {
+	/// Appends to the file, creating it if needed.
+	fn file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+		let file = OpenOptions::new().create(true).append(true).open(path)?;
+		Ok(LogOutput::File(RefCell::new(file)))
+	}
+	
+	/// Lines that can't be written to a file are dropped.
+	fn write_line(&self, line: &str) {
+		match self {
+			LogOutput::Stdout => println!("{}", line),
+			LogOutput::File(file) => {
+				let _ = writeln!(file.borrow_mut(), "{}", line);
+			},
+		}
+	}
+}
+
+/// Writes colored lines to stdout, or plain ones to a file.
 pub struct StdoutLogger {
 	colorer: RefCell<UuidColorer>,
+	output: LogOutput,
+}
+
+impl Default for StdoutLogger {
+	fn default() -> Self {
+		Self::new()
+	}
 }
 
 impl StdoutLogger {
 	pub fn new() -> Self {
 		StdoutLogger {
 			colorer: RefCell::new(UuidColorer::new()),
+			output: LogOutput::Stdout,
 		}
 	}
 	
+	pub fn to_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+		Ok(StdoutLogger {
+			colorer: RefCell::new(UuidColorer::new()),
+			output: LogOutput::file(path)?,
+		})
+	}
+	
 	fn print(&self, client: Uuid, text: String) {
-		let color = self.colorer.borrow().get_color(client);
-		let line = format!("{} {} {}", short_time(), short_id(client).color(color), text);
+		let line = match self.output {
+			LogOutput::Stdout => {
+				let color = self.colorer.borrow().get_color(client);
+				format!("{} {} {}", short_time(), short_id(client).color(color), text)
+			},
+			LogOutput::File(_) => format!("{} {} {}", short_time(), short_id(client), text),
+		};
+		
+		self.output.write_line(&line);
+	}
+	
+	fn print_system(&self, text: String) {
+		let line = match self.output {
+			LogOutput::Stdout => format!("{} {} {}", short_time(), "system ".red(), text),
+			LogOutput::File(_) => format!("{} {} {}", short_time(), "system ", text),
+		};
 		
-		println!("{}", line);
+		self.output.write_line(&line);
 	}
 }
 
 impl Logger for StdoutLogger {
 	fn log(&self, message: &LogMessage) {
 		match message {
-			LogMessage::ClientConnect { client } => {
+			LogMessage::ClientConnect { client, addr, identity } => {
 				self.colorer.borrow_mut().assign_color(*client);
-				self.print(*client, format!("connect"));
+				
+				let mut text = "connect".to_string();
+				
+				if let Some(addr) = addr {
+					text += &format!(" from {}", addr);
+				}
+				
+				if let Some(identity) = identity {
+					text += &format!(" as {}", identity);
+				}
+				
+				self.print(*client, text);
 			},
 			LogMessage::ClientDisconnect { client } => {
-				self.print(*client, format!("disconnect"));
+				self.print(*client, "disconnect".to_string());
 				self.colorer.borrow_mut().unassign_color(*client);
 			},
 			LogMessage::Get { pattern, client } => self.print(*client, format!("get {}", pattern)),
@@ -129,8 +324,281 @@ impl Logger for StdoutLogger {
 			LogMessage::Patch { object, value, client } => self.print(*client, format!("patch {} {}", object, value)),
 			LogMessage::Remove { object, client } => self.print(*client, format!("remove {}", object)),
 			LogMessage::Emit { object, event, data, client } => self.print(*client, format!("emit {} {} {}", object, event, data)),
+			LogMessage::GetEvents { pattern, client } => self.print(*client, format!("get-events {}", pattern)),
+			LogMessage::GetHistory { object, client } => self.print(*client, format!("get-history {}", object)),
+			LogMessage::GetAudit { client } => self.print(*client, "get-audit".to_string()),
+			LogMessage::Backup { client } => self.print(*client, "backup".to_string()),
+			LogMessage::Restore { objects, client } => self.print(*client, format!("restore {} objects", objects)),
 			LogMessage::Invoke { object, method, args, invocation_id, client } => self.print(*client, format!("invoke {} {} {} {}", short_id(*invocation_id), object, method, args)),
 			LogMessage::InvokeResult { invocation_id, result, client } => self.print(*client, format!("invoke-result {} {}", short_id(*invocation_id), result)),
+			LogMessage::StorageError { operation, object, error, degraded } => {
+				let object = object.as_ref().map(|object| format!(" {}", object)).unwrap_or_default();
+				let degraded = if *degraded { " (storage disabled, continuing memory-only)" } else { "" };
+				self.print_system(format!("storage-error {}{}: {}{}", operation, object, error, degraded));
+			},
+			LogMessage::StorageMaintenance { duration, reclaimed } => {
+				let reclaimed = reclaimed.map(|bytes| format!(", reclaimed {} bytes", bytes)).unwrap_or_default();
+				self.print_system(format!("storage-maintenance took {:.3}s{}", duration, reclaimed));
+			},
+			LogMessage::WebhookDelivery { url, object, attempts, error, .. } => {
+				let outcome = error.as_ref().map(|error| format!("failed: {}", error)).unwrap_or_else(|| "delivered".to_string());
+				self.print_system(format!("webhook {} {}: {} after {} attempts", url, object, outcome, attempts));
+			},
+			LogMessage::SetLogLevel { level, duration, client } => {
+				let duration = duration.map(|seconds| format!(" for {}s", seconds)).unwrap_or_default();
+				self.print(*client, format!("set-log-level {}{}", level, duration));
+			},
+			LogMessage::SlowOperation { operation, object, duration, storage } => {
+				let object = object.as_ref().map(|object| format!(" {}", object)).unwrap_or_default();
+				let held = if *storage { "storage write took" } else { "held the state lock for" };
+				self.print_system(format!("slow-operation {}{}: {} {:.3}s", operation, object, held, duration));
+			},
+		}
+	}
+}
+
+/// Selects the messages of a type, and if there is a pattern only those about
+/// matching objects, of which a `SamplingLogger` logs one in `every`.
+pub struct SampleRule {
+	kind: String,
+	pattern: Option<Pattern>,
+	every: u64,
+}
+
+impl SampleRule {
+	pub fn new(kind: &str, pattern: Option<&str>, every: u64) -> Result<Self, String> {
+		if !MESSAGE_KINDS.contains(&kind) {
+			return Err(format!("unknown log message type {}", kind));
+		}
+		
+		if every == 0 {
+			return Err("log sampling needs every >= 1".to_string());
+		}
+		
+		Ok(SampleRule {
+			kind: kind.to_string(),
+			pattern: pattern.map(Pattern::compile).transpose()?,
+			every,
+		})
+	}
+	
+	fn matches(&self, message: &LogMessage) -> bool {
+		message.kind() == self.kind && match &self.pattern {
+			Some(pattern) => message.object().is_some_and(|object| pattern.matches_str(object)),
+			None => true,
+		}
+	}
+}
+
+/// Passes only the first and then every n-th message selected by a rule to
+/// another logger, so chatty objects still leave a trace. The first matching
+/// rule of a message applies, other messages are all passed on.
+pub struct SamplingLogger {
+	inner: Box<dyn Logger + Send>,
+	rules: Vec<SampleRule>,
+	counts: RefCell<Vec<u64>>,
+}
+
+impl SamplingLogger {
+	pub fn new(inner: Box<dyn Logger + Send>, rules: Vec<SampleRule>) -> Self {
+		let counts = RefCell::new(vec![0; rules.len()]);
+		SamplingLogger { inner, rules, counts }
+	}
+}
+
+impl Logger for SamplingLogger {
+	fn log(&self, message: &LogMessage) {
+		if let Some(index) = self.rules.iter().position(|rule| rule.matches(message)) {
+			let mut counts = self.counts.borrow_mut();
+			let count = counts[index];
+			counts[index] += 1;
+			
+			if !count.is_multiple_of(self.rules[index].every) {
+				return;
+			}
+		}
+		
+		self.inner.log(message);
+	}
+}
+
+struct ClientInfo {
+	addr: Option<IpAddr>,
+	identity: Option<String>,
+}
+
+/// Writes every message as a line of json with a `timestamp`, e.g. for log
+/// collectors. Messages of a client also get the `addr` and `identity` it
+/// connected with.
+pub struct JsonLogger {
+	clients: RefCell<HashMap<Uuid, ClientInfo>>,
+	output: LogOutput,
+}
+
+impl Default for JsonLogger {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl JsonLogger {
+	pub fn new() -> Self {
+		JsonLogger {
+			clients: RefCell::new(HashMap::new()),
+			output: LogOutput::Stdout,
+		}
+	}
+	
+	pub fn to_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+		Ok(JsonLogger {
+			clients: RefCell::new(HashMap::new()),
+			output: LogOutput::file(path)?,
+		})
+	}
+	
+	fn line(&self, message: &LogMessage) -> Value {
+		if let LogMessage::ClientConnect { client, addr, identity } = message {
+			self.clients.borrow_mut().insert(*client, ClientInfo { addr: *addr, identity: identity.clone() });
+		}
+		
+		let mut line = Map::new();
+		line.insert("timestamp".to_string(), Value::String(Utc::now().to_rfc3339_opts(SecondsFormat::Micros, true)));
+		line.insert("level".to_string(), serde_json::to_value(message.level()).unwrap());
+		
+		if let Value::Object(fields) = serde_json::to_value(message).unwrap() {
+			line.extend(fields);
+		}
+		
+		if let Some(info) = message.client().and_then(|client| self.clients.borrow().get(&client).map(|info| (info.addr, info.identity.clone()))) {
+			// connect messages have them already
+			if let Some(addr) = info.0 {
+				line.entry("addr").or_insert_with(|| Value::String(addr.to_string()));
+			}
+			
+			if let Some(identity) = info.1 {
+				line.entry("identity").or_insert(Value::String(identity));
+			}
+		}
+		
+		if let LogMessage::ClientDisconnect { client } = message {
+			self.clients.borrow_mut().remove(client);
+		}
+		
+		Value::Object(line)
+	}
+}
+
+impl Logger for JsonLogger {
+	fn log(&self, message: &LogMessage) {
+		self.output.write_line(&self.line(message).to_string());
+	}
+}
+
+/// Passes every message to several loggers, e.g. to stdout and a file.
+pub struct FanoutLogger {
+	loggers: Vec<Box<dyn Logger + Send>>,
+}
+
+impl FanoutLogger {
+	pub fn new(loggers: Vec<Box<dyn Logger + Send>>) -> Self {
+		FanoutLogger { loggers }
+	}
+}
+
+impl Logger for FanoutLogger {
+	fn log(&self, message: &LogMessage) {
+		for logger in &self.loggers {
+			logger.log(message);
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use serde_json::json;
+	use std::sync::{Arc, Mutex};
+	
+	#[test]
+	fn test_json_logger() {
+		let logger = JsonLogger::new();
+		let client = Uuid::new_v4();
+		
+		let line = logger.line(&LogMessage::ClientConnect { client, addr: Some("10.0.0.5".parse().unwrap()), identity: Some("sensor-1".to_string()) });
+		assert_eq!(line["type"], json!("clientConnect"));
+		assert_eq!(line["addr"], json!("10.0.0.5"));
+		assert!(line["timestamp"].is_string());
+		
+		let line = logger.line(&LogMessage::Set { object: "sensor/1".to_string(), value: json!(21), client });
+		assert_eq!(line["type"], json!("set"));
+		assert_eq!(line["level"], json!("info"));
+		assert_eq!(line["object"], json!("sensor/1"));
+		assert_eq!(line["client"], json!(client.to_string()));
+		assert_eq!(line["addr"], json!("10.0.0.5"));
+		assert_eq!(line["identity"], json!("sensor-1"));
+		
+		logger.line(&LogMessage::ClientDisconnect { client });
+		let line = logger.line(&LogMessage::Remove { object: "sensor/1".to_string(), client });
+		assert_eq!(line.get("addr"), None);
+		
+		let line = logger.line(&LogMessage::StorageMaintenance { duration: 0.5, reclaimed: None });
+		assert_eq!(line["type"], json!("storageMaintenance"));
+		assert_eq!(line.get("client"), None);
+	}
+	
+	#[test]
+	fn test_sampling_logger() {
+		struct RecordingLogger(Arc<Mutex<Vec<String>>>);
+		
+		impl Logger for RecordingLogger {
+			fn log(&self, message: &LogMessage) {
+				self.0.lock().unwrap().push(format!("{} {}", message.kind(), message.object().unwrap_or("-")));
+			}
+		}
+		
+		let lines = Arc::new(Mutex::new(vec![]));
+		let logger = SamplingLogger::new(Box::new(RecordingLogger(lines.clone())), vec![
+			SampleRule::new("set", Some("sensor/*"), 3).unwrap(),
+		]);
+		let client = Uuid::new_v4();
+		
+		for i in 0..7 {
+			logger.log(&LogMessage::Set { object: format!("sensor/{}", i), value: json!(i), client });
+		}
+		logger.log(&LogMessage::Set { object: "lamp".to_string(), value: json!(true), client });
+		logger.log(&LogMessage::Remove { object: "sensor/1".to_string(), client });
+		
+		assert_eq!(*lines.lock().unwrap(), vec!["set sensor/0", "set sensor/3", "set sensor/6", "set lamp", "remove sensor/1"]);
+		
+		assert!(SampleRule::new("Set", None, 10).is_err());
+		assert!(SampleRule::new("set", None, 0).is_err());
+		
+		let message = LogMessage::GetAudit { client };
+		assert_eq!(serde_json::to_value(&message).unwrap()["type"], json!(message.kind()));
+	}
+	
+	#[test]
+	fn test_fanout_logger() {
+		let dir = std::env::temp_dir();
+		let text = dir.join(format!("objtalk-log-{}.txt", std::process::id()));
+		let json = dir.join(format!("objtalk-log-{}.jsonl", std::process::id()));
+		
+		let logger = FanoutLogger::new(vec![
+			Box::new(StdoutLogger::to_file(&text).unwrap()),
+			Box::new(JsonLogger::to_file(&json).unwrap()),
+		]);
+		logger.log(&LogMessage::Set { object: "sensor/1".to_string(), value: json!(21), client: Uuid::new_v4() });
+		drop(logger);
+		
+		let text_log = std::fs::read_to_string(&text).unwrap();
+		let json_log = std::fs::read_to_string(&json).unwrap();
+		let _ = std::fs::remove_file(&text);
+		let _ = std::fs::remove_file(&json);
+		
+		assert!(text_log.ends_with(" set sensor/1 21\n"));
+		assert!(!text_log.contains('\u{1b}'));
+		
+		let line: Value = serde_json::from_str(json_log.trim()).unwrap();
+		assert_eq!(line["object"], json!("sensor/1"));
+	}
+}
@@ -0,0 +1,97 @@
+use crate::{MDNS_SERVICE_TYPE, VERSION_STRING};
+use crate::server::config::{HttpConfig, MdnsConfig, MessageFormat, TcpConfig};
+use mdns_sd::{ServiceDaemon, ServiceInfo};
+use std::net::SocketAddr;
+
+struct Transport {
+	kind: &'static str,
+	addr: SocketAddr,
+	tls: bool,
+	format: Option<MessageFormat>,
+}
+
+fn service_info(config: &MdnsConfig, transport: &Transport) -> Result<ServiceInfo, String> {
+	let instance = format!("{} {} {}", config.name, transport.kind, transport.addr.port());
+	let host_name = format!("{}.local.", config.name.replace(' ', "-"));
+	
+	let mut properties = vec![
+		("transport", transport.kind),
+		("tls", if transport.tls { "true" } else { "false" }),
+		("version", VERSION_STRING),
+	];
+	
+	match transport.format {
+		Some(MessageFormat::Json) => properties.push(("format", "json")),
+		Some(MessageFormat::Cbor) => properties.push(("format", "cbor")),
+		None => {},
+	}
+	
+	let info = if transport.addr.ip().is_unspecified() {
+		ServiceInfo::new(MDNS_SERVICE_TYPE, &instance, &host_name, (), transport.addr.port(), &properties[..])
+			.map(ServiceInfo::enable_addr_auto)
+	} else {
+		ServiceInfo::new(MDNS_SERVICE_TYPE, &instance, &host_name, transport.addr.ip(), transport.addr.port(), &properties[..])
+	};
+	
+	info.map_err(|e| format!("can't advertise {} transport: {}", transport.kind, e))
+}
+
+/// Advertises the http and tcp transports that aren't bound to a loopback
+/// address. They stay advertised as long as the returned daemon is running.
+pub fn advertise(config: &MdnsConfig, http: &[HttpConfig], tcp: &[TcpConfig]) -> Result<ServiceDaemon, String> {
+	let transports = http.iter()
+		.map(|conf| Transport { kind: "http", addr: conf.addr, tls: conf.tls.is_some(), format: None })
+		.chain(tcp.iter().map(|conf| Transport { kind: "tcp", addr: conf.addr, tls: conf.tls.is_some(), format: Some(conf.format) }));
+	
+	let daemon = ServiceDaemon::new().map_err(|e| format!("can't start mdns: {}", e))?;
+	
+	for transport in transports {
+		if transport.addr.ip().is_loopback() {
+			println!("not advertising {} transport on {} via mdns", transport.kind, transport.addr);
+			continue;
+		}
+		
+		daemon.register(service_info(config, &transport)?)
+			.map_err(|e| format!("can't advertise {} transport: {}", transport.kind, e))?;
+		
+		println!("advertising {} transport on {} via mdns", transport.kind, transport.addr);
+	}
+	
+	Ok(daemon)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	
+	#[test]
+	fn test_service_info() {
+		let config = MdnsConfig { name: "greenhouse".to_string() };
+		
+		let info = service_info(&config, &Transport {
+			kind: "tcp",
+			addr: "0.0.0.0:3001".parse().unwrap(),
+			tls: true,
+			format: Some(MessageFormat::Cbor),
+		}).unwrap();
+		
+		assert_eq!(info.get_fullname(), "greenhouse tcp 3001._objtalk._tcp.local.");
+		assert_eq!(info.get_hostname(), "greenhouse.local.");
+		assert_eq!(info.get_port(), 3001);
+		assert!(info.is_addr_auto());
+		assert_eq!(info.get_property_val_str("transport"), Some("tcp"));
+		assert_eq!(info.get_property_val_str("tls"), Some("true"));
+		assert_eq!(info.get_property_val_str("format"), Some("cbor"));
+		
+		let info = service_info(&config, &Transport {
+			kind: "http",
+			addr: "192.168.1.10:3000".parse().unwrap(),
+			tls: false,
+			format: None,
+		}).unwrap();
+		
+		assert!(!info.is_addr_auto());
+		assert_eq!(info.get_addresses_v4().len(), 1);
+		assert_eq!(info.get_property_val_str("format"), None);
+	}
+}
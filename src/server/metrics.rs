@@ -0,0 +1,82 @@
+use crate::{Stats, LATENCY_BUCKETS};
+use std::fmt::Write;
+
+fn metric(out: &mut String, name: &str, kind: &str, value: impl ToString) {
+	writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+	writeln!(out, "{} {}", name, value.to_string()).unwrap();
+}
+
+/// Renders stats in the prometheus text exposition format.
+pub fn render(stats: &Stats) -> String {
+	let mut out = String::new();
+	
+	if let Some(storage) = &stats.storage {
+		metric(&mut out, "objtalk_storage_writes_total", "counter", storage.writes);
+		metric(&mut out, "objtalk_storage_write_errors_total", "counter", storage.errors);
+		metric(&mut out, "objtalk_storage_queue_depth", "gauge", storage.queue_depth);
+		metric(&mut out, "objtalk_storage_write_latency_seconds_sum", "counter", storage.write_latency_sum);
+		metric(&mut out, "objtalk_storage_write_latency_seconds_max", "gauge", storage.write_latency_max);
+		metric(&mut out, "objtalk_storage_degraded", "gauge", storage.degraded as u8);
+		
+		if let Some(size) = storage.database_size {
+			metric(&mut out, "objtalk_storage_database_size_bytes", "gauge", size);
+		}
+	}
+	
+	if !stats.operations.is_empty() {
+		let name = "objtalk_operation_duration_seconds";
+		writeln!(out, "# TYPE {} histogram", name).unwrap();
+		
+		for (operation, op) in &stats.operations {
+			for (bound, count) in LATENCY_BUCKETS.iter().zip(op.buckets.iter()) {
+				writeln!(out, "{}_bucket{{operation=\"{}\",le=\"{}\"}} {}", name, operation, bound, count).unwrap();
+			}
+			writeln!(out, "{}_bucket{{operation=\"{}\",le=\"+Inf\"}} {}", name, operation, op.count).unwrap();
+			writeln!(out, "{}_sum{{operation=\"{}\"}} {}", name, operation, op.latency_sum).unwrap();
+			writeln!(out, "{}_count{{operation=\"{}\"}} {}", name, operation, op.count).unwrap();
+		}
+	}
+	
+	out
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{OperationStats, StorageStats};
+	
+	#[test]
+	fn test_render() {
+		let stats = Stats {
+			storage: Some(StorageStats {
+				writes: 3,
+				database_size: Some(4096),
+				..StorageStats::default()
+			}),
+			..Stats::default()
+		};
+		
+		let out = render(&stats);
+		assert!(out.contains("# TYPE objtalk_storage_writes_total counter\nobjtalk_storage_writes_total 3\n"));
+		assert!(out.contains("objtalk_storage_database_size_bytes 4096\n"));
+		assert_eq!(render(&Stats::default()), "");
+	}
+	
+	#[test]
+	fn test_render_operations() {
+		let mut set = OperationStats::default();
+		set.record(0.0003);
+		set.record(0.5);
+		
+		let mut stats = Stats::default();
+		stats.operations.insert("set".to_string(), set);
+		
+		let out = render(&stats);
+		assert!(out.starts_with("# TYPE objtalk_operation_duration_seconds histogram\n"));
+		assert!(out.contains("objtalk_operation_duration_seconds_bucket{operation=\"set\",le=\"0.00025\"} 0\n"));
+		assert!(out.contains("objtalk_operation_duration_seconds_bucket{operation=\"set\",le=\"0.0005\"} 1\n"));
+		assert!(out.contains("objtalk_operation_duration_seconds_bucket{operation=\"set\",le=\"1\"} 2\n"));
+		assert!(out.contains("objtalk_operation_duration_seconds_bucket{operation=\"set\",le=\"+Inf\"} 2\n"));
+		assert!(out.contains("objtalk_operation_duration_seconds_count{operation=\"set\"} 2\n"));
+	}
+}
@@ -1,24 +1,58 @@
 use chrono::prelude::*;
-use crate::{Object, Command, VERSION_STRING};
+use crate::{Object, Event, Revision, AuditEntry, AuditOperation, Backup, Stats, OperationStats, StorageErrorEntry, StorageStatus, Command, LogLevel, VERSION_STRING};
 use crate::patterns::Pattern;
-use crate::server::logger::{Logger, LogMessage};
-use crate::server::storage::Storage;
+use crate::server::config::{StorageConfig, SystemLogConfig};
+use crate::server::hooks::Hooks;
+use crate::server::logger::{Logger, LogMessage, SampleRule, SamplingLogger, StdoutLogger, MESSAGE_KINDS};
+use crate::server::storage::{AuditFilter, Storage, Backends};
+use crate::server::storage::writer::{StorageWriter, StorageFailure, StorageMetrics, MaintenanceReport, SlowWrite};
 use futures::channel::mpsc::{unbounded, UnboundedSender, UnboundedReceiver, TryRecvError};
+use futures::executor::block_on;
 use futures::StreamExt;
+use serde::de::DeserializeOwned;
 use serde_json::{Value, json};
-use std::collections::{HashMap, HashSet};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fs;
 use std::iter::FromIterator;
-use std::sync::{Arc, Mutex};
+use std::mem;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
 use thiserror::Error;
+use tokio::sync::watch;
 use uuid::Uuid;
 
 pub mod storage;
 pub mod json_rpc;
 pub mod http_transport;
 pub mod tcp_transport;
+pub mod udp_transport;
 pub mod config;
 pub mod logger;
 pub mod admin;
+pub mod metrics;
+pub mod cli;
+#[cfg(unix)]
+mod daemon;
+pub mod tls;
+pub mod auth;
+pub mod hooks;
+pub mod connection;
+pub mod upstream;
+pub mod replication;
+pub mod nats_bridge;
+#[cfg(feature = "mqtt")]
+pub mod mqtt_bridge;
+#[cfg(feature = "mdns")]
+pub mod mdns;
+#[cfg(feature = "webhooks")]
+pub mod webhook;
+#[cfg(feature = "influxdb")]
+pub mod influxdb;
 
 #[derive(Error, Debug, PartialEq)]
 pub enum Error {
@@ -36,10 +70,42 @@ pub enum Error {
 	ObjectNotInvocable,
 	#[error("invocation not found")]
 	InvocationNotFound,
+	#[error("storage error: {0}")]
+	Storage(String),
+	#[error("no storage configured")]
+	NoStorage,
+	#[error("read-only follower")]
+	ReadOnly,
+	#[error("backup failed: {0}")]
+	Backup(String),
+	#[error("rejected: {0}")]
+	Rejected(String),
+}
+
+/// How often loading the stored objects is attempted at startup.
+const LOAD_ATTEMPTS: u32 = 3;
+const LOAD_RETRY_DELAY: Duration = Duration::from_millis(500);
+/// How many of the busiest clients live stats list.
+const TOP_CLIENTS: usize = 10;
+/// How many storage errors the storage status keeps.
+const RECENT_STORAGE_ERRORS: usize = 10;
+
+async fn load_objects(storage: &dyn Storage) -> Result<Vec<Object>, storage::Error> {
+	let mut attempt = 1;
+	
+	loop {
+		match storage.get_objects().await {
+			Err(_) if attempt < LOAD_ATTEMPTS => {
+				tokio::time::sleep(LOAD_RETRY_DELAY * attempt).await;
+				attempt += 1;
+			},
+			result => return result,
+		}
+	}
 }
 
 fn validate_object_name(name: &str) -> Result<(), Error> {
-	if name == "" || name.starts_with("$") {
+	if name.is_empty() || name.starts_with("$") {
 		Err(Error::InvalidObjectName)
 	} else {
 		Ok(())
@@ -48,7 +114,7 @@ fn validate_object_name(name: &str) -> Result<(), Error> {
 
 fn merge_into_object(old: &mut Value, new: &Value) -> Result<(), Error> {
 	match (old, new) {
-		(&mut Value::Object(ref mut a), &Value::Object(ref b)) => {
+		(&mut Value::Object(ref mut a), Value::Object(b)) => {
 			for (k, v) in b {
 				a.insert(k.to_string(), v.clone());
 			}
@@ -68,6 +134,7 @@ pub enum Message {
 	QueryChange {
 		query_id: Uuid,
 		object: Object,
+		previous: Option<Value>,
 	},
 	QueryRemove {
 		query_id: Uuid,
@@ -98,6 +165,7 @@ struct Invocation {
 	client_id: Uuid,
 	request_id: Value,
 	query_id: Uuid,
+	started: Instant,
 }
 
 #[derive(Debug)]
@@ -105,32 +173,103 @@ struct Query {
 	id: Uuid,
 	pattern: Pattern,
 	provide_rpc: bool,
+	include_previous: bool,
 	objects: HashSet<String>,
 }
 
+#[derive(Debug)]
+struct Inbox {
+	tx: UnboundedSender<Message>,
+	/// Messages sent to the client that it hasn't taken from its inbox yet.
+	depth: Arc<AtomicUsize>,
+}
+
+impl Inbox {
+	fn send(&self, msg: Message) {
+		if self.tx.unbounded_send(msg).is_ok() {
+			self.depth.fetch_add(1, Ordering::Relaxed);
+		}
+	}
+}
+
 #[derive(Debug)]
 pub struct ClientState {
 	#[allow(dead_code)]
 	id: Uuid,
+	addr: Option<IpAddr>,
+	identity: Option<String>,
 	queries: Vec<Query>,
 	invocations: Vec<Invocation>,
-	inbox_tx: UnboundedSender<Message>,
+	inbox: Inbox,
 	disconnect_commands: Vec<Command>,
+	/// Operations since the last live stats.
+	operations: u64,
 }
 
 pub struct Client {
 	id: Uuid,
+	addr: Option<IpAddr>,
+	identity: Option<String>,
 	server: Server,
 	inbox_rx: UnboundedReceiver<Message>,
+	inbox_depth: Arc<AtomicUsize>,
+	read_only: bool,
+	admin: bool,
 }
 
 impl Client {
+	/// The address the client connected from, as reported by a trusted proxy
+	/// if there is one.
+	pub fn addr(&self) -> Option<IpAddr> {
+		self.addr
+	}
+	
+	/// The authenticated identity of the client, e.g. the common name of its
+	/// tls client certificate.
+	pub fn identity(&self) -> Option<&str> {
+		self.identity.as_deref()
+	}
+	
+	/// The json-rpc handler rejects requests of read-only clients that
+	/// change objects or the server.
+	pub fn set_read_only(&mut self, read_only: bool) {
+		self.read_only = read_only;
+	}
+	
+	pub fn is_read_only(&self) -> bool {
+		self.read_only
+	}
+	
+	/// Admin clients may use the json-rpc requests which expose or replace
+	/// the data of all clients or change the server, like `backup` and
+	/// `restore`. Clients with one of the server's admin identities are
+	/// admins from the start.
+	pub fn set_admin(&mut self, admin: bool) {
+		self.admin = admin;
+	}
+	
+	pub fn is_admin(&self) -> bool {
+		self.admin
+	}
+	
 	pub async fn inbox_next(&mut self) -> Option<Message> {
-		self.inbox_rx.next().await
+		let msg = self.inbox_rx.next().await;
+		
+		if msg.is_some() {
+			self.inbox_depth.fetch_sub(1, Ordering::Relaxed);
+		}
+		
+		msg
 	}
 	
 	pub fn inbox_try_next(&mut self) -> Result<Option<Message>, TryRecvError> {
-		self.inbox_rx.try_next()
+		let msg = self.inbox_rx.try_next();
+		
+		if let Ok(Some(_)) = msg {
+			self.inbox_depth.fetch_sub(1, Ordering::Relaxed);
+		}
+		
+		msg
 	}
 }
 
@@ -147,73 +286,167 @@ pub struct Server {
 
 struct Shared {
 	state: Mutex<State>,
+	/// Set once the server is shutting down.
+	shutdown: watch::Sender<bool>,
+	shutdown_rx: watch::Receiver<bool>,
+	hooks: Hooks,
 }
 
 struct State {
 	objects: HashMap<String,Object>,
 	clients: HashMap<Uuid,ClientState>,
-	storage: Option<Box<dyn Storage + Send>>,
+	storage: Option<StorageWriter>,
+	/// Kept after the storage has been given up, so its failures stay visible.
+	storage_metrics: Option<StorageMetrics>,
+	/// Set by the builder from the storage config.
+	storage_backend: Option<String>,
+	/// The latest storage failures, newest first.
+	storage_errors: VecDeque<StorageErrorEntry>,
 	logger: Box<dyn Logger + Send>,
+	/// Messages below this level aren't passed to the logger.
+	log_level: LogLevel,
+	/// Set at runtime with `setLogLevel`, until it expires.
+	log_level_override: Option<(LogLevel, Option<Instant>)>,
+	/// Set on followers, whose objects only change through replication.
+	read_only: bool,
+	operation_stats: BTreeMap<&'static str, OperationStats>,
+	/// Operations holding the lock for longer are logged and emitted as
+	/// `slowOperation` on `$system`.
+	slow_threshold: Option<Duration>,
+	system_log: SystemLogConfig,
+	/// Clients connecting with one of these identities are admins.
+	admin_identities: HashSet<String>,
 }
 
 impl State {
-	fn set(&mut self, name: &str, value: Value, client_id: Uuid) -> Result<(), Error> {
-		let inserted: bool;
+	fn record(&mut self, operation: &'static str, start: Instant) {
+		self.operation_stats.entry(operation).or_default().record(start.elapsed().as_secs_f64());
+	}
+	
+	/// Warns if the lock has been held since `locked` for longer than the
+	/// slow operation threshold.
+	fn check_slow(&mut self, operation: &str, object: &str, locked: Instant) {
+		let duration = locked.elapsed();
 		
-		validate_object_name(name)?;
+		// $system events are dispatched while warning, which must not warn again
+		if object == "$system" || self.slow_threshold.is_none_or(|threshold| duration <= threshold) {
+			return;
+		}
 		
-		self.log(LogMessage::Set { object: name.to_string(), value: value.clone(), client: client_id });
+		self.slow_operation(operation, Some(object), duration, false);
+	}
+	
+	fn slow_operation(&mut self, operation: &str, object: Option<&str>, duration: Duration, storage: bool) {
+		let duration = duration.as_secs_f64();
+		
+		self.log(LogMessage::SlowOperation {
+			operation: operation.to_string(),
+			object: object.map(|object| object.to_string()),
+			duration,
+			storage,
+		});
 		
-		if let Some(object) = self.objects.get_mut(name) {
-			object.value = value;
-			object.last_modified = Utc::now();
-			inserted = false;
+		self.internal_emit("$system", "slowOperation", json!({
+			"operation": operation,
+			"object": object,
+			"duration": duration,
+			"storage": storage,
+		})).unwrap()
+	}
+	
+	fn writable(&self) -> Result<(), Error> {
+		if self.read_only {
+			Err(Error::ReadOnly)
 		} else {
-			self.objects.insert(name.to_string(), Object {
-				name: name.to_string(),
-				value,
-				last_modified: Utc::now(),
-			});
-			inserted = true;
+			Ok(())
 		}
-		
-		let object = self.objects[name].clone();
+	}
+	
+	fn run_disconnect_commands(&mut self, commands: Vec<Command>, client_id: Uuid) {
+		for command in commands {
+			match command {
+				Command::Set { name, value } => {
+					let _ = self.set(&name, value, client_id);
+				},
+				Command::Patch { name, value } => {
+					let _ = self.patch(&name, value, client_id);
+				},
+				Command::Remove { name } => {
+					let _ = self.remove(&name, client_id);
+				},
+				Command::Emit { object, event, data } => {
+					let _ = self.emit(&object, &event, data, client_id);
+				},
+			}
+		}
+	}
+	
+	/// Stores a new version of an object and notifies the queries matching it.
+	fn put(&mut self, object: Object, client_id: Uuid) {
+		let name = object.name.clone();
+		let previous = self.objects.insert(name.clone(), object.clone()).map(|previous| previous.value);
 		
 		if let Some(storage) = &self.storage {
-			if inserted {
+			if previous.is_none() {
 				storage.add_object(object.clone());
 			} else {
 				storage.change_object(object.clone());
 			}
+			
+			if storage.records_history() {
+				storage.add_revision(Revision {
+					name: name.clone(),
+					value: object.value.clone(),
+					removed: false,
+					timestamp: object.last_modified,
+					client: client_id,
+				});
+			}
 		}
 		
+		let dispatch = Instant::now();
+		
 		for client in self.clients.values_mut() {
 			for query in &mut client.queries {
-				if query.pattern.matches_str(name) {
-					let msg = if query.objects.contains(name) {
+				if query.pattern.matches_str(&name) {
+					let msg = if query.objects.contains(&name) {
 						Message::QueryChange {
 							query_id: query.id,
 							object: object.clone(),
+							previous: if query.include_previous { previous.clone() } else { None },
 						}
 					} else {
-						query.objects.insert(name.to_string());
+						query.objects.insert(name.clone());
 						Message::QueryAdd {
 							query_id: query.id,
 							object: object.clone(),
 						}
 					};
 					
-					let _ = client.inbox_tx.unbounded_send(msg);
+					client.inbox.send(msg);
 				}
 			}
 		}
 		
+		self.record("dispatch", dispatch);
+		self.check_slow("dispatch", &name, dispatch);
+	}
+	
+	fn set(&mut self, name: &str, value: Value, client_id: Uuid) -> Result<(), Error> {
+		validate_object_name(name)?;
+		
+		self.log(LogMessage::Set { object: name.to_string(), value: value.clone(), client: client_id });
+		
+		self.put(Object {
+			name: name.to_string(),
+			value,
+			last_modified: Utc::now(),
+		}, client_id);
+		
 		Ok(())
 	}
 	
 	fn patch(&mut self, name: &str, value: Value, client_id: Uuid) -> Result<(), Error> {
-		let inserted: bool;
-		
 		validate_object_name(name)?;
 		
 		if !value.is_object() {
@@ -222,49 +455,20 @@ impl State {
 		
 		self.log(LogMessage::Patch { object: name.to_string(), value: value.clone(), client: client_id });
 		
-		if let Some(object) = self.objects.get_mut(name) {
-			merge_into_object(&mut object.value, &value)?;
-			object.last_modified = Utc::now();
-			inserted = false;
-		} else {
-			self.objects.insert(name.to_string(), Object {
-				name: name.to_string(),
-				value,
-				last_modified: Utc::now(),
-			});
-			inserted = true;
-		}
-		
-		let object = self.objects[name].clone();
-		
-		if let Some(storage) = &self.storage {
-			if inserted {
-				storage.add_object(object.clone());
-			} else {
-				storage.change_object(object.clone());
-			}
-		}
+		let value = match self.objects.get(name) {
+			Some(object) => {
+				let mut merged = object.value.clone();
+				merge_into_object(&mut merged, &value)?;
+				merged
+			},
+			None => value,
+		};
 		
-		for client in self.clients.values_mut() {
-			for query in &mut client.queries {
-				if query.pattern.matches_str(name) {
-					let msg = if query.objects.contains(name) {
-						Message::QueryChange {
-							query_id: query.id,
-							object: object.clone(),
-						}
-					} else {
-						query.objects.insert(name.to_string());
-						Message::QueryAdd {
-							query_id: query.id,
-							object: object.clone(),
-						}
-					};
-					
-					let _ = client.inbox_tx.unbounded_send(msg);
-				}
-			}
-		}
+		self.put(Object {
+			name: name.to_string(),
+			value,
+			last_modified: Utc::now(),
+		}, client_id);
 		
 		Ok(())
 	}
@@ -277,8 +481,20 @@ impl State {
 			
 			if let Some(storage) = &self.storage {
 				storage.remove_object(object.clone());
+				
+				if storage.records_history() {
+					storage.add_revision(Revision {
+						name: name.to_string(),
+						value: Value::Null,
+						removed: true,
+						timestamp: Utc::now(),
+						client: client_id,
+					});
+				}
 			}
 			
+			let dispatch = Instant::now();
+			
 			for client in self.clients.values_mut() {
 				for query in &mut client.queries {
 					if query.objects.contains(name) {
@@ -286,13 +502,16 @@ impl State {
 							query_id: query.id,
 							object: object.clone()
 						};
-						let _ = client.inbox_tx.unbounded_send(msg);
+						client.inbox.send(msg);
 						
 						query.objects.remove(name);
 					}
 				}
 			}
 			
+			self.record("dispatch", dispatch);
+			self.check_slow("dispatch", name, dispatch);
+			
 			Ok(true)
 		} else {
 			Ok(false)
@@ -300,10 +519,12 @@ impl State {
 	}
 	
 	fn internal_emit(&mut self, object: &str, event: &str, data: Value) -> Result<(), Error> {
-		if self.objects.get(object).is_none() {
+		if !self.objects.contains_key(object) {
 			return Err(Error::ObjectNotFound)
 		}
 		
+		let dispatch = Instant::now();
+		
 		for client in self.clients.values_mut() {
 			for query in &mut client.queries {
 				if query.objects.contains(object) {
@@ -313,7 +534,70 @@ impl State {
 						event: event.to_string(),
 						data: data.clone(),
 					};
-					let _ = client.inbox_tx.unbounded_send(msg);
+					client.inbox.send(msg);
+				}
+			}
+		}
+		
+		self.record("dispatch", dispatch);
+		self.check_slow("dispatch", object, dispatch);
+		
+		Ok(())
+	}
+	
+	fn restore(&mut self, backup: Backup, client_id: Uuid) -> Result<(), Error> {
+		for object in &backup.objects {
+			validate_object_name(&object.name)?;
+		}
+		
+		self.log(LogMessage::Restore { objects: backup.objects.len(), client: client_id });
+		
+		let restored: HashMap<String, Object> = backup.objects.iter()
+			.map(|object| (object.name.clone(), object.clone()))
+			.collect();
+		let mut previous = mem::replace(&mut self.objects, restored);
+		
+		if let Some(system) = previous.remove("$system") {
+			self.objects.insert(system.name.clone(), system);
+		}
+		
+		if let Some(storage) = &self.storage {
+			storage.restore(backup);
+		}
+		
+		for client in self.clients.values_mut() {
+			for query in &mut client.queries {
+				for (name, object) in &previous {
+					if query.objects.contains(name) && !self.objects.contains_key(name) {
+						query.objects.remove(name);
+						
+						client.inbox.send(Message::QueryRemove {
+							query_id: query.id,
+							object: object.clone(),
+						});
+					}
+				}
+				
+				for (name, object) in &self.objects {
+					if name == "$system" || !query.pattern.matches(name) {
+						continue;
+					}
+					
+					let msg = if query.objects.contains(name) {
+						Message::QueryChange {
+							query_id: query.id,
+							object: object.clone(),
+							previous: if query.include_previous { previous.get(name).map(|object| object.value.clone()) } else { None },
+						}
+					} else {
+						query.objects.insert(name.to_string());
+						Message::QueryAdd {
+							query_id: query.id,
+							object: object.clone(),
+						}
+					};
+					
+					client.inbox.send(msg);
 				}
 			}
 		}
@@ -325,7 +609,18 @@ impl State {
 		validate_object_name(object)?;
 		
 		self.log(LogMessage::Emit { object: object.to_string(), event: event.to_string(), data: data.clone(), client: client_id });
-		self.internal_emit(object, event, data)
+		self.internal_emit(object, event, data.clone())?;
+		
+		if let Some(storage) = &self.storage {
+			storage.add_event(Event {
+				object: object.to_string(),
+				event: event.to_string(),
+				data,
+				timestamp: Utc::now(),
+			});
+		}
+		
+		Ok(())
 	}
 	
 	fn invoke(&mut self, object: &str, method: &str, args: Value, request_id: Value, client_id: Uuid) -> Result<(), Error> {
@@ -333,34 +628,33 @@ impl State {
 		
 		let invocation_id = Uuid::new_v4();
 		
-		self.log(LogMessage::Invoke { object: object.to_string(), method: method.to_string(), args: args.clone(), invocation_id: invocation_id.clone(), client: client_id });
+		self.log(LogMessage::Invoke { object: object.to_string(), method: method.to_string(), args: args.clone(), invocation_id, client: client_id });
 		
-		if self.objects.get(object).is_none() {
+		if !self.objects.contains_key(object) {
 			return Err(Error::ObjectNotFound)
 		}
 		
 		for responder in self.clients.values_mut() {
 			for query in &mut responder.queries {
-				if query.provide_rpc {
-					if query.objects.contains(object) {
-						responder.invocations.push(Invocation {
-							id: invocation_id,
-							client_id,
-							request_id,
-							query_id: query.id,
-						});
-						
-						let msg = Message::QueryInvocation {
-							query_id: query.id,
-							invocation_id,
-							object: object.to_string(),
-							method: method.to_string(),
-							args: args.clone(),
-						};
-						let _ = responder.inbox_tx.unbounded_send(msg);
-						
-						return Ok(())
-					}
+				if query.provide_rpc && query.objects.contains(object) {
+					responder.invocations.push(Invocation {
+						id: invocation_id,
+						client_id,
+						request_id,
+						query_id: query.id,
+						started: Instant::now(),
+					});
+					
+					let msg = Message::QueryInvocation {
+						query_id: query.id,
+						invocation_id,
+						object: object.to_string(),
+						method: method.to_string(),
+						args: args.clone(),
+					};
+					responder.inbox.send(msg);
+					
+					return Ok(())
 				}
 			}
 		}
@@ -368,133 +662,787 @@ impl State {
 		Err(Error::ObjectNotInvocable)
 	}
 	
-	fn log(&mut self, message: LogMessage) {
-		self.logger.log(&message);
+	/// Makes the objects matching `pattern` equal to a snapshot of the leader's.
+	fn replicate(&mut self, pattern: &Pattern, objects: Vec<Object>, client_id: Uuid) {
+		let names: HashSet<&str> = objects.iter().map(|object| object.name.as_str()).collect();
+		let removed: Vec<String> = self.objects.keys()
+			.filter(|name| pattern.matches(name) && !names.contains(name.as_str()))
+			.cloned()
+			.collect();
+		
+		for name in removed {
+			let _ = self.remove(&name, client_id);
+		}
 		
-		self.internal_emit("$system", "log", serde_json::to_value(message).unwrap()).unwrap()
+		for object in objects {
+			let unchanged = self.objects.get(&object.name)
+				.is_some_and(|current| current.value == object.value && current.last_modified == object.last_modified);
+			
+			if !unchanged {
+				let _ = self.replicate_change(object, client_id);
+			}
+		}
 	}
-}
-
-impl Server {
-	pub fn new(storage: Option<Box<dyn Storage + Send>>, logger: Box<dyn Logger + Send>) -> Self {
-		let mut objects = HashMap::new();
+	
+	fn replicate_change(&mut self, object: Object, client_id: Uuid) -> Result<(), Error> {
+		validate_object_name(&object.name)?;
 		
-		objects.insert("$system".to_string(), Object {
-			name: "$system".to_string(),
-			value: json!({ "version": VERSION_STRING }),
-			last_modified: Utc::now(),
-		});
+		self.log(LogMessage::Set { object: object.name.clone(), value: object.value.clone(), client: client_id });
+		self.put(object, client_id);
 		
-		if let Some(ref storage) = storage {
-			for object in storage.get_objects() {
-				objects.insert(object.name.clone(), object);
+		Ok(())
+	}
+	
+	fn effective_log_level(&mut self) -> LogLevel {
+		if let Some((level, expires)) = self.log_level_override {
+			if expires.is_none_or(|expires| Instant::now() < expires) {
+				return level;
 			}
+			
+			self.log_level_override = None;
 		}
 		
-		let shared = Arc::new(Shared {
-			state: Mutex::new(State {
-				objects,
-				clients: HashMap::new(),
-				storage,
-				logger,
-			})
-		});
-		
-		Server { shared }
+		self.log_level
 	}
 	
-	pub fn client_connect(&self) -> Client {
-		let mut state = self.shared.state.lock().unwrap();
-		
-		let id = Uuid::new_v4();
-		
-		let (tx, rx) = unbounded();
-		
-		let client = ClientState {
-			id,
-			queries: vec![],
-			invocations: vec![],
-			inbox_tx: tx,
-			disconnect_commands: vec![],
-		};
+	fn log(&mut self, message: LogMessage) {
+		if message.level() <= self.effective_log_level() {
+			self.logger.log(&message);
+		}
 		
-		state.log(LogMessage::ClientConnect { client: id });
+		self.audit(&message);
 		
-		state.clients.insert(id, client);
+		if let Some(client) = message.client().and_then(|client| self.clients.get_mut(&client)) {
+			client.operations += 1;
+		}
 		
-		Client { id, server: self.clone(), inbox_rx: rx }
+		self.emit_log(&message);
 	}
 	
-	fn client_disconnect(&self, client_id: Uuid) {
-		let mut state = self.shared.state.lock().unwrap();
+	/// Emits a `log` event on `$system` to the clients allowed by the system
+	/// log config. The message is only serialized if someone receives it.
+	fn emit_log(&mut self, message: &LogMessage) {
+		let config = &self.system_log;
 		
-		let client = state.clients.remove(&client_id);
+		if !config.enabled || config.types.as_ref().is_some_and(|types| !types.iter().any(|kind| kind == message.kind())) {
+			return;
+		}
 		
-		if let Some(client) = client {
-			for invocation in client.invocations {
-				if let Some(client) = state.clients.get_mut(&invocation.client_id) {
-					let msg = Message::InvocationResult {
-						request_id: invocation.request_id,
-						result: Err(Error::ObjectNotInvocable),
-					};
-					let _ = client.inbox_tx.unbounded_send(msg);
+		let dispatch = Instant::now();
+		let mut data = None;
+		
+		for client in self.clients.values_mut() {
+			if let Some(identities) = &config.identities {
+				if !client.identity.as_ref().is_some_and(|identity| identities.contains(identity)) {
+					continue;
 				}
 			}
 			
-			for command in client.disconnect_commands {
-				match command {
-					Command::Set { name, value } => {
-						let _ = state.set(&name, value, client.id);
-					},
-					Command::Patch { name, value } => {
-						let _ = state.patch(&name, value, client.id);
-					},
-					Command::Remove { name } => {
-						let _ = state.remove(&name, client.id);
-					},
-					Command::Emit { object, event, data } => {
-						let _ = state.emit(&object, &event, data, client.id);
-					},
+			for query in &mut client.queries {
+				if query.objects.contains("$system") {
+					let data = data.get_or_insert_with(|| serde_json::to_value(message).unwrap());
+					
+					let msg = Message::QueryEvent {
+						query_id: query.id,
+						object: "$system".to_string(),
+						event: "log".to_string(),
+						data: data.clone(),
+					};
+					client.inbox.send(msg);
 				}
 			}
 		}
 		
-		state.log(LogMessage::ClientDisconnect { client: client_id });
+		self.record("dispatch", dispatch);
 	}
 	
-	pub fn set_disconnect_commands(&self, commands: Vec<Command>, client: &Client) -> Result<(), Error> {
-		let mut state = self.shared.state.lock().unwrap();
+	/// Records the operations of clients in the storage's audit log, with the
+	/// address and identity they connected with.
+	fn audit(&self, message: &LogMessage) {
+		let storage = match &self.storage {
+			Some(storage) => storage,
+			None => return,
+		};
 		
-		if let Some(client) = state.clients.get_mut(&client.id) {
-			client.disconnect_commands = commands;
-			Ok(())
-		} else {
-			Err(Error::ClientNotFound)
+		let (operation, object, event, method, value, client) = match message {
+			LogMessage::Set { object, value, client } => (AuditOperation::Set, object, None, None, Some(value), client),
+			LogMessage::Patch { object, value, client } => (AuditOperation::Patch, object, None, None, Some(value), client),
+			LogMessage::Remove { object, client } => (AuditOperation::Remove, object, None, None, None, client),
+			LogMessage::Emit { object, event, data, client } => (AuditOperation::Emit, object, Some(event), None, Some(data), client),
+			LogMessage::Invoke { object, method, args, client, .. } => (AuditOperation::Invoke, object, None, Some(method), Some(args), client),
+			_ => return,
+		};
+		
+		if !storage.records_audit(operation) {
+			return;
 		}
+		
+		let state = self.clients.get(client);
+		
+		storage.add_audit_entry(AuditEntry {
+			timestamp: Utc::now(),
+			operation,
+			object: object.clone(),
+			event: event.cloned(),
+			method: method.cloned(),
+			value: value.cloned().unwrap_or(Value::Null),
+			client: *client,
+			addr: state.and_then(|state| state.addr),
+			identity: state.and_then(|state| state.identity.clone()),
+		});
 	}
 	
-	pub fn set(&self, name: &str, value: Value, client: &Client) -> Result<(), Error> {
-		let mut state = self.shared.state.lock().unwrap();
-		state.set(name, value, client.id)
+	fn storage_failure(&mut self, failure: StorageFailure) {
+		if failure.degraded {
+			self.storage = None;
+		}
+		
+		self.storage_errors.push_front(StorageErrorEntry {
+			timestamp: Utc::now(),
+			operation: failure.operation.to_string(),
+			object: failure.object.clone(),
+			error: failure.error.clone(),
+			degraded: failure.degraded,
+		});
+		self.storage_errors.truncate(RECENT_STORAGE_ERRORS);
+		
+		let data = json!({
+			"operation": failure.operation,
+			"object": failure.object,
+			"error": failure.error,
+			"degraded": failure.degraded,
+		});
+		
+		self.log(LogMessage::StorageError {
+			operation: failure.operation.to_string(),
+			object: failure.object,
+			error: failure.error,
+			degraded: failure.degraded,
+		});
+		
+		self.internal_emit("$system", "storageError", data).unwrap()
 	}
 	
-	pub fn patch(&self, name: &str, value: Value, client: &Client) -> Result<(), Error> {
-		let mut state = self.shared.state.lock().unwrap();
-		state.patch(name, value, client.id)
+	fn storage_maintenance(&mut self, report: MaintenanceReport) {
+		let duration = report.duration.as_secs_f64();
+		
+		self.log(LogMessage::StorageMaintenance { duration, reclaimed: report.reclaimed });
+		
+		self.internal_emit("$system", "storageMaintenance", json!({
+			"duration": duration,
+			"reclaimed": report.reclaimed,
+		})).unwrap()
 	}
 	
-	pub fn get(&self, pattern: &Pattern, client: &Client) -> Vec<Object> {
+	/// Emits a `stats` event on `$system` with the load since the last one,
+	/// `interval` ago.
+	fn live_stats(&mut self, interval: Duration) {
+		let seconds = interval.as_secs_f64();
+		let mut operations = 0;
+		let mut queue_depth = 0;
+		let mut clients = vec![];
+		
+		for client in self.clients.values_mut() {
+			let client_operations = mem::take(&mut client.operations);
+			let client_queue_depth = client.inbox.depth.load(Ordering::Relaxed);
+			
+			operations += client_operations;
+			queue_depth += client_queue_depth;
+			
+			clients.push((client_operations, client_queue_depth, json!({
+				"client": client.id,
+				"addr": client.addr,
+				"identity": client.identity,
+				"operationsPerSecond": client_operations as f64 / seconds,
+				"queueDepth": client_queue_depth,
+				"queries": client.queries.len(),
+			})));
+		}
+		
+		clients.sort_by_key(|(operations, queue_depth, _)| Reverse((*operations, *queue_depth)));
+		
+		let data = json!({
+			"clients": self.clients.len(),
+			"objects": self.objects.len() - 1,
+			"operationsPerSecond": operations as f64 / seconds,
+			"queueDepth": queue_depth,
+			"topClients": clients.into_iter().take(TOP_CLIENTS).map(|(_, _, client)| client).collect::<Vec<_>>(),
+			"storage": self.storage_metrics.as_ref().map(|metrics| metrics.stats()),
+		});
+		
+		self.internal_emit("$system", "stats", data).unwrap()
+	}
+	
+	fn webhook_delivery(&mut self, delivery: WebhookDelivery) {
+		let data = json!({
+			"url": delivery.url,
+			"object": delivery.object,
+			"attempts": delivery.attempts,
+			"status": delivery.status,
+			"error": delivery.error,
+			"delivered": delivery.error.is_none(),
+		});
+		
+		self.log(LogMessage::WebhookDelivery {
+			url: delivery.url,
+			object: delivery.object,
+			attempts: delivery.attempts,
+			status: delivery.status,
+			error: delivery.error,
+		});
+		
+		self.internal_emit("$system", "webhookDelivery", data).unwrap()
+	}
+}
+
+/// The outcome of posting a change or event to a webhook.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WebhookDelivery {
+	pub url: String,
+	/// The object that changed or emitted the event.
+	pub object: String,
+	pub attempts: u32,
+	/// The response status of the last attempt, if there was a response.
+	pub status: Option<u16>,
+	/// Why the last attempt failed, `None` if the payload was delivered.
+	pub error: Option<String>,
+}
+
+/// Builds a `Server` whose storage is created from a config section, with
+/// storage backends provided by the embedder, see `storage::Backends`.
+pub struct ServerBuilder {
+	logger: Box<dyn Logger + Send>,
+	log_level: LogLevel,
+	sample_rules: Vec<SampleRule>,
+	slow_threshold: Option<Duration>,
+	system_log: SystemLogConfig,
+	admin_identities: Vec<String>,
+	backends: Backends,
+	storage: Option<StorageConfig>,
+	hooks: Hooks,
+}
+
+impl ServerBuilder {
+	pub fn logger(mut self, logger: Box<dyn Logger + Send>) -> Self {
+		self.logger = logger;
+		self
+	}
+	
+	/// Messages below `level` aren't passed to the logger, `trace` by default.
+	pub fn log_level(mut self, level: LogLevel) -> Self {
+		self.log_level = level;
+		self
+	}
+	
+	/// Logs only some of the messages selected by the rules, see `SamplingLogger`.
+	pub fn log_sampling(mut self, rules: Vec<SampleRule>) -> Self {
+		self.sample_rules = rules;
+		self
+	}
+	
+	/// Warns about operations holding the state lock and storage writes
+	/// taking longer than `threshold`.
+	pub fn slow_operation_threshold(mut self, threshold: Duration) -> Self {
+		self.slow_threshold = Some(threshold);
+		self
+	}
+	
+	/// Selects the log messages emitted as `log` events on `$system`, all by
+	/// default.
+	pub fn system_log(mut self, config: SystemLogConfig) -> Self {
+		self.system_log = config;
+		self
+	}
+	
+	/// Makes clients connecting with one of `identities` admins, see
+	/// `Client::set_admin`.
+	pub fn admin_identities(mut self, identities: Vec<String>) -> Self {
+		self.admin_identities = identities;
+		self
+	}
+	
+	/// Registers a custom storage backend, see `Backends::register`.
+	pub fn backend<C, S, F>(mut self, name: &str, factory: F) -> Self
+	where
+		C: DeserializeOwned,
+		S: Storage + 'static,
+		F: Fn(C) -> Result<S, String> + Send + Sync + 'static,
+	{
+		self.backends.register(name, factory);
+		self
+	}
+	
+	pub fn backends(&self) -> &Backends {
+		&self.backends
+	}
+	
+	pub fn storage(mut self, config: StorageConfig) -> Self {
+		self.storage = Some(config);
+		self
+	}
+	
+	/// Runs `hook` before a client sets an object, see `Hooks`.
+	pub fn on_set<F>(mut self, hook: F) -> Self
+	where
+		F: Fn(&Client, &str, &mut Value) -> Result<(), String> + Send + Sync + 'static,
+	{
+		self.hooks.set.push(Box::new(hook));
+		self
+	}
+	
+	/// Runs `hook` before a client patches an object, with the patch as value.
+	pub fn on_patch<F>(mut self, hook: F) -> Self
+	where
+		F: Fn(&Client, &str, &mut Value) -> Result<(), String> + Send + Sync + 'static,
+	{
+		self.hooks.patch.push(Box::new(hook));
+		self
+	}
+	
+	/// Runs `hook` before a client removes an object.
+	pub fn on_remove<F>(mut self, hook: F) -> Self
+	where
+		F: Fn(&Client, &str) -> Result<(), String> + Send + Sync + 'static,
+	{
+		self.hooks.remove.push(Box::new(hook));
+		self
+	}
+	
+	/// Runs `hook` before a client emits an event.
+	pub fn on_emit<F>(mut self, hook: F) -> Self
+	where
+		F: Fn(&Client, &str, &str, &mut Value) -> Result<(), String> + Send + Sync + 'static,
+	{
+		self.hooks.emit.push(Box::new(hook));
+		self
+	}
+	
+	/// Runs `hook` before a client invokes a method, with the method name and
+	/// its arguments.
+	pub fn on_invoke<F>(mut self, hook: F) -> Self
+	where
+		F: Fn(&Client, &str, &str, &mut Value) -> Result<(), String> + Send + Sync + 'static,
+	{
+		self.hooks.invoke.push(Box::new(hook));
+		self
+	}
+	
+	pub fn build(self) -> Result<Server, String> {
+		let storage = match &self.storage {
+			Some(config) => Some(storage::from_config(config, &self.backends)?),
+			None => None,
+		};
+		let storage_backend = self.storage.as_ref().map(|config| config.backend.name().to_string());
+		
+		for kind in self.system_log.types.iter().flatten() {
+			if !MESSAGE_KINDS.contains(&kind.as_str()) {
+				return Err(format!("unknown log message type {} in system-log", kind));
+			}
+		}
+		
+		let logger: Box<dyn Logger + Send> = if self.sample_rules.is_empty() {
+			self.logger
+		} else {
+			Box::new(SamplingLogger::new(self.logger, self.sample_rules))
+		};
+		
+		let server = Server::with_hooks(storage, logger, self.hooks);
+		
+		let mut state = server.shared.state.lock().unwrap();
+		state.storage_backend = storage_backend;
+		state.log_level = self.log_level;
+		state.slow_threshold = self.slow_threshold;
+		state.system_log = self.system_log;
+		state.admin_identities = self.admin_identities.into_iter().collect();
+		
+		if let (Some(threshold), Some(storage)) = (self.slow_threshold, &state.storage) {
+			let weak = Arc::downgrade(&server.shared);
+			storage.report_slow_writes(threshold, Arc::new(move |slow: SlowWrite| {
+				if let Some(shared) = weak.upgrade() {
+					shared.state.lock().unwrap().slow_operation(slow.operation, slow.object.as_deref(), slow.duration, true);
+				}
+			}));
+		}
+		
+		drop(state);
+		Ok(server)
+	}
+}
+
+impl Server {
+	pub fn builder() -> ServerBuilder {
+		ServerBuilder {
+			logger: Box::new(StdoutLogger::new()),
+			log_level: LogLevel::default(),
+			sample_rules: vec![],
+			slow_threshold: None,
+			system_log: SystemLogConfig::default(),
+			admin_identities: vec![],
+			backends: Backends::default(),
+			storage: None,
+			hooks: Hooks::default(),
+		}
+	}
+	
+	pub fn new(storage: Option<Box<dyn Storage>>, logger: Box<dyn Logger + Send>) -> Self {
+		Server::with_hooks(storage, logger, Hooks::default())
+	}
+	
+	fn with_hooks(storage: Option<Box<dyn Storage>>, logger: Box<dyn Logger + Send>, hooks: Hooks) -> Self {
+		let mut objects = HashMap::new();
+		
+		objects.insert("$system".to_string(), Object {
+			name: "$system".to_string(),
+			value: json!({ "version": VERSION_STRING }),
+			last_modified: Utc::now(),
+		});
+		
+		let (shutdown, shutdown_rx) = watch::channel(false);
+		
+		let shared = Arc::new(Shared {
+			state: Mutex::new(State {
+				objects,
+				clients: HashMap::new(),
+				storage: None,
+				storage_metrics: None,
+				storage_backend: None,
+				storage_errors: VecDeque::new(),
+				logger,
+				log_level: LogLevel::default(),
+				log_level_override: None,
+				read_only: false,
+				operation_stats: BTreeMap::new(),
+				slow_threshold: None,
+				system_log: SystemLogConfig::default(),
+				admin_identities: HashSet::new(),
+			}),
+			shutdown,
+			shutdown_rx,
+			hooks,
+		});
+		
+		if let Some(storage) = storage {
+			// failures are reported from the storage thread, which must not keep the server alive
+			let weak: Weak<Shared> = Arc::downgrade(&shared);
+			let writer = StorageWriter::new(storage, Box::new(move |failure| {
+				if let Some(shared) = weak.upgrade() {
+					shared.state.lock().unwrap().storage_failure(failure);
+				}
+			}));
+			
+			let stored = writer.read_blocking(|storage| Box::pin(load_objects(storage)))
+				.unwrap_or_else(|| Err(storage::Error::Other("storage thread exited".to_string())));
+			
+			let mut state = shared.state.lock().unwrap();
+			state.storage_metrics = Some(writer.metrics());
+			
+			match stored {
+				Ok(stored) => {
+					for object in stored {
+						state.objects.insert(object.name.clone(), object);
+					}
+					
+					state.storage = Some(writer);
+				},
+				Err(e) => state.storage_failure(StorageFailure {
+					operation: "load",
+					object: None,
+					error: e.to_string(),
+					degraded: true,
+				}),
+			}
+		}
+		
+		Server { shared }
+	}
+	
+	/// Runs storage maintenance every `interval` on a background thread until
+	/// the server is dropped.
+	pub fn start_maintenance(&self, interval: Duration) {
+		let weak = Arc::downgrade(&self.shared);
+		
+		thread::Builder::new()
+			.name("objtalk-maintenance".to_string())
+			.spawn(move || {
+				loop {
+					thread::sleep(interval);
+					
+					let storage = match weak.upgrade() {
+						Some(shared) => shared.state.lock().unwrap().storage.clone(),
+						None => break,
+					};
+					
+					let result = match storage {
+						Some(storage) => storage.maintain(),
+						None => continue,
+					};
+					
+					let shared = match weak.upgrade() {
+						Some(shared) => shared,
+						None => break,
+					};
+					let mut state = shared.state.lock().unwrap();
+					
+					match result {
+						Ok(report) => state.storage_maintenance(report),
+						Err(e) => state.storage_failure(StorageFailure {
+							operation: "maintenance",
+							object: None,
+							error: e.to_string(),
+							degraded: false,
+						}),
+					}
+				}
+			})
+			.unwrap();
+	}
+	
+	/// Emits a `stats` event on `$system` every `interval` on a background
+	/// thread until the server is dropped.
+	pub fn start_live_stats(&self, interval: Duration) {
+		let weak = Arc::downgrade(&self.shared);
+		
+		thread::Builder::new()
+			.name("objtalk-stats".to_string())
+			.spawn(move || {
+				loop {
+					thread::sleep(interval);
+					
+					match weak.upgrade() {
+						Some(shared) => shared.state.lock().unwrap().live_stats(interval),
+						None => break,
+					}
+				}
+			})
+			.unwrap();
+	}
+	
+	/// Asks the transports to stop accepting connections and to close the
+	/// ones they have.
+	pub fn shutdown(&self) {
+		let _ = self.shared.shutdown.send(true);
+	}
+	
+	/// Completes once `shutdown` was called.
+	pub async fn shutting_down(&self) {
+		let mut shutdown = self.shared.shutdown_rx.clone();
+		
+		while !*shutdown.borrow() {
+			if shutdown.changed().await.is_err() {
+				return;
+			}
+		}
+	}
+	
+	/// The level below which messages aren't logged right now.
+	pub fn log_level(&self) -> LogLevel {
+		self.shared.state.lock().unwrap().effective_log_level()
+	}
+	
+	/// Overrides the configured log level, for `duration` if given, e.g. to
+	/// debug a production server. `None` restores the configured level.
+	/// Returns the level in effect.
+	pub fn set_log_level(&self, level: Option<LogLevel>, duration: Option<Duration>, client: &Client) -> LogLevel {
 		let mut state = self.shared.state.lock().unwrap();
 		
+		let duration = duration.filter(|_| level.is_some());
+		state.log_level_override = level.map(|level| (level, duration.map(|duration| Instant::now() + duration)));
+		let level = state.effective_log_level();
+		
+		state.log(LogMessage::SetLogLevel {
+			level,
+			duration: duration.map(|duration| duration.as_secs()),
+			client: client.id,
+		});
+		
+		level
+	}
+	
+	/// Makes the server reject all changes made by clients, for followers that
+	/// replicate the objects of a leader.
+	pub fn set_read_only(&self, read_only: bool) {
+		self.shared.state.lock().unwrap().read_only = read_only;
+	}
+	
+	/// Replaces the objects matching `pattern` with a snapshot of the leader's,
+	/// notifying the queries about the differences.
+	pub fn replicate(&self, pattern: &Pattern, objects: Vec<Object>, client: &Client) {
+		self.shared.state.lock().unwrap().replicate(pattern, objects, client.id);
+	}
+	
+	/// Applies a change made on the leader, keeping its modification time.
+	pub fn replicate_change(&self, object: Object, client: &Client) -> Result<(), Error> {
+		self.shared.state.lock().unwrap().replicate_change(object, client.id)
+	}
+	
+	pub fn replicate_remove(&self, name: &str, client: &Client) -> Result<bool, Error> {
+		self.shared.state.lock().unwrap().remove(name, client.id)
+	}
+	
+	/// Emits an event that was emitted on the leader.
+	pub fn replicate_event(&self, object: &str, event: &str, data: Value, client: &Client) -> Result<(), Error> {
+		self.shared.state.lock().unwrap().emit(object, event, data, client.id)
+	}
+	
+	pub fn connected_clients(&self) -> usize {
+		self.shared.state.lock().unwrap().clients.len()
+	}
+	
+	/// Runs the disconnect commands of all clients that are still connected,
+	/// for shutting down without waiting for their connections to close.
+	pub fn run_disconnect_commands(&self) {
+		let mut state = self.shared.state.lock().unwrap();
+		
+		let commands: Vec<(Uuid, Vec<Command>)> = state.clients.values_mut()
+			.map(|client| (client.id, mem::take(&mut client.disconnect_commands)))
+			.collect();
+		
+		for (client_id, commands) in commands {
+			state.run_disconnect_commands(commands, client_id);
+		}
+	}
+	
+	/// Reports the outcome of a webhook delivery as a `webhookDelivery` event
+	/// on `$system`.
+	pub fn webhook_delivery(&self, delivery: WebhookDelivery) {
+		self.shared.state.lock().unwrap().webhook_delivery(delivery);
+	}
+	
+	/// Blocks until all pending storage writes have been applied.
+	pub fn flush(&self) {
+		let storage = self.shared.state.lock().unwrap().storage.clone();
+		
+		if let Some(storage) = storage {
+			storage.flush();
+		}
+	}
+	
+	/// Writes the pending changes and closes the storage before the server
+	/// exits, see `StorageWriter::close`. Later changes aren't persisted.
+	pub fn close_storage(&self) -> Result<(), Error> {
+		let storage = self.shared.state.lock().unwrap().storage.clone();
+		
+		match storage {
+			Some(storage) => storage.close().map_err(|e| Error::Storage(e.to_string())),
+			None => Ok(()),
+		}
+	}
+	
+	pub fn client_connect(&self) -> Client {
+		self.client_connect_from(None, None)
+	}
+	
+	pub fn client_connect_from(&self, addr: Option<IpAddr>, identity: Option<String>) -> Client {
+		let mut state = self.shared.state.lock().unwrap();
+		
+		let id = Uuid::new_v4();
+		
+		let (tx, rx) = unbounded();
+		
+		let inbox_depth = Arc::new(AtomicUsize::new(0));
+		
+		let client = ClientState {
+			id,
+			addr,
+			identity: identity.clone(),
+			queries: vec![],
+			invocations: vec![],
+			inbox: Inbox { tx, depth: inbox_depth.clone() },
+			disconnect_commands: vec![],
+			operations: 0,
+		};
+		
+		state.log(LogMessage::ClientConnect { client: id, addr, identity: identity.clone() });
+		
+		state.clients.insert(id, client);
+		
+		let admin = identity.as_ref().is_some_and(|identity| state.admin_identities.contains(identity));
+		
+		Client { id, addr, identity, server: self.clone(), inbox_rx: rx, inbox_depth, read_only: false, admin }
+	}
+	
+	fn client_disconnect(&self, client_id: Uuid) {
+		let mut state = self.shared.state.lock().unwrap();
+		
+		let client = state.clients.remove(&client_id);
+		
+		if let Some(client) = client {
+			for invocation in client.invocations {
+				if let Some(client) = state.clients.get_mut(&invocation.client_id) {
+					let msg = Message::InvocationResult {
+						request_id: invocation.request_id,
+						result: Err(Error::ObjectNotInvocable),
+					};
+					client.inbox.send(msg);
+				}
+			}
+			
+			state.run_disconnect_commands(client.disconnect_commands, client.id);
+		}
+		
+		state.log(LogMessage::ClientDisconnect { client: client_id });
+	}
+	
+	pub fn set_disconnect_commands(&self, mut commands: Vec<Command>, client: &Client) -> Result<(), Error> {
+		for command in &mut commands {
+			self.shared.hooks.run_command(client, command)?;
+		}
+		
+		let mut state = self.shared.state.lock().unwrap();
+		state.writable()?;
+		
+		if let Some(client) = state.clients.get_mut(&client.id) {
+			client.disconnect_commands = commands;
+			Ok(())
+		} else {
+			Err(Error::ClientNotFound)
+		}
+	}
+	
+	pub fn set(&self, name: &str, mut value: Value, client: &Client) -> Result<(), Error> {
+		let start = Instant::now();
+		self.shared.hooks.run_set(client, name, &mut value)?;
+		let mut state = self.shared.state.lock().unwrap();
+		let locked = Instant::now();
+		state.writable()?;
+		let result = state.set(name, value, client.id);
+		state.record("set", start);
+		state.check_slow("set", name, locked);
+		result
+	}
+	
+	pub fn patch(&self, name: &str, mut value: Value, client: &Client) -> Result<(), Error> {
+		let start = Instant::now();
+		self.shared.hooks.run_patch(client, name, &mut value)?;
+		let mut state = self.shared.state.lock().unwrap();
+		let locked = Instant::now();
+		state.writable()?;
+		let result = state.patch(name, value, client.id);
+		state.record("patch", start);
+		state.check_slow("patch", name, locked);
+		result
+	}
+	
+	pub fn get(&self, pattern: &Pattern, client: &Client) -> Vec<Object> {
+		let start = Instant::now();
+		let mut state = self.shared.state.lock().unwrap();
+		let locked = Instant::now();
+		
 		state.log(LogMessage::Get { pattern: pattern.string.clone(), client: client.id });
 		
-		state.objects.values().filter(|object| {
+		let objects = state.objects.values().filter(|object| {
 			pattern.matches(&object.name)
-		}).cloned().collect()
+		}).cloned().collect();
+		
+		state.record("get", start);
+		state.check_slow("get", &pattern.string, locked);
+		objects
 	}
 	
-	pub fn query(&self, pattern: &Pattern, provide_rpc: bool, client: &Client) -> Result<(Uuid, Vec<Object>),Error> {
+	pub fn query(&self, pattern: &Pattern, provide_rpc: bool, include_previous: bool, client: &Client) -> Result<(Uuid, Vec<Object>),Error> {
+		let start = Instant::now();
 		let mut state = self.shared.state.lock().unwrap();
+		let locked = Instant::now();
 		
 		let id = Uuid::new_v4();
 		
@@ -509,8 +1457,11 @@ impl Server {
 				id,
 				pattern: pattern.clone(),
 				provide_rpc,
+				include_previous,
 				objects: HashSet::from_iter(objects.iter().map(|object| object.name.clone())),
 			});
+			state.record("query", start);
+			state.check_slow("query", &pattern.string, locked);
 			Ok((id, objects))
 		} else {
 			Err(Error::ClientNotFound)
@@ -533,9 +1484,9 @@ impl Server {
 				client.invocations.retain(|invocation| {
 					if invocation.query_id == query_id {
 						invocations.push(invocation.clone());
-						return false;
+						false
 					} else {
-						return true;
+						true
 					}
 				});
 			} else {
@@ -549,7 +1500,7 @@ impl Server {
 					request_id: invocation.request_id,
 					result: Err(Error::ObjectNotInvocable),
 				};
-				let _ = client.inbox_tx.unbounded_send(msg);
+				client.inbox.send(msg);
 			}
 		}
 		
@@ -557,45 +1508,188 @@ impl Server {
 	}
 	
 	pub fn remove(&self, name: &str, client: &Client) -> Result<bool, Error> {
+		let start = Instant::now();
+		self.shared.hooks.run_remove(client, name)?;
 		let mut state = self.shared.state.lock().unwrap();
-		state.remove(name, client.id)
+		let locked = Instant::now();
+		state.writable()?;
+		let result = state.remove(name, client.id);
+		state.record("remove", start);
+		state.check_slow("remove", name, locked);
+		result
 	}
 	
-	pub fn emit(&self, object: &str, event: &str, data: Value, client: &Client) -> Result<(), Error> {
+	pub fn emit(&self, object: &str, event: &str, mut data: Value, client: &Client) -> Result<(), Error> {
+		let start = Instant::now();
+		self.shared.hooks.run_emit(client, object, event, &mut data)?;
 		let mut state = self.shared.state.lock().unwrap();
-		state.emit(object, event, data, client.id)
+		let locked = Instant::now();
+		state.writable()?;
+		let result = state.emit(object, event, data, client.id);
+		state.record("emit", start);
+		state.check_slow("emit", object, locked);
+		result
 	}
 	
-	pub fn invoke(&self, object: &str, method: &str, args: Value, request_id: Value, client: &Client) -> Result<(), Error> {
-		let mut state = self.shared.state.lock().unwrap();
-		state.invoke(object, method, args, request_id, client.id)
+	pub async fn get_events(&self, pattern: &Pattern, since: Option<DateTime<Utc>>, limit: usize, client: &Client) -> Result<Vec<Event>, Error> {
+		let storage = {
+			let mut state = self.shared.state.lock().unwrap();
+			state.log(LogMessage::GetEvents { pattern: pattern.string.clone(), client: client.id });
+			state.storage.clone()
+		};
+		
+		match storage {
+			Some(storage) => storage.get_events(pattern.clone(), since, limit).await.map_err(|e| Error::Storage(e.to_string())),
+			None => Ok(vec![]),
+		}
 	}
 	
-	pub fn invoke_result(&self, invocation_id: Uuid, result: Value, client: &Client) -> Result<(), Error> {
-		let mut state = self.shared.state.lock().unwrap();
+	pub async fn get_history(&self, name: &str, since: Option<DateTime<Utc>>, until: Option<DateTime<Utc>>, limit: usize, client: &Client) -> Result<Vec<Revision>, Error> {
+		validate_object_name(name)?;
 		
-		state.log(LogMessage::InvokeResult { invocation_id, result: result.clone(), client: client.id });
+		let storage = {
+			let mut state = self.shared.state.lock().unwrap();
+			state.log(LogMessage::GetHistory { object: name.to_string(), client: client.id });
+			state.storage.clone()
+		};
 		
-		let invocation: Option<Invocation> = (|| {
-			let client = state.clients.get_mut(&client.id).unwrap();
-			
-			if let Some(index) = client.invocations.iter().position(|invocation| invocation.id == invocation_id) {
-				Some(client.invocations.remove(index))
-			} else {
-				None
-			}
-		})();
+		match storage {
+			Some(storage) => storage.get_history(name.to_string(), since, until, limit).await.map_err(|e| Error::Storage(e.to_string())),
+			None => Ok(vec![]),
+		}
+	}
+	
+	/// Returns the entries of the audit log selected by `filter`, newest first.
+	pub async fn get_audit(&self, filter: AuditFilter, client: &Client) -> Result<Vec<AuditEntry>, Error> {
+		let storage = {
+			let mut state = self.shared.state.lock().unwrap();
+			state.log(LogMessage::GetAudit { client: client.id });
+			state.storage.clone()
+		};
 		
-		if let Some(invocation) = invocation {
-			if let Some(client) = state.clients.get_mut(&invocation.client_id) {
-				let msg = Message::InvocationResult {
-					request_id: invocation.request_id,
-					result: Ok(result),
-				};
-				let _ = client.inbox_tx.unbounded_send(msg);
-				
-				Ok(())
-			} else {
+		match storage {
+			Some(storage) => storage.get_audit(filter).await.map_err(|e| Error::Storage(e.to_string())),
+			None => Ok(vec![]),
+		}
+	}
+	
+	pub fn stats(&self) -> Stats {
+		let state = self.shared.state.lock().unwrap();
+		
+		Stats {
+			storage: state.storage_metrics.as_ref().map(|metrics| metrics.stats()),
+			operations: state.operation_stats.iter()
+				.map(|(operation, stats)| (operation.to_string(), stats.clone()))
+				.collect(),
+		}
+	}
+	
+	/// Which backend is used and how it is doing, `None` without storage.
+	pub fn storage_status(&self) -> Option<StorageStatus> {
+		let state = self.shared.state.lock().unwrap();
+		
+		state.storage_metrics.as_ref().map(|metrics| StorageStatus {
+			backend: state.storage_backend.clone(),
+			stats: metrics.stats(),
+			recent_errors: state.storage_errors.iter().cloned().collect(),
+		})
+	}
+	
+	/// Exports the persisted store without stopping the server, once all
+	/// pending writes have been applied.
+	pub async fn backup(&self, client: &Client) -> Result<Backup, Error> {
+		let storage = {
+			let mut state = self.shared.state.lock().unwrap();
+			state.log(LogMessage::Backup { client: client.id });
+			state.storage.clone()
+		};
+		
+		storage.ok_or(Error::NoStorage)?
+			.export().await
+			.map_err(|e| Error::Storage(e.to_string()))
+	}
+	
+	/// Writes a backup to a new file in `dir` and emits `backupCompleted` or
+	/// `backupFailed` on `$system`. Returns the path of the file. Blocks
+	/// while the backup is taken and written, so call it outside of the
+	/// tokio runtime.
+	pub fn backup_to_dir(&self, dir: &Path, client: &Client) -> Result<PathBuf, Error> {
+		let start = Instant::now();
+		
+		let result = block_on(self.backup(client)).and_then(|backup| {
+			let path = dir.join(backup.filename());
+			// written next to the target first, so there is never a partial backup
+			let partial = path.with_extension("json.partial");
+			
+			fs::write(&partial, serde_json::to_vec(&backup).unwrap())
+				.and_then(|_| fs::rename(&partial, &path))
+				.map_err(|e| Error::Backup(format!("can't write {}: {}", path.display(), e)))?;
+			
+			Ok((path, backup))
+		});
+		
+		let mut state = self.shared.state.lock().unwrap();
+		
+		match result {
+			Ok((path, backup)) => {
+				state.internal_emit("$system", "backupCompleted", json!({
+					"file": path,
+					"objects": backup.objects.len(),
+					"events": backup.events.len(),
+					"revisions": backup.revisions.len(),
+					"duration": start.elapsed().as_secs_f64(),
+				})).unwrap();
+				
+				Ok(path)
+			},
+			Err(e) => {
+				state.internal_emit("$system", "backupFailed", json!({ "error": e.to_string() })).unwrap();
+				Err(e)
+			},
+		}
+	}
+	
+	/// Atomically replaces all objects and the storage contents with a backup
+	/// and notifies all live queries about the differences.
+	pub fn restore(&self, backup: Backup, client: &Client) -> Result<(), Error> {
+		let mut state = self.shared.state.lock().unwrap();
+		state.writable()?;
+		state.restore(backup, client.id)
+	}
+	
+	pub fn invoke(&self, object: &str, method: &str, mut args: Value, request_id: Value, client: &Client) -> Result<(), Error> {
+		self.shared.hooks.run_invoke(client, object, method, &mut args)?;
+		let mut state = self.shared.state.lock().unwrap();
+		state.invoke(object, method, args, request_id, client.id)
+	}
+	
+	pub fn invoke_result(&self, invocation_id: Uuid, result: Value, client: &Client) -> Result<(), Error> {
+		let mut state = self.shared.state.lock().unwrap();
+		
+		state.log(LogMessage::InvokeResult { invocation_id, result: result.clone(), client: client.id });
+		
+		let invocation: Option<Invocation> = {
+			let client = state.clients.get_mut(&client.id).unwrap();
+			
+			if let Some(index) = client.invocations.iter().position(|invocation| invocation.id == invocation_id) {
+				Some(client.invocations.remove(index))
+			} else {
+				None
+			}
+		};
+		
+		if let Some(invocation) = invocation {
+			state.record("invoke", invocation.started);
+			
+			if let Some(client) = state.clients.get_mut(&invocation.client_id) {
+				let msg = Message::InvocationResult {
+					request_id: invocation.request_id,
+					result: Ok(result),
+				};
+				client.inbox.send(msg);
+				
+				Ok(())
+			} else {
 				// client disconnected -> ignore
 				Ok(())
 			}
@@ -609,6 +1703,9 @@ impl Server {
 mod tests {
 	use super::*;
 	use crate::server::logger::NullLogger;
+	use crate::server::storage::StorageFuture;
+	use crate::LATENCY_BUCKETS;
+	use futures::future;
 	use serde_json::json;
 	
 	fn create_server() -> Server {
@@ -672,275 +1769,1175 @@ mod tests {
 	}
 	
 	#[test]
-	fn test_patch_insert_non_object() {
+	fn test_patch_insert_non_object() {
+		let server = create_server();
+		let client = server.client_connect();
+		
+		let result = server.patch("foo", json!(42), &client);
+		assert_eq!(result, Err(Error::CantMergeObjects));
+	}
+	
+	#[test]
+	fn test_patch_update_non_object() {
+		let server = create_server();
+		let client = server.client_connect();
+		
+		server.set("foo", json!(42), &client).unwrap();
+		
+		let result = server.patch("foo", json!({ "baz": true }), &client);
+		assert_eq!(result, Err(Error::CantMergeObjects));
+	}
+	
+	#[test]
+	fn test_patch_update_with_non_object() {
+		let server = create_server();
+		let client = server.client_connect();
+		
+		server.set("foo", json!({ "bar": true }), &client).unwrap();
+		
+		let result = server.patch("foo", json!(42), &client);
+		assert_eq!(result, Err(Error::CantMergeObjects));
+	}
+	
+	#[test]
+	fn test_patch_update() {
+		let server = create_server();
+		let client = server.client_connect();
+		
+		server.set("foo", json!({ "bar": true }), &client).unwrap();
+		server.patch("foo", json!({ "baz": true }), &client).unwrap();
+		
+		let state = server.shared.state.lock().unwrap();
+		assert!(state.objects.contains_key("foo"));
+		assert_eq!(state.objects["foo"].name, "foo");
+		assert_eq!(state.objects["foo"].value, json!({ "bar": true, "baz": true }));
+	}
+	
+	#[test]
+	fn test_patch_update_non_deep() {
+		let server = create_server();
+		let client = server.client_connect();
+		
+		server.set("foo", json!({ "on": true, "color": { "hue": 100, "saturation": 100 } }), &client).unwrap();
+		server.patch("foo", json!({ "color": { "temp": 50 } }), &client).unwrap();
+		
+		let state = server.shared.state.lock().unwrap();
+		assert!(state.objects.contains_key("foo"));
+		assert_eq!(state.objects["foo"].name, "foo");
+		assert_eq!(state.objects["foo"].value, json!({ "on": true, "color": { "temp": 50 } }));
+	}
+	
+	#[test]
+	fn test_get() {
+		let server = create_server();
+		let client = server.client_connect();
+		
+		server.set("livingroom/temperature", json!({ "temp": 20.3 }), &client).unwrap();
+		server.set("livingroom/humidity", json!({ "humid": 40 }), &client).unwrap();
+		server.set("bedroom/temperature", json!({ "temp": 19 }), &client).unwrap();
+		
+		let result = server.get(&Pattern::compile("$system").unwrap(), &client);
+		assert_eq!(result.len(), 1);
+		
+		let result = server.get(&Pattern::compile("*").unwrap(), &client);
+		assert_eq!(result.len(), 3);
+		
+		let result = server.get(&Pattern::compile("*,$system").unwrap(), &client);
+		assert_eq!(result.len(), 4);
+		
+		let result = server.get(&Pattern::compile("+/temperature,+/humidity").unwrap(), &client);
+		assert_eq!(result.len(), 3);
+		
+		let result = server.get(&Pattern::compile("livingroom/+").unwrap(), &client);
+		assert_eq!(result.len(), 2);
+		
+		let result = server.get(&Pattern::compile("+/humidity").unwrap(), &client);
+		assert_eq!(result.len(), 1);
+	}
+	
+	#[test]
+	fn test_query() {
+		let server = create_server();
+		let client1 = server.client_connect();
+		let mut client2 = server.client_connect();
+		
+		server.set("livingroom/temperature", json!({ "temp": 20.3 }), &client1).unwrap();
+		
+		let (query_id, objects) = server.query(&Pattern::compile("+/temperature").unwrap(), false, false, &client2).unwrap();
+		
+		assert_eq!(objects.len(), 1);
+		assert_eq!(objects[0].name, "livingroom/temperature");
+		assert_eq!(objects[0].value, json!({ "temp": 20.3 }));
+		
+		server.set("livingroom/temperature", json!({ "temp": 20.4 }), &client1).unwrap();
+		server.set("livingroom/temperature", json!({ "temp": 20.5 }), &client1).unwrap();
+		server.set("bedroom/temperature", json!({ "temp": 19.0 }), &client1).unwrap();
+		server.set("bedroom/temperature", json!({ "temp": 19.1 }), &client1).unwrap();
+		
+		let msg = client2.inbox_try_next().unwrap().unwrap();
+		
+		if let Message::QueryChange { query_id: msg_query_id, object, .. } = msg {
+			assert_eq!(msg_query_id, query_id);
+			assert_eq!(object.name, "livingroom/temperature");
+			assert_eq!(object.value, json!({ "temp": 20.4 }));
+		} else {
+			panic!("unexpected message");
+		}
+		
+		let msg = client2.inbox_try_next().unwrap().unwrap();
+		
+		if let Message::QueryChange { query_id: msg_query_id, object, .. } = msg {
+			assert_eq!(msg_query_id, query_id);
+			assert_eq!(object.name, "livingroom/temperature");
+			assert_eq!(object.value, json!({ "temp": 20.5 }));
+		} else {
+			panic!("unexpected message");
+		}
+		
+		let msg = client2.inbox_try_next().unwrap().unwrap();
+		
+		if let Message::QueryAdd { query_id: msg_query_id, object } = msg {
+			assert_eq!(msg_query_id, query_id);
+			assert_eq!(object.name, "bedroom/temperature");
+			assert_eq!(object.value, json!({ "temp": 19.0 }));
+		} else {
+			panic!("unexpected message");
+		}
+		
+		let msg = client2.inbox_try_next().unwrap().unwrap();
+		
+		if let Message::QueryChange { query_id: msg_query_id, object, .. } = msg {
+			assert_eq!(msg_query_id, query_id);
+			assert_eq!(object.name, "bedroom/temperature");
+			assert_eq!(object.value, json!({ "temp": 19.1 }));
+		} else {
+			panic!("unexpected message");
+		}
+		
+		assert!(client2.inbox_try_next().is_err());
+	}
+	
+	#[test]
+	fn test_query_include_previous() {
+		let server = create_server();
+		let client1 = server.client_connect();
+		let mut client2 = server.client_connect();
+		
+		server.set("lamp", json!({ "on": false }), &client1).unwrap();
+		
+		let (query_id, _) = server.query(&Pattern::compile("lamp").unwrap(), false, true, &client2).unwrap();
+		
+		server.patch("lamp", json!({ "on": true }), &client1).unwrap();
+		server.set("lamp", json!({ "on": false, "brightness": 50 }), &client1).unwrap();
+		
+		let msg = client2.inbox_try_next().unwrap().unwrap();
+		
+		if let Message::QueryChange { query_id: msg_query_id, object, previous } = msg {
+			assert_eq!(msg_query_id, query_id);
+			assert_eq!(object.value, json!({ "on": true }));
+			assert_eq!(previous, Some(json!({ "on": false })));
+		} else {
+			panic!("unexpected message");
+		}
+		
+		let msg = client2.inbox_try_next().unwrap().unwrap();
+		
+		if let Message::QueryChange { query_id: msg_query_id, object, previous } = msg {
+			assert_eq!(msg_query_id, query_id);
+			assert_eq!(object.value, json!({ "on": false, "brightness": 50 }));
+			assert_eq!(previous, Some(json!({ "on": true })));
+		} else {
+			panic!("unexpected message");
+		}
+		
+		assert!(client2.inbox_try_next().is_err());
+	}
+	
+	#[test]
+	fn test_query_without_previous() {
+		let server = create_server();
+		let client1 = server.client_connect();
+		let mut client2 = server.client_connect();
+		
+		server.set("lamp", json!({ "on": false }), &client1).unwrap();
+		
+		server.query(&Pattern::compile("lamp").unwrap(), false, false, &client2).unwrap();
+		
+		server.set("lamp", json!({ "on": true }), &client1).unwrap();
+		
+		let msg = client2.inbox_try_next().unwrap().unwrap();
+		
+		if let Message::QueryChange { previous, .. } = msg {
+			assert_eq!(previous, None);
+		} else {
+			panic!("unexpected message");
+		}
+	}
+	
+	#[test]
+	fn test_unsubscribe() {
+		let server = create_server();
+		let client1 = server.client_connect();
+		let mut client2 = server.client_connect();
+		
+		server.set("livingroom/temperature", json!({ "temp": 20.3 }), &client1).unwrap();
+		
+		let (query_id, _) = server.query(&Pattern::compile("+/temperature").unwrap(), false, false, &client2).unwrap();
+		
+		server.set("livingroom/temperature", json!({ "temp": 20.4 }), &client1).unwrap();
+		
+		let msg = client2.inbox_try_next().unwrap().unwrap();
+		if let Message::QueryChange { query_id: msg_query_id, object, .. } = msg {
+			assert_eq!(msg_query_id, query_id);
+			assert_eq!(object.name, "livingroom/temperature");
+			assert_eq!(object.value, json!({ "temp": 20.4 }));
+		} else {
+			panic!("unexpected message");
+		}
+		
+		server.unsubscribe(query_id, &client2).unwrap();
+		
+		server.set("livingroom/temperature", json!({ "temp": 20.5 }), &client1).unwrap();
+		
+		assert!(client2.inbox_try_next().is_err());
+	}
+	
+	#[test]
+	fn test_remove_non_existing() {
+		let server = create_server();
+		let client = server.client_connect();
+		
+		let existed = server.remove("foo", &client).unwrap();
+		assert!(!existed);
+	}
+	
+	#[test]
+	fn test_remove_existing() {
+		let server = create_server();
+		let client = server.client_connect();
+		
+		server.set("foo", json!({ "bar": 1 }), &client).unwrap();
+		
+		let existed = server.remove("foo", &client).unwrap();
+		assert!(existed);
+	}
+	
+	#[test]
+	fn test_remove_query() {
+		let server = create_server();
+		let client = server.client_connect();
+		
+		server.set("foo", json!({ "bar": 1 }), &client).unwrap();
+		
+		let mut client = server.client_connect();
+		
+		let (query_id, _) = server.query(&Pattern::compile("*").unwrap(), false, false, &client).unwrap();
+		
+		server.remove("foo", &client).unwrap();
+		
+		let msg = client.inbox_try_next().unwrap().unwrap();
+		
+		if let Message::QueryRemove { query_id: msg_query_id, object } = msg {
+			assert_eq!(msg_query_id, query_id);
+			assert_eq!(object.name, "foo");
+			assert_eq!(object.value, json!({ "bar": 1 }));
+		} else {
+			panic!("unexpected message");
+		}
+		
+		server.set("foo", json!({ "bar": 1 }), &client).unwrap();
+		
+		let msg = client.inbox_try_next().unwrap().unwrap();
+		
+		if let Message::QueryAdd { query_id: msg_query_id, object } = msg {
+			assert_eq!(msg_query_id, query_id);
+			assert_eq!(object.name, "foo");
+			assert_eq!(object.value, json!({ "bar": 1 }));
+		} else {
+			panic!("unexpected message");
+		}
+		
+		assert!(client.inbox_try_next().is_err());
+	}
+	
+	#[test]
+	fn test_emit_event() {
+		let server = create_server();
+		let client = server.client_connect();
+		
+		server.set("gamepad", json!({ "buttons": ["a", "b"] }), &client).unwrap();
+		
+		let mut client = server.client_connect();
+		
+		let (query_id, _) = server.query(&Pattern::compile("*").unwrap(), false, false, &client).unwrap();
+		
+		server.emit("gamepad", "buttonpress", json!({ "button": "a" }), &client).unwrap();
+		
+		let msg = client.inbox_try_next().unwrap().unwrap();
+		
+		if let Message::QueryEvent { query_id: msg_query_id, object, event, data } = msg {
+			assert_eq!(msg_query_id, query_id);
+			assert_eq!(object, "gamepad");
+			assert_eq!(event, "buttonpress");
+			assert_eq!(data, json!({ "button": "a" }));
+		} else {
+			panic!("unexpected message");
+		}
+		
+		assert!(client.inbox_try_next().is_err());
+	}
+	
+	#[test]
+	fn test_emit_event_doesnt_exist() {
+		let server = create_server();
+		let client = server.client_connect();
+		
+		let result = server.emit("gamepad", "buttonpress", json!({ "button": "a" }), &client);
+		
+		assert_eq!(result, Err(Error::ObjectNotFound));
+	}
+	
+	struct StaticStorage {
+		objects: Vec<Object>,
+	}
+	
+	impl Storage for StaticStorage {
+		fn get_objects(&self) -> StorageFuture<'_, Vec<Object>> {
+			Box::pin(future::ok(self.objects.clone()))
+		}
+		
+		fn add_object(&self, _object: Object) -> StorageFuture<'_, ()> {
+			Box::pin(future::ok(()))
+		}
+		
+		fn change_object(&self, _object: Object) -> StorageFuture<'_, ()> {
+			Box::pin(future::ok(()))
+		}
+		
+		fn remove_object(&self, _object: Object) -> StorageFuture<'_, ()> {
+			Box::pin(future::ok(()))
+		}
+	}
+	
+	#[derive(serde::Deserialize)]
+	struct StaticConfig {
+		names: Vec<String>,
+	}
+	
+	#[test]
+	fn test_builder_custom_backend() {
+		let config: crate::server::config::Config = toml::from_str(r#"
+			[storage]
+			backend = "static"
+			static.names = ["foo", "bar"]
+		"#).unwrap();
+		
+		let server = Server::builder()
+			.logger(Box::new(NullLogger))
+			.backend("static", |config: StaticConfig| Ok(StaticStorage {
+				objects: config.names.into_iter().map(|name| Object { name, value: json!(1), last_modified: Utc::now() }).collect(),
+			}))
+			.storage(config.storage.unwrap())
+			.build()
+			.unwrap();
+		
+		let client = server.client_connect();
+		assert_eq!(server.get(&Pattern::compile("*").unwrap(), &client).len(), 2);
+	}
+	
+	#[test]
+	fn test_builder_invalid_backend() {
+		let config: crate::server::config::Config = toml::from_str(r#"
+			[storage]
+			backend = "static"
+			static.foo = 42
+		"#).unwrap();
+		
+		let result = Server::builder()
+			.logger(Box::new(NullLogger))
+			.storage(config.storage.unwrap())
+			.build();
+		assert_eq!(result.err(), Some("unknown storage backend static".to_string()));
+		
+		let config: crate::server::config::Config = toml::from_str(r#"
+			[storage]
+			backend = "static"
+			static.foo = 42
+		"#).unwrap();
+		
+		let result = Server::builder()
+			.logger(Box::new(NullLogger))
+			.backend("static", |_: StaticConfig| Ok(StaticStorage { objects: vec![] }))
+			.storage(config.storage.unwrap())
+			.build();
+		assert!(result.err().unwrap().starts_with("invalid static config"));
+	}
+	
+	#[test]
+	fn test_builder_custom_backend_retention() {
+		let config: crate::server::config::Config = toml::from_str(r#"
+			[storage]
+			backend = "static"
+			static.names = []
+			events.enabled = true
+		"#).unwrap();
+		
+		let result = Server::builder()
+			.logger(Box::new(NullLogger))
+			.backend("static", |_: StaticConfig| Ok(StaticStorage { objects: vec![] }))
+			.storage(config.storage.unwrap())
+			.build();
+		assert_eq!(result.err(), Some("static backend doesn't support event persistence".to_string()));
+		
+		let config: crate::server::config::Config = toml::from_str(r#"
+			[storage]
+			backend = "static"
+			static.names = []
+			audit.enabled = true
+		"#).unwrap();
+		
+		let result = Server::builder()
+			.logger(Box::new(NullLogger))
+			.backend("static", |_: StaticConfig| Ok(StaticStorage { objects: vec![] }))
+			.storage(config.storage.unwrap())
+			.build();
+		assert_eq!(result.err(), Some("only the sqlite backend supports the audit log".to_string()));
+	}
+	
+	#[test]
+	fn test_builder_hooks() {
+		let emitted = Arc::new(AtomicUsize::new(0));
+		let counter = emitted.clone();
+		
+		let server = Server::builder()
+			.logger(Box::new(NullLogger))
+			.on_set(|_, _, value| match value.get("temperature") {
+				Some(temperature) if !temperature.is_number() => Err("temperature must be a number".to_string()),
+				_ => Ok(()),
+			})
+			.on_set(|client, _, value| {
+				if let Some(identity) = client.identity() {
+					value["setBy"] = json!(identity);
+				}
+				Ok(())
+			})
+			.on_patch(|_, name, _| if name == "locked" { Err("locked".to_string()) } else { Ok(()) })
+			.on_remove(|client, _| if client.identity() == Some("admin") { Ok(()) } else { Err("only admin can remove".to_string()) })
+			.on_emit(move |_, _, _, data| {
+				counter.fetch_add(1, Ordering::SeqCst);
+				data["seen"] = json!(true);
+				Ok(())
+			})
+			.on_invoke(|_, _, method, _| if method == "reboot" { Err("not allowed".to_string()) } else { Ok(()) })
+			.build()
+			.unwrap();
+		
+		let admin = server.client_connect_from(None, Some("admin".to_string()));
+		let mut client = server.client_connect();
+		
+		assert_eq!(server.set("sensor", json!({ "temperature": "hot" }), &client),
+			Err(Error::Rejected("temperature must be a number".to_string())));
+		assert_eq!(server.get(&Pattern::compile("sensor").unwrap(), &client).len(), 0);
+		
+		server.set("sensor", json!({ "temperature": 20 }), &admin).unwrap();
+		assert_eq!(server.get(&Pattern::compile("sensor").unwrap(), &client)[0].value,
+			json!({ "temperature": 20, "setBy": "admin" }));
+		
+		server.set("locked", json!({}), &client).unwrap();
+		assert_eq!(server.patch("locked", json!({ "a": 1 }), &client), Err(Error::Rejected("locked".to_string())));
+		
+		assert_eq!(server.remove("sensor", &client), Err(Error::Rejected("only admin can remove".to_string())));
+		assert_eq!(server.remove("sensor", &admin), Ok(true));
+		
+		server.query(&Pattern::compile("sensor").unwrap(), false, false, &client).unwrap();
+		server.emit("sensor", "tick", json!({}), &admin).unwrap_err();
+		server.set("sensor", json!({}), &admin).unwrap();
+		server.emit("sensor", "tick", json!({}), &admin).unwrap();
+		assert_eq!(emitted.load(Ordering::SeqCst), 2);
+		
+		let data = std::iter::from_fn(|| client.inbox_try_next().ok().flatten())
+			.find_map(|msg| match msg {
+				Message::QueryEvent { data, .. } => Some(data),
+				_ => None,
+			});
+		assert_eq!(data, Some(json!({ "seen": true })));
+		
+		let result = server.invoke("sensor", "reboot", json!({}), json!(1), &admin);
+		assert_eq!(result, Err(Error::Rejected("not allowed".to_string())));
+		
+		// disconnect commands can't get around the hooks
+		let result = server.set_disconnect_commands(vec![
+			Command::Set { name: "status".to_string(), value: json!("offline") },
+			Command::Set { name: "sensor".to_string(), value: json!({ "temperature": "gone" }) },
+		], &client);
+		assert_eq!(result, Err(Error::Rejected("temperature must be a number".to_string())));
+		
+		server.set_disconnect_commands(vec![
+			Command::Set { name: "status".to_string(), value: json!({}) },
+		], &admin).unwrap();
+		server.client_disconnect(client.id);
+		server.client_disconnect(admin.id);
+		
+		let observer = server.client_connect();
+		assert_eq!(server.get(&Pattern::compile("sensor").unwrap(), &observer)[0].value, json!({ "setBy": "admin" }));
+		assert_eq!(server.get(&Pattern::compile("status").unwrap(), &observer)[0].value, json!({ "setBy": "admin" }));
+	}
+	
+	struct FailingStorage;
+	
+	impl Storage for FailingStorage {
+		fn get_objects(&self) -> StorageFuture<'_, Vec<Object>> {
+			Box::pin(future::ok(vec![]))
+		}
+		
+		fn add_object(&self, _object: Object) -> StorageFuture<'_, ()> {
+			Box::pin(future::err(storage::Error::Other("database is locked".to_string())))
+		}
+		
+		fn change_object(&self, object: Object) -> StorageFuture<'_, ()> {
+			self.add_object(object)
+		}
+		
+		fn remove_object(&self, object: Object) -> StorageFuture<'_, ()> {
+			self.add_object(object)
+		}
+	}
+	
+	#[test]
+	fn test_storage_error_event() {
+		let server = Server::new(Some(Box::new(FailingStorage)), Box::new(NullLogger));
+		let mut client = server.client_connect();
+		
+		let (query_id, _) = server.query(&Pattern::compile("$system").unwrap(), false, false, &client).unwrap();
+		
+		server.set("foo", json!(42), &client).unwrap();
+		let storage = server.shared.state.lock().unwrap().storage.clone().unwrap();
+		storage.flush();
+		
+		let mut errors = vec![];
+		while let Ok(Some(msg)) = client.inbox_try_next() {
+			if let Message::QueryEvent { query_id: msg_query_id, event, data, .. } = msg {
+				if event == "storageError" {
+					assert_eq!(msg_query_id, query_id);
+					errors.push(data);
+				}
+			}
+		}
+		
+		assert_eq!(errors, vec![json!({
+			"operation": "add",
+			"object": "foo",
+			"error": "database is locked",
+			"degraded": false,
+		})]);
+		
+		// the object is still served from memory
+		assert_eq!(server.get(&Pattern::compile("foo").unwrap(), &client)[0].value, json!(42));
+	}
+	
+	#[test]
+	fn test_storage_status() {
+		assert_eq!(create_server().storage_status(), None);
+		
+		let server = Server::new(Some(Box::new(FailingStorage)), Box::new(NullLogger));
+		let client = server.client_connect();
+		
+		server.set("foo", json!(1), &client).unwrap();
+		server.set("bar", json!(2), &client).unwrap();
+		let storage = server.shared.state.lock().unwrap().storage.clone().unwrap();
+		storage.flush();
+		
+		let status = server.storage_status().unwrap();
+		assert_eq!(status.backend, None);
+		assert_eq!(status.stats.last_write, None);
+		assert_eq!(status.stats.errors, 6);
+		assert_eq!(status.recent_errors.len(), 2);
+		assert_eq!(status.recent_errors[0].object.as_deref(), Some("bar"));
+		assert_eq!(status.recent_errors[1].error, "database is locked");
+	}
+	
+	#[test]
+	fn test_live_stats() {
+		let server = create_server();
+		let mut admin = server.client_connect();
+		let busy = server.client_connect_from(Some("10.0.0.5".parse().unwrap()), Some("sensor-gateway".to_string()));
+		let mut backlogged = server.client_connect();
+		
+		server.query(&Pattern::compile("$system").unwrap(), false, false, &admin).unwrap();
+		server.query(&Pattern::compile("sensor/*").unwrap(), false, false, &backlogged).unwrap();
+		while let Ok(Some(_)) = admin.inbox_try_next() {}
+		
+		for i in 0..4 {
+			server.set("sensor/1", json!(i), &busy).unwrap();
+		}
+		
+		server.get(&Pattern::compile("sensor/*").unwrap(), &backlogged);
+		server.get(&Pattern::compile("sensor/*").unwrap(), &backlogged);
+		
+		// the admin has log events of the sets and gets in its inbox, too
+		server.shared.state.lock().unwrap().live_stats(Duration::from_secs(2));
+		
+		let mut stats = vec![];
+		while let Ok(Some(msg)) = admin.inbox_try_next() {
+			if let Message::QueryEvent { event, data, .. } = msg {
+				if event == "stats" {
+					stats.push(data);
+				}
+			}
+		}
+		
+		assert_eq!(stats.len(), 1);
+		assert_eq!(stats[0]["clients"], 3);
+		assert_eq!(stats[0]["objects"], 1);
+		assert_eq!(stats[0]["operationsPerSecond"], json!(4.0));
+		assert_eq!(stats[0]["queueDepth"], 10);
+		assert_eq!(stats[0]["topClients"][0], json!({
+			"client": busy.id,
+			"addr": "10.0.0.5",
+			"identity": "sensor-gateway",
+			"operationsPerSecond": 2.0,
+			"queueDepth": 0,
+			"queries": 0,
+		}));
+		assert_eq!(stats[0]["topClients"][1]["client"], json!(backlogged.id));
+		assert_eq!(stats[0]["topClients"][1]["queueDepth"], 4);
+		assert_eq!(stats[0]["topClients"][2]["client"], json!(admin.id));
+		
+		while let Ok(Some(_)) = backlogged.inbox_try_next() {}
+		server.shared.state.lock().unwrap().live_stats(Duration::from_secs(2));
+		
+		let stats = std::iter::from_fn(|| admin.inbox_try_next().ok().flatten())
+			.find_map(|msg| match msg {
+				Message::QueryEvent { event, data, .. } if event == "stats" => Some(data),
+				_ => None,
+			})
+			.unwrap();
+		assert_eq!(stats["operationsPerSecond"], json!(0.0));
+		assert_eq!(stats["queueDepth"], 0);
+	}
+	
+	#[test]
+	fn test_webhook_delivery_event() {
 		let server = create_server();
-		let client = server.client_connect();
+		let mut client = server.client_connect();
 		
-		let result = server.patch("foo", json!(42), &client);
-		assert_eq!(result, Err(Error::CantMergeObjects));
+		server.query(&Pattern::compile("$system").unwrap(), false, false, &client).unwrap();
+		
+		server.webhook_delivery(WebhookDelivery {
+			url: "http://127.0.0.1:8080/".to_string(),
+			object: "sensor".to_string(),
+			attempts: 3,
+			status: Some(503),
+			error: Some("status 503".to_string()),
+		});
+		
+		let mut deliveries = vec![];
+		while let Ok(Some(msg)) = client.inbox_try_next() {
+			if let Message::QueryEvent { event, data, .. } = msg {
+				if event == "webhookDelivery" {
+					deliveries.push(data);
+				}
+			}
+		}
+		
+		assert_eq!(deliveries, vec![json!({
+			"url": "http://127.0.0.1:8080/",
+			"object": "sensor",
+			"attempts": 3,
+			"status": 503,
+			"error": "status 503",
+			"delivered": false,
+		})]);
 	}
 	
+	#[cfg(feature = "sqlite-backend")]
 	#[test]
-	fn test_patch_update_non_object() {
-		let server = create_server();
-		let client = server.client_connect();
+	fn test_storage_maintenance() {
+		use crate::server::storage::sqlite::SqliteStorage;
 		
-		server.set("foo", json!(42), &client).unwrap();
+		let storage = SqliteStorage::new(rusqlite::Connection::open_in_memory().unwrap()).unwrap();
+		let server = Server::new(Some(Box::new(storage)), Box::new(NullLogger));
+		let mut client = server.client_connect();
 		
-		let result = server.patch("foo", json!({ "baz": true }), &client);
-		assert_eq!(result, Err(Error::CantMergeObjects));
+		server.query(&Pattern::compile("$system").unwrap(), false, false, &client).unwrap();
+		server.start_maintenance(Duration::from_millis(10));
+		
+		for _ in 0..200 {
+			while let Ok(Some(msg)) = client.inbox_try_next() {
+				if let Message::QueryEvent { event, data, .. } = msg {
+					if event == "storageMaintenance" {
+						assert!(data["duration"].is_f64());
+						assert!(data["reclaimed"].is_u64());
+						return;
+					}
+				}
+			}
+			
+			thread::sleep(Duration::from_millis(10));
+		}
+		
+		panic!("no maintenance event");
 	}
 	
 	#[test]
-	fn test_patch_update_with_non_object() {
+	fn test_restore() {
 		let server = create_server();
-		let client = server.client_connect();
+		let mut client = server.client_connect();
 		
-		server.set("foo", json!({ "bar": true }), &client).unwrap();
+		server.set("foo", json!(1), &client).unwrap();
+		server.set("bar", json!(2), &client).unwrap();
 		
-		let result = server.patch("foo", json!(42), &client);
-		assert_eq!(result, Err(Error::CantMergeObjects));
+		let (query_id, _) = server.query(&Pattern::compile("*").unwrap(), false, false, &client).unwrap();
+		
+		let object = |name: &str, value: Value| Object { name: name.to_string(), value, last_modified: Utc::now() };
+		
+		server.restore(Backup {
+			created: Utc::now(),
+			objects: vec![object("foo", json!(3)), object("baz", json!(4))],
+			events: vec![],
+			revisions: vec![],
+		}, &client).unwrap();
+		
+		let mut changes = vec![];
+		while let Ok(Some(msg)) = client.inbox_try_next() {
+			match msg {
+				Message::QueryAdd { query_id: msg_query_id, object } if msg_query_id == query_id => changes.push(format!("add {}", object.name)),
+				Message::QueryChange { query_id: msg_query_id, object, .. } if msg_query_id == query_id => changes.push(format!("change {}", object.name)),
+				Message::QueryRemove { query_id: msg_query_id, object } if msg_query_id == query_id => changes.push(format!("remove {}", object.name)),
+				_ => {},
+			}
+		}
+		changes.sort();
+		
+		assert_eq!(changes, vec!["add baz", "change foo", "remove bar"]);
+		
+		let mut names: Vec<String> = server.get(&Pattern::compile("*").unwrap(), &client).into_iter().map(|object| object.name).collect();
+		names.sort();
+		assert_eq!(names, vec!["baz", "foo"]);
+		assert_eq!(server.get(&Pattern::compile("$system").unwrap(), &client).len(), 1);
 	}
 	
 	#[test]
-	fn test_patch_update() {
+	fn test_read_only() {
 		let server = create_server();
 		let client = server.client_connect();
 		
-		server.set("foo", json!({ "bar": true }), &client).unwrap();
-		server.patch("foo", json!({ "baz": true }), &client).unwrap();
+		server.set("foo", json!(1), &client).unwrap();
+		server.set_read_only(true);
 		
-		let state = server.shared.state.lock().unwrap();
-		assert!(state.objects.contains_key("foo"));
-		assert_eq!(state.objects["foo"].name, "foo");
-		assert_eq!(state.objects["foo"].value, json!({ "bar": true, "baz": true }));
+		assert_eq!(server.set("foo", json!(2), &client), Err(Error::ReadOnly));
+		assert_eq!(server.patch("foo", json!({}), &client), Err(Error::ReadOnly));
+		assert_eq!(server.remove("foo", &client), Err(Error::ReadOnly));
+		assert_eq!(server.emit("foo", "pressed", json!(null), &client), Err(Error::ReadOnly));
+		assert_eq!(server.set_disconnect_commands(vec![], &client), Err(Error::ReadOnly));
+		assert_eq!(server.get(&Pattern::compile("foo").unwrap(), &client)[0].value, json!(1));
+		
+		server.set_read_only(false);
+		server.set("foo", json!(2), &client).unwrap();
 	}
 	
 	#[test]
-	fn test_patch_update_non_deep() {
+	fn test_replicate() {
 		let server = create_server();
-		let client = server.client_connect();
+		let mut client = server.client_connect();
 		
-		server.set("foo", json!({ "on": true, "color": { "hue": 100, "saturation": 100 } }), &client).unwrap();
-		server.patch("foo", json!({ "color": { "temp": 50 } }), &client).unwrap();
+		server.set("sensor/1", json!(1), &client).unwrap();
+		server.set("sensor/2", json!(2), &client).unwrap();
+		server.set("other", json!(0), &client).unwrap();
+		server.set_read_only(true);
 		
-		let state = server.shared.state.lock().unwrap();
-		assert!(state.objects.contains_key("foo"));
-		assert_eq!(state.objects["foo"].name, "foo");
-		assert_eq!(state.objects["foo"].value, json!({ "on": true, "color": { "temp": 50 } }));
+		let (query_id, _) = server.query(&Pattern::compile("*").unwrap(), false, false, &client).unwrap();
+		
+		let last_modified = Utc::now() - chrono::Duration::hours(1);
+		let object = |name: &str, value: Value| Object { name: name.to_string(), value, last_modified };
+		
+		server.replicate(&Pattern::compile("sensor/+").unwrap(), vec![object("sensor/1", json!(3)), object("sensor/3", json!(4))], &client);
+		server.replicate_change(object("sensor/3", json!(5)), &client).unwrap();
+		
+		let mut changes = vec![];
+		while let Ok(Some(msg)) = client.inbox_try_next() {
+			match msg {
+				Message::QueryAdd { query_id: msg_query_id, object } if msg_query_id == query_id => changes.push(format!("add {}", object.name)),
+				Message::QueryChange { query_id: msg_query_id, object, .. } if msg_query_id == query_id => changes.push(format!("change {}", object.name)),
+				Message::QueryRemove { query_id: msg_query_id, object } if msg_query_id == query_id => changes.push(format!("remove {}", object.name)),
+				_ => {},
+			}
+		}
+		
+		assert_eq!(changes, vec!["remove sensor/2", "change sensor/1", "add sensor/3", "change sensor/3"]);
+		
+		let objects = server.get(&Pattern::compile("sensor/3").unwrap(), &client);
+		assert_eq!(objects[0].value, json!(5));
+		assert_eq!(objects[0].last_modified, last_modified);
+		assert_eq!(server.get(&Pattern::compile("other").unwrap(), &client).len(), 1);
+		assert_eq!(server.replicate_change(object("$system", json!(null)), &client), Err(Error::InvalidObjectName));
 	}
 	
+	#[cfg(feature = "sqlite-backend")]
 	#[test]
-	fn test_get() {
-		let server = create_server();
+	fn test_restore_storage() {
+		use crate::server::storage::sqlite::SqliteStorage;
+		
+		let storage = SqliteStorage::new(rusqlite::Connection::open_in_memory().unwrap()).unwrap();
+		let server = Server::new(Some(Box::new(storage)), Box::new(NullLogger));
 		let client = server.client_connect();
 		
-		server.set("livingroom/temperature", json!({ "temp": 20.3 }), &client).unwrap();
-		server.set("livingroom/humidity", json!({ "humid": 40 }), &client).unwrap();
-		server.set("bedroom/temperature", json!({ "temp": 19 }), &client).unwrap();
+		server.set("foo", json!(1), &client).unwrap();
+		let backup = block_on(server.backup(&client)).unwrap();
 		
-		let result = server.get(&Pattern::compile("$system").unwrap(), &client);
-		assert_eq!(result.len(), 1);
+		server.remove("foo", &client).unwrap();
+		server.set("bar", json!(2), &client).unwrap();
 		
-		let result = server.get(&Pattern::compile("*").unwrap(), &client);
-		assert_eq!(result.len(), 3);
+		server.restore(backup, &client).unwrap();
 		
-		let result = server.get(&Pattern::compile("*,$system").unwrap(), &client);
-		assert_eq!(result.len(), 4);
+		let backup = block_on(server.backup(&client)).unwrap();
+		assert_eq!(backup.objects.len(), 1);
+		assert_eq!(backup.objects[0].name, "foo");
+	}
+	
+	#[cfg(feature = "sled-backend")]
+	#[test]
+	fn test_restore_sled_storage() {
+		use crate::server::storage::{Retention, sled::SledStorage};
 		
-		let result = server.get(&Pattern::compile("+/temperature,+/humidity").unwrap(), &client);
-		assert_eq!(result.len(), 3);
+		let mut storage = SledStorage::new(::sled::Config::new().temporary(true).open().unwrap()).unwrap();
+		storage.enable_events(Retention::new(chrono::Duration::days(1))).unwrap();
+		let server = Server::new(Some(Box::new(storage)), Box::new(NullLogger));
+		let client = server.client_connect();
 		
-		let result = server.get(&Pattern::compile("livingroom/+").unwrap(), &client);
-		assert_eq!(result.len(), 2);
+		server.set("foo", json!(1), &client).unwrap();
+		server.emit("foo", "before", json!(null), &client).unwrap();
+		let backup = block_on(server.backup(&client)).unwrap();
 		
-		let result = server.get(&Pattern::compile("+/humidity").unwrap(), &client);
-		assert_eq!(result.len(), 1);
+		server.emit("foo", "after", json!(null), &client).unwrap();
+		server.remove("foo", &client).unwrap();
+		server.set("bar", json!(2), &client).unwrap();
+		
+		server.restore(backup, &client).unwrap();
+		
+		let backup = block_on(server.backup(&client)).unwrap();
+		assert_eq!(backup.objects.len(), 1);
+		assert_eq!(backup.objects[0].name, "foo");
+		assert_eq!(backup.events.len(), 1);
+		assert_eq!(backup.events[0].event, "before");
 	}
 	
 	#[test]
-	fn test_query() {
+	fn test_backup_without_storage() {
 		let server = create_server();
-		let client1 = server.client_connect();
-		let mut client2 = server.client_connect();
-		
-		server.set("livingroom/temperature", json!({ "temp": 20.3 }), &client1).unwrap();
-		
-		let (query_id, objects) = server.query(&Pattern::compile("+/temperature").unwrap(), false, &client2).unwrap();
-		
-		assert_eq!(objects.len(), 1);
-		assert_eq!(objects[0].name, "livingroom/temperature");
-		assert_eq!(objects[0].value, json!({ "temp": 20.3 }));
+		let client = server.client_connect();
 		
-		server.set("livingroom/temperature", json!({ "temp": 20.4 }), &client1).unwrap();
-		server.set("livingroom/temperature", json!({ "temp": 20.5 }), &client1).unwrap();
-		server.set("bedroom/temperature", json!({ "temp": 19.0 }), &client1).unwrap();
-		server.set("bedroom/temperature", json!({ "temp": 19.1 }), &client1).unwrap();
+		assert_eq!(block_on(server.backup(&client)).unwrap_err(), Error::NoStorage);
+	}
+	
+	#[cfg(feature = "sqlite-backend")]
+	#[test]
+	fn test_backup() {
+		use crate::server::storage::sqlite::SqliteStorage;
 		
-		let msg = client2.inbox_try_next().unwrap().unwrap();
+		let storage = SqliteStorage::new(rusqlite::Connection::open_in_memory().unwrap()).unwrap();
+		let server = Server::new(Some(Box::new(storage)), Box::new(NullLogger));
+		let client = server.client_connect();
 		
-		if let Message::QueryChange { query_id: msg_query_id, object } = msg {
-			assert_eq!(msg_query_id, query_id);
-			assert_eq!(object.name, "livingroom/temperature");
-			assert_eq!(object.value, json!({ "temp": 20.4 }));
-		} else {
-			assert!(false);
-		}
+		server.set("foo", json!(1), &client).unwrap();
+		server.set("bar", json!(2), &client).unwrap();
+		server.remove("bar", &client).unwrap();
 		
-		let msg = client2.inbox_try_next().unwrap().unwrap();
+		let backup = block_on(server.backup(&client)).unwrap();
+		assert_eq!(backup.objects.len(), 1);
+		assert_eq!(backup.objects[0].name, "foo");
+		assert_eq!(backup.objects[0].value, json!(1));
+	}
+	
+	#[test]
+	fn test_close_storage() {
+		use crate::server::storage::snapshot::{FileTarget, SnapshotStorage};
 		
-		if let Message::QueryChange { query_id: msg_query_id, object } = msg {
-			assert_eq!(msg_query_id, query_id);
-			assert_eq!(object.name, "livingroom/temperature");
-			assert_eq!(object.value, json!({ "temp": 20.5 }));
-		} else {
-			assert!(false);
-		}
+		let filename = std::env::temp_dir().join(format!("objtalk-close-{}.json", std::process::id()));
+		let snapshot = || SnapshotStorage::new(Box::new(FileTarget::new(filename.clone())), Duration::from_secs(3600), None).unwrap();
 		
-		let msg = client2.inbox_try_next().unwrap().unwrap();
+		let server = Server::new(Some(Box::new(snapshot())), Box::new(NullLogger));
+		let client = server.client_connect();
+		server.set("foo", json!(1), &client).unwrap();
 		
-		if let Message::QueryAdd { query_id: msg_query_id, object } = msg {
-			assert_eq!(msg_query_id, query_id);
-			assert_eq!(object.name, "bedroom/temperature");
-			assert_eq!(object.value, json!({ "temp": 19.0 }));
-		} else {
-			assert!(false);
-		}
+		// the server is still running, only closing the storage wrote the snapshot
+		server.close_storage().unwrap();
 		
-		let msg = client2.inbox_try_next().unwrap().unwrap();
+		let reopened = Server::new(Some(Box::new(snapshot())), Box::new(NullLogger));
+		let objects = reopened.get(&Pattern::compile("foo").unwrap(), &reopened.client_connect());
+		fs::remove_file(&filename).unwrap();
 		
-		if let Message::QueryChange { query_id: msg_query_id, object } = msg {
-			assert_eq!(msg_query_id, query_id);
-			assert_eq!(object.name, "bedroom/temperature");
-			assert_eq!(object.value, json!({ "temp": 19.1 }));
-		} else {
-			assert!(false);
-		}
+		assert_eq!(objects.len(), 1);
+		assert_eq!(objects[0].value, json!(1));
 		
-		assert!(client2.inbox_try_next().is_err());
+		// later changes are dropped instead of blocking
+		server.set("bar", json!(2), &client).unwrap();
+		server.flush();
 	}
 	
+	#[cfg(feature = "sqlite-backend")]
 	#[test]
-	fn test_unsubscribe() {
-		let server = create_server();
-		let client1 = server.client_connect();
-		let mut client2 = server.client_connect();
+	fn test_backup_to_dir() {
+		use crate::server::storage::sqlite::SqliteStorage;
 		
-		server.set("livingroom/temperature", json!({ "temp": 20.3 }), &client1).unwrap();
+		let dir = std::env::temp_dir().join(format!("objtalk-backups-{}", std::process::id()));
+		fs::create_dir_all(&dir).unwrap();
 		
-		let (query_id, _) = server.query(&Pattern::compile("+/temperature").unwrap(), false, &client2).unwrap();
+		let storage = SqliteStorage::new(rusqlite::Connection::open_in_memory().unwrap()).unwrap();
+		let server = Server::new(Some(Box::new(storage)), Box::new(NullLogger));
+		let mut client = server.client_connect();
 		
-		server.set("livingroom/temperature", json!({ "temp": 20.4 }), &client1).unwrap();
+		server.query(&Pattern::compile("$system").unwrap(), false, false, &client).unwrap();
+		server.set("foo", json!(1), &client).unwrap();
 		
-		let msg = client2.inbox_try_next().unwrap().unwrap();
-		if let Message::QueryChange { query_id: msg_query_id, object } = msg {
-			assert_eq!(msg_query_id, query_id);
-			assert_eq!(object.name, "livingroom/temperature");
-			assert_eq!(object.value, json!({ "temp": 20.4 }));
-		} else {
-			assert!(false);
-		}
+		let path = server.backup_to_dir(&dir, &client).unwrap();
+		let backup: Backup = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+		assert_eq!(backup.objects[0].name, "foo");
+		assert_eq!(path, dir.join(backup.filename()));
 		
-		server.unsubscribe(query_id, &client2).unwrap();
+		let result = server.backup_to_dir(&dir.join("missing"), &client);
+		fs::remove_dir_all(&dir).unwrap();
+		assert!(matches!(result, Err(Error::Backup(_))));
 		
-		server.set("livingroom/temperature", json!({ "temp": 20.5 }), &client1).unwrap();
+		let events: Vec<(String, Value)> = std::iter::from_fn(|| client.inbox_try_next().ok().flatten())
+			.filter_map(|msg| match msg {
+				Message::QueryEvent { event, data, .. } if event.starts_with("backup") => Some((event, data)),
+				_ => None,
+			})
+			.collect();
 		
-		assert!(client2.inbox_try_next().is_err());
+		assert_eq!(events.len(), 2);
+		assert_eq!(events[0].0, "backupCompleted");
+		assert_eq!(events[0].1["file"], json!(path));
+		assert_eq!(events[0].1["objects"], json!(1));
+		assert_eq!(events[1].0, "backupFailed");
 	}
 	
+	#[cfg(feature = "sqlite-backend")]
 	#[test]
-	fn test_remove_non_existing() {
-		let server = create_server();
+	fn test_get_events() {
+		use crate::server::storage::Retention;
+		use crate::server::storage::sqlite::SqliteStorage;
+		
+		let mut storage = SqliteStorage::new(rusqlite::Connection::open_in_memory().unwrap()).unwrap();
+		storage.enable_events(Retention::new(chrono::Duration::days(1))).unwrap();
+		
+		let server = Server::new(Some(Box::new(storage)), Box::new(NullLogger));
 		let client = server.client_connect();
 		
-		let existed = server.remove("foo", &client).unwrap();
-		assert!(!existed);
+		server.set("gamepad", json!({}), &client).unwrap();
+		server.set("keyboard", json!({}), &client).unwrap();
+		server.emit("gamepad", "buttonpress", json!({ "button": "a" }), &client).unwrap();
+		server.emit("keyboard", "keypress", json!({ "key": "x" }), &client).unwrap();
+		server.emit("gamepad", "buttonpress", json!({ "button": "b" }), &client).unwrap();
+		
+		let events = block_on(server.get_events(&Pattern::compile("gamepad").unwrap(), None, 100, &client)).unwrap();
+		assert_eq!(events.len(), 2);
+		assert_eq!(events[0].data, json!({ "button": "a" }));
+		assert_eq!(events[1].data, json!({ "button": "b" }));
+		
+		let events = block_on(server.get_events(&Pattern::compile("*").unwrap(), None, 1, &client)).unwrap();
+		assert_eq!(events.len(), 1);
+		assert_eq!(events[0].object, "gamepad");
+		assert_eq!(events[0].data, json!({ "button": "b" }));
 	}
 	
+	#[cfg(feature = "sqlite-backend")]
 	#[test]
-	fn test_remove_existing() {
-		let server = create_server();
-		let client = server.client_connect();
+	fn test_get_history() {
+		use crate::server::config::HistoryConfig;
+		use crate::server::storage::sqlite::SqliteStorage;
 		
-		server.set("foo", json!({ "bar": 1 }), &client).unwrap();
+		let mut storage = SqliteStorage::new(rusqlite::Connection::open_in_memory().unwrap()).unwrap();
+		storage.enable_history(&HistoryConfig { enabled: true, retention: None, max_revisions: Some(3) }).unwrap();
 		
-		let existed = server.remove("foo", &client).unwrap();
-		assert!(existed);
-	}
-	
-	#[test]
-	fn test_remove_query() {
-		let server = create_server();
+		let server = Server::new(Some(Box::new(storage)), Box::new(NullLogger));
 		let client = server.client_connect();
 		
-		server.set("foo", json!({ "bar": 1 }), &client).unwrap();
+		server.set("lamp", json!({ "on": false }), &client).unwrap();
+		server.patch("lamp", json!({ "on": true }), &client).unwrap();
+		server.set("sensor", json!(20), &client).unwrap();
+		server.remove("lamp", &client).unwrap();
+		server.set("lamp", json!({ "on": false }), &client).unwrap();
 		
-		let mut client = server.client_connect();
+		let revisions = block_on(server.get_history("lamp", None, None, 100, &client)).unwrap();
+		assert_eq!(revisions.len(), 3);
+		assert_eq!(revisions[0].value, json!({ "on": true }));
+		assert!(revisions[1].removed);
+		assert_eq!(revisions[2].value, json!({ "on": false }));
+		assert_eq!(revisions[2].client, client.id);
 		
-		let (query_id, _) = server.query(&Pattern::compile("*").unwrap(), false, &client).unwrap();
+		let revisions = block_on(server.get_history("lamp", None, None, 1, &client)).unwrap();
+		assert_eq!(revisions.len(), 1);
+		assert_eq!(revisions[0].value, json!({ "on": false }));
+	}
+	
+	#[cfg(feature = "sqlite-backend")]
+	#[test]
+	fn test_get_audit() {
+		use crate::AuditQuery;
+		use crate::server::config::AuditConfig;
+		use crate::server::storage::sqlite::SqliteStorage;
 		
-		server.remove("foo", &client).unwrap();
+		let mut storage = SqliteStorage::new(rusqlite::Connection::open_in_memory().unwrap()).unwrap();
+		storage.enable_audit(&AuditConfig { enabled: true, ..AuditConfig::default() }).unwrap();
 		
-		let msg = client.inbox_try_next().unwrap().unwrap();
+		let server = Server::new(Some(Box::new(storage)), Box::new(NullLogger));
+		let client = server.client_connect();
+		let gateway = server.client_connect_from(Some("10.0.0.5".parse().unwrap()), Some("gateway".to_string()));
+		
+		server.set("heating", json!({ "on": true }), &client).unwrap();
+		server.patch("heating", json!({ "on": false }), &gateway).unwrap();
+		server.emit("heating", "alarm", json!(null), &gateway).unwrap();
+		server.remove("lamp", &client).unwrap();
+		server.set("lamp", json!(true), &client).unwrap();
+		server.remove("lamp", &client).unwrap();
+		
+		let filter = |query: AuditQuery| AuditFilter::new(query, 100).unwrap();
+		
+		let entries = block_on(server.get_audit(filter(AuditQuery::default()), &client)).unwrap();
+		assert_eq!(entries.len(), 4);
+		assert_eq!(entries[0].operation, AuditOperation::Remove);
+		assert_eq!(entries[0].value, Value::Null);
+		assert_eq!(entries[3].operation, AuditOperation::Set);
+		assert_eq!(entries[3].client, client.id);
+		
+		let entries = block_on(server.get_audit(filter(AuditQuery { pattern: Some("heating".to_string()), operation: Some(AuditOperation::Patch), ..AuditQuery::default() }), &client)).unwrap();
+		assert_eq!(entries.len(), 1);
+		assert_eq!(entries[0].value, json!({ "on": false }));
+		assert_eq!(entries[0].addr, Some("10.0.0.5".parse().unwrap()));
+		assert_eq!(entries[0].identity.as_deref(), Some("gateway"));
+		
+		let entries = block_on(server.get_audit(filter(AuditQuery { identity: Some("gateway".to_string()), ..AuditQuery::default() }), &client)).unwrap();
+		assert_eq!(entries.len(), 1);
+		
+		let entries = block_on(server.get_audit(filter(AuditQuery { offset: 1, limit: Some(2), ..AuditQuery::default() }), &client)).unwrap();
+		assert_eq!(entries.len(), 2);
+		assert_eq!(entries[0].operation, AuditOperation::Set);
+		assert_eq!(entries[0].object, "lamp");
+		assert_eq!(entries[1].operation, AuditOperation::Patch);
+	}
+	
+	#[test]
+	fn test_log_level() {
+		struct RecordingLogger(Arc<Mutex<Vec<String>>>);
 		
-		if let Message::QueryRemove { query_id: msg_query_id, object } = msg {
-			assert_eq!(msg_query_id, query_id);
-			assert_eq!(object.name, "foo");
-			assert_eq!(object.value, json!({ "bar": 1 }));
-		} else {
-			assert!(false);
+		impl Logger for RecordingLogger {
+			fn log(&self, message: &LogMessage) {
+				let message = serde_json::to_value(message).unwrap();
+				self.0.lock().unwrap().push(message["type"].as_str().unwrap().to_string());
+			}
 		}
 		
-		server.set("foo", json!({ "bar": 1 }), &client).unwrap();
+		let messages = Arc::new(Mutex::new(vec![]));
+		let server = Server::builder()
+			.logger(Box::new(RecordingLogger(messages.clone())))
+			.log_level(LogLevel::Info)
+			.build().unwrap();
+		let client = server.client_connect();
 		
-		let msg = client.inbox_try_next().unwrap().unwrap();
+		server.set("lamp", json!(true), &client).unwrap();
+		server.get(&Pattern::compile("lamp").unwrap(), &client);
+		assert_eq!(*messages.lock().unwrap(), vec!["clientConnect", "set"]);
 		
-		if let Message::QueryAdd { query_id: msg_query_id, object } = msg {
-			assert_eq!(msg_query_id, query_id);
-			assert_eq!(object.name, "foo");
-			assert_eq!(object.value, json!({ "bar": 1 }));
-		} else {
-			assert!(false);
-		}
+		assert_eq!(server.set_log_level(Some(LogLevel::Trace), None, &client), LogLevel::Trace);
+		server.get(&Pattern::compile("lamp").unwrap(), &client);
+		assert_eq!(messages.lock().unwrap()[2..], ["setLogLevel", "get"]);
 		
-		assert!(client.inbox_try_next().is_err());
+		server.set_log_level(Some(LogLevel::Trace), Some(Duration::from_millis(0)), &client);
+		assert_eq!(server.log_level(), LogLevel::Info);
+		
+		server.set_log_level(Some(LogLevel::Error), None, &client);
+		server.set("lamp", json!(false), &client).unwrap();
+		assert_eq!(server.set_log_level(None, None, &client), LogLevel::Info);
+		assert_eq!(messages.lock().unwrap()[4..], ["setLogLevel", "setLogLevel"]);
 	}
 	
 	#[test]
-	fn test_emit_event() {
+	fn test_operation_stats() {
 		let server = create_server();
-		let client = server.client_connect();
+		let mut provider = server.client_connect();
+		let consumer = server.client_connect();
 		
-		server.set("gamepad", json!({ "buttons": ["a", "b"] }), &client).unwrap();
+		server.query(&Pattern::compile("lamp").unwrap(), true, false, &provider).unwrap();
+		server.set("lamp", json!({ "on": false }), &consumer).unwrap();
+		server.patch("lamp", json!({ "on": true }), &consumer).unwrap();
+		server.get(&Pattern::compile("+").unwrap(), &consumer);
 		
+		server.invoke("lamp", "setState", json!({}), json!(1), &consumer).unwrap();
+		let invocation_id = loop {
+			if let Message::QueryInvocation { invocation_id, .. } = provider.inbox_try_next().unwrap().unwrap() {
+				break invocation_id;
+			}
+		};
+		server.invoke_result(invocation_id, json!(true), &provider).unwrap();
+		
+		let operations = server.stats().operations;
+		assert_eq!(operations.keys().collect::<Vec<_>>(), ["dispatch", "get", "invoke", "patch", "query", "set"]);
+		assert_eq!(operations["set"].count, 1);
+		assert!(operations["dispatch"].count >= 2);
+		assert_eq!(operations["invoke"].count, 1);
+		assert_eq!(operations["set"].buckets.len(), LATENCY_BUCKETS.len());
+		assert!(operations["set"].latency_max <= operations["set"].latency_sum);
+	}
+	
+	#[test]
+	fn test_slow_operations() {
+		let server = Server::builder()
+			.logger(Box::new(NullLogger))
+			.slow_operation_threshold(Duration::ZERO)
+			.build().unwrap();
 		let mut client = server.client_connect();
 		
-		let (query_id, _) = server.query(&Pattern::compile("*").unwrap(), false, &client).unwrap();
-		
-		server.emit("gamepad", "buttonpress", json!({ "button": "a" }), &client).unwrap();
-		
-		let msg = client.inbox_try_next().unwrap().unwrap();
+		server.query(&Pattern::compile("$system").unwrap(), false, false, &client).unwrap();
+		server.set("lamp", json!(true), &client).unwrap();
 		
-		if let Message::QueryEvent { query_id: msg_query_id, object, event, data } = msg {
-			assert_eq!(msg_query_id, query_id);
-			assert_eq!(object, "gamepad");
-			assert_eq!(event, "buttonpress");
-			assert_eq!(data, json!({ "button": "a" }));
-		} else {
-			assert!(false);
+		let mut warnings = vec![];
+		while let Ok(Some(Message::QueryEvent { event, data, .. })) = client.inbox_try_next() {
+			if event == "slowOperation" {
+				warnings.push((data["operation"].clone(), data["object"].clone()));
+			}
 		}
 		
-		assert!(client.inbox_try_next().is_err());
+		assert_eq!(warnings, vec![
+			(json!("dispatch"), json!("lamp")),
+			(json!("set"), json!("lamp")),
+		]);
 	}
 	
 	#[test]
-	fn test_emit_event_doesnt_exist() {
-		let server = create_server();
-		let client = server.client_connect();
+	fn test_system_log() {
+		let server = Server::builder()
+			.logger(Box::new(NullLogger))
+			.system_log(SystemLogConfig {
+				enabled: true,
+				identities: Some(vec!["admin".to_string()]),
+				types: Some(vec!["set".to_string()]),
+			})
+			.build().unwrap();
+		let mut admin = server.client_connect_from(None, Some("admin".to_string()));
+		let mut other = server.client_connect();
+		
+		server.query(&Pattern::compile("$system").unwrap(), false, false, &admin).unwrap();
+		server.query(&Pattern::compile("$system").unwrap(), false, false, &other).unwrap();
+		server.set("lamp", json!(true), &other).unwrap();
+		server.get(&Pattern::compile("lamp").unwrap(), &other);
+		
+		let msg = admin.inbox_try_next().unwrap().unwrap();
+		if let Message::QueryEvent { event, data, .. } = msg {
+			assert_eq!(event, "log");
+			assert_eq!(data["type"], json!("set"));
+		} else {
+			panic!("unexpected message");
+		}
 		
-		let result = server.emit("gamepad", "buttonpress", json!({ "button": "a" }), &client);
+		assert!(admin.inbox_try_next().is_err());
+		assert!(other.inbox_try_next().is_err());
 		
-		assert_eq!(result, Err(Error::ObjectNotFound));
+		let result = Server::builder()
+			.system_log(SystemLogConfig { types: Some(vec!["sett".to_string()]), ..SystemLogConfig::default() })
+			.build();
+		assert!(result.is_err());
 	}
 	
 	#[test]
@@ -972,7 +2969,7 @@ mod tests {
 		let mut consumer = server.client_connect();
 		
 		server.set("lamp", json!({ "on": false }), &provider).unwrap();
-		let (query_id, _) = server.query(&Pattern::compile("lamp").unwrap(), true, &provider).unwrap();
+		let (query_id, _) = server.query(&Pattern::compile("lamp").unwrap(), true, false, &provider).unwrap();
 		
 		let result = server.invoke("lamp", "setState", json!({ "on": true }), json!(1), &consumer);
 		assert_eq!(result, Ok(()));
@@ -988,8 +2985,7 @@ mod tests {
 			assert_eq!(args, json!({ "on": true }));
 			invocation_id = msg_invocation_id;
 		} else {
-			assert!(false);
-			return;
+			panic!("unexpected message");
 		}
 		
 		server.invoke_result(invocation_id, json!({ "success": true }), &provider).unwrap();
@@ -1000,7 +2996,7 @@ mod tests {
 			assert_eq!(request_id, json!(1));
 			assert_eq!(result, Ok(json!({ "success": true })));
 		} else {
-			assert!(false);
+			panic!("unexpected message");
 		}
 	}
 	
@@ -1011,7 +3007,7 @@ mod tests {
 		let mut consumer = server.client_connect();
 		
 		server.set("lamp", json!({ "on": false }), &provider).unwrap();
-		let (query_id, _) = server.query(&Pattern::compile("lamp").unwrap(), true, &provider).unwrap();
+		let (query_id, _) = server.query(&Pattern::compile("lamp").unwrap(), true, false, &provider).unwrap();
 		
 		let result = server.invoke("lamp", "setState", json!({ "on": true }), json!(1), &consumer);
 		assert_eq!(result, Ok(()));
@@ -1024,8 +3020,7 @@ mod tests {
 			assert_eq!(method, "setState");
 			assert_eq!(args, json!({ "on": true }));
 		} else {
-			assert!(false);
-			return;
+			panic!("unexpected message");
 		}
 		
 		// disconnect before providing an invocation result
@@ -1037,7 +3032,7 @@ mod tests {
 			assert_eq!(request_id, json!(1));
 			assert_eq!(result, Err(Error::ObjectNotInvocable));
 		} else {
-			assert!(false);
+			panic!("unexpected message");
 		}
 	}
 	
@@ -1048,7 +3043,7 @@ mod tests {
 		let mut consumer = server.client_connect();
 		
 		server.set("lamp", json!({ "on": false }), &provider).unwrap();
-		let (query_id, _) = server.query(&Pattern::compile("lamp").unwrap(), true, &provider).unwrap();
+		let (query_id, _) = server.query(&Pattern::compile("lamp").unwrap(), true, false, &provider).unwrap();
 		
 		let result = server.invoke("lamp", "setState", json!({ "on": true }), json!(1), &consumer);
 		assert_eq!(result, Ok(()));
@@ -1061,8 +3056,7 @@ mod tests {
 			assert_eq!(method, "setState");
 			assert_eq!(args, json!({ "on": true }));
 		} else {
-			assert!(false);
-			return;
+			panic!("unexpected message");
 		}
 		
 		// unsubscribe before providing an invocation result
@@ -1074,7 +3068,7 @@ mod tests {
 			assert_eq!(request_id, json!(1));
 			assert_eq!(result, Err(Error::ObjectNotInvocable));
 		} else {
-			assert!(false);
+			panic!("unexpected message");
 		}
 	}
 	
@@ -1092,18 +3086,18 @@ mod tests {
 			}
 		], &device).unwrap();
 		
-		let (query_id, _) = server.query(&Pattern::compile("lamp").unwrap(), true, &observer).unwrap();
+		let (query_id, _) = server.query(&Pattern::compile("lamp").unwrap(), true, false, &observer).unwrap();
 		
 		drop(device);
 		
 		let msg = observer.inbox_try_next().unwrap().unwrap();
 		
-		if let Message::QueryChange { query_id: msg_query_id, object } = msg {
+		if let Message::QueryChange { query_id: msg_query_id, object, .. } = msg {
 			assert_eq!(msg_query_id, query_id);
 			assert_eq!(object.name, "lamp");
 			assert_eq!(object.value, json!({ "online": false }));
 		} else {
-			assert!(false);
+			panic!("unexpected message");
 		}
 		
 		assert!(observer.inbox_try_next().is_err());
@@ -1123,18 +3117,18 @@ mod tests {
 			}
 		], &device).unwrap();
 		
-		let (query_id, _) = server.query(&Pattern::compile("lamp").unwrap(), true, &observer).unwrap();
+		let (query_id, _) = server.query(&Pattern::compile("lamp").unwrap(), true, false, &observer).unwrap();
 		
 		drop(device);
 		
 		let msg = observer.inbox_try_next().unwrap().unwrap();
 		
-		if let Message::QueryChange { query_id: msg_query_id, object } = msg {
+		if let Message::QueryChange { query_id: msg_query_id, object, .. } = msg {
 			assert_eq!(msg_query_id, query_id);
 			assert_eq!(object.name, "lamp");
 			assert_eq!(object.value, json!({ "online": false }));
 		} else {
-			assert!(false);
+			panic!("unexpected message");
 		}
 		
 		assert!(observer.inbox_try_next().is_err());
@@ -1153,7 +3147,7 @@ mod tests {
 			}
 		], &device).unwrap();
 		
-		let (query_id, _) = server.query(&Pattern::compile("client").unwrap(), true, &observer).unwrap();
+		let (query_id, _) = server.query(&Pattern::compile("client").unwrap(), true, false, &observer).unwrap();
 		
 		drop(device);
 		
@@ -1163,7 +3157,7 @@ mod tests {
 			assert_eq!(msg_query_id, query_id);
 			assert_eq!(object.name, "client");
 		} else {
-			assert!(false);
+			panic!("unexpected message");
 		}
 		
 		assert!(observer.inbox_try_next().is_err());
@@ -1184,7 +3178,7 @@ mod tests {
 			}
 		], &device).unwrap();
 		
-		let (query_id, _) = server.query(&Pattern::compile("lamp").unwrap(), true, &observer).unwrap();
+		let (query_id, _) = server.query(&Pattern::compile("lamp").unwrap(), true, false, &observer).unwrap();
 		
 		drop(device);
 		
@@ -1196,9 +3190,38 @@ mod tests {
 			assert_eq!(event, "offline");
 			assert_eq!(data, json!({}));
 		} else {
-			assert!(false);
+			panic!("unexpected message");
 		}
 		
 		assert!(observer.inbox_try_next().is_err());
 	}
+	
+	#[tokio::test]
+	async fn test_shutdown() {
+		let server = create_server();
+		let device = server.client_connect();
+		
+		server.set_disconnect_commands(vec![
+			Command::Set {
+				name: "lamp".to_string(),
+				value: json!({ "online": false }),
+			}
+		], &device).unwrap();
+		
+		let shutting_down = server.shutting_down();
+		server.shutdown();
+		shutting_down.await;
+		// also completes for calls after the shutdown
+		server.shutting_down().await;
+		
+		assert_eq!(server.connected_clients(), 1);
+		server.run_disconnect_commands();
+		assert_eq!(server.get(&Pattern::compile("lamp").unwrap(), &device)[0].value, json!({ "online": false }));
+		
+		// the commands only run once
+		server.set("lamp", json!({ "online": true }), &device).unwrap();
+		drop(device);
+		assert_eq!(server.connected_clients(), 0);
+		assert_eq!(server.get(&Pattern::compile("lamp").unwrap(), &server.client_connect())[0].value, json!({ "online": true }));
+	}
 }
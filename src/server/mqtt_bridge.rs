@@ -0,0 +1,357 @@
+use crate::patterns::Pattern;
+use crate::server::config::{MqttConfig, MqttDirection};
+use crate::server::{Client, Message, Server};
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures::future::join;
+use futures::StreamExt;
+use rumqttc::{AsyncClient, Event, EventLoop, MqttOptions, Packet, QoS};
+use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+const REQUEST_CAPACITY: usize = 64;
+/// How many forwarded events are remembered to recognize them when they come back.
+const MAX_PENDING_EVENTS: usize = 256;
+/// Object names can't start with `$`, so event topics never collide with object topics.
+const EVENTS_TOPIC: &str = "$events/";
+
+#[derive(Debug, PartialEq)]
+enum Target {
+	Object(String),
+	Event { object: String, event: String },
+}
+
+/// Maps a topic below `prefix` to an object or an event.
+fn parse_topic(prefix: &str, topic: &str) -> Option<Target> {
+	let name = topic.strip_prefix(prefix)?;
+	
+	match name.strip_prefix(EVENTS_TOPIC) {
+		Some(path) => {
+			let (object, event) = path.rsplit_once('/')?;
+			
+			if object.is_empty() || event.is_empty() {
+				return None;
+			}
+			
+			Some(Target::Event { object: object.to_string(), event: event.to_string() })
+		},
+		None => Some(Target::Object(name.to_string())),
+	}
+}
+
+/// Payloads are JSON; anything else is taken as a string so plain text
+/// devices can be bridged, too.
+fn decode_payload(payload: &[u8]) -> Option<Value> {
+	serde_json::from_slice(payload).ok()
+		.or_else(|| String::from_utf8(payload.to_vec()).ok().map(Value::String))
+}
+
+fn remember<T>(queue: &mut VecDeque<T>, item: T) {
+	if queue.len() == MAX_PENDING_EVENTS {
+		queue.pop_front();
+	}
+	
+	queue.push_back(item);
+}
+
+fn forget<T: PartialEq>(queue: &mut VecDeque<T>, item: &T) -> bool {
+	match queue.iter().position(|pending| pending == item) {
+		Some(index) => {
+			queue.remove(index);
+			true
+		},
+		None => false,
+	}
+}
+
+enum BrokerMessage {
+	Connected,
+	Publish { topic: String, payload: Vec<u8> },
+}
+
+async fn poll_broker(mut eventloop: EventLoop, messages: UnboundedSender<BrokerMessage>) {
+	loop {
+		match eventloop.poll().await {
+			Ok(Event::Incoming(Packet::ConnAck(_))) => {
+				let _ = messages.unbounded_send(BrokerMessage::Connected);
+			},
+			Ok(Event::Incoming(Packet::Publish(publish))) => {
+				let _ = messages.unbounded_send(BrokerMessage::Publish {
+					topic: publish.topic,
+					payload: publish.payload.to_vec(),
+				});
+			},
+			Ok(_) => {},
+			Err(e) => {
+				println!("mqtt bridge error: {}", e);
+				tokio::time::sleep(RECONNECT_DELAY).await;
+			},
+		}
+	}
+}
+
+/// Connects to an MQTT broker as a client and mirrors objects as retained
+/// messages on `<prefix><name>` and events as messages on
+/// `<prefix>$events/<object>/<event>`.
+pub struct MqttBridge {
+	server: Server,
+	options: MqttOptions,
+	prefix: String,
+	pattern: Pattern,
+	direction: MqttDirection,
+}
+
+impl MqttBridge {
+	pub fn new(config: MqttConfig, server: Server) -> Result<Self, String> {
+		let pattern = Pattern::compile(&config.pattern)
+			.map_err(|e| format!("invalid mqtt pattern {}: {}", config.pattern, e))?;
+		
+		let mut options = MqttOptions::new(config.client_id, config.host, config.port);
+		
+		if let Some(username) = config.username {
+			options.set_credentials(username, config.password.unwrap_or_default());
+		}
+		
+		Ok(MqttBridge { server, options, prefix: config.prefix, pattern, direction: config.direction })
+	}
+	
+	pub async fn serve(self) {
+		let (mqtt, eventloop) = AsyncClient::new(self.options.clone(), REQUEST_CAPACITY);
+		let (messages_tx, messages_rx) = unbounded();
+		
+		let (host, port) = self.options.broker_address();
+		println!("mqtt bridge connecting to {}:{}", host, port);
+		
+		let bridge = Bridge {
+			client: self.server.client_connect(),
+			server: self.server,
+			mqtt,
+			prefix: self.prefix,
+			pattern: self.pattern,
+			direction: self.direction,
+			synced: HashMap::new(),
+			published_events: VecDeque::new(),
+			emitted_events: VecDeque::new(),
+		};
+		
+		join(poll_broker(eventloop, messages_tx), bridge.run(messages_rx)).await;
+	}
+}
+
+struct Bridge {
+	server: Server,
+	client: Client,
+	mqtt: AsyncClient,
+	prefix: String,
+	pattern: Pattern,
+	direction: MqttDirection,
+	/// Object values known to be the same in objtalk and on the broker, to
+	/// not send changes back to where they came from.
+	synced: HashMap<String, Value>,
+	/// Events published to the broker that will be received again.
+	published_events: VecDeque<(String, Value)>,
+	/// Events emitted in objtalk that the query will report again.
+	emitted_events: VecDeque<(String, String, Value)>,
+}
+
+impl Bridge {
+	fn outgoing(&self) -> bool {
+		matches!(self.direction, MqttDirection::Both | MqttDirection::Out)
+	}
+	
+	fn incoming(&self) -> bool {
+		matches!(self.direction, MqttDirection::Both | MqttDirection::In)
+	}
+	
+	async fn run(mut self, mut messages: UnboundedReceiver<BrokerMessage>) {
+		if self.outgoing() {
+			if let Err(e) = self.server.query(&self.pattern, false, false, &self.client) {
+				println!("mqtt bridge error: {}", e);
+				return;
+			}
+		}
+		
+		loop {
+			tokio::select! {
+				Some(msg) = self.client.inbox_next() => self.handle_server_message(msg).await,
+				Some(msg) = messages.next() => match msg {
+					BrokerMessage::Connected => self.handle_connected().await,
+					BrokerMessage::Publish { topic, payload } => self.handle_publish(&topic, &payload),
+				},
+				else => break,
+			}
+		}
+	}
+	
+	async fn publish(&self, topic: String, retain: bool, payload: Vec<u8>) {
+		if let Err(e) = self.mqtt.publish(topic, QoS::AtLeastOnce, retain, payload).await {
+			println!("mqtt bridge error: {}", e);
+		}
+	}
+	
+	async fn publish_object(&mut self, name: &str, value: &Value) {
+		if self.synced.get(name) == Some(value) {
+			return;
+		}
+		
+		self.synced.insert(name.to_string(), value.clone());
+		self.publish(format!("{}{}", self.prefix, name), true, serde_json::to_vec(value).unwrap()).await;
+	}
+	
+	/// Publishes all objects before subscribing, so objtalk's state takes
+	/// precedence over retained messages.
+	async fn handle_connected(&mut self) {
+		if self.outgoing() {
+			self.synced.clear();
+			
+			for object in self.server.get(&self.pattern, &self.client) {
+				self.publish_object(&object.name, &object.value).await;
+			}
+		}
+		
+		if self.incoming() {
+			let mut topics = vec![format!("{}#", self.prefix)];
+			
+			// topics starting with $ aren't matched by a leading wildcard
+			if self.prefix.is_empty() {
+				topics.push(format!("{}#", EVENTS_TOPIC));
+			}
+			
+			for topic in topics {
+				if let Err(e) = self.mqtt.subscribe(topic, QoS::AtLeastOnce).await {
+					println!("mqtt bridge error: {}", e);
+				}
+			}
+		}
+	}
+	
+	async fn handle_server_message(&mut self, msg: Message) {
+		match msg {
+			Message::QueryAdd { object, .. } | Message::QueryChange { object, .. } => {
+				self.publish_object(&object.name, &object.value).await;
+			},
+			Message::QueryRemove { object, .. } => {
+				let was_synced = self.synced.remove(&object.name).is_some();
+				
+				if was_synced {
+					self.publish(format!("{}{}", self.prefix, object.name), true, vec![]).await;
+				}
+			},
+			Message::QueryEvent { object, event, data, .. } => {
+				if forget(&mut self.emitted_events, &(object.clone(), event.clone(), data.clone())) {
+					return;
+				}
+				
+				let topic = format!("{}{}{}/{}", self.prefix, EVENTS_TOPIC, object, event);
+				
+				if self.incoming() {
+					remember(&mut self.published_events, (topic.clone(), data.clone()));
+				}
+				
+				self.publish(topic, false, serde_json::to_vec(&data).unwrap()).await;
+			},
+			_ => {},
+		}
+	}
+	
+	fn handle_publish(&mut self, topic: &str, payload: &[u8]) {
+		let target = match parse_topic(&self.prefix, topic) {
+			Some(target) => target,
+			None => return,
+		};
+		
+		let result = match target {
+			Target::Object(name) if self.pattern.matches(&name) => {
+				// an empty retained message clears the topic
+				if payload.is_empty() {
+					self.synced.remove(&name);
+					self.server.remove(&name, &self.client).map(|_| ())
+				} else {
+					let value = match decode_payload(payload) {
+						Some(value) => value,
+						None => return,
+					};
+					
+					if self.synced.get(&name) == Some(&value) {
+						return;
+					}
+					
+					self.synced.insert(name.clone(), value.clone());
+					self.server.set(&name, value, &self.client)
+				}
+			},
+			Target::Event { object, event } if self.pattern.matches(&object) => {
+				let data = if payload.is_empty() {
+					Value::Null
+				} else {
+					match decode_payload(payload) {
+						Some(data) => data,
+						None => return,
+					}
+				};
+				
+				if forget(&mut self.published_events, &(topic.to_string(), data.clone())) {
+					return;
+				}
+				
+				let result = self.server.emit(&object, &event, data.clone(), &self.client);
+				
+				if result.is_ok() && self.outgoing() {
+					remember(&mut self.emitted_events, (object, event, data));
+				}
+				
+				result
+			},
+			_ => return,
+		};
+		
+		if let Err(e) = result {
+			println!("mqtt bridge error: can't apply {}: {}", topic, e);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use serde_json::json;
+	
+	#[test]
+	fn test_parse_topic() {
+		assert_eq!(parse_topic("objtalk/", "objtalk/lamp/livingroom"), Some(Target::Object("lamp/livingroom".to_string())));
+		assert_eq!(parse_topic("objtalk/", "objtalk/$events/lamp/livingroom/toggled"), Some(Target::Event {
+			object: "lamp/livingroom".to_string(),
+			event: "toggled".to_string(),
+		}));
+		assert_eq!(parse_topic("", "$events/lamp/toggled"), Some(Target::Event {
+			object: "lamp".to_string(),
+			event: "toggled".to_string(),
+		}));
+		assert_eq!(parse_topic("objtalk/", "other/lamp"), None);
+		assert_eq!(parse_topic("objtalk/", "objtalk/$events/lamp"), None);
+		assert_eq!(parse_topic("objtalk/", "objtalk/$events/lamp/"), None);
+	}
+	
+	#[test]
+	fn test_decode_payload() {
+		assert_eq!(decode_payload(br#"{"on":true}"#), Some(json!({ "on": true })));
+		assert_eq!(decode_payload(b"21.5"), Some(json!(21.5)));
+		assert_eq!(decode_payload(b"on"), Some(json!("on")));
+		assert_eq!(decode_payload(&[0xff, 0xfe]), None);
+	}
+	
+	#[test]
+	fn test_pending_events() {
+		let mut queue = VecDeque::new();
+		
+		for i in 0..MAX_PENDING_EVENTS + 1 {
+			remember(&mut queue, i);
+		}
+		
+		assert_eq!(queue.len(), MAX_PENDING_EVENTS);
+		assert!(!forget(&mut queue, &0));
+		assert!(forget(&mut queue, &1));
+		assert!(!forget(&mut queue, &1));
+	}
+}
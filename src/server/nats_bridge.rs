@@ -0,0 +1,471 @@
+use crate::{Command, VERSION_STRING};
+use crate::patterns::Pattern;
+use crate::server::config::{NatsConfig, NatsDirection};
+use crate::server::{Client, Message, Server};
+use bytes::{Buf, BufMut, BytesMut};
+use futures::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use std::io;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::time::sleep;
+use tokio_util::codec::{Decoder, Encoder, Framed};
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+/// Longest protocol line accepted, payloads are limited by the NATS server.
+const MAX_LINE_LENGTH: usize = 4096;
+/// Subscribed below the prefix, with the object name as the following tokens.
+const COMMANDS: [&str; 4] = ["set", "patch", "remove", "emit"];
+
+fn invalid(message: &str) -> io::Error {
+	io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn valid_token(token: &str) -> bool {
+	!token.is_empty() && !token.contains(|c: char| c == '.' || c == '*' || c == '>' || c.is_whitespace())
+}
+
+/// Object names become subject tokens by replacing `/` with `.`. Names that
+/// can't be mapped back, e.g. because they contain a `.`, aren't bridged.
+fn subject_tokens(name: &str) -> Option<String> {
+	if name.split('/').all(valid_token) {
+		Some(name.replace('/', "."))
+	} else {
+		None
+	}
+}
+
+/// Payloads are JSON; anything else is taken as a string so plain text
+/// publishers can send commands, too.
+fn decode_payload(payload: &[u8]) -> Option<Value> {
+	serde_json::from_slice(payload).ok()
+		.or_else(|| String::from_utf8(payload.to_vec()).ok().map(Value::String))
+}
+
+/// Maps a message on `<prefix><command>.<object tokens>` to a command, for
+/// `emit` the last token is the event.
+fn parse_command(prefix: &str, subject: &str, payload: &[u8]) -> Option<Command> {
+	let (command, tokens) = subject.strip_prefix(prefix)?.split_once('.')?;
+	
+	match command {
+		"set" => Some(Command::Set { name: tokens.replace('.', "/"), value: decode_payload(payload)? }),
+		"patch" => Some(Command::Patch { name: tokens.replace('.', "/"), value: decode_payload(payload)? }),
+		"remove" => Some(Command::Remove { name: tokens.replace('.', "/") }),
+		"emit" => {
+			let (object, event) = tokens.rsplit_once('.')?;
+			let data = if payload.is_empty() { Value::Null } else { decode_payload(payload)? };
+			
+			Some(Command::Emit { object: object.replace('.', "/"), event: event.to_string(), data })
+		},
+		_ => None,
+	}
+}
+
+#[derive(Debug, PartialEq)]
+enum ServerOp {
+	Info(Value),
+	Msg { subject: String, reply: Option<String>, payload: Vec<u8> },
+	Ping,
+	Pong,
+	Ok,
+	Err(String),
+}
+
+#[derive(Debug, PartialEq)]
+enum ClientOp {
+	Connect(Value),
+	Pub { subject: String, payload: Vec<u8> },
+	Sub { subject: String, sid: usize },
+	Pong,
+}
+
+/// The parts of the NATS client protocol the bridge needs, without headers.
+struct NatsCodec;
+
+impl Decoder for NatsCodec {
+	type Item = ServerOp;
+	type Error = io::Error;
+	
+	fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<ServerOp>, io::Error> {
+		let line_end = match buf.windows(2).position(|window| window == b"\r\n") {
+			Some(position) => position,
+			None if buf.len() > MAX_LINE_LENGTH => return Err(invalid("line too long")),
+			None => return Ok(None),
+		};
+		
+		let line = std::str::from_utf8(&buf[..line_end]).map_err(|_| invalid("invalid line"))?.to_string();
+		let (op, args) = line.split_once(' ').unwrap_or((&line, ""));
+		
+		let op = match op.to_uppercase().as_str() {
+			"MSG" => {
+				let (subject, reply, len) = match args.split_whitespace().collect::<Vec<_>>().as_slice() {
+					[subject, _sid, len] => (subject.to_string(), None, len.parse::<usize>()),
+					[subject, _sid, reply, len] => (subject.to_string(), Some(reply.to_string()), len.parse::<usize>()),
+					_ => return Err(invalid("invalid MSG")),
+				};
+				let len = len.map_err(|_| invalid("invalid MSG"))?;
+				
+				// the payload is followed by another \r\n
+				let end = line_end + 2 + len + 2;
+				
+				if buf.len() < end {
+					buf.reserve(end - buf.len());
+					return Ok(None);
+				}
+				
+				let payload = buf[line_end + 2..line_end + 2 + len].to_vec();
+				buf.advance(end);
+				
+				return Ok(Some(ServerOp::Msg { subject, reply, payload }));
+			},
+			"INFO" => ServerOp::Info(serde_json::from_str(args).map_err(|_| invalid("invalid INFO"))?),
+			"PING" => ServerOp::Ping,
+			"PONG" => ServerOp::Pong,
+			"+OK" => ServerOp::Ok,
+			"-ERR" => ServerOp::Err(args.trim().trim_matches('\'').to_string()),
+			_ => return Err(invalid("unknown operation")),
+		};
+		
+		buf.advance(line_end + 2);
+		Ok(Some(op))
+	}
+}
+
+impl Encoder<ClientOp> for NatsCodec {
+	type Error = io::Error;
+	
+	fn encode(&mut self, op: ClientOp, buf: &mut BytesMut) -> Result<(), io::Error> {
+		match op {
+			ClientOp::Connect(options) => buf.put_slice(format!("CONNECT {}\r\n", options).as_bytes()),
+			ClientOp::Pub { subject, payload } => {
+				buf.put_slice(format!("PUB {} {}\r\n", subject, payload.len()).as_bytes());
+				buf.put_slice(&payload);
+				buf.put_slice(b"\r\n");
+			},
+			ClientOp::Sub { subject, sid } => buf.put_slice(format!("SUB {} {}\r\n", subject, sid).as_bytes()),
+			ClientOp::Pong => buf.put_slice(b"PONG\r\n"),
+		}
+		
+		Ok(())
+	}
+}
+
+/// Connects to a NATS server and publishes the changes of objects on
+/// `<prefix>changed.<name>` and `<prefix>removed.<name>` and their events on
+/// `<prefix>events.<name>.<event>`. Messages on `<prefix>set.<name>`,
+/// `<prefix>patch.<name>`, `<prefix>remove.<name>` and
+/// `<prefix>emit.<name>.<event>` are applied, and answered if they have a
+/// reply subject.
+pub struct NatsBridge {
+	server: Server,
+	client: Client,
+	addr: String,
+	options: Value,
+	prefix: String,
+	pattern: Pattern,
+	direction: NatsDirection,
+}
+
+impl NatsBridge {
+	pub fn new(config: NatsConfig, server: Server) -> Result<Self, String> {
+		let pattern = Pattern::compile(&config.pattern)
+			.map_err(|e| format!("invalid nats pattern {}: {}", config.pattern, e))?;
+		
+		let mut options = json!({
+			"verbose": false,
+			"pedantic": false,
+			"name": "objtalk",
+			"lang": "rust",
+			"version": VERSION_STRING,
+			"protocol": 0,
+		});
+		
+		if let Some(username) = config.username {
+			options["user"] = json!(username);
+			options["pass"] = json!(config.password.unwrap_or_default());
+		}
+		
+		if let Some(token) = config.token {
+			options["auth_token"] = json!(token);
+		}
+		
+		Ok(NatsBridge {
+			client: server.client_connect(),
+			server,
+			addr: config.addr,
+			options,
+			prefix: config.prefix,
+			pattern,
+			direction: config.direction,
+		})
+	}
+	
+	fn outgoing(&self) -> bool {
+		matches!(self.direction, NatsDirection::Both | NatsDirection::Out)
+	}
+	
+	fn incoming(&self) -> bool {
+		matches!(self.direction, NatsDirection::Both | NatsDirection::In)
+	}
+	
+	pub async fn serve(mut self) {
+		if self.outgoing() {
+			if let Err(e) = self.server.query(&self.pattern, false, false, &self.client) {
+				println!("nats bridge error: {}", e);
+				return;
+			}
+		}
+		
+		loop {
+			println!("nats bridge connecting to {}", self.addr);
+			
+			let result = match TcpStream::connect(&self.addr).await {
+				Ok(stream) => self.run(stream).await,
+				Err(e) => Err(e.to_string()),
+			};
+			
+			if let Err(e) = result {
+				println!("nats bridge {}: {}, reconnecting in {}s", self.addr, e, RECONNECT_DELAY.as_secs());
+			}
+			
+			sleep(RECONNECT_DELAY).await;
+		}
+	}
+	
+	async fn run<S: AsyncRead + AsyncWrite + Unpin>(&mut self, stream: S) -> Result<(), String> {
+		let mut frames = Framed::new(stream, NatsCodec);
+		
+		match frames.next().await {
+			Some(Ok(ServerOp::Info(info))) if info["tls_required"] == json!(true) => return Err("tls isn't supported".to_string()),
+			Some(Ok(ServerOp::Info(_))) => {},
+			Some(Ok(_)) => return Err("expected INFO".to_string()),
+			Some(Err(e)) => return Err(e.to_string()),
+			None => return Err("connection closed".to_string()),
+		}
+		
+		frames.send(ClientOp::Connect(self.options.clone())).await.map_err(|e| e.to_string())?;
+		
+		if self.incoming() {
+			for (index, command) in COMMANDS.iter().enumerate() {
+				let subject = format!("{}{}.>", self.prefix, command);
+				frames.send(ClientOp::Sub { subject, sid: index + 1 }).await.map_err(|e| e.to_string())?;
+			}
+		}
+		
+		// nothing is retained, so the current values are published after connecting
+		while let Ok(Some(_)) = self.client.inbox_try_next() {}
+		
+		if self.outgoing() {
+			for object in self.server.get(&self.pattern, &self.client) {
+				if let Some(tokens) = subject_tokens(&object.name) {
+					let payload = serde_json::to_vec(&object.value).unwrap();
+					frames.send(ClientOp::Pub { subject: format!("{}changed.{}", self.prefix, tokens), payload }).await.map_err(|e| e.to_string())?;
+				}
+			}
+		}
+		
+		loop {
+			let op = tokio::select! {
+				Some(msg) = self.client.inbox_next() => self.handle_server_message(msg),
+				op = frames.next() => match op {
+					Some(Ok(ServerOp::Msg { subject, reply, payload })) => self.handle_command(&subject, reply, &payload),
+					Some(Ok(ServerOp::Ping)) => Some(ClientOp::Pong),
+					Some(Ok(ServerOp::Err(e))) => {
+						println!("nats bridge error: {}", e);
+						None
+					},
+					Some(Ok(_)) => None,
+					Some(Err(e)) => return Err(e.to_string()),
+					None => return Err("connection closed".to_string()),
+				},
+			};
+			
+			if let Some(op) = op {
+				frames.send(op).await.map_err(|e| e.to_string())?;
+			}
+		}
+	}
+	
+	fn handle_server_message(&self, msg: Message) -> Option<ClientOp> {
+		let (subject, payload) = match msg {
+			Message::QueryAdd { object, .. } | Message::QueryChange { object, .. } => {
+				(format!("{}changed.{}", self.prefix, subject_tokens(&object.name)?), serde_json::to_vec(&object.value).unwrap())
+			},
+			Message::QueryRemove { object, .. } => {
+				(format!("{}removed.{}", self.prefix, subject_tokens(&object.name)?), vec![])
+			},
+			Message::QueryEvent { object, event, data, .. } if valid_token(&event) => {
+				(format!("{}events.{}.{}", self.prefix, subject_tokens(&object)?, event), serde_json::to_vec(&data).unwrap())
+			},
+			_ => return None,
+		};
+		
+		Some(ClientOp::Pub { subject, payload })
+	}
+	
+	fn apply(&self, command: Command) -> Result<(), String> {
+		let target = match &command {
+			Command::Set { name, .. } | Command::Patch { name, .. } | Command::Remove { name } => name,
+			Command::Emit { object, .. } => object,
+		};
+		
+		if !self.pattern.matches(target) {
+			return Err("object not bridged".to_string());
+		}
+		
+		let result = match command {
+			Command::Set { name, value } => self.server.set(&name, value, &self.client),
+			Command::Patch { name, value } => self.server.patch(&name, value, &self.client),
+			Command::Remove { name } => self.server.remove(&name, &self.client).map(|_| ()),
+			Command::Emit { object, event, data } => self.server.emit(&object, &event, data, &self.client),
+		};
+		
+		result.map_err(|e| e.to_string())
+	}
+	
+	fn handle_command(&self, subject: &str, reply: Option<String>, payload: &[u8]) -> Option<ClientOp> {
+		let result = match parse_command(&self.prefix, subject, payload) {
+			Some(command) => self.apply(command),
+			None => Err("invalid command".to_string()),
+		};
+		
+		if let Err(e) = &result {
+			println!("nats bridge error: can't apply {}: {}", subject, e);
+		}
+		
+		let response = match result {
+			Ok(()) => json!({ "success": true }),
+			Err(e) => json!({ "error": e }),
+		};
+		
+		reply.map(|subject| ClientOp::Pub { subject, payload: response.to_string().into_bytes() })
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::server::logger::NullLogger;
+	use tokio::io::{duplex, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+	
+	#[test]
+	fn test_subject_tokens() {
+		assert_eq!(subject_tokens("lamp/livingroom"), Some("lamp.livingroom".to_string()));
+		assert_eq!(subject_tokens("lamp"), Some("lamp".to_string()));
+		assert_eq!(subject_tokens("lamp.1"), None);
+		assert_eq!(subject_tokens("lamp//1"), None);
+		assert_eq!(subject_tokens("lamp 1"), None);
+	}
+	
+	#[test]
+	fn test_parse_command() {
+		assert!(matches!(
+			parse_command("objtalk.", "objtalk.set.lamp.livingroom", br#"{"on":true}"#),
+			Some(Command::Set { name, value }) if name == "lamp/livingroom" && value == json!({ "on": true })
+		));
+		assert!(matches!(
+			parse_command("objtalk.", "objtalk.emit.button.pressed", b""),
+			Some(Command::Emit { object, event, data }) if object == "button" && event == "pressed" && data == Value::Null
+		));
+		assert!(matches!(parse_command("objtalk.", "objtalk.remove.lamp", b""), Some(Command::Remove { name }) if name == "lamp"));
+		assert!(parse_command("objtalk.", "objtalk.emit.button", b"").is_none());
+		assert!(parse_command("objtalk.", "objtalk.get.lamp", b"").is_none());
+		assert!(parse_command("objtalk.", "other.set.lamp", b"1").is_none());
+	}
+	
+	#[test]
+	fn test_codec() {
+		let mut buf = BytesMut::from(&b"INFO {\"server_id\":\"test\"}\r\nPING\r\nMSG objtalk.set.lamp 1 _INBOX.1 4\r\ntr"[..]);
+		
+		assert_eq!(NatsCodec.decode(&mut buf).unwrap(), Some(ServerOp::Info(json!({ "server_id": "test" }))));
+		assert_eq!(NatsCodec.decode(&mut buf).unwrap(), Some(ServerOp::Ping));
+		assert_eq!(NatsCodec.decode(&mut buf).unwrap(), None);
+		
+		buf.put_slice(b"ue\r\n-ERR 'Permissions Violation'\r\n");
+		assert_eq!(NatsCodec.decode(&mut buf).unwrap(), Some(ServerOp::Msg {
+			subject: "objtalk.set.lamp".to_string(),
+			reply: Some("_INBOX.1".to_string()),
+			payload: b"true".to_vec(),
+		}));
+		assert_eq!(NatsCodec.decode(&mut buf).unwrap(), Some(ServerOp::Err("Permissions Violation".to_string())));
+		assert!(buf.is_empty());
+		
+		assert!(NatsCodec.decode(&mut BytesMut::from(&b"HELLO\r\n"[..])).is_err());
+		
+		let mut buf = BytesMut::new();
+		NatsCodec.encode(ClientOp::Pub { subject: "objtalk.changed.lamp".to_string(), payload: b"true".to_vec() }, &mut buf).unwrap();
+		NatsCodec.encode(ClientOp::Sub { subject: "objtalk.set.>".to_string(), sid: 1 }, &mut buf).unwrap();
+		assert_eq!(&buf[..], b"PUB objtalk.changed.lamp 4\r\ntrue\r\nSUB objtalk.set.> 1\r\n");
+	}
+	
+	#[tokio::test]
+	async fn test_bridge() {
+		let server = Server::new(None, Box::new(NullLogger));
+		let client = server.client_connect();
+		server.set("sensor/1", json!(21.5), &client).unwrap();
+		
+		let config = NatsConfig {
+			addr: "127.0.0.1:4222".to_string(),
+			username: None,
+			password: None,
+			token: Some("secret".to_string()),
+			prefix: "objtalk.".to_string(),
+			pattern: "*".to_string(),
+			direction: NatsDirection::Both,
+		};
+		let mut bridge = NatsBridge::new(config, server.clone()).unwrap();
+		server.query(&bridge.pattern, false, false, &bridge.client).unwrap();
+		
+		let (stream, nats) = duplex(4096);
+		let (nats_read, mut nats_write) = tokio::io::split(nats);
+		let mut nats_read = BufReader::new(nats_read);
+		
+		async fn read_line<R: AsyncBufReadExt + Unpin>(reader: &mut R) -> String {
+			let mut line = String::new();
+			reader.read_line(&mut line).await.unwrap();
+			line.trim_end().to_string()
+		}
+		
+		async fn read_pub<R: AsyncBufReadExt + Unpin>(reader: &mut R) -> (String, String) {
+			let line = read_line(reader).await;
+			let parts: Vec<&str> = line.split(' ').collect();
+			assert_eq!(parts[0], "PUB");
+			
+			let mut payload = vec![0; parts[2].parse::<usize>().unwrap() + 2];
+			reader.read_exact(&mut payload).await.unwrap();
+			(parts[1].to_string(), String::from_utf8(payload).unwrap().trim_end().to_string())
+		}
+		
+		tokio::select! {
+			result = bridge.run(stream) => panic!("bridge stopped: {:?}", result),
+			_ = async {
+				nats_write.write_all(b"INFO {\"server_id\":\"test\"}\r\n").await.unwrap();
+				
+				let connect = read_line(&mut nats_read).await;
+				assert!(connect.starts_with("CONNECT "));
+				assert_eq!(serde_json::from_str::<Value>(&connect[8..]).unwrap()["auth_token"], "secret");
+				
+				for (index, command) in COMMANDS.iter().enumerate() {
+					assert_eq!(read_line(&mut nats_read).await, format!("SUB objtalk.{}.> {}", command, index + 1));
+				}
+				
+				assert_eq!(read_pub(&mut nats_read).await, ("objtalk.changed.sensor.1".to_string(), "21.5".to_string()));
+				
+				nats_write.write_all(b"MSG objtalk.set.lamp.1 1 _INBOX.1 4\r\ntrue\r\n").await.unwrap();
+				assert_eq!(read_pub(&mut nats_read).await, ("_INBOX.1".to_string(), r#"{"success":true}"#.to_string()));
+				assert_eq!(read_pub(&mut nats_read).await, ("objtalk.changed.lamp.1".to_string(), "true".to_string()));
+				
+				nats_write.write_all(b"MSG objtalk.patch.lamp.1 2 _INBOX.2 4\r\n{}{}\r\nPING\r\n").await.unwrap();
+				assert_eq!(read_pub(&mut nats_read).await, ("_INBOX.2".to_string(), r#"{"error":"object values not mergeable"}"#.to_string()));
+				assert_eq!(read_line(&mut nats_read).await, "PONG");
+				
+				server.emit("lamp/1", "toggled", json!(null), &client).unwrap();
+				assert_eq!(read_pub(&mut nats_read).await, ("objtalk.events.lamp.1.toggled".to_string(), "null".to_string()));
+				
+				server.remove("lamp/1", &client).unwrap();
+				assert_eq!(read_pub(&mut nats_read).await, ("objtalk.removed.lamp.1".to_string(), "".to_string()));
+			} => {},
+		}
+	}
+}
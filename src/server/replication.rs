@@ -0,0 +1,221 @@
+use crate::Object;
+use crate::patterns::Pattern;
+use crate::server::config::ReplicationConfig;
+use crate::server::{Client, Server};
+use futures::{SinkExt, StreamExt};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::time::sleep;
+use tokio_util::codec::{Framed, LinesCodec};
+
+const RESYNC_DELAY: Duration = Duration::from_secs(5);
+/// Request id of the query for the leader's objects.
+const SNAPSHOT_ID: &str = "snapshot";
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LeaderResponse {
+	request_id: Value,
+	#[serde(default)]
+	result: Value,
+	#[serde(default)]
+	error: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "camelCase")]
+enum LeaderEvent {
+	QueryAdd { object: Object },
+	QueryChange { object: Object },
+	QueryRemove { object: Object },
+	QueryEvent { object: String, event: String, data: Value },
+	Ping,
+	Shutdown,
+	#[serde(other)]
+	Other,
+}
+
+/// Keeps the objects of a read-only follower equal to those of a leader. It
+/// connects to the leader's tcp transport, takes a snapshot of its objects and
+/// applies the changes made afterwards, starting over after disconnects.
+pub struct Follower {
+	server: Server,
+	client: Client,
+	leader: String,
+	pattern: Pattern,
+}
+
+impl Follower {
+	pub fn new(config: ReplicationConfig, server: Server) -> Result<Self, String> {
+		let pattern = Pattern::compile(&config.pattern)
+			.map_err(|_| format!("invalid replication pattern {}", config.pattern))?;
+		
+		Ok(Follower {
+			client: server.client_connect(),
+			server,
+			leader: config.leader,
+			pattern,
+		})
+	}
+	
+	pub async fn serve(self) {
+		loop {
+			println!("replicating from {}", self.leader);
+			
+			let result = match TcpStream::connect(&self.leader).await {
+				Ok(stream) => self.follow(stream).await,
+				Err(e) => Err(e.to_string()),
+			};
+			
+			if let Err(e) = result {
+				println!("replication from {}: {}, resyncing in {}s", self.leader, e, RESYNC_DELAY.as_secs());
+			}
+			
+			sleep(RESYNC_DELAY).await;
+		}
+	}
+	
+	async fn follow<S: AsyncRead + AsyncWrite + Unpin>(&self, stream: S) -> Result<(), String> {
+		let mut frames = Framed::new(stream, LinesCodec::new());
+		
+		let query = json!({ "id": SNAPSHOT_ID, "type": "query", "pattern": self.pattern.string });
+		frames.send(query.to_string()).await.map_err(|e| e.to_string())?;
+		
+		loop {
+			let line = match frames.next().await {
+				Some(Ok(line)) => line,
+				Some(Err(e)) => return Err(e.to_string()),
+				None => return Err("connection closed".to_string()),
+			};
+			
+			if let Some(reply) = self.handle_message(&line)? {
+				frames.send(reply.to_string()).await.map_err(|e| e.to_string())?;
+			}
+		}
+	}
+	
+	/// Applies a message of the leader, returning what to send back.
+	fn handle_message(&self, line: &str) -> Result<Option<Value>, String> {
+		let value: Value = serde_json::from_str(line).map_err(|_| "invalid message".to_string())?;
+		
+		if value.get("requestId").is_some() {
+			let response: LeaderResponse = serde_json::from_value(value).map_err(|_| "invalid response".to_string())?;
+			
+			if let Some(error) = response.error {
+				return Err(format!("snapshot failed: {}", error));
+			}
+			
+			if response.request_id == SNAPSHOT_ID {
+				let objects: Vec<Object> = serde_json::from_value(response.result["objects"].clone())
+					.map_err(|_| "invalid snapshot".to_string())?;
+				
+				println!("replication from {}: synced {} objects", self.leader, objects.len());
+				self.server.replicate(&self.pattern, objects, &self.client);
+			}
+			
+			return Ok(None);
+		}
+		
+		match serde_json::from_value(value) {
+			Ok(LeaderEvent::QueryAdd { object }) | Ok(LeaderEvent::QueryChange { object }) => {
+				if let Err(e) = self.server.replicate_change(object, &self.client) {
+					println!("replication error: {}", e);
+				}
+			},
+			Ok(LeaderEvent::QueryRemove { object }) => {
+				if let Err(e) = self.server.replicate_remove(&object.name, &self.client) {
+					println!("replication error: {}", e);
+				}
+			},
+			Ok(LeaderEvent::QueryEvent { object, event, data }) => {
+				// events on objects that were removed meanwhile have nowhere to go
+				let _ = self.server.replicate_event(&object, &event, data, &self.client);
+			},
+			// any message shows the connection is alive, notifications aren't answered
+			Ok(LeaderEvent::Ping) => return Ok(Some(json!({ "type": "ping" }))),
+			Ok(LeaderEvent::Shutdown) => return Err("leader is shutting down".to_string()),
+			Ok(LeaderEvent::Other) | Err(_) => {},
+		}
+		
+		Ok(None)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::server::config::MessageFormat;
+	use crate::server::logger::NullLogger;
+	use crate::server::tcp_transport::serve_connection;
+	use crate::server::{Error, Message};
+	use tokio::io::duplex;
+	
+	fn value(server: &Server, name: &str) -> Option<Value> {
+		server.get(&Pattern::compile(name).unwrap(), &server.client_connect())
+			.into_iter()
+			.next()
+			.map(|object| object.value)
+	}
+	
+	/// Polls until `f` holds, failing after a second.
+	async fn eventually<F: FnMut() -> bool>(mut f: F) {
+		for _ in 0..100 {
+			if f() {
+				return;
+			}
+			
+			sleep(Duration::from_millis(10)).await;
+		}
+		
+		panic!("condition not met");
+	}
+	
+	#[tokio::test]
+	async fn test_follow() {
+		let leader = Server::new(None, Box::new(NullLogger));
+		let follower = Server::new(None, Box::new(NullLogger));
+		let leader_client = leader.client_connect();
+		let mut follower_client = follower.client_connect();
+		
+		leader.set("sensor/1", json!(1), &leader_client).unwrap();
+		follower.set("sensor/2", json!(2), &follower_client).unwrap();
+		follower.set_read_only(true);
+		
+		follower.query(&Pattern::compile("sensor/1").unwrap(), false, false, &follower_client).unwrap();
+		
+		let replication = Follower::new(ReplicationConfig {
+			leader: "127.0.0.1:3001".to_string(),
+			pattern: "*".to_string(),
+		}, follower.clone()).unwrap();
+		
+		let (stream, leader_stream) = duplex(4096);
+		let leader_connection = serve_connection(leader_stream, "127.0.0.1:1234".parse().unwrap(), leader.clone(), None, MessageFormat::Json, None);
+		
+		tokio::select! {
+			result = replication.follow(stream) => panic!("replication stopped: {:?}", result),
+			result = leader_connection => panic!("connection closed: {:?}", result.is_ok()),
+			_ = async {
+				eventually(|| value(&follower, "sensor/1") == Some(json!(1))).await;
+				assert_eq!(value(&follower, "sensor/2"), None);
+				
+				leader.set("sensor/1", json!(5), &leader_client).unwrap();
+				eventually(|| value(&follower, "sensor/1") == Some(json!(5))).await;
+				
+				leader.emit("sensor/1", "calibrated", json!(true), &leader_client).unwrap();
+				eventually(|| {
+					std::iter::from_fn(|| follower_client.inbox_try_next().ok().flatten())
+						.any(|msg| matches!(msg, Message::QueryEvent { event, .. } if event == "calibrated"))
+				}).await;
+				
+				leader.remove("sensor/1", &leader_client).unwrap();
+				eventually(|| value(&follower, "sensor/1").is_none()).await;
+				
+				assert_eq!(follower.set("sensor/1", json!(6), &follower_client), Err(Error::ReadOnly));
+			} => {},
+		}
+	}
+}
@@ -0,0 +1,338 @@
+use crate::{Object, Event, Revision, AuditEntry, AuditOperation, Backup};
+use crate::patterns::Pattern;
+use crate::server::config::EncryptionConfig;
+use crate::server::storage::{AuditFilter, Error, StorageFuture};
+use crate::server::Storage;
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+use std::env;
+
+const PREFIX: &str = "enc:v1:";
+const NONCE_LEN: usize = 12;
+
+fn parse_hex_key(string: &str) -> Result<[u8; 32], String> {
+	let string = string.trim();
+	
+	if string.len() != 64 || !string.is_ascii() {
+		return Err("encryption key must be 64 hex characters".to_string());
+	}
+	
+	let mut key = [0u8; 32];
+	for (i, byte) in key.iter_mut().enumerate() {
+		*byte = u8::from_str_radix(&string[i * 2..i * 2 + 2], 16)
+			.map_err(|_| "encryption key must be 64 hex characters".to_string())?;
+	}
+	
+	Ok(key)
+}
+
+/// Wraps another storage and encrypts object values with ChaCha20-Poly1305
+/// before they are persisted. Object names and timestamps stay readable, but
+/// are bound to the values as associated data, so a value moved to another
+/// object fails to decrypt.
+pub struct EncryptedStorage {
+	inner: Box<dyn Storage>,
+	cipher: ChaCha20Poly1305,
+	/// Passes values without the prefix through instead of rejecting them,
+	/// for databases written before encryption was enabled.
+	allow_plaintext: bool,
+}
+
+impl EncryptedStorage {
+	pub fn new(inner: Box<dyn Storage>, key: [u8; 32]) -> Self {
+		EncryptedStorage {
+			inner,
+			cipher: ChaCha20Poly1305::new(Key::from_slice(&key)),
+			allow_plaintext: false,
+		}
+	}
+	
+	pub fn allow_plaintext(mut self, allow: bool) -> Self {
+		self.allow_plaintext = allow;
+		self
+	}
+	
+	pub fn from_config(inner: Box<dyn Storage>, config: &EncryptionConfig) -> Result<Self, String> {
+		let key_string = match (&config.key, &config.key_env) {
+			(Some(key), None) => key.clone(),
+			(None, Some(var)) => env::var(var).map_err(|_| format!("environment variable {} not set", var))?,
+			_ => return Err("exactly one of key and key-env must be set".to_string()),
+		};
+		
+		Ok(EncryptedStorage::new(inner, parse_hex_key(&key_string)?).allow_plaintext(config.allow_plaintext))
+	}
+	
+	fn encrypt_value(&self, value: &Value, name: &str) -> Result<Value, Error> {
+		let plaintext = serde_json::to_vec(value)?;
+		
+		let mut nonce = [0u8; NONCE_LEN];
+		getrandom::getrandom(&mut nonce)
+			.map_err(|e| Error::Other(format!("can't generate nonce: {}", e)))?;
+		
+		let payload = Payload { msg: &plaintext, aad: name.as_bytes() };
+		let ciphertext = self.cipher.encrypt(Nonce::from_slice(&nonce), payload)
+			.map_err(|_| Error::Other("can't encrypt value".to_string()))?;
+		
+		let mut data = nonce.to_vec();
+		data.extend(ciphertext);
+		
+		Ok(Value::String(PREFIX.to_string() + &base64::encode(data)))
+	}
+	
+	fn decrypt_value(&self, value: Value, name: &str) -> Result<Value, Error> {
+		let encoded = match &value {
+			Value::String(string) if string.starts_with(PREFIX) => &string[PREFIX.len()..],
+			_ if self.allow_plaintext => return Ok(value),
+			_ => return Err(Error::Other(format!("value of {} isn't encrypted", name))),
+		};
+		
+		let data = base64::decode(encoded)
+			.map_err(|_| Error::Other(format!("can't decode encrypted value of {}", name)))?;
+		
+		if data.len() < NONCE_LEN {
+			return Err(Error::Other(format!("can't decode encrypted value of {}", name)));
+		}
+		
+		let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+		let payload = Payload { msg: ciphertext, aad: name.as_bytes() };
+		let plaintext = self.cipher.decrypt(Nonce::from_slice(nonce), payload)
+			.map_err(|_| Error::Other(format!("can't decrypt value of {}, wrong key?", name)))?;
+		
+		Ok(serde_json::from_slice(&plaintext)?)
+	}
+	
+	fn encrypt(&self, mut object: Object) -> Result<Object, Error> {
+		object.value = self.encrypt_value(&object.value, &object.name)?;
+		Ok(object)
+	}
+	
+	fn decrypt(&self, mut object: Object) -> Result<Object, Error> {
+		object.value = self.decrypt_value(object.value, &object.name)?;
+		Ok(object)
+	}
+}
+
+impl Storage for EncryptedStorage {
+	fn get_objects(&self) -> StorageFuture<'_, Vec<Object>> {
+		Box::pin(async move {
+			self.inner.get_objects().await?.into_iter().map(|object| self.decrypt(object)).collect()
+		})
+	}
+	
+	fn add_object(&self, object: Object) -> StorageFuture<'_, ()> {
+		Box::pin(async move {
+			self.inner.add_object(self.encrypt(object)?).await
+		})
+	}
+	
+	fn change_object(&self, object: Object) -> StorageFuture<'_, ()> {
+		Box::pin(async move {
+			self.inner.change_object(self.encrypt(object)?).await
+		})
+	}
+	
+	fn remove_object(&self, object: Object) -> StorageFuture<'_, ()> {
+		self.inner.remove_object(object)
+	}
+	
+	fn add_event(&self, mut event: Event) -> StorageFuture<'_, ()> {
+		Box::pin(async move {
+			event.data = self.encrypt_value(&event.data, &event.object)?;
+			self.inner.add_event(event).await
+		})
+	}
+	
+	fn get_events<'a>(&'a self, pattern: &'a Pattern, since: Option<DateTime<Utc>>, limit: usize) -> StorageFuture<'a, Vec<Event>> {
+		Box::pin(async move {
+			self.inner.get_events(pattern, since, limit).await?.into_iter().map(|mut event| {
+				event.data = self.decrypt_value(event.data, &event.object)?;
+				Ok(event)
+			}).collect()
+		})
+	}
+	
+	fn records_history(&self) -> bool {
+		self.inner.records_history()
+	}
+	
+	fn add_revision(&self, mut revision: Revision) -> StorageFuture<'_, ()> {
+		Box::pin(async move {
+			if !revision.removed {
+				revision.value = self.encrypt_value(&revision.value, &revision.name)?;
+			}
+			self.inner.add_revision(revision).await
+		})
+	}
+	
+	fn get_history<'a>(&'a self, name: &'a str, since: Option<DateTime<Utc>>, until: Option<DateTime<Utc>>, limit: usize) -> StorageFuture<'a, Vec<Revision>> {
+		Box::pin(async move {
+			self.inner.get_history(name, since, until, limit).await?.into_iter().map(|mut revision| {
+				if !revision.removed {
+					revision.value = self.decrypt_value(revision.value, name)?;
+				}
+				Ok(revision)
+			}).collect()
+		})
+	}
+	
+	fn restore(&self, mut backup: Backup) -> StorageFuture<'_, ()> {
+		Box::pin(async move {
+			for object in &mut backup.objects {
+				object.value = self.encrypt_value(&object.value, &object.name)?;
+			}
+			
+			for event in &mut backup.events {
+				event.data = self.encrypt_value(&event.data, &event.object)?;
+			}
+			
+			for revision in backup.revisions.iter_mut().filter(|revision| !revision.removed) {
+				revision.value = self.encrypt_value(&revision.value, &revision.name)?;
+			}
+			
+			self.inner.restore(backup).await
+		})
+	}
+	
+	fn size(&self) -> StorageFuture<'_, Option<u64>> {
+		self.inner.size()
+	}
+	
+	fn maintain(&self) -> StorageFuture<'_, Option<u64>> {
+		self.inner.maintain()
+	}
+	
+	fn flush_interval(&self) -> Option<std::time::Duration> {
+		self.inner.flush_interval()
+	}
+	
+	fn flush(&self) -> StorageFuture<'_, ()> {
+		self.inner.flush()
+	}
+	
+	fn close(&self) -> StorageFuture<'_, ()> {
+		self.inner.close()
+	}
+	
+	fn get_all_revisions(&self) -> StorageFuture<'_, Vec<Revision>> {
+		Box::pin(async move {
+			self.inner.get_all_revisions().await?.into_iter().map(|mut revision| {
+				if !revision.removed {
+					revision.value = self.decrypt_value(revision.value, &revision.name)?;
+				}
+				Ok(revision)
+			}).collect()
+		})
+	}
+	
+	fn records_audit(&self, operation: AuditOperation) -> bool {
+		self.inner.records_audit(operation)
+	}
+	
+	fn add_audit_entry(&self, mut entry: AuditEntry) -> StorageFuture<'_, ()> {
+		Box::pin(async move {
+			entry.value = self.encrypt_value(&entry.value, &entry.object)?;
+			self.inner.add_audit_entry(entry).await
+		})
+	}
+	
+	fn get_audit<'a>(&'a self, filter: &'a AuditFilter) -> StorageFuture<'a, Vec<AuditEntry>> {
+		Box::pin(async move {
+			self.inner.get_audit(filter).await?.into_iter().map(|mut entry| {
+				entry.value = self.decrypt_value(entry.value, &entry.object)?;
+				Ok(entry)
+			}).collect()
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use chrono::Utc;
+	use futures::executor::block_on;
+	use futures::future;
+	use serde_json::json;
+	use std::sync::Mutex;
+	
+	#[derive(Default)]
+	struct MemoryStorage {
+		objects: Mutex<Vec<Object>>,
+	}
+	
+	impl Storage for MemoryStorage {
+		fn get_objects(&self) -> StorageFuture<'_, Vec<Object>> {
+			Box::pin(future::ok(self.objects.lock().unwrap().clone()))
+		}
+		
+		fn add_object(&self, object: Object) -> StorageFuture<'_, ()> {
+			self.objects.lock().unwrap().push(object);
+			Box::pin(future::ok(()))
+		}
+		
+		fn change_object(&self, object: Object) -> StorageFuture<'_, ()> {
+			self.add_object(object)
+		}
+		
+		fn remove_object(&self, _object: Object) -> StorageFuture<'_, ()> {
+			Box::pin(future::ok(()))
+		}
+	}
+	
+	#[test]
+	fn test_parse_hex_key() {
+		assert_eq!(parse_hex_key(&"ab".repeat(32)), Ok([0xab; 32]));
+		assert!(parse_hex_key("abcd").is_err());
+		assert!(parse_hex_key(&"zz".repeat(32)).is_err());
+	}
+	
+	#[test]
+	fn test_roundtrip() {
+		let storage = EncryptedStorage::new(Box::new(MemoryStorage::default()), [7; 32]);
+		
+		let object = Object { name: "token".to_string(), value: json!({ "secret": "hunter2" }), last_modified: Utc::now() };
+		
+		let encrypted = storage.encrypt(object.clone()).unwrap();
+		assert!(encrypted.value.as_str().unwrap().starts_with(PREFIX));
+		assert!(!encrypted.value.as_str().unwrap().contains("hunter2"));
+		
+		block_on(storage.add_object(object)).unwrap();
+		let objects = block_on(storage.get_objects()).unwrap();
+		assert_eq!(objects[0].value, json!({ "secret": "hunter2" }));
+	}
+	
+	#[test]
+	fn test_plaintext() {
+		let storage = EncryptedStorage::new(Box::new(MemoryStorage::default()), [7; 32]);
+		
+		// planted into the database by someone without the key
+		let object = Object { name: "foo".to_string(), value: json!(42), last_modified: Utc::now() };
+		assert!(storage.decrypt(object.clone()).is_err());
+		
+		let storage = storage.allow_plaintext(true);
+		assert_eq!(storage.decrypt(object).unwrap().value, json!(42));
+	}
+	
+	#[test]
+	fn test_wrong_key() {
+		let storage = EncryptedStorage::new(Box::new(MemoryStorage::default()), [7; 32]);
+		let other = EncryptedStorage::new(Box::new(MemoryStorage::default()), [8; 32]);
+		
+		let object = Object { name: "foo".to_string(), value: json!(42), last_modified: Utc::now() };
+		let encrypted = storage.encrypt(object).unwrap();
+		assert!(other.decrypt(encrypted).is_err());
+	}
+	
+	#[test]
+	fn test_swapped_values() {
+		let storage = EncryptedStorage::new(Box::new(MemoryStorage::default()), [7; 32]);
+		
+		let token = Object { name: "token".to_string(), value: json!("hunter2"), last_modified: Utc::now() };
+		let public = Object { name: "public".to_string(), value: json!("hello"), last_modified: Utc::now() };
+		
+		let mut encrypted = storage.encrypt(public).unwrap();
+		encrypted.value = storage.encrypt(token).unwrap().value;
+		assert!(storage.decrypt(encrypted).is_err());
+	}
+}
@@ -1,11 +1,380 @@
-use crate::server::Object;
+use crate::{Object, Event, Revision, AuditEntry, AuditOperation, AuditQuery, Backup};
+use crate::patterns::Pattern;
+use crate::server::config::{StorageConfig, StorageBackendConfig, EventsConfig, AuditConfig};
+use chrono::{DateTime, Duration, Utc};
+use futures::future::{self, LocalBoxFuture};
+use serde::de::DeserializeOwned;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::io;
+use thiserror::Error;
 
 #[cfg(feature = "sqlite-backend")]
 pub mod sqlite;
+#[cfg(feature = "sled-backend")]
+pub mod sled;
+pub mod snapshot;
+#[cfg(feature = "s3")]
+pub mod s3;
+#[cfg(feature = "encryption")]
+pub mod encrypted;
+pub mod writer;
 
-pub trait Storage {
-	fn get_objects(&self) -> Vec<Object>;
-	fn add_object(&self, object: Object);
-	fn change_object(&self, object: Object);
-	fn remove_object(&self, object: Object);
+#[derive(Error, Debug)]
+pub enum Error {
+	#[cfg(feature = "sqlite-backend")]
+	#[error("sqlite error: {0}")]
+	Sqlite(#[from] rusqlite::Error),
+	#[cfg(feature = "sled-backend")]
+	#[error("sled error: {0}")]
+	Sled(#[from] ::sled::Error),
+	#[error("io error: {0}")]
+	Io(#[from] io::Error),
+	#[error("invalid json: {0}")]
+	Json(#[from] serde_json::Error),
+	#[error("{0}")]
+	Other(String),
+}
+
+/// An `AuditQuery` with its pattern compiled.
+#[derive(Debug, Clone)]
+pub struct AuditFilter {
+	pub pattern: Option<Pattern>,
+	pub operation: Option<AuditOperation>,
+	pub identity: Option<String>,
+	pub since: Option<DateTime<Utc>>,
+	pub until: Option<DateTime<Utc>>,
+	pub offset: usize,
+	pub limit: usize,
+}
+
+impl AuditFilter {
+	pub fn new(query: AuditQuery, default_limit: usize) -> Result<Self, String> {
+		let pattern = match &query.pattern {
+			Some(pattern) => Some(Pattern::compile(pattern).map_err(|_| "invalid pattern".to_string())?),
+			None => None,
+		};
+		
+		Ok(AuditFilter {
+			pattern,
+			operation: query.operation,
+			identity: query.identity,
+			since: query.since,
+			until: query.until,
+			offset: query.offset,
+			limit: query.limit.unwrap_or(default_limit),
+		})
+	}
+	
+	pub fn matches(&self, entry: &AuditEntry) -> bool {
+		self.pattern.as_ref().is_none_or(|pattern| pattern.matches(&entry.object))
+			&& self.operation.is_none_or(|operation| operation == entry.operation)
+			&& self.identity.as_ref().is_none_or(|identity| entry.identity.as_ref() == Some(identity))
+			&& self.since.is_none_or(|since| entry.timestamp >= since)
+			&& self.until.is_none_or(|until| entry.timestamp <= until)
+	}
+}
+
+/// Future returned by storage operations. Storages are only ever driven by
+/// the runtime of their `StorageWriter`'s thread, so it doesn't need to be `Send`.
+pub type StorageFuture<'a, T> = LocalBoxFuture<'a, Result<T, Error>>;
+
+/// A persistence backend. Backends are moved to the storage thread of a
+/// `StorageWriter`, which owns them (e.g. the sqlite connection) from then on
+/// and drives their futures on a tokio runtime, so network backends can use
+/// async io instead of blocking the thread.
+pub trait Storage: Send {
+	fn get_objects(&self) -> StorageFuture<'_, Vec<Object>>;
+	fn add_object(&self, object: Object) -> StorageFuture<'_, ()>;
+	fn change_object(&self, object: Object) -> StorageFuture<'_, ()>;
+	fn remove_object(&self, object: Object) -> StorageFuture<'_, ()>;
+	
+	/// Persists an emitted event. Backends without event support ignore it.
+	fn add_event(&self, _event: Event) -> StorageFuture<'_, ()> {
+		Box::pin(future::ok(()))
+	}
+	
+	/// Returns the latest `limit` persisted events on objects matching
+	/// `pattern`, oldest first.
+	fn get_events<'a>(&'a self, _pattern: &'a Pattern, _since: Option<DateTime<Utc>>, _limit: usize) -> StorageFuture<'a, Vec<Event>> {
+		Box::pin(future::ok(vec![]))
+	}
+	
+	/// Whether the backend records object revisions. Revisions are only
+	/// passed to `add_revision` if this returns true.
+	fn records_history(&self) -> bool {
+		false
+	}
+	
+	fn add_revision(&self, _revision: Revision) -> StorageFuture<'_, ()> {
+		Box::pin(future::ok(()))
+	}
+	
+	/// Returns the latest `limit` revisions of an object between `since` and
+	/// `until`, oldest first.
+	fn get_history<'a>(&'a self, _name: &'a str, _since: Option<DateTime<Utc>>, _until: Option<DateTime<Utc>>, _limit: usize) -> StorageFuture<'a, Vec<Revision>> {
+		Box::pin(future::ok(vec![]))
+	}
+	
+	/// Returns all recorded revisions of all objects, oldest first.
+	fn get_all_revisions(&self) -> StorageFuture<'_, Vec<Revision>> {
+		Box::pin(future::ok(vec![]))
+	}
+	
+	/// Whether the backend keeps an audit log of `operation`. Entries are
+	/// only passed to `add_audit_entry` if this returns true.
+	fn records_audit(&self, _operation: AuditOperation) -> bool {
+		false
+	}
+	
+	fn add_audit_entry(&self, _entry: AuditEntry) -> StorageFuture<'_, ()> {
+		Box::pin(future::ok(()))
+	}
+	
+	/// Returns the entries of the audit log selected by `filter`, newest first.
+	fn get_audit<'a>(&'a self, _filter: &'a AuditFilter) -> StorageFuture<'a, Vec<AuditEntry>> {
+		Box::pin(future::ok(vec![]))
+	}
+	
+	/// Replaces all objects with the contents of a backup and adds its events
+	/// and revisions, as the trait has no way to remove those. Backends that
+	/// keep events or revisions should override this to replace them, and to
+	/// do it atomically.
+	fn restore(&self, backup: Backup) -> StorageFuture<'_, ()> {
+		Box::pin(async move {
+			for object in self.get_objects().await? {
+				self.remove_object(object).await?;
+			}
+			
+			for object in backup.objects {
+				self.add_object(object).await?;
+			}
+			
+			for event in backup.events {
+				self.add_event(event).await?;
+			}
+			
+			if self.records_history() {
+				for revision in backup.revisions {
+					self.add_revision(revision).await?;
+				}
+			}
+			
+			Ok(())
+		})
+	}
+	
+	/// Returns the size of the underlying store in bytes if the backend can tell.
+	fn size(&self) -> StorageFuture<'_, Option<u64>> {
+		Box::pin(future::ok(None))
+	}
+	
+	/// Compacts and optimizes the underlying store. Returns the number of
+	/// reclaimed bytes if the backend can tell.
+	fn maintain(&self) -> StorageFuture<'_, Option<u64>> {
+		Box::pin(future::ok(None))
+	}
+	
+	/// How often `flush` is called, for backends that buffer changes.
+	fn flush_interval(&self) -> Option<std::time::Duration> {
+		None
+	}
+	
+	/// Persists the changes the backend buffers. Failures are retried and
+	/// reported like those of other writes.
+	fn flush(&self) -> StorageFuture<'_, ()> {
+		Box::pin(future::ok(()))
+	}
+	
+	/// Persists whatever the backend still buffers. Called once before the
+	/// server exits, no other calls follow.
+	fn close(&self) -> StorageFuture<'_, ()> {
+		Box::pin(future::ok(()))
+	}
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct MigrationStats {
+	pub objects: usize,
+	pub events: usize,
+	pub revisions: usize,
+}
+
+/// Copies all objects, persisted events and revisions from one storage to
+/// another.
+pub async fn migrate(from: &dyn Storage, to: &dyn Storage) -> Result<MigrationStats, Error> {
+	let mut stats = MigrationStats::default();
+	let all = Pattern::compile("*").unwrap();
+	
+	for object in from.get_objects().await? {
+		to.add_object(object).await?;
+		stats.objects += 1;
+	}
+	
+	for event in from.get_events(&all, None, usize::MAX).await? {
+		to.add_event(event).await?;
+		stats.events += 1;
+	}
+	
+	if to.records_history() {
+		for revision in from.get_all_revisions().await? {
+			to.add_revision(revision).await?;
+			stats.revisions += 1;
+		}
+	}
+	
+	Ok(stats)
+}
+
+/// Exports all objects, persisted events and revisions of a storage.
+pub async fn export(storage: &dyn Storage) -> Result<Backup, Error> {
+	let all = Pattern::compile("*").unwrap();
+	
+	Ok(Backup {
+		created: Utc::now(),
+		objects: storage.get_objects().await?,
+		events: storage.get_events(&all, None, usize::MAX).await?,
+		revisions: storage.get_all_revisions().await?,
+	})
+}
+
+/// Decides when persisted events or revisions have to be pruned. Pruning
+/// happens at most once per minute.
+pub struct Retention {
+	retention: Duration,
+	next_prune: Cell<DateTime<Utc>>,
+}
+
+impl Retention {
+	pub fn new(retention: Duration) -> Self {
+		Retention {
+			retention,
+			next_prune: Cell::new(Utc::now()),
+		}
+	}
+	
+	/// Returns the cutoff timestamp if entries should be pruned now.
+	pub fn prune_before(&self) -> Option<DateTime<Utc>> {
+		let now = Utc::now();
+		
+		if now >= self.next_prune.get() {
+			self.next_prune.set(now + Duration::minutes(1));
+			Some(now - self.retention)
+		} else {
+			None
+		}
+	}
+}
+
+#[cfg(any(feature = "sqlite-backend", feature = "sled-backend"))]
+fn event_retention(config: &EventsConfig) -> Option<Retention> {
+	if config.enabled {
+		Some(Retention::new(Duration::seconds(config.retention as i64)))
+	} else {
+		None
+	}
+}
+
+type BackendFactory = Box<dyn Fn(toml::Value) -> Result<Box<dyn Storage>, String> + Send + Sync>;
+
+/// Storage backends provided by the embedder in addition to the built-in ones.
+/// A backend registered as `name` is selected with `backend = "name"` and
+/// created from the options in the `[storage.name]` table.
+#[derive(Default)]
+pub struct Backends {
+	factories: HashMap<String, BackendFactory>,
+}
+
+impl Backends {
+	/// Registers a backend. `factory` gets the backend's options deserialized
+	/// into `C`. The built-in backend names can't be overridden.
+	pub fn register<C, S, F>(&mut self, name: &str, factory: F)
+	where
+		C: DeserializeOwned,
+		S: Storage + 'static,
+		F: Fn(C) -> Result<S, String> + Send + Sync + 'static,
+	{
+		let backend = name.to_string();
+		
+		self.factories.insert(name.to_string(), Box::new(move |options| {
+			let config = options.try_into()
+				.map_err(|e| format!("invalid {} config: {}", backend, e))?;
+			
+			Ok(Box::new(factory(config)?))
+		}));
+	}
+}
+
+fn backend_from_config(config: &StorageBackendConfig, events: &EventsConfig, audit: &AuditConfig, backends: &Backends) -> Result<Box<dyn Storage>, String> {
+	match config {
+		StorageBackendConfig::Sled { .. } | StorageBackendConfig::Snapshot { .. } | StorageBackendConfig::Custom { .. } if audit.enabled => {
+			Err("only the sqlite backend supports the audit log".to_string())
+		},
+		#[cfg(feature = "sqlite-backend")]
+		StorageBackendConfig::Sqlite { sqlite: config } => {
+			let mut storage = sqlite::SqliteStorage::from_config(config)
+				.map_err(|e| format!("can't open sqlite database: {}", e))?;
+			
+			if let Some(retention) = event_retention(events) {
+				storage.enable_events(retention)
+					.map_err(|e| format!("can't open sqlite database: {}", e))?;
+			}
+			
+			if audit.enabled {
+				storage.enable_audit(audit)
+					.map_err(|e| format!("can't open sqlite database: {}", e))?;
+			}
+			
+			Ok(Box::new(storage))
+		},
+		#[cfg(not(feature = "sqlite-backend"))]
+		StorageBackendConfig::Sqlite { .. } => {
+			Err("build without sqlite backend support".to_string())
+		},
+		#[cfg(feature = "sled-backend")]
+		StorageBackendConfig::Sled { sled: config } => {
+			let mut storage = sled::SledStorage::from_config(config)
+				.map_err(|e| format!("can't open sled database: {}", e))?;
+			
+			if let Some(retention) = event_retention(events) {
+				storage.enable_events(retention)
+					.map_err(|e| format!("can't open sled database: {}", e))?;
+			}
+			
+			Ok(Box::new(storage))
+		},
+		#[cfg(not(feature = "sled-backend"))]
+		StorageBackendConfig::Sled { .. } => {
+			Err("build without sled backend support".to_string())
+		},
+		StorageBackendConfig::Snapshot { .. } if events.enabled => {
+			Err("snapshot backend doesn't support event persistence".to_string())
+		},
+		StorageBackendConfig::Snapshot { snapshot: config } => {
+			Ok(Box::new(snapshot::SnapshotStorage::from_config(config)
+				.map_err(|e| format!("can't open snapshot: {}", e))?))
+		},
+		StorageBackendConfig::Custom { backend, .. } if events.enabled => {
+			Err(format!("{} backend doesn't support event persistence", backend))
+		},
+		StorageBackendConfig::Custom { backend, options } => {
+			match backends.factories.get(backend) {
+				Some(factory) => factory(options.clone()),
+				None => Err(format!("unknown storage backend {}", backend)),
+			}
+		},
+	}
+}
+
+/// Creates the storage described by the `[storage]` config section.
+pub fn from_config(config: &StorageConfig, backends: &Backends) -> Result<Box<dyn Storage>, String> {
+	let storage = backend_from_config(&config.backend, &config.events, &config.audit, backends)?;
+	
+	match &config.encryption {
+		#[cfg(feature = "encryption")]
+		Some(encryption) => Ok(Box::new(encrypted::EncryptedStorage::from_config(storage, encryption)?)),
+		#[cfg(not(feature = "encryption"))]
+		Some(_) => Err("build without encryption support".to_string()),
+		None => Ok(storage),
+	}
 }
@@ -0,0 +1,187 @@
+use crate::{Object, Event, Backup};
+use crate::patterns::Pattern;
+use crate::server::config::SledConfig;
+use crate::server::storage::{Retention, Error, StorageFuture};
+use crate::server::Storage;
+use chrono::{DateTime, Utc};
+use sled::{Batch, Db, Tree};
+
+fn event_key(timestamp: DateTime<Utc>, id: u64) -> [u8; 16] {
+	let mut key = [0u8; 16];
+	key[..8].copy_from_slice(&(timestamp.timestamp_millis() as u64).to_be_bytes());
+	key[8..].copy_from_slice(&id.to_be_bytes());
+	key
+}
+
+pub struct SledStorage {
+	db: Db,
+	objects: Tree,
+	events: Option<(Tree, Retention)>,
+}
+
+impl SledStorage {
+	pub fn new(db: Db) -> Result<Self, Error> {
+		let objects = db.open_tree("objects")?;
+		
+		Ok(Self {
+			db,
+			objects,
+			events: None,
+		})
+	}
+	
+	pub fn from_config(config: &SledConfig) -> Result<Self, Error> {
+		let db = sled::open(&config.path)?;
+		
+		SledStorage::new(db)
+	}
+	
+	pub fn enable_events(&mut self, retention: Retention) -> Result<(), Error> {
+		let tree = self.db.open_tree("events")?;
+		self.events = Some((tree, retention));
+		Ok(())
+	}
+}
+
+impl Storage for SledStorage {
+	fn get_objects(&self) -> StorageFuture<'_, Vec<Object>> {
+		Box::pin(async move {
+			let mut objects = vec![];
+			
+			for value in self.objects.iter().values() {
+				objects.push(serde_json::from_slice(&value?)?);
+			}
+			
+			Ok(objects)
+		})
+	}
+	
+	fn add_object(&self, object: Object) -> StorageFuture<'_, ()> {
+		Box::pin(async move {
+			let value = serde_json::to_vec(&object)?;
+			
+			self.objects.insert(object.name.as_bytes(), value)?;
+			Ok(())
+		})
+	}
+	
+	fn change_object(&self, object: Object) -> StorageFuture<'_, ()> {
+		self.add_object(object)
+	}
+	
+	fn remove_object(&self, object: Object) -> StorageFuture<'_, ()> {
+		Box::pin(async move {
+			self.objects.remove(object.name.as_bytes())?;
+			Ok(())
+		})
+	}
+	
+	fn add_event(&self, event: Event) -> StorageFuture<'_, ()> {
+		Box::pin(async move {
+			let (tree, retention) = match &self.events {
+				Some(events) => events,
+				None => return Ok(()),
+			};
+			
+			let key = event_key(event.timestamp, self.db.generate_id()?);
+			let value = serde_json::to_vec(&event)?;
+			tree.insert(key, value)?;
+			
+			if let Some(before) = retention.prune_before() {
+				for entry in tree.range(..event_key(before, 0)) {
+					let (key, _) = entry?;
+					tree.remove(key)?;
+				}
+			}
+			
+			Ok(())
+		})
+	}
+	
+	fn get_events<'a>(&'a self, pattern: &'a Pattern, since: Option<DateTime<Utc>>, limit: usize) -> StorageFuture<'a, Vec<Event>> {
+		Box::pin(async move {
+			let tree = match &self.events {
+				Some((tree, _)) => tree,
+				None => return Ok(vec![]),
+			};
+			
+			let start = since.map(|since| event_key(since, 0)).unwrap_or([0; 16]);
+			
+			let mut events = vec![];
+			
+			for entry in tree.range(start..).rev() {
+				if events.len() >= limit {
+					break;
+				}
+				
+				let event: Event = serde_json::from_slice(&entry?.1)?;
+				
+				if pattern.matches(&event.object) {
+					events.push(event);
+				}
+			}
+			
+			events.reverse();
+			Ok(events)
+		})
+	}
+	
+	/// Replaces the objects and the persisted events, each tree in a single
+	/// batch.
+	fn restore(&self, backup: Backup) -> StorageFuture<'_, ()> {
+		Box::pin(async move {
+			let mut objects = Batch::default();
+			
+			for key in self.objects.iter().keys() {
+				objects.remove(key?);
+			}
+			
+			for object in &backup.objects {
+				objects.insert(object.name.as_bytes(), serde_json::to_vec(object)?);
+			}
+			
+			self.objects.apply_batch(objects)?;
+			
+			if let Some((tree, _)) = &self.events {
+				let mut events = Batch::default();
+				
+				for key in tree.iter().keys() {
+					events.remove(key?);
+				}
+				
+				for event in &backup.events {
+					events.insert(&event_key(event.timestamp, self.db.generate_id()?), serde_json::to_vec(event)?);
+				}
+				
+				tree.apply_batch(events)?;
+			}
+			
+			Ok(())
+		})
+	}
+	
+	fn size(&self) -> StorageFuture<'_, Option<u64>> {
+		Box::pin(async move {
+			Ok(Some(self.db.size_on_disk()?))
+		})
+	}
+	
+	/// sled compacts its segments continuously, flushing lets it reclaim the
+	/// space of rewritten segments.
+	fn maintain(&self) -> StorageFuture<'_, Option<u64>> {
+		Box::pin(async move {
+			let before = self.db.size_on_disk()?;
+			self.db.flush()?;
+			let after = self.db.size_on_disk()?;
+			
+			Ok(Some(before.saturating_sub(after)))
+		})
+	}
+	
+	fn close(&self) -> StorageFuture<'_, ()> {
+		Box::pin(async move {
+			self.db.flush_async().await?;
+			Ok(())
+		})
+	}
+}
@@ -0,0 +1,330 @@
+use crate::Object;
+use crate::server::config::SnapshotConfig;
+use crate::server::storage::{Error, StorageFuture};
+use crate::server::Storage;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Where snapshots are written to and loaded from. The methods may block,
+/// `SnapshotStorage` calls `store` on tokio's blocking threads.
+pub trait SnapshotTarget: Send + Sync {
+	/// Returns the latest snapshot, or `None` if there is none yet.
+	fn load(&self) -> Result<Option<Vec<u8>>, Error>;
+	fn store(&self, data: Vec<u8>) -> Result<(), Error>;
+	
+	fn size(&self) -> Result<Option<u64>, Error> {
+		Ok(None)
+	}
+	
+	/// Human readable location used in error messages.
+	fn describe(&self) -> String;
+}
+
+/// Writes snapshots to a local file, atomically via a temporary file.
+pub struct FileTarget {
+	filename: PathBuf,
+}
+
+impl FileTarget {
+	pub fn new(filename: PathBuf) -> Self {
+		FileTarget { filename }
+	}
+}
+
+impl SnapshotTarget for FileTarget {
+	fn load(&self) -> Result<Option<Vec<u8>>, Error> {
+		match fs::read(&self.filename) {
+			Ok(contents) => Ok(Some(contents)),
+			Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+			Err(e) => Err(e.into()),
+		}
+	}
+	
+	fn store(&self, data: Vec<u8>) -> Result<(), Error> {
+		let mut tmp_filename = self.filename.clone().into_os_string();
+		tmp_filename.push(".tmp");
+		
+		fs::write(&tmp_filename, data)?;
+		fs::rename(&tmp_filename, &self.filename)?;
+		Ok(())
+	}
+	
+	fn size(&self) -> Result<Option<u64>, Error> {
+		match fs::metadata(&self.filename) {
+			Ok(metadata) => Ok(Some(metadata.len())),
+			Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Some(0)),
+			Err(e) => Err(e.into()),
+		}
+	}
+	
+	fn describe(&self) -> String {
+		format!("{:?}", self.filename)
+	}
+}
+
+struct State {
+	objects: BTreeMap<String, Object>,
+	changes: u64,
+}
+
+struct Shared {
+	target: Box<dyn SnapshotTarget>,
+	max_changes: Option<u64>,
+	state: Mutex<State>,
+	/// Held while a snapshot is taken and stored, so they are stored in order.
+	writing: Mutex<()>,
+}
+
+impl Shared {
+	/// The state is only locked while the snapshot is serialized, not while
+	/// the target stores it, e.g. uploads it to S3.
+	fn write_if_changed(&self) -> Result<(), Error> {
+		let _writing = self.writing.lock().unwrap();
+		
+		let (json, changes) = {
+			let state = self.state.lock().unwrap();
+			
+			if state.changes == 0 {
+				return Ok(());
+			}
+			
+			let objects: Vec<&Object> = state.objects.values().collect();
+			(serde_json::to_vec(&objects)?, state.changes)
+		};
+		
+		self.target.store(json)?;
+		
+		// changes made while storing go into the next snapshot
+		self.state.lock().unwrap().changes -= changes;
+		Ok(())
+	}
+	
+	/// Returns whether `max_changes` changes haven't been written yet.
+	fn record_change(&self, f: impl FnOnce(&mut BTreeMap<String, Object>)) -> bool {
+		let mut state = self.state.lock().unwrap();
+		
+		f(&mut state.objects);
+		state.changes += 1;
+		
+		self.max_changes.is_some_and(|max_changes| state.changes >= max_changes)
+	}
+}
+
+/// Keeps all objects in memory and periodically writes them as a single json
+/// snapshot, trading durability of the latest changes for far fewer writes.
+/// The periodic writes are made through `Storage::flush`.
+pub struct SnapshotStorage {
+	shared: Arc<Shared>,
+	interval: Duration,
+}
+
+impl SnapshotStorage {
+	pub fn new(target: Box<dyn SnapshotTarget>, interval: Duration, max_changes: Option<u64>) -> Result<Self, Error> {
+		let objects = match target.load()? {
+			Some(contents) => {
+				let objects: Vec<Object> = serde_json::from_slice(&contents)?;
+				objects.into_iter().map(|object| (object.name.clone(), object)).collect()
+			},
+			None => BTreeMap::new(),
+		};
+		
+		let shared = Arc::new(Shared {
+			target,
+			max_changes,
+			state: Mutex::new(State {
+				objects,
+				changes: 0,
+			}),
+			writing: Mutex::new(()),
+		});
+		
+		Ok(SnapshotStorage { shared, interval })
+	}
+	
+	pub fn from_config(config: &SnapshotConfig) -> Result<Self, Error> {
+		let target: Box<dyn SnapshotTarget> = match (&config.filename, &config.s3) {
+			(Some(filename), None) => Box::new(FileTarget::new(filename.into())),
+			#[cfg(feature = "s3")]
+			(None, Some(s3)) => Box::new(super::s3::S3Target::from_config(s3)?),
+			#[cfg(not(feature = "s3"))]
+			(None, Some(_)) => return Err(Error::Other("build without s3 support".to_string())),
+			_ => return Err(Error::Other("exactly one of filename and s3 must be set".to_string())),
+		};
+		
+		SnapshotStorage::new(target, Duration::from_secs(config.interval), config.max_changes)
+	}
+	
+	/// Stores the snapshot on tokio's blocking threads, as targets block
+	/// while storing it.
+	async fn write_if_changed(&self) -> Result<(), Error> {
+		let shared = self.shared.clone();
+		
+		tokio::task::spawn_blocking(move || shared.write_if_changed()).await
+			.map_err(|e| Error::Other(format!("can't write snapshot: {}", e)))?
+	}
+}
+
+impl Drop for SnapshotStorage {
+	/// Only has changes to write when the storage wasn't closed.
+	fn drop(&mut self) {
+		if let Err(e) = self.shared.write_if_changed() {
+			eprintln!("can't write snapshot {}: {}", self.shared.target.describe(), e);
+		}
+	}
+}
+
+impl Storage for SnapshotStorage {
+	fn get_objects(&self) -> StorageFuture<'_, Vec<Object>> {
+		Box::pin(async move {
+			Ok(self.shared.state.lock().unwrap().objects.values().cloned().collect())
+		})
+	}
+	
+	fn add_object(&self, object: Object) -> StorageFuture<'_, ()> {
+		Box::pin(async move {
+			let due = self.shared.record_change(|objects| {
+				objects.insert(object.name.clone(), object);
+			});
+			
+			if due {
+				self.write_if_changed().await?;
+			}
+			
+			Ok(())
+		})
+	}
+	
+	fn change_object(&self, object: Object) -> StorageFuture<'_, ()> {
+		self.add_object(object)
+	}
+	
+	fn remove_object(&self, object: Object) -> StorageFuture<'_, ()> {
+		Box::pin(async move {
+			let due = self.shared.record_change(|objects| {
+				objects.remove(&object.name);
+			});
+			
+			if due {
+				self.write_if_changed().await?;
+			}
+			
+			Ok(())
+		})
+	}
+	
+	fn size(&self) -> StorageFuture<'_, Option<u64>> {
+		Box::pin(async move {
+			self.shared.target.size()
+		})
+	}
+	
+	fn maintain(&self) -> StorageFuture<'_, Option<u64>> {
+		Box::pin(async move {
+			self.write_if_changed().await?;
+			Ok(None)
+		})
+	}
+	
+	fn flush_interval(&self) -> Option<Duration> {
+		Some(self.interval)
+	}
+	
+	fn flush(&self) -> StorageFuture<'_, ()> {
+		Box::pin(async move {
+			self.write_if_changed().await
+		})
+	}
+	
+	fn close(&self) -> StorageFuture<'_, ()> {
+		Box::pin(async move {
+			self.write_if_changed().await
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::server::storage::writer::StorageWriter;
+	use chrono::Utc;
+	use serde_json::json;
+	use futures::executor::block_on;
+	use std::sync::mpsc;
+	use std::thread;
+	use std::time::Instant;
+	
+	fn object(name: &str) -> Object {
+		Object { name: name.to_string(), value: json!(1), last_modified: Utc::now() }
+	}
+	
+	struct FailingTarget;
+	
+	impl SnapshotTarget for FailingTarget {
+		fn load(&self) -> Result<Option<Vec<u8>>, Error> {
+			Ok(None)
+		}
+		
+		fn store(&self, _data: Vec<u8>) -> Result<(), Error> {
+			Err(Error::Other("disk full".to_string()))
+		}
+		
+		fn describe(&self) -> String {
+			"failing".to_string()
+		}
+	}
+	
+	struct SlowTarget;
+	
+	impl SnapshotTarget for SlowTarget {
+		fn load(&self) -> Result<Option<Vec<u8>>, Error> {
+			Ok(None)
+		}
+		
+		fn store(&self, _data: Vec<u8>) -> Result<(), Error> {
+			thread::sleep(Duration::from_millis(300));
+			Ok(())
+		}
+		
+		fn describe(&self) -> String {
+			"slow".to_string()
+		}
+	}
+	
+	#[test]
+	fn test_changes_while_storing() {
+		let storage = SnapshotStorage::new(Box::new(SlowTarget), Duration::from_secs(3600), None).unwrap();
+		block_on(storage.add_object(object("a"))).unwrap();
+		
+		let shared = storage.shared.clone();
+		let writing = thread::spawn(move || shared.write_if_changed());
+		thread::sleep(Duration::from_millis(50));
+		
+		let start = Instant::now();
+		block_on(storage.add_object(object("b"))).unwrap();
+		assert!(start.elapsed() < Duration::from_millis(200));
+		
+		writing.join().unwrap().unwrap();
+		assert_eq!(storage.shared.state.lock().unwrap().changes, 1);
+	}
+	
+	#[test]
+	fn test_failed_snapshot_is_reported() {
+		let storage = SnapshotStorage::new(Box::new(FailingTarget), Duration::from_millis(50), None).unwrap();
+		
+		let (tx, rx) = mpsc::channel();
+		let writer = StorageWriter::new(Box::new(storage), Box::new(move |failure| {
+			let _ = tx.send(failure);
+		}));
+		
+		writer.add_object(object("sensor"));
+		
+		let failure = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+		assert_eq!(failure.operation, "flush");
+		assert_eq!(failure.error, "disk full");
+		assert_eq!(writer.metrics().stats().errors, 3);
+	}
+}
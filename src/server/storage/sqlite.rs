@@ -1,66 +1,484 @@
-use crate::Object;
-use crate::server::config::SqliteConfig;
+use crate::{Object, Event, Revision, AuditEntry, AuditOperation, Backup};
+use crate::patterns::Pattern;
+use crate::server::config::{SqliteConfig, SqliteSynchronous, HistoryConfig, AuditConfig};
+use crate::server::storage::{AuditFilter, Retention, Error, StorageFuture};
 use crate::server::Storage;
-use rusqlite::{params, Connection, Result, Error};
+use chrono::{DateTime, Duration, Utc, TimeZone};
+use rusqlite::{params, Connection, Row, types::Type};
+use serde_json::Value;
+use std::time;
+
+fn json_column(row: &Row, index: usize) -> rusqlite::Result<Value> {
+	let value_str: String = row.get(index)?;
+	serde_json::from_str(&value_str)
+		.map_err(|e| rusqlite::Error::FromSqlConversionFailure(index, Type::Text, Box::new(e)))
+}
+
+fn row_to_revision(row: &Row) -> rusqlite::Result<Revision> {
+	let removed = row.get::<_, Option<String>>(1)?.is_none();
+	let timestamp: i64 = row.get(2)?;
+	let client: String = row.get(3)?;
+	
+	Ok(Revision {
+		name: row.get(0)?,
+		removed,
+		value: if removed { Value::Null } else { json_column(row, 1)? },
+		timestamp: Utc.timestamp_millis(timestamp),
+		client: client.parse()
+			.map_err(|e: uuid::Error| rusqlite::Error::FromSqlConversionFailure(3, Type::Text, e.to_string().into()))?,
+	})
+}
+
+fn conversion_error<E: ToString>(index: usize, e: E) -> rusqlite::Error {
+	rusqlite::Error::FromSqlConversionFailure(index, Type::Text, e.to_string().into())
+}
+
+fn row_to_audit_entry(row: &Row) -> rusqlite::Result<AuditEntry> {
+	let timestamp: i64 = row.get(0)?;
+	let operation: String = row.get(1)?;
+	let client: String = row.get(6)?;
+	let addr: Option<String> = row.get(7)?;
+	
+	Ok(AuditEntry {
+		timestamp: Utc.timestamp_millis(timestamp),
+		operation: operation.parse().map_err(|e| conversion_error(1, e))?,
+		object: row.get(2)?,
+		event: row.get(3)?,
+		method: row.get(4)?,
+		value: json_column(row, 5)?,
+		client: client.parse().map_err(|e: uuid::Error| conversion_error(6, e))?,
+		addr: addr.map(|addr| addr.parse()).transpose().map_err(|e: std::net::AddrParseError| conversion_error(7, e))?,
+		identity: row.get(8)?,
+	})
+}
+
+fn apply_pragmas(conn: &Connection, config: &SqliteConfig) -> rusqlite::Result<()> {
+	if let Some(busy_timeout) = config.busy_timeout {
+		conn.busy_timeout(time::Duration::from_millis(busy_timeout))?;
+	}
+	
+	if config.wal {
+		// journal_mode returns the new mode as a row
+		conn.query_row("PRAGMA journal_mode = WAL", [], |_| Ok(()))?;
+	}
+	
+	if let Some(synchronous) = config.synchronous {
+		let level = match synchronous {
+			SqliteSynchronous::Off => "OFF",
+			SqliteSynchronous::Normal => "NORMAL",
+			SqliteSynchronous::Full => "FULL",
+			SqliteSynchronous::Extra => "EXTRA",
+		};
+		conn.pragma_update(None, "synchronous", &level)?;
+	}
+	
+	if let Some(cache_size) = config.cache_size {
+		conn.pragma_update(None, "cache_size", &cache_size)?;
+	}
+	
+	Ok(())
+}
+
+struct History {
+	retention: Option<Retention>,
+	max_revisions: Option<u32>,
+}
+
+struct Audit {
+	retention: Option<Retention>,
+	operations: Vec<AuditOperation>,
+}
 
 pub struct SqliteStorage {
 	conn: Connection,
+	event_retention: Option<Retention>,
+	history: Option<History>,
+	audit: Option<Audit>,
 }
 
 impl SqliteStorage {
-	pub fn new(conn: Connection) -> Self {
+	pub fn new(conn: Connection) -> Result<Self, Error> {
 		conn.execute("create table if not exists objects (
 			name text primary key,
 			value text not null,
 			last_modified text not null
-		)", []).unwrap();
+		)", [])?;
 		
-		Self {
-			conn
-		}
+		Ok(Self {
+			conn,
+			event_retention: None,
+			history: None,
+			audit: None,
+		})
 	}
 	
 	pub fn from_config(config: &SqliteConfig) -> Result<Self, Error> {
-		let conn = Connection::open(config.filename.clone()).unwrap();
+		let conn = Connection::open(config.filename.clone())?;
+		apply_pragmas(&conn, config)?;
+		
+		let mut storage = SqliteStorage::new(conn)?;
 		
-		Ok(SqliteStorage::new(conn))
+		if config.history.enabled {
+			storage.enable_history(&config.history)?;
+		}
+		
+		Ok(storage)
+	}
+	
+	pub fn enable_history(&mut self, config: &HistoryConfig) -> Result<(), Error> {
+		self.conn.execute_batch("create table if not exists object_history (
+			id integer primary key autoincrement,
+			name text not null,
+			value text,
+			timestamp integer not null,
+			client text not null
+		);
+		create index if not exists object_history_name on object_history (name, id);")?;
+		
+		self.history = Some(History {
+			retention: config.retention.map(|seconds| Retention::new(Duration::seconds(seconds as i64))),
+			max_revisions: config.max_revisions,
+		});
+		
+		Ok(())
+	}
+	
+	pub fn enable_audit(&mut self, config: &AuditConfig) -> Result<(), Error> {
+		self.conn.execute_batch("create table if not exists audit_log (
+			id integer primary key autoincrement,
+			timestamp integer not null,
+			operation text not null,
+			object text not null,
+			event text,
+			method text,
+			value text not null,
+			client text not null,
+			addr text,
+			identity text
+		);
+		create index if not exists audit_log_timestamp on audit_log (timestamp);")?;
+		
+		self.audit = Some(Audit {
+			retention: config.retention.map(|seconds| Retention::new(Duration::seconds(seconds as i64))),
+			operations: config.operations.clone(),
+		});
+		
+		Ok(())
+	}
+	
+	pub fn enable_events(&mut self, retention: Retention) -> Result<(), Error> {
+		self.conn.execute("create table if not exists events (
+			id integer primary key autoincrement,
+			object text not null,
+			event text not null,
+			data text not null,
+			timestamp integer not null
+		)", [])?;
+		
+		self.event_retention = Some(retention);
+		
+		Ok(())
 	}
 }
 
 impl Storage for SqliteStorage {
-	fn get_objects(&self) -> Vec<Object> {
-		let mut stmt = self.conn.prepare("SELECT name, value, last_modified FROM objects").unwrap();
-		let iter = stmt.query_map([], |row| {
-			let value_str: String = row.get(1).unwrap();
-			let value = serde_json::from_str(&value_str).unwrap();
-			
-			Ok(Object {
-				name: row.get(0).unwrap(),
-				value,
-				last_modified: row.get(2).unwrap(),
-			})
-		}).unwrap();
-		
-		iter.collect::<Result<Vec<Object>,rusqlite::Error>>().unwrap()
+	fn get_objects(&self) -> StorageFuture<'_, Vec<Object>> {
+		Box::pin(async move {
+			let mut stmt = self.conn.prepare("SELECT name, value, last_modified FROM objects")?;
+			let iter = stmt.query_map([], |row| {
+				Ok(Object {
+					name: row.get(0)?,
+					value: json_column(row, 1)?,
+					last_modified: row.get(2)?,
+				})
+			})?;
+			
+			Ok(iter.collect::<rusqlite::Result<Vec<Object>>>()?)
+		})
 	}
 	
-	fn add_object(&self, object: Object) {
-		let value = serde_json::to_string(&object.value).unwrap();
-		
-		self.conn.execute(
-			"REPLACE INTO objects (name, value, last_modified) VALUES (?1, ?2, ?3)",
-			params![object.name, value, object.last_modified]
-		).unwrap();
+	fn add_object(&self, object: Object) -> StorageFuture<'_, ()> {
+		Box::pin(async move {
+			let value = serde_json::to_string(&object.value)?;
+			
+			self.conn.execute(
+				"REPLACE INTO objects (name, value, last_modified) VALUES (?1, ?2, ?3)",
+				params![object.name, value, object.last_modified]
+			)?;
+			
+			Ok(())
+		})
 	}
 	
-	fn change_object(&self, object: Object) {
-		self.add_object(object);
+	fn change_object(&self, object: Object) -> StorageFuture<'_, ()> {
+		self.add_object(object)
 	}
 	
-	fn remove_object(&self, object: Object) {
-		self.conn.execute(
-			"DELETE FROM objects WHERE name = ?1",
-			params![object.name]
-		).unwrap();
+	fn remove_object(&self, object: Object) -> StorageFuture<'_, ()> {
+		Box::pin(async move {
+			self.conn.execute(
+				"DELETE FROM objects WHERE name = ?1",
+				params![object.name]
+			)?;
+			
+			Ok(())
+		})
+	}
+	
+	fn add_event(&self, event: Event) -> StorageFuture<'_, ()> {
+		Box::pin(async move {
+			let retention = match &self.event_retention {
+				Some(retention) => retention,
+				None => return Ok(()),
+			};
+			
+			let data = serde_json::to_string(&event.data)?;
+			
+			self.conn.execute(
+				"INSERT INTO events (object, event, data, timestamp) VALUES (?1, ?2, ?3, ?4)",
+				params![event.object, event.event, data, event.timestamp.timestamp_millis()]
+			)?;
+			
+			if let Some(before) = retention.prune_before() {
+				self.conn.execute(
+					"DELETE FROM events WHERE timestamp < ?1",
+					params![before.timestamp_millis()]
+				)?;
+			}
+			
+			Ok(())
+		})
+	}
+	
+	fn get_events<'a>(&'a self, pattern: &'a Pattern, since: Option<DateTime<Utc>>, limit: usize) -> StorageFuture<'a, Vec<Event>> {
+		Box::pin(async move {
+			if self.event_retention.is_none() {
+				return Ok(vec![]);
+			}
+			
+			let since = since.map(|since| since.timestamp_millis()).unwrap_or(i64::MIN);
+			
+			let mut stmt = self.conn.prepare("SELECT object, event, data, timestamp FROM events WHERE timestamp >= ?1 ORDER BY id DESC")?;
+			let iter = stmt.query_map(params![since], |row| {
+				let timestamp: i64 = row.get(3)?;
+				
+				Ok(Event {
+					object: row.get(0)?,
+					event: row.get(1)?,
+					data: json_column(row, 2)?,
+					timestamp: Utc.timestamp_millis(timestamp),
+				})
+			})?;
+			
+			let mut events = vec![];
+			
+			for event in iter {
+				let event = event?;
+				
+				if events.len() >= limit {
+					break;
+				}
+				
+				if pattern.matches(&event.object) {
+					events.push(event);
+				}
+			}
+			
+			events.reverse();
+			Ok(events)
+		})
+	}
+	
+	fn records_history(&self) -> bool {
+		self.history.is_some()
+	}
+	
+	fn add_revision(&self, revision: Revision) -> StorageFuture<'_, ()> {
+		Box::pin(async move {
+			let history = match &self.history {
+				Some(history) => history,
+				None => return Ok(()),
+			};
+			
+			let value = if revision.removed {
+				None
+			} else {
+				Some(serde_json::to_string(&revision.value)?)
+			};
+			
+			self.conn.execute(
+				"INSERT INTO object_history (name, value, timestamp, client) VALUES (?1, ?2, ?3, ?4)",
+				params![revision.name, value, revision.timestamp.timestamp_millis(), revision.client.to_string()]
+			)?;
+			
+			if let Some(max_revisions) = history.max_revisions {
+				self.conn.execute(
+					"DELETE FROM object_history WHERE name = ?1 AND id NOT IN
+						(SELECT id FROM object_history WHERE name = ?1 ORDER BY id DESC LIMIT ?2)",
+					params![revision.name, max_revisions]
+				)?;
+			}
+			
+			if let Some(before) = history.retention.as_ref().and_then(|retention| retention.prune_before()) {
+				self.conn.execute(
+					"DELETE FROM object_history WHERE timestamp < ?1",
+					params![before.timestamp_millis()]
+				)?;
+			}
+			
+			Ok(())
+		})
+	}
+	
+	fn get_history<'a>(&'a self, name: &'a str, since: Option<DateTime<Utc>>, until: Option<DateTime<Utc>>, limit: usize) -> StorageFuture<'a, Vec<Revision>> {
+		Box::pin(async move {
+			if self.history.is_none() {
+				return Ok(vec![]);
+			}
+			
+			let since = since.map(|since| since.timestamp_millis()).unwrap_or(i64::MIN);
+			let until = until.map(|until| until.timestamp_millis()).unwrap_or(i64::MAX);
+			
+			let mut stmt = self.conn.prepare("SELECT name, value, timestamp, client FROM object_history
+				WHERE name = ?1 AND timestamp >= ?2 AND timestamp <= ?3 ORDER BY id DESC LIMIT ?4")?;
+			let iter = stmt.query_map(params![name, since, until, limit as i64], row_to_revision)?;
+			
+			let mut revisions = iter.collect::<rusqlite::Result<Vec<Revision>>>()?;
+			revisions.reverse();
+			Ok(revisions)
+		})
+	}
+	
+	fn restore(&self, backup: Backup) -> StorageFuture<'_, ()> {
+		Box::pin(async move {
+			let tx = self.conn.unchecked_transaction()?;
+			
+			self.conn.execute("DELETE FROM objects", [])?;
+			for object in backup.objects {
+				self.add_object(object).await?;
+			}
+			
+			if self.event_retention.is_some() {
+				self.conn.execute("DELETE FROM events", [])?;
+				for event in backup.events {
+					self.add_event(event).await?;
+				}
+			}
+			
+			if self.history.is_some() {
+				self.conn.execute("DELETE FROM object_history", [])?;
+				for revision in backup.revisions {
+					self.add_revision(revision).await?;
+				}
+			}
+			
+			tx.commit()?;
+			Ok(())
+		})
+	}
+	
+	fn size(&self) -> StorageFuture<'_, Option<u64>> {
+		Box::pin(async move {
+			let size: i64 = self.conn.query_row(
+				"SELECT page_count * page_size FROM pragma_page_count(), pragma_page_size()",
+				[], |row| row.get(0)
+			)?;
+			
+			Ok(Some(size as u64))
+		})
+	}
+	
+	fn maintain(&self) -> StorageFuture<'_, Option<u64>> {
+		Box::pin(async move {
+			let before = self.size().await?.unwrap_or(0);
+			self.conn.execute_batch("VACUUM; ANALYZE;")?;
+			let after = self.size().await?.unwrap_or(0);
+			
+			Ok(Some(before.saturating_sub(after)))
+		})
+	}
+	
+	fn records_audit(&self, operation: AuditOperation) -> bool {
+		self.audit.as_ref().is_some_and(|audit| audit.operations.contains(&operation))
+	}
+	
+	fn add_audit_entry(&self, entry: AuditEntry) -> StorageFuture<'_, ()> {
+		Box::pin(async move {
+			let audit = match &self.audit {
+				Some(audit) => audit,
+				None => return Ok(()),
+			};
+			
+			let value = serde_json::to_string(&entry.value)?;
+			
+			self.conn.execute(
+				"INSERT INTO audit_log (timestamp, operation, object, event, method, value, client, addr, identity)
+					VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+				params![
+					entry.timestamp.timestamp_millis(), entry.operation.as_str(), entry.object, entry.event, entry.method,
+					value, entry.client.to_string(), entry.addr.map(|addr| addr.to_string()), entry.identity
+				]
+			)?;
+			
+			if let Some(before) = audit.retention.as_ref().and_then(|retention| retention.prune_before()) {
+				self.conn.execute(
+					"DELETE FROM audit_log WHERE timestamp < ?1",
+					params![before.timestamp_millis()]
+				)?;
+			}
+			
+			Ok(())
+		})
+	}
+	
+	fn get_audit<'a>(&'a self, filter: &'a AuditFilter) -> StorageFuture<'a, Vec<AuditEntry>> {
+		Box::pin(async move {
+			if self.audit.is_none() {
+				return Ok(vec![]);
+			}
+			
+			let since = filter.since.map(|since| since.timestamp_millis()).unwrap_or(i64::MIN);
+			let until = filter.until.map(|until| until.timestamp_millis()).unwrap_or(i64::MAX);
+			
+			let mut stmt = self.conn.prepare("SELECT timestamp, operation, object, event, method, value, client, addr, identity
+				FROM audit_log WHERE timestamp >= ?1 AND timestamp <= ?2 ORDER BY id DESC")?;
+			let iter = stmt.query_map(params![since, until], row_to_audit_entry)?;
+			
+			let mut entries = vec![];
+			let mut skipped = 0;
+			
+			for entry in iter {
+				let entry = entry?;
+				
+				if entries.len() >= filter.limit {
+					break;
+				}
+				
+				if !filter.matches(&entry) {
+					continue;
+				}
+				
+				if skipped < filter.offset {
+					skipped += 1;
+				} else {
+					entries.push(entry);
+				}
+			}
+			
+			Ok(entries)
+		})
+	}
+	
+	fn get_all_revisions(&self) -> StorageFuture<'_, Vec<Revision>> {
+		Box::pin(async move {
+			if self.history.is_none() {
+				return Ok(vec![]);
+			}
+			
+			let mut stmt = self.conn.prepare("SELECT name, value, timestamp, client FROM object_history ORDER BY id")?;
+			let iter = stmt.query_map([], row_to_revision)?;
+			
+			Ok(iter.collect::<rusqlite::Result<Vec<Revision>>>()?)
+		})
 	}
 }
@@ -0,0 +1,667 @@
+use crate::{Object, Event, Revision, AuditEntry, AuditOperation, Backup, StorageStats};
+use crate::patterns::Pattern;
+use crate::server::storage::{self, AuditFilter, Storage, Error};
+use chrono::{DateTime, Utc};
+use futures::channel::mpsc::{unbounded, UnboundedSender, UnboundedReceiver};
+use futures::channel::oneshot;
+use futures::executor::block_on;
+use futures::future::LocalBoxFuture;
+use futures::StreamExt;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// How often a failed write is attempted before it is reported.
+const WRITE_ATTEMPTS: u32 = 3;
+const RETRY_DELAY: Duration = Duration::from_millis(100);
+/// Number of consecutive failed writes after which the storage is given up.
+const MAX_CONSECUTIVE_FAILURES: u32 = 5;
+/// How often the database size is measured.
+const SIZE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Result of a successful maintenance run.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MaintenanceReport {
+	pub duration: Duration,
+	pub reclaimed: Option<u64>,
+}
+
+/// Counters shared between a `StorageWriter` and its storage thread.
+#[derive(Clone, Default)]
+pub struct StorageMetrics(Arc<Mutex<StorageStats>>);
+
+impl StorageMetrics {
+	pub fn stats(&self) -> StorageStats {
+		self.0.lock().unwrap().clone()
+	}
+	
+	fn update(&self, f: impl FnOnce(&mut StorageStats)) {
+		f(&mut self.0.lock().unwrap());
+	}
+}
+
+type ReadFn = Box<dyn for<'a> FnOnce(&'a dyn Storage) -> LocalBoxFuture<'a, ()> + Send>;
+pub type FailureHandler = Box<dyn Fn(StorageFailure) + Send>;
+pub type SlowWriteHandler = Arc<dyn Fn(SlowWrite) + Send + Sync>;
+type SlowWrites = Arc<Mutex<Option<(Duration, SlowWriteHandler)>>>;
+
+/// A write that failed even after retrying. Once `degraded` is set the writer
+/// drops all further writes and the server continues memory-only.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StorageFailure {
+	pub operation: &'static str,
+	pub object: Option<String>,
+	pub error: String,
+	pub degraded: bool,
+}
+
+/// A write that took longer than the threshold of `report_slow_writes`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SlowWrite {
+	pub operation: &'static str,
+	pub object: Option<String>,
+	pub duration: Duration,
+}
+
+enum Operation {
+	Add(Object),
+	Change(Object),
+	Remove(Object),
+	AddEvent(Event),
+	AddRevision(Revision),
+	AddAuditEntry(AuditEntry),
+	Restore(Box<Backup>),
+	/// Made by the storage thread every `Storage::flush_interval`.
+	Flush,
+	Read(ReadFn),
+}
+
+impl Operation {
+	fn name(&self) -> &'static str {
+		match self {
+			Operation::Add(_) => "add",
+			Operation::Change(_) => "change",
+			Operation::Remove(_) => "remove",
+			Operation::AddEvent(_) => "add-event",
+			Operation::AddRevision(_) => "add-revision",
+			Operation::AddAuditEntry(_) => "add-audit-entry",
+			Operation::Restore(_) => "restore",
+			Operation::Flush => "flush",
+			Operation::Read(_) => "read",
+		}
+	}
+	
+	fn object(&self) -> Option<&str> {
+		match self {
+			Operation::Add(object) | Operation::Change(object) | Operation::Remove(object) => Some(&object.name),
+			Operation::AddEvent(event) => Some(&event.object),
+			Operation::AddRevision(revision) => Some(&revision.name),
+			Operation::AddAuditEntry(entry) => Some(&entry.object),
+			Operation::Restore(_) | Operation::Flush | Operation::Read(_) => None,
+		}
+	}
+	
+	async fn apply(&self, storage: &dyn Storage) -> Result<(), Error> {
+		match self {
+			Operation::Add(object) => storage.add_object(object.clone()).await,
+			Operation::Change(object) => storage.change_object(object.clone()).await,
+			Operation::Remove(object) => storage.remove_object(object.clone()).await,
+			Operation::AddEvent(event) => storage.add_event(event.clone()).await,
+			Operation::AddRevision(revision) => storage.add_revision(revision.clone()).await,
+			Operation::AddAuditEntry(entry) => storage.add_audit_entry(entry.clone()).await,
+			Operation::Restore(backup) => storage.restore((**backup).clone()).await,
+			Operation::Flush => storage.flush().await,
+			Operation::Read(_) => unreachable!(),
+		}
+	}
+}
+
+/// Batch of pending operations where only the latest operation per object is kept.
+/// Reads are answered after all writes of the batch have been applied.
+struct Batch {
+	received: u64,
+	operations: Vec<Option<Operation>>,
+	positions: HashMap<String, usize>,
+	reads: Vec<ReadFn>,
+}
+
+impl Batch {
+	fn new() -> Self {
+		Batch {
+			received: 0,
+			operations: vec![],
+			positions: HashMap::new(),
+			reads: vec![],
+		}
+	}
+	
+	fn push(&mut self, operation: Operation) {
+		if !matches!(operation, Operation::Read(_)) {
+			self.received += 1;
+		}
+		
+		let name = match &operation {
+			Operation::Add(object) | Operation::Change(object) | Operation::Remove(object) => object.name.clone(),
+			Operation::AddEvent(_) | Operation::AddRevision(_) | Operation::AddAuditEntry(_) | Operation::Flush => {
+				self.operations.push(Some(operation));
+				return;
+			},
+			Operation::Restore(_) => {
+				// later writes must not be coalesced with writes from before the restore
+				self.positions.clear();
+				self.operations.push(Some(operation));
+				return;
+			},
+			Operation::Read(_) => {
+				if let Operation::Read(read) = operation {
+					self.reads.push(read);
+				}
+				return;
+			},
+		};
+		
+		let operation = match (self.positions.get(&name), operation) {
+			// an object that was added in this batch is still new to the storage
+			(Some(&index), Operation::Change(object)) if matches!(self.operations[index], Some(Operation::Add(_))) => Operation::Add(object),
+			(_, operation) => operation,
+		};
+		
+		if let Some(index) = self.positions.get(&name) {
+			self.operations[*index] = None;
+		}
+		
+		self.positions.insert(name, self.operations.len());
+		self.operations.push(Some(operation));
+	}
+	
+	async fn apply(self, worker: &mut Worker) {
+		for operation in self.operations.into_iter().flatten() {
+			worker.write(operation).await;
+		}
+		
+		let received = self.received;
+		worker.metrics.update(|stats| stats.queue_depth = stats.queue_depth.saturating_sub(received));
+		worker.measure_size().await;
+		
+		for read in self.reads {
+			read(worker.storage.as_ref()).await;
+		}
+	}
+}
+
+struct Worker {
+	storage: Box<dyn Storage>,
+	on_failure: FailureHandler,
+	slow_writes: SlowWrites,
+	metrics: StorageMetrics,
+	consecutive_failures: u32,
+	degraded: bool,
+	size_measured: Option<Instant>,
+}
+
+impl Worker {
+	fn new(storage: Box<dyn Storage>, on_failure: FailureHandler, slow_writes: SlowWrites, metrics: StorageMetrics) -> Self {
+		Worker {
+			storage,
+			on_failure,
+			slow_writes,
+			metrics,
+			consecutive_failures: 0,
+			degraded: false,
+			size_measured: None,
+		}
+	}
+	
+	async fn measure_size(&mut self) {
+		if self.size_measured.is_some_and(|measured| measured.elapsed() < SIZE_INTERVAL) {
+			return;
+		}
+		
+		self.size_measured = Some(Instant::now());
+		
+		if let Ok(size) = self.storage.size().await {
+			self.metrics.update(|stats| stats.database_size = size);
+		}
+	}
+	
+	async fn write(&mut self, operation: Operation) {
+		if self.degraded {
+			return;
+		}
+		
+		let mut attempt = 1;
+		
+		let error = loop {
+			let start = Instant::now();
+			let result = operation.apply(self.storage.as_ref()).await;
+			let elapsed = start.elapsed();
+			let latency = elapsed.as_secs_f64();
+			
+			let slow_writes = self.slow_writes.lock().unwrap().clone();
+			if let Some((threshold, handler)) = slow_writes {
+				if elapsed > threshold {
+					handler(SlowWrite {
+						operation: operation.name(),
+						object: operation.object().map(|name| name.to_string()),
+						duration: elapsed,
+					});
+				}
+			}
+			
+			self.metrics.update(|stats| {
+				stats.writes += 1;
+				stats.write_latency_sum += latency;
+				stats.write_latency_max = stats.write_latency_max.max(latency);
+				
+				match result {
+					Ok(()) => stats.last_write = Some(Utc::now()),
+					Err(_) => stats.errors += 1,
+				}
+			});
+			
+			match result {
+				Ok(()) => {
+					self.consecutive_failures = 0;
+					return;
+				},
+				Err(e) if attempt >= WRITE_ATTEMPTS => break e,
+				Err(_) => {
+					tokio::time::sleep(RETRY_DELAY * attempt).await;
+					attempt += 1;
+				},
+			}
+		};
+		
+		self.consecutive_failures += 1;
+		self.degraded = self.consecutive_failures >= MAX_CONSECUTIVE_FAILURES;
+		
+		let degraded = self.degraded;
+		self.metrics.update(|stats| stats.degraded = degraded);
+		
+		(self.on_failure)(StorageFailure {
+			operation: operation.name(),
+			object: operation.object().map(|name| name.to_string()),
+			error: error.to_string(),
+			degraded: self.degraded,
+		});
+	}
+}
+
+async fn run(mut worker: Worker, mut rx: UnboundedReceiver<Operation>) {
+	let flush_interval = worker.storage.flush_interval();
+	let mut next_flush = tokio::time::Instant::now() + flush_interval.unwrap_or_default();
+	
+	loop {
+		tokio::select! {
+			operation = rx.next() => {
+				let operation = match operation {
+					Some(operation) => operation,
+					None => break,
+				};
+				
+				let mut batch = Batch::new();
+				batch.push(operation);
+				
+				while let Ok(Some(operation)) = rx.try_next() {
+					batch.push(operation);
+				}
+				
+				batch.apply(&mut worker).await;
+			},
+			_ = tokio::time::sleep_until(next_flush), if flush_interval.is_some() => {
+				worker.write(Operation::Flush).await;
+				next_flush = tokio::time::Instant::now() + flush_interval.unwrap_or_default();
+			},
+		}
+	}
+}
+
+/// Applies storage operations on a dedicated thread that owns the backend and
+/// drives it on its own tokio runtime, so slow backends don't block the server
+/// while it holds the state lock.
+/// Consecutive writes to the same object are coalesced. Failed writes are
+/// retried and then reported to the failure handler.
+#[derive(Clone)]
+pub struct StorageWriter {
+	tx: UnboundedSender<Operation>,
+	records_history: bool,
+	audited: Vec<AuditOperation>,
+	slow_writes: SlowWrites,
+	metrics: StorageMetrics,
+	thread: Arc<Mutex<Option<JoinHandle<()>>>>,
+}
+
+impl StorageWriter {
+	pub fn new(storage: Box<dyn Storage>, on_failure: FailureHandler) -> Self {
+		let (tx, rx) = unbounded();
+		let records_history = storage.records_history();
+		let audited = AuditOperation::ALL.iter().copied()
+			.filter(|operation| storage.records_audit(*operation))
+			.collect();
+		
+		let slow_writes = SlowWrites::default();
+		let metrics = StorageMetrics::default();
+		let worker = Worker::new(storage, on_failure, slow_writes.clone(), metrics.clone());
+		
+		let thread = thread::Builder::new()
+			.name("objtalk-storage".to_string())
+			.spawn(move || {
+				let runtime = tokio::runtime::Builder::new_current_thread()
+					.enable_all()
+					.build()
+					.unwrap();
+				
+				runtime.block_on(run(worker, rx));
+			})
+			.unwrap();
+		
+		StorageWriter { tx, records_history, audited, slow_writes, metrics, thread: Arc::new(Mutex::new(Some(thread))) }
+	}
+	
+	fn write(&self, operation: Operation) {
+		self.metrics.update(|stats| stats.queue_depth += 1);
+		
+		if self.tx.unbounded_send(operation).is_err() {
+			self.metrics.update(|stats| stats.queue_depth -= 1);
+		}
+	}
+	
+	pub fn metrics(&self) -> StorageMetrics {
+		self.metrics.clone()
+	}
+	
+	/// Calls `handler` on the storage thread for every write attempt that
+	/// takes longer than `threshold`.
+	pub fn report_slow_writes(&self, threshold: Duration, handler: SlowWriteHandler) {
+		*self.slow_writes.lock().unwrap() = Some((threshold, handler));
+	}
+	
+	pub fn add_object(&self, object: Object) {
+		self.write(Operation::Add(object));
+	}
+	
+	pub fn change_object(&self, object: Object) {
+		self.write(Operation::Change(object));
+	}
+	
+	pub fn remove_object(&self, object: Object) {
+		self.write(Operation::Remove(object));
+	}
+	
+	pub fn add_event(&self, event: Event) {
+		self.write(Operation::AddEvent(event));
+	}
+	
+	pub fn records_history(&self) -> bool {
+		self.records_history
+	}
+	
+	pub fn add_revision(&self, revision: Revision) {
+		if self.records_history {
+			self.write(Operation::AddRevision(revision));
+		}
+	}
+	
+	pub fn records_audit(&self, operation: AuditOperation) -> bool {
+		self.audited.contains(&operation)
+	}
+	
+	pub fn add_audit_entry(&self, entry: AuditEntry) {
+		if self.records_audit(entry.operation) {
+			self.write(Operation::AddAuditEntry(entry));
+		}
+	}
+	
+	/// Runs the future returned by `f` on the storage thread once all
+	/// previously queued operations have been written and resolves with its
+	/// result.
+	pub async fn read<T, F>(&self, f: F) -> Option<T>
+	where
+		T: Send + 'static,
+		F: for<'a> FnOnce(&'a dyn Storage) -> LocalBoxFuture<'a, T> + Send + 'static,
+	{
+		let (reply_tx, reply_rx) = oneshot::channel();
+		
+		let read: ReadFn = Box::new(move |storage| Box::pin(async move {
+			let _ = reply_tx.send(f(storage).await);
+		}));
+		
+		self.tx.unbounded_send(Operation::Read(read)).ok()?;
+		reply_rx.await.ok()
+	}
+	
+	/// Like `read`, but blocks the calling thread, for callers outside of
+	/// the tokio runtime.
+	pub fn read_blocking<T, F>(&self, f: F) -> Option<T>
+	where
+		T: Send + 'static,
+		F: for<'a> FnOnce(&'a dyn Storage) -> LocalBoxFuture<'a, T> + Send + 'static,
+	{
+		block_on(self.read(f))
+	}
+	
+	/// Blocks until all previously queued operations have been written.
+	pub fn flush(&self) {
+		self.read_blocking(|_| Box::pin(async {}));
+	}
+	
+	/// Runs the backend's maintenance on the storage thread.
+	pub fn maintain(&self) -> Result<MaintenanceReport, Error> {
+		self.read_blocking(|storage| Box::pin(async move {
+			let start = Instant::now();
+			
+			storage.maintain().await.map(|reclaimed| MaintenanceReport {
+				duration: start.elapsed(),
+				reclaimed,
+			})
+		})).unwrap_or_else(|| Err(Error::Other("storage thread exited".to_string())))
+	}
+	
+	/// Writes all queued operations, lets the backend persist what it buffers
+	/// and waits for the storage thread to exit, which drops the backend.
+	/// Later operations are discarded.
+	pub fn close(&self) -> Result<(), Error> {
+		let result = self.read_blocking(|storage| storage.close())
+			.unwrap_or_else(|| Err(Error::Other("storage thread exited".to_string())));
+		
+		self.tx.close_channel();
+		
+		if let Some(thread) = self.thread.lock().unwrap().take() {
+			let _ = thread.join();
+		}
+		
+		result
+	}
+	
+	/// Replaces the whole contents of the storage with a backup.
+	pub fn restore(&self, backup: Backup) {
+		self.write(Operation::Restore(Box::new(backup)));
+	}
+	
+	/// Exports the storage once all previously queued operations have been
+	/// written, so the backup is consistent.
+	pub async fn export(&self) -> Result<Backup, Error> {
+		self.read(|storage| Box::pin(storage::export(storage))).await
+			.unwrap_or_else(|| Err(Error::Other("storage thread exited".to_string())))
+	}
+	
+	pub async fn get_events(&self, pattern: Pattern, since: Option<DateTime<Utc>>, limit: usize) -> Result<Vec<Event>, Error> {
+		self.read(move |storage| Box::pin(async move { storage.get_events(&pattern, since, limit).await })).await.unwrap_or_else(|| Ok(vec![]))
+	}
+	
+	pub async fn get_history(&self, name: String, since: Option<DateTime<Utc>>, until: Option<DateTime<Utc>>, limit: usize) -> Result<Vec<Revision>, Error> {
+		self.read(move |storage| Box::pin(async move { storage.get_history(&name, since, until, limit).await })).await.unwrap_or_else(|| Ok(vec![]))
+	}
+	
+	pub async fn get_audit(&self, filter: AuditFilter) -> Result<Vec<AuditEntry>, Error> {
+		self.read(move |storage| Box::pin(async move { storage.get_audit(&filter).await })).await.unwrap_or_else(|| Ok(vec![]))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::server::storage::StorageFuture;
+	use chrono::Utc;
+	use futures::future;
+	use serde_json::json;
+	use std::sync::{Arc, Mutex};
+	
+	struct RecordingStorage {
+		log: Arc<Mutex<Vec<String>>>,
+	}
+	
+	impl Storage for RecordingStorage {
+		fn get_objects(&self) -> StorageFuture<'_, Vec<Object>> {
+			Box::pin(future::ok(vec![]))
+		}
+		
+		fn add_object(&self, object: Object) -> StorageFuture<'_, ()> {
+			self.log.lock().unwrap().push(format!("add {} {}", object.name, object.value));
+			Box::pin(future::ok(()))
+		}
+		
+		fn change_object(&self, object: Object) -> StorageFuture<'_, ()> {
+			self.log.lock().unwrap().push(format!("change {} {}", object.name, object.value));
+			Box::pin(future::ok(()))
+		}
+		
+		fn remove_object(&self, object: Object) -> StorageFuture<'_, ()> {
+			self.log.lock().unwrap().push(format!("remove {}", object.name));
+			Box::pin(future::ok(()))
+		}
+	}
+	
+	struct FailingStorage;
+	
+	impl Storage for FailingStorage {
+		fn get_objects(&self) -> StorageFuture<'_, Vec<Object>> {
+			Box::pin(future::ok(vec![]))
+		}
+		
+		fn add_object(&self, _object: Object) -> StorageFuture<'_, ()> {
+			Box::pin(future::err(Error::Other("database is locked".to_string())))
+		}
+		
+		fn change_object(&self, object: Object) -> StorageFuture<'_, ()> {
+			self.add_object(object)
+		}
+		
+		fn remove_object(&self, object: Object) -> StorageFuture<'_, ()> {
+			self.add_object(object)
+		}
+	}
+	
+	fn object(name: &str, value: i64) -> Object {
+		Object { name: name.to_string(), value: json!(value), last_modified: Utc::now() }
+	}
+	
+	#[test]
+	fn test_batch_coalescing() {
+		let log = Arc::new(Mutex::new(vec![]));
+		let storage = RecordingStorage { log: log.clone() };
+		
+		let mut batch = Batch::new();
+		batch.push(Operation::Add(object("foo", 1)));
+		batch.push(Operation::Change(object("bar", 1)));
+		batch.push(Operation::Change(object("foo", 2)));
+		batch.push(Operation::Change(object("bar", 2)));
+		batch.push(Operation::Remove(object("baz", 1)));
+		
+		let mut worker = Worker::new(Box::new(storage), Box::new(|_| {}), SlowWrites::default(), StorageMetrics::default());
+		block_on(batch.apply(&mut worker));
+		
+		assert_eq!(*log.lock().unwrap(), vec![
+			"add foo 2".to_string(),
+			"change bar 2".to_string(),
+			"remove baz".to_string(),
+		]);
+	}
+	
+	#[test]
+	fn test_writer_flush() {
+		let log = Arc::new(Mutex::new(vec![]));
+		let writer = StorageWriter::new(Box::new(RecordingStorage { log: log.clone() }), Box::new(|_| {}));
+		
+		writer.add_object(object("foo", 1));
+		writer.remove_object(object("foo", 1));
+		writer.flush();
+		
+		assert_eq!(log.lock().unwrap().last(), Some(&"remove foo".to_string()));
+		
+		let stats = writer.metrics().stats();
+		assert_eq!(stats.writes, 1);
+		assert_eq!(stats.errors, 0);
+		assert_eq!(stats.queue_depth, 0);
+	}
+	
+	#[tokio::test]
+	async fn test_writer_read_doesnt_block() {
+		let writer = StorageWriter::new(Box::new(RecordingStorage { log: Arc::default() }), Box::new(|_| {}));
+		
+		// runs on the same thread as the read below
+		let ticker = tokio::spawn(async {
+			tokio::time::sleep(Duration::from_millis(20)).await;
+			Instant::now()
+		});
+		
+		let slow_read = writer.read(|_| Box::pin(async {
+			thread::sleep(Duration::from_millis(300));
+			42
+		}));
+		assert_eq!(slow_read.await, Some(42));
+		
+		let read_done = Instant::now();
+		assert!(ticker.await.unwrap() + Duration::from_millis(200) < read_done);
+	}
+	
+	#[test]
+	fn test_writer_slow_writes() {
+		let slow = Arc::new(Mutex::new(vec![]));
+		let slow2 = slow.clone();
+		
+		let writer = StorageWriter::new(Box::new(RecordingStorage { log: Arc::default() }), Box::new(|_| {}));
+		writer.add_object(object("foo", 1));
+		writer.flush();
+		
+		writer.report_slow_writes(Duration::ZERO, Arc::new(move |write| slow2.lock().unwrap().push(write)));
+		writer.change_object(object("foo", 2));
+		writer.flush();
+		
+		let slow = slow.lock().unwrap();
+		assert_eq!(slow.len(), 1);
+		assert_eq!(slow[0].operation, "change");
+		assert_eq!(slow[0].object.as_deref(), Some("foo"));
+	}
+	
+	#[test]
+	fn test_writer_failures() {
+		let failures = Arc::new(Mutex::new(vec![]));
+		let failures2 = failures.clone();
+		
+		let writer = StorageWriter::new(Box::new(FailingStorage), Box::new(move |failure| {
+			failures2.lock().unwrap().push(failure);
+		}));
+		
+		for i in 0..MAX_CONSECUTIVE_FAILURES + 1 {
+			writer.add_object(object(&format!("foo{}", i), 1));
+			writer.flush();
+		}
+		
+		let stats = writer.metrics().stats();
+		assert_eq!(stats.errors, (MAX_CONSECUTIVE_FAILURES * WRITE_ATTEMPTS) as u64);
+		assert_eq!(stats.queue_depth, 0);
+		assert!(stats.degraded);
+		
+		let failures = failures.lock().unwrap();
+		assert_eq!(failures.len() as u32, MAX_CONSECUTIVE_FAILURES);
+		assert_eq!(failures[0], StorageFailure {
+			operation: "add",
+			object: Some("foo0".to_string()),
+			error: "database is locked".to_string(),
+			degraded: false,
+		});
+		assert!(failures.last().unwrap().degraded);
+	}
+}
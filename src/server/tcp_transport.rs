@@ -1,34 +1,138 @@
-use crate::json_rpc::RequestMessage;
-use crate::server::json_rpc::{handle_message, handle_inbox_message};
+use crate::json_rpc::Requests;
+use crate::json_rpc::EventMessage;
+use crate::server::config::{MessageFormat, TcpConfig, TcpKeepaliveConfig};
+use crate::server::connection::{self, Connections, IdleTimeout, Ping};
+use crate::server::json_rpc::{handle_batch, handle_message, handle_inbox_message};
 use crate::server::Server;
+use crate::server::tls::{self, TlsAcceptor};
+use bytes::{Bytes, BytesMut};
 use futures::{StreamExt,SinkExt};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::error::Error;
 use std::net::SocketAddr;
-use tokio::net::{TcpStream, TcpListener};
-use tokio_util::codec::{Framed, LinesCodec};
+use std::time::Duration;
+use tokio::time::{sleep_until, Instant};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio_util::codec::{Decoder, Encoder, Framed, LengthDelimitedCodec, LinesCodec};
 
-async fn handle_connection(stream: TcpStream, _addr: SocketAddr, server: Server) -> Result<(), Box<dyn std::error::Error>> {
-	let mut client = server.client_connect();
+/// How messages are framed and encoded on a connection.
+trait Format {
+	type Codec: Decoder + Encoder<Self::Frame>;
+	type Frame;
 	
-	let mut lines = Framed::new(stream, LinesCodec::new());
+	fn codec() -> Self::Codec;
+	fn decode(frame: <Self::Codec as Decoder>::Item) -> Result<Requests, ()>;
+	fn encode<T: Serialize>(message: &T) -> Self::Frame;
+}
+
+struct JsonLines;
+
+impl Format for JsonLines {
+	type Codec = LinesCodec;
+	type Frame = String;
+	
+	fn codec() -> LinesCodec {
+		LinesCodec::new()
+	}
+	
+	fn decode(line: String) -> Result<Requests, ()> {
+		serde_json::from_str(&line).map_err(|_| ()).and_then(|value| Requests::from_value(value).map_err(|_| ()))
+	}
+	
+	fn encode<T: Serialize>(message: &T) -> String {
+		serde_json::to_string(message).unwrap()
+	}
+}
+
+/// Messages are converted from and to json values, so uuids and timestamps
+/// are strings like in the json format.
+struct Cbor;
+
+impl Format for Cbor {
+	type Codec = LengthDelimitedCodec;
+	type Frame = Bytes;
+	
+	fn codec() -> LengthDelimitedCodec {
+		LengthDelimitedCodec::new()
+	}
+	
+	fn decode(frame: BytesMut) -> Result<Requests, ()> {
+		let value: Value = ciborium::de::from_reader(&frame[..]).map_err(|_| ())?;
+		Requests::from_value(value).map_err(|_| ())
+	}
+	
+	fn encode<T: Serialize>(message: &T) -> Bytes {
+		let mut data = vec![];
+		ciborium::ser::into_writer(&serde_json::to_value(message).unwrap(), &mut data).unwrap();
+		Bytes::from(data)
+	}
+}
+
+async fn handle_connection<F, S>(stream: S, addr: SocketAddr, server: Server, identity: Option<String>, ping: Option<Ping>) -> Result<(), Box<dyn Error>>
+where
+	F: Format,
+	S: AsyncRead + AsyncWrite + Unpin,
+	<F::Codec as Decoder>::Error: Error + 'static,
+	<F::Codec as Encoder<F::Frame>>::Error: Error + 'static,
+{
+	let mut client = server.client_connect_from(Some(addr.ip()), identity);
+	
+	let mut frames = Framed::new(stream, F::codec());
+	
+	let mut last_received = Instant::now();
+	let mut ping_sent: Option<Instant> = None;
+	
+	let shutting_down = server.shutting_down();
+	tokio::pin!(shutting_down);
 	
 	loop {
+		let ping_deadline = match (ping, ping_sent) {
+			(Some(ping), Some(sent)) => sent + ping.timeout,
+			(Some(ping), None) => last_received + ping.interval,
+			(None, _) => Instant::now(),
+		};
+		
 		tokio::select! {
 			Some(msg) = client.inbox_next() => {
-				let response = handle_inbox_message(msg);
-				let json_string = serde_json::to_string(&response).unwrap();
-				lines.send(json_string).await?;
+				if let Some(response) = handle_inbox_message(msg) {
+					frames.send(F::encode(&response)).await?;
+				}
 			},
-			result = lines.next() => match result {
-				Some(Ok(line)) => {
-					match serde_json::from_str::<RequestMessage>(&line) {
-						Ok(request) => {
-							if let Some(response) = handle_message(request, &client, server.clone()) {
-								let json_string = serde_json::to_string(&response).unwrap();
-								lines.send(json_string).await?;
+			_ = sleep_until(ping_deadline), if ping.is_some() => {
+				if ping_sent.is_some() {
+					println!("tcp transport: closing connection from {}, ping not answered", addr);
+					break;
+				}
+				
+				frames.send(F::encode(&EventMessage::Ping)).await?;
+				ping_sent = Some(Instant::now());
+			},
+			_ = &mut shutting_down => {
+				frames.send(F::encode(&EventMessage::Shutdown)).await?;
+				break;
+			},
+			result = frames.next() => match result {
+				Some(Ok(frame)) => {
+					last_received = Instant::now();
+					ping_sent = None;
+					
+					match F::decode(frame) {
+						Ok(Requests::Single(request)) => {
+							if let Some(response) = handle_message(request, &client, server.clone()).await {
+								frames.send(F::encode(&response)).await?;
+							}
+						},
+						Ok(Requests::Batch(requests)) => {
+							let responses = handle_batch(requests, &client, server.clone()).await;
+							
+							if !responses.is_empty() {
+								frames.send(F::encode(&responses)).await?;
 							}
 						},
-						Err(_) => {
-							lines.send("{\"type\":\"error\",\"error\":\"invalid message\"}").await?;
+						Err(()) => {
+							frames.send(F::encode(&json!({ "type": "error", "error": "invalid message" }))).await?;
 						},
 					}
 				},
@@ -43,30 +147,227 @@ async fn handle_connection(stream: TcpStream, _addr: SocketAddr, server: Server)
 	Ok(())
 }
 
+pub(crate) async fn serve_connection<S: AsyncRead + AsyncWrite + Unpin>(stream: S, addr: SocketAddr, server: Server, identity: Option<String>, format: MessageFormat, ping: Option<Ping>) -> Result<(), Box<dyn Error>> {
+	match format {
+		MessageFormat::Json => handle_connection::<JsonLines, _>(stream, addr, server, identity, ping).await,
+		MessageFormat::Cbor => handle_connection::<Cbor, _>(stream, addr, server, identity, ping).await,
+	}
+}
+
 pub struct TcpTransport {
 	addr: SocketAddr,
 	server: Server,
+	tls: Option<TlsAcceptor>,
+	format: MessageFormat,
+	connections: Connections,
+	idle_timeout: Option<Duration>,
+	keepalive: Option<TcpKeepaliveConfig>,
+	ping: Option<Ping>,
+	proxy_protocol: bool,
 }
 
 impl TcpTransport {
-	pub fn new(addr: SocketAddr, server: Server) -> Self {
-		TcpTransport { addr, server }
+	pub fn new(config: &TcpConfig, server: Server, tls: Option<TlsAcceptor>) -> Self {
+		TcpTransport {
+			addr: config.addr,
+			server,
+			tls,
+			format: config.format,
+			connections: Connections::new(config.max_connections),
+			idle_timeout: config.idle_timeout.map(Duration::from_secs),
+			keepalive: config.keepalive,
+			ping: config.ping_interval.map(|interval| Ping {
+				interval: Duration::from_secs(interval),
+				timeout: Duration::from_secs(config.ping_timeout),
+			}),
+			proxy_protocol: config.proxy_protocol,
+		}
 	}
 	
 	pub async fn serve(&self) {
-		println!("tcp transport listening on {}", self.addr);
-		
 		let listener = TcpListener::bind(self.addr).await.unwrap();
+		self.serve_on(listener).await;
+	}
+	
+	/// See `HttpTransport::serve_on`.
+	pub async fn serve_on(&self, listener: TcpListener) {
+		if self.tls.is_some() {
+			println!("tcp transport listening on {} (tls)", self.addr);
+		} else {
+			println!("tcp transport listening on {}", self.addr);
+		}
 		
 		loop {
-			let (stream, addr) = listener.accept().await.unwrap();
+			let (mut stream, addr) = connection::accept(&listener, "tcp").await;
+			
+			let slot = match self.connections.acquire() {
+				Some(slot) => slot,
+				None => {
+					println!("tcp transport: too many connections, closing connection from {}", addr);
+					continue;
+				},
+			};
+			
+			if let Some(keepalive) = &self.keepalive {
+				if let Err(e) = connection::set_keepalive(&stream, keepalive) {
+					println!("tcp transport: can't enable keepalive for {}: {}", addr, e);
+				}
+			}
 			
 			let server = self.server.clone();
+			let tls = self.tls.clone();
+			let format = self.format;
+			let ping = self.ping;
+			let idle_timeout = self.idle_timeout;
+			let proxy_protocol = self.proxy_protocol;
+			
 			tokio::spawn(async move {
-				if let Err(e) = handle_connection(stream, addr, server).await {
+				let addr = if proxy_protocol {
+					match connection::proxied_addr(&mut stream, addr, "tcp").await {
+						Some(addr) => addr,
+						None => return,
+					}
+				} else {
+					addr
+				};
+				
+				let stream = IdleTimeout::new(stream, "tcp", addr, idle_timeout);
+				
+				let result = match tls {
+					Some(tls) => match tls.accept(stream).await {
+						Ok(stream) => {
+							let identity = tls::client_identity(&stream);
+							serve_connection(stream, addr, server, identity, format, ping).await
+						},
+						Err(e) => Err(e.into()),
+					},
+					None => serve_connection(stream, addr, server, None, format, ping).await,
+				};
+				
+				if let Err(e) = result {
 					dbg!(e);
 				}
+				
+				drop(slot);
+			});
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::json_rpc::Request;
+	
+	#[test]
+	fn test_cbor_roundtrip() {
+		let frame = Cbor::encode(&json!({ "type": "get", "id": 1, "pattern": "sensor/+" }));
+		let request = match Cbor::decode(BytesMut::from(&frame[..])).unwrap() {
+			Requests::Single(request) => request,
+			Requests::Batch(_) => panic!("unexpected batch"),
+		};
+		assert_eq!(request.id, json!(1));
+		assert!(matches!(request.request, Request::Get { pattern } if pattern == "sensor/+"));
+		
+		assert!(Cbor::decode(BytesMut::from(&b"\xff"[..])).is_err());
+		assert!(Cbor::decode(BytesMut::from(&Cbor::encode(&json!({ "type": "unknown" }))[..])).is_err());
+	}
+	
+	#[tokio::test]
+	async fn test_ping() {
+		use crate::server::logger::NullLogger;
+		use tokio::io::{duplex, AsyncBufReadExt, AsyncWriteExt, BufReader};
+		
+		let server = Server::new(None, Box::new(NullLogger));
+		let (client, stream) = duplex(1024);
+		let ping = Ping { interval: Duration::from_millis(100), timeout: Duration::from_millis(100) };
+		let connection = handle_connection::<JsonLines, _>(stream, "127.0.0.1:1234".parse().unwrap(), server, None, Some(ping));
+		
+		let (client_read, mut client_write) = tokio::io::split(client);
+		let mut lines = BufReader::new(client_read).lines();
+		
+		let (result, _) = tokio::join!(connection, async move {
+			assert_eq!(lines.next_line().await.unwrap(), Some(r#"{"type":"ping"}"#.to_string()));
+			client_write.write_all(b"{\"id\":1,\"type\":\"ping\"}\n").await.unwrap();
+			assert_eq!(lines.next_line().await.unwrap(), Some(r#"{"requestId":1,"result":{"success":true}}"#.to_string()));
+			
+			// unanswered pings close the connection
+			assert_eq!(lines.next_line().await.unwrap(), Some(r#"{"type":"ping"}"#.to_string()));
+			assert_eq!(lines.next_line().await.unwrap(), None);
+		});
+		
+		assert!(result.is_ok());
+	}
+	
+	#[tokio::test]
+	async fn test_shutdown() {
+		use crate::server::logger::NullLogger;
+		use tokio::io::{duplex, AsyncBufReadExt, BufReader};
+		
+		let server = Server::new(None, Box::new(NullLogger));
+		let (client, stream) = duplex(1024);
+		let connection = handle_connection::<JsonLines, _>(stream, "127.0.0.1:1234".parse().unwrap(), server.clone(), None, None);
+		
+		let mut lines = BufReader::new(client).lines();
+		
+		let (result, _) = tokio::join!(connection, async move {
+			server.shutdown();
+			assert_eq!(lines.next_line().await.unwrap(), Some(r#"{"type":"shutdown"}"#.to_string()));
+			assert_eq!(lines.next_line().await.unwrap(), None);
+		});
+		
+		assert!(result.is_ok());
+	}
+	
+	#[tokio::test]
+	async fn test_admin_requests() {
+		use crate::server::logger::NullLogger;
+		use tokio::io::{duplex, AsyncBufReadExt, AsyncWriteExt, BufReader};
+		
+		let server = Server::builder()
+			.logger(Box::new(NullLogger))
+			.admin_identities(vec!["ops".to_string()])
+			.build()
+			.unwrap();
+		server.set("sensor", json!(20), &server.client_connect()).unwrap();
+		
+		let restore = b"{\"id\":1,\"type\":\"restore\",\"backup\":{\"created\":\"2024-01-01T00:00:00Z\",\"objects\":[],\"events\":[],\"revisions\":[]}}\n";
+		
+		for (identity, response) in [
+			(None, r#"{"requestId":1,"error":"admin only"}"#),
+			(Some("sensor-1"), r#"{"requestId":1,"error":"admin only"}"#),
+			(Some("ops"), r#"{"requestId":1,"result":{"success":true}}"#),
+		] {
+			let (client, stream) = duplex(1024);
+			let connection = handle_connection::<JsonLines, _>(stream, "127.0.0.1:1234".parse().unwrap(), server.clone(), identity.map(str::to_string), None);
+			
+			let (client_read, mut client_write) = tokio::io::split(client);
+			let mut lines = BufReader::new(client_read).lines();
+			let server = server.clone();
+			
+			let (result, _) = tokio::join!(connection, async move {
+				client_write.write_all(restore).await.unwrap();
+				assert_eq!(lines.next_line().await.unwrap(), Some(response.to_string()));
+				
+				let objects = server.get(&crate::patterns::Pattern::compile("sensor").unwrap(), &server.client_connect());
+				assert_eq!(objects.len(), if identity == Some("ops") { 0 } else { 1 });
+				
+				client_write.shutdown().await.unwrap();
 			});
+			
+			assert!(result.is_ok());
 		}
+		
+		let (client, stream) = duplex(1024);
+		let connection = handle_connection::<JsonLines, _>(stream, "127.0.0.1:1234".parse().unwrap(), server, None, None);
+		let mut lines = BufReader::new(client).lines();
+		
+		let (result, _) = tokio::join!(connection, async move {
+			lines.get_mut().write_all(b"{\"id\":2,\"type\":\"backup\"}\n").await.unwrap();
+			assert_eq!(lines.next_line().await.unwrap(), Some(r#"{"requestId":2,"error":"admin only"}"#.to_string()));
+			lines.get_mut().shutdown().await.unwrap();
+		});
+		
+		assert!(result.is_ok());
 	}
 }
@@ -0,0 +1,202 @@
+use crate::server::config::TlsConfig;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+use tokio_rustls::rustls::{AllowAnyAuthenticatedClient, NoClientAuth, RootCertStore, ServerConfig, Session};
+use tokio_rustls::rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+use tokio_rustls::server::TlsStream;
+
+pub use tokio_rustls::TlsAcceptor;
+
+const COMMON_NAME_OID: &[u8] = &[0x55, 0x04, 0x03];
+
+fn open(path: &Path) -> Result<BufReader<File>, String> {
+	File::open(path)
+		.map(BufReader::new)
+		.map_err(|e| format!("can't open {}: {}", path.display(), e))
+}
+
+/// Splits a DER element into its tag, its contents and the remaining input.
+fn der_element(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+	let (&tag, rest) = input.split_first()?;
+	let (&first, rest) = rest.split_first()?;
+	
+	let (len, rest) = if first < 0x80 {
+		(first as usize, rest)
+	} else {
+		let len_bytes = (first & 0x7f) as usize;
+		
+		if len_bytes == 0 || len_bytes > 4 || rest.len() < len_bytes {
+			return None;
+		}
+		
+		let len = rest[..len_bytes].iter().fold(0, |len, &byte| len << 8 | byte as usize);
+		(len, &rest[len_bytes..])
+	};
+	
+	if rest.len() < len {
+		return None;
+	}
+	
+	Some((tag, &rest[..len], &rest[len..]))
+}
+
+/// Returns the subject common name of a DER encoded x509 certificate.
+fn common_name(certificate: &[u8]) -> Option<String> {
+	let (_, certificate, _) = der_element(certificate)?;
+	let (_, mut fields, _) = der_element(certificate)?;
+	
+	// skip the optional version, the serial number, signature algorithm, issuer and validity
+	if fields.first() == Some(&0xa0) {
+		fields = der_element(fields)?.2;
+	}
+	
+	for _ in 0..4 {
+		fields = der_element(fields)?.2;
+	}
+	
+	let (_, mut subject, _) = der_element(fields)?;
+	
+	while !subject.is_empty() {
+		let (_, set, rest) = der_element(subject)?;
+		subject = rest;
+		
+		let (_, attribute, _) = der_element(set)?;
+		let (_, oid, value) = der_element(attribute)?;
+		
+		if oid == COMMON_NAME_OID {
+			let (_, value, _) = der_element(value)?;
+			return String::from_utf8(value.to_vec()).ok();
+		}
+	}
+	
+	None
+}
+
+/// Returns the common name of the verified client certificate of a
+/// connection if client certificates are required.
+pub fn client_identity<IO>(stream: &TlsStream<IO>) -> Option<String> {
+	let certificates = stream.get_ref().1.get_peer_certificates()?;
+	common_name(&certificates.first()?.0)
+}
+
+/// Returns whether the client chose HTTP/2 during the handshake.
+pub fn negotiated_http2<IO>(stream: &TlsStream<IO>) -> bool {
+	stream.get_ref().1.get_alpn_protocol() == Some(b"h2")
+}
+
+/// Loads the certificate chain and private key of a `tls` config section.
+pub fn acceptor(config: &TlsConfig) -> Result<TlsAcceptor, String> {
+	Ok(TlsAcceptor::from(Arc::new(server_config(config)?)))
+}
+
+/// Like `acceptor`, but offers HTTP/2 and HTTP/1.1 via ALPN.
+pub fn http_acceptor(config: &TlsConfig) -> Result<TlsAcceptor, String> {
+	let mut server_config = server_config(config)?;
+	server_config.set_protocols(&[b"h2".to_vec(), b"http/1.1".to_vec()]);
+	
+	Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+fn server_config(config: &TlsConfig) -> Result<ServerConfig, String> {
+	let certs = certs(&mut open(&config.cert)?)
+		.map_err(|_| format!("invalid certificate file {}", config.cert.display()))?;
+	
+	if certs.is_empty() {
+		return Err(format!("no certificates found in {}", config.cert.display()));
+	}
+	
+	let invalid_key = || format!("invalid private key file {}", config.key.display());
+	
+	let mut keys = pkcs8_private_keys(&mut open(&config.key)?).map_err(|_| invalid_key())?;
+	
+	if keys.is_empty() {
+		keys = rsa_private_keys(&mut open(&config.key)?).map_err(|_| invalid_key())?;
+	}
+	
+	let key = keys.into_iter().next()
+		.ok_or_else(|| format!("no private key found in {}", config.key.display()))?;
+	
+	let client_verifier = match &config.client_ca {
+		Some(client_ca) => {
+			let mut roots = RootCertStore::empty();
+			
+			let (valid, _) = roots.add_pem_file(&mut open(client_ca)?)
+				.map_err(|_| format!("invalid certificate file {}", client_ca.display()))?;
+			
+			if valid == 0 {
+				return Err(format!("no certificates found in {}", client_ca.display()));
+			}
+			
+			AllowAnyAuthenticatedClient::new(roots)
+		},
+		None => NoClientAuth::new(),
+	};
+	
+	let mut server_config = ServerConfig::new(client_verifier);
+	server_config.set_single_cert(certs, key)
+		.map_err(|e| format!("invalid certificate or private key: {}", e))?;
+	
+	Ok(server_config)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::env;
+	use std::fs;
+	use std::path::PathBuf;
+	
+	#[test]
+	fn test_missing_files() {
+		let config = TlsConfig {
+			cert: PathBuf::from("/nonexistent/cert.pem"),
+			key: PathBuf::from("/nonexistent/key.pem"),
+			client_ca: None,
+		};
+		
+		assert!(acceptor(&config).err().unwrap().starts_with("can't open /nonexistent/cert.pem"));
+	}
+	
+	#[test]
+	fn test_empty_files() {
+		let filename = env::temp_dir().join(format!("objtalk-tls-{}.pem", std::process::id()));
+		fs::write(&filename, "").unwrap();
+		
+		let config = TlsConfig {
+			cert: filename.clone(),
+			key: filename.clone(),
+			client_ca: None,
+		};
+		
+		let result = acceptor(&config);
+		fs::remove_file(&filename).unwrap();
+		
+		assert_eq!(result.err(), Some(format!("no certificates found in {}", filename.display())));
+	}
+	
+	#[test]
+	fn test_common_name() {
+		// issued for "/O=objtalk/CN=sensor-42"
+		let pem = concat!(
+			"-----BEGIN CERTIFICATE-----\n",
+			"MIIBgzCCASqgAwIBAgIUU/FfQtdpjsDHy+JXyMzeha9x16cwCgYIKoZIzj0EAwIw\n",
+			"GjEYMBYGA1UEAwwPb2JqdGFsayB0ZXN0IGNhMB4XDTI2MTAxNzA1MjEwNFoXDTM2\n",
+			"MTAxNDA1MjEwNFowJjEQMA4GA1UECgwHb2JqdGFsazESMBAGA1UEAwwJc2Vuc29y\n",
+			"LTQyMFkwEwYHKoZIzj0CAQYIKoZIzj0DAQcDQgAEPCL6eI6HebWAopo9Rum+njFZ\n",
+			"YSUBipDQ+pwCWDE9KhcBfHXEJ25tRLhxUESr7vYs05RkL3rc1Gnx2eSrfBVBZ6NC\n",
+			"MEAwHQYDVR0OBBYEFB3Cs5VdXahicqx7G91vSq6RcErYMB8GA1UdIwQYMBaAFMvI\n",
+			"A0JKMrnr31P8HDBBJLnNwD4tMAoGCCqGSM49BAMCA0cAMEQCIH06Z+2492YbMR4A\n",
+			"pLxoQvQFp78D2UjQ4FXOe4jnpv/wAiASI3FmdAPpBzBDUh9ZJAPX5d/rnDJfNjtS\n",
+			"WmBSVCPDWQ==\n",
+			"-----END CERTIFICATE-----\n",
+		);
+		
+		let certificates = certs(&mut pem.as_bytes()).unwrap();
+		assert_eq!(common_name(&certificates[0].0), Some("sensor-42".to_string()));
+		
+		assert_eq!(common_name(&certificates[0].0[..100]), None);
+		assert_eq!(common_name(&[]), None);
+	}
+}
@@ -0,0 +1,134 @@
+use crate::server::config::MessageFormat;
+use crate::server::{Client, Error, Server};
+use serde::Deserialize;
+use serde_json::Value;
+use std::net::SocketAddr;
+use tokio::net::UdpSocket;
+
+const MAX_DATAGRAM_SIZE: usize = 65536;
+
+/// The requests that make sense without a response.
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(tag = "type")]
+#[serde(rename_all = "lowercase")]
+enum Datagram {
+	Set {
+		name: String,
+		value: Value,
+	},
+	Patch {
+		name: String,
+		value: Value,
+	},
+	Emit {
+		object: String,
+		event: String,
+		data: Value,
+	},
+}
+
+fn decode(data: &[u8], format: MessageFormat) -> Option<Datagram> {
+	match format {
+		MessageFormat::Json => serde_json::from_slice(data).ok(),
+		MessageFormat::Cbor => {
+			let value: Value = ciborium::de::from_reader(data).ok()?;
+			serde_json::from_value(value).ok()
+		},
+	}
+}
+
+fn handle_datagram(datagram: Datagram, client: &Client, server: &Server) -> Result<(), Error> {
+	match datagram {
+		Datagram::Set { name, value } => server.set(&name, value, client),
+		Datagram::Patch { name, value } => server.patch(&name, value, client),
+		Datagram::Emit { object, event, data } => server.emit(&object, &event, data, client),
+	}
+}
+
+/// Accepts `set`, `patch` and `emit` requests, one per datagram, without
+/// sending responses.
+pub struct UdpTransport {
+	addr: SocketAddr,
+	server: Server,
+	format: MessageFormat,
+}
+
+impl UdpTransport {
+	pub fn new(addr: SocketAddr, server: Server, format: MessageFormat) -> Self {
+		UdpTransport { addr, server, format }
+	}
+	
+	pub async fn serve(&self) {
+		let socket = UdpSocket::bind(self.addr).await.unwrap();
+		self.serve_on(socket).await;
+	}
+	
+	/// See `HttpTransport::serve_on`.
+	pub async fn serve_on(&self, socket: UdpSocket) {
+		println!("udp transport listening on {}", self.addr);
+		
+		let client = self.server.client_connect();
+		let mut buffer = vec![0; MAX_DATAGRAM_SIZE];
+		
+		loop {
+			let (len, addr) = match socket.recv_from(&mut buffer).await {
+				Ok(result) => result,
+				Err(e) => {
+					println!("error {}", e);
+					continue;
+				},
+			};
+			
+			let result = match decode(&buffer[..len], self.format) {
+				Some(datagram) => handle_datagram(datagram, &client, &self.server)
+					.map_err(|e| e.to_string()),
+				None => Err("invalid message".to_string()),
+			};
+			
+			if let Err(e) = result {
+				println!("udp message from {} failed: {}", addr, e);
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::patterns::Pattern;
+	use crate::server::logger::NullLogger;
+	use serde_json::json;
+	
+	#[test]
+	fn test_decode() {
+		assert_eq!(decode(br#"{"type":"set","name":"sensor","value":21.5}"#, MessageFormat::Json), Some(Datagram::Set {
+			name: "sensor".to_string(),
+			value: json!(21.5),
+		}));
+		
+		let mut cbor = vec![];
+		ciborium::ser::into_writer(&json!({ "type": "emit", "object": "button", "event": "pressed", "data": null }), &mut cbor).unwrap();
+		assert_eq!(decode(&cbor, MessageFormat::Cbor), Some(Datagram::Emit {
+			object: "button".to_string(),
+			event: "pressed".to_string(),
+			data: Value::Null,
+		}));
+		
+		assert_eq!(decode(&cbor, MessageFormat::Json), None);
+		assert_eq!(decode(br#"{"type":"get","pattern":"*"}"#, MessageFormat::Json), None);
+	}
+	
+	#[test]
+	fn test_handle_datagram() {
+		let server = Server::new(None, Box::new(NullLogger));
+		let client = server.client_connect();
+		
+		handle_datagram(Datagram::Set { name: "sensor".to_string(), value: json!({ "temperature": 20 }) }, &client, &server).unwrap();
+		handle_datagram(Datagram::Patch { name: "sensor".to_string(), value: json!({ "humidity": 40 }) }, &client, &server).unwrap();
+		
+		let objects = server.get(&Pattern::compile("sensor").unwrap(), &client);
+		assert_eq!(objects[0].value, json!({ "temperature": 20, "humidity": 40 }));
+		
+		assert_eq!(handle_datagram(Datagram::Emit { object: "missing".to_string(), event: "pressed".to_string(), data: Value::Null }, &client, &server), Err(Error::ObjectNotFound));
+	}
+}
@@ -0,0 +1,361 @@
+use crate::Object;
+use crate::patterns::Pattern;
+use crate::server::config::{UpstreamConfig, UpstreamDirection};
+use crate::server::{Client, Message, Server};
+use futures::{SinkExt, StreamExt};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::time::sleep;
+use tokio_util::codec::{Framed, LinesCodec};
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+/// Request id of the query for the upstream server's objects.
+const QUERY_ID: &str = "query";
+
+async fn send<S: AsyncRead + AsyncWrite + Unpin>(frames: &mut Framed<S, LinesCodec>, message: Value) -> Result<(), String> {
+	frames.send(message.to_string()).await.map_err(|e| e.to_string())
+}
+
+/// Prepends `prefix` to each part of a comma separated pattern.
+fn prefix_pattern(prefix: &str, pattern: &str) -> String {
+	pattern.split(',')
+		.map(|part| format!("{}{}", prefix, part))
+		.collect::<Vec<_>>()
+		.join(",")
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct UpstreamResponse {
+	request_id: Value,
+	#[serde(default)]
+	result: Value,
+	#[serde(default)]
+	error: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "camelCase")]
+enum UpstreamEvent {
+	QueryAdd { object: Object },
+	QueryChange { object: Object },
+	QueryRemove { object: Object },
+	Ping,
+	Shutdown,
+	#[serde(other)]
+	Other,
+}
+
+/// Mirrors objects between this server and another objtalk server, which it
+/// connects to like any other tcp client.
+pub struct Upstream {
+	server: Server,
+	client: Client,
+	addr: String,
+	local_prefix: String,
+	remote_prefix: String,
+	local_pattern: Pattern,
+	remote_pattern: String,
+	direction: UpstreamDirection,
+	/// Values known to be the same on both servers, by name relative to the
+	/// prefixes, to not send changes back to where they came from.
+	synced: HashMap<String, Value>,
+	next_id: u64,
+}
+
+impl Upstream {
+	pub fn new(config: UpstreamConfig, server: Server) -> Result<Self, String> {
+		let local_pattern = Pattern::compile(&prefix_pattern(&config.local_prefix, &config.pattern))
+			.map_err(|_| format!("invalid upstream pattern {}", config.pattern))?;
+		
+		Ok(Upstream {
+			client: server.client_connect(),
+			server,
+			addr: config.addr,
+			remote_pattern: prefix_pattern(&config.remote_prefix, &config.pattern),
+			local_prefix: config.local_prefix,
+			remote_prefix: config.remote_prefix,
+			local_pattern,
+			direction: config.direction,
+			synced: HashMap::new(),
+			next_id: 0,
+		})
+	}
+	
+	fn outgoing(&self) -> bool {
+		matches!(self.direction, UpstreamDirection::Both | UpstreamDirection::Out)
+	}
+	
+	fn incoming(&self) -> bool {
+		matches!(self.direction, UpstreamDirection::Both | UpstreamDirection::In)
+	}
+	
+	fn local_name(&self, remote_name: &str) -> Option<String> {
+		remote_name.strip_prefix(&self.remote_prefix).map(|name| format!("{}{}", self.local_prefix, name))
+	}
+	
+	fn remote_name(&self, local_name: &str) -> Option<String> {
+		local_name.strip_prefix(&self.local_prefix).map(|name| format!("{}{}", self.remote_prefix, name))
+	}
+	
+	pub async fn serve(mut self) {
+		if self.outgoing() {
+			if let Err(e) = self.server.query(&self.local_pattern, false, false, &self.client) {
+				println!("upstream error: {}", e);
+				return;
+			}
+		}
+		
+		loop {
+			println!("upstream connecting to {}", self.addr);
+			
+			let result = match TcpStream::connect(&self.addr).await {
+				Ok(stream) => self.mirror(stream).await,
+				Err(e) => Err(e.to_string()),
+			};
+			
+			if let Err(e) = result {
+				println!("upstream {}: {}, reconnecting in {}s", self.addr, e, RECONNECT_DELAY.as_secs());
+			}
+			
+			sleep(RECONNECT_DELAY).await;
+		}
+	}
+	
+	fn request(&mut self, mut request: Value) -> Value {
+		self.next_id += 1;
+		request["id"] = json!(self.next_id);
+		request
+	}
+	
+	/// Copies the local objects to the upstream server, then queries its
+	/// objects, so this server's state takes precedence after reconnecting.
+	async fn mirror<S: AsyncRead + AsyncWrite + Unpin>(&mut self, stream: S) -> Result<(), String> {
+		let mut frames = Framed::new(stream, LinesCodec::new());
+		self.synced.clear();
+		
+		// changes made while disconnected are covered by copying all objects
+		while let Ok(Some(_)) = self.client.inbox_try_next() {}
+		
+		if self.outgoing() {
+			for object in self.server.get(&self.local_pattern, &self.client) {
+				self.handle_local_change(&mut frames, object).await?;
+			}
+		}
+		
+		if self.incoming() {
+			let query = json!({ "id": QUERY_ID, "type": "query", "pattern": self.remote_pattern });
+			send(&mut frames, query).await?;
+		}
+		
+		loop {
+			tokio::select! {
+				Some(msg) = self.client.inbox_next() => match msg {
+					Message::QueryAdd { object, .. } | Message::QueryChange { object, .. } => {
+						self.handle_local_change(&mut frames, object).await?;
+					},
+					Message::QueryRemove { object, .. } => {
+						self.handle_local_remove(&mut frames, object).await?;
+					},
+					_ => {},
+				},
+				line = frames.next() => match line {
+					Some(Ok(line)) => self.handle_upstream_message(&mut frames, &line).await?,
+					Some(Err(e)) => return Err(e.to_string()),
+					None => return Err("connection closed".to_string()),
+				},
+			}
+		}
+	}
+	
+	async fn handle_local_change<S: AsyncRead + AsyncWrite + Unpin>(&mut self, frames: &mut Framed<S, LinesCodec>, object: Object) -> Result<(), String> {
+		let remote_name = match self.remote_name(&object.name) {
+			Some(remote_name) => remote_name,
+			None => return Ok(()),
+		};
+		
+		if self.synced.get(&remote_name) == Some(&object.value) {
+			return Ok(());
+		}
+		
+		self.synced.insert(remote_name.clone(), object.value.clone());
+		let request = self.request(json!({ "type": "set", "name": remote_name, "value": object.value }));
+		send(frames, request).await
+	}
+	
+	async fn handle_local_remove<S: AsyncRead + AsyncWrite + Unpin>(&mut self, frames: &mut Framed<S, LinesCodec>, object: Object) -> Result<(), String> {
+		let remote_name = match self.remote_name(&object.name) {
+			Some(remote_name) => remote_name,
+			None => return Ok(()),
+		};
+		
+		if self.synced.remove(&remote_name).is_none() {
+			return Ok(());
+		}
+		
+		let request = self.request(json!({ "type": "remove", "name": remote_name }));
+		send(frames, request).await
+	}
+	
+	fn handle_upstream_change(&mut self, object: Object) {
+		let local_name = match self.local_name(&object.name) {
+			Some(local_name) => local_name,
+			None => return,
+		};
+		
+		if self.synced.get(&object.name) == Some(&object.value) {
+			return;
+		}
+		
+		self.synced.insert(object.name, object.value.clone());
+		
+		if let Err(e) = self.server.set(&local_name, object.value, &self.client) {
+			println!("upstream error: can't apply {}: {}", local_name, e);
+		}
+	}
+	
+	fn handle_upstream_remove(&mut self, object: Object) {
+		let local_name = match self.local_name(&object.name) {
+			Some(local_name) => local_name,
+			None => return,
+		};
+		
+		if self.synced.remove(&object.name).is_none() {
+			return;
+		}
+		
+		if let Err(e) = self.server.remove(&local_name, &self.client) {
+			println!("upstream error: can't remove {}: {}", local_name, e);
+		}
+	}
+	
+	async fn handle_upstream_message<S: AsyncRead + AsyncWrite + Unpin>(&mut self, frames: &mut Framed<S, LinesCodec>, line: &str) -> Result<(), String> {
+		let value: Value = serde_json::from_str(line).map_err(|_| "invalid message".to_string())?;
+		
+		if value.get("requestId").is_some() {
+			let response: UpstreamResponse = serde_json::from_value(value).map_err(|_| "invalid response".to_string())?;
+			
+			if let Some(error) = response.error {
+				println!("upstream {}: request failed: {}", self.addr, error);
+				return Ok(());
+			}
+			
+			if response.request_id == QUERY_ID {
+				let objects: Vec<Object> = serde_json::from_value(response.result["objects"].clone())
+					.map_err(|_| "invalid query response".to_string())?;
+				
+				for object in objects {
+					self.handle_upstream_change(object);
+				}
+			}
+			
+			return Ok(());
+		}
+		
+		match serde_json::from_value(value) {
+			Ok(UpstreamEvent::QueryAdd { object }) | Ok(UpstreamEvent::QueryChange { object }) => self.handle_upstream_change(object),
+			Ok(UpstreamEvent::QueryRemove { object }) => self.handle_upstream_remove(object),
+			// any message shows the connection is alive, notifications aren't answered
+			Ok(UpstreamEvent::Ping) => send(frames, json!({ "type": "ping" })).await?,
+			Ok(UpstreamEvent::Shutdown) => return Err("upstream server is shutting down".to_string()),
+			Ok(UpstreamEvent::Other) | Err(_) => {},
+		}
+		
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::server::config::MessageFormat;
+	use crate::server::logger::NullLogger;
+	use crate::server::tcp_transport::serve_connection;
+	use tokio::io::duplex;
+	
+	fn value(server: &Server, name: &str) -> Option<Value> {
+		server.get(&Pattern::compile(name).unwrap(), &server.client_connect())
+			.into_iter()
+			.next()
+			.map(|object| object.value)
+	}
+	
+	/// Polls until `f` holds, failing after a second.
+	async fn eventually<F: Fn() -> bool>(f: F) {
+		for _ in 0..100 {
+			if f() {
+				return;
+			}
+			
+			sleep(Duration::from_millis(10)).await;
+		}
+		
+		panic!("condition not met");
+	}
+	
+	#[test]
+	fn test_prefixes() {
+		assert_eq!(prefix_pattern("building-1/", "sensor/+,lamp"), "building-1/sensor/+,building-1/lamp");
+		assert_eq!(prefix_pattern("", "sensor/+"), "sensor/+");
+		
+		let upstream = Upstream::new(UpstreamConfig {
+			addr: "127.0.0.1:3001".to_string(),
+			pattern: "sensor/+".to_string(),
+			local_prefix: "local/".to_string(),
+			remote_prefix: "building-1/".to_string(),
+			direction: UpstreamDirection::Both,
+		}, Server::new(None, Box::new(NullLogger))).unwrap();
+		
+		assert_eq!(upstream.remote_name("local/sensor/1"), Some("building-1/sensor/1".to_string()));
+		assert_eq!(upstream.local_name("building-1/sensor/1"), Some("local/sensor/1".to_string()));
+		assert_eq!(upstream.local_name("sensor/1"), None);
+	}
+	
+	#[tokio::test]
+	async fn test_mirror() {
+		let local = Server::new(None, Box::new(NullLogger));
+		let remote = Server::new(None, Box::new(NullLogger));
+		let local_client = local.client_connect();
+		let remote_client = remote.client_connect();
+		
+		local.set("sensor/1", json!(1), &local_client).unwrap();
+		local.set("other", json!(0), &local_client).unwrap();
+		remote.set("building-1/sensor/2", json!(2), &remote_client).unwrap();
+		
+		let mut upstream = Upstream::new(UpstreamConfig {
+			addr: "127.0.0.1:3001".to_string(),
+			pattern: "sensor/+".to_string(),
+			local_prefix: "".to_string(),
+			remote_prefix: "building-1/".to_string(),
+			direction: UpstreamDirection::Both,
+		}, local.clone()).unwrap();
+		local.query(&upstream.local_pattern, false, false, &upstream.client).unwrap();
+		
+		let (stream, remote_stream) = duplex(4096);
+		let remote_connection = serve_connection(remote_stream, "127.0.0.1:1234".parse().unwrap(), remote.clone(), None, MessageFormat::Json, None);
+		
+		tokio::select! {
+			result = upstream.mirror(stream) => panic!("mirroring stopped: {:?}", result),
+			result = remote_connection => panic!("connection closed: {:?}", result.is_ok()),
+			_ = async {
+				eventually(|| value(&remote, "building-1/sensor/1") == Some(json!(1))).await;
+				eventually(|| value(&local, "sensor/2") == Some(json!(2))).await;
+				assert_eq!(value(&remote, "building-1/other"), None);
+				
+				local.set("sensor/1", json!(5), &local_client).unwrap();
+				eventually(|| value(&remote, "building-1/sensor/1") == Some(json!(5))).await;
+				
+				remote.set("building-1/sensor/1", json!(6), &remote_client).unwrap();
+				eventually(|| value(&local, "sensor/1") == Some(json!(6))).await;
+				
+				remote.remove("building-1/sensor/2", &remote_client).unwrap();
+				eventually(|| value(&local, "sensor/2").is_none()).await;
+			} => {},
+		}
+	}
+}
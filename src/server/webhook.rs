@@ -0,0 +1,250 @@
+use crate::patterns::Pattern;
+use crate::server::config::WebhookConfig;
+use crate::server::{Message, Server, WebhookDelivery};
+use serde_json::{json, Value};
+use std::sync::mpsc::{channel, Receiver};
+use std::thread;
+use std::time::Duration;
+use ureq::Agent;
+
+/// Delay before the first retry, doubled for each further one.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+fn backoff(attempt: u32) -> Duration {
+	INITIAL_BACKOFF.checked_mul(1 << attempt.saturating_sub(1).min(16))
+		.unwrap_or(MAX_BACKOFF)
+		.min(MAX_BACKOFF)
+}
+
+/// The object and JSON body posted for a query notification, named like the
+/// events of http event streams.
+fn payload(msg: Message, events: bool) -> Option<(String, Value)> {
+	match msg {
+		Message::QueryAdd { object, .. } => Some((object.name.clone(), json!({ "type": "add", "object": object }))),
+		Message::QueryChange { object, .. } => Some((object.name.clone(), json!({ "type": "change", "object": object }))),
+		Message::QueryRemove { object, .. } => Some((object.name.clone(), json!({ "type": "remove", "object": object }))),
+		Message::QueryEvent { object, event, data, .. } if events => {
+			Some((object.clone(), json!({ "type": "event", "object": object, "event": event, "data": data })))
+		},
+		_ => None,
+	}
+}
+
+/// Posts payloads one after another on its own thread, so retries of a slow
+/// or unreachable service neither block the server nor reorder deliveries.
+struct Deliverer {
+	server: Server,
+	agent: Agent,
+	url: String,
+	headers: Vec<(String, String)>,
+	retries: u32,
+}
+
+impl Deliverer {
+	fn post(&self, body: &str) -> Result<u16, (Option<u16>, String)> {
+		let mut request = self.agent.post(&self.url).set("content-type", "application/json");
+		
+		for (name, value) in &self.headers {
+			request = request.set(name, value);
+		}
+		
+		match request.send_string(body) {
+			Ok(response) => Ok(response.status()),
+			Err(ureq::Error::Status(status, _)) => Err((Some(status), format!("status {}", status))),
+			Err(e) => Err((None, e.to_string())),
+		}
+	}
+	
+	fn deliver(&self, object: String, payload: &Value) -> WebhookDelivery {
+		let body = payload.to_string();
+		let mut attempts = 0;
+		
+		loop {
+			attempts += 1;
+			
+			let (status, error) = match self.post(&body) {
+				Ok(status) => (Some(status), None),
+				Err((status, error)) => (status, Some(error)),
+			};
+			
+			if error.is_none() || attempts > self.retries {
+				return WebhookDelivery { url: self.url.clone(), object, attempts, status, error };
+			}
+			
+			thread::sleep(backoff(attempts));
+		}
+	}
+	
+	fn run(self, payloads: Receiver<(String, Value)>) {
+		for (object, payload) in payloads {
+			let delivery = self.deliver(object, &payload);
+			self.server.webhook_delivery(delivery);
+		}
+	}
+}
+
+/// Posts the changes of objects matching a pattern, and optionally their
+/// events, to a URL.
+pub struct Webhook {
+	server: Server,
+	pattern: Pattern,
+	events: bool,
+	deliverer: Deliverer,
+}
+
+impl Webhook {
+	pub fn new(config: WebhookConfig, server: Server) -> Result<Self, String> {
+		let pattern = Pattern::compile(&config.pattern)
+			.map_err(|_| format!("invalid webhook pattern {}", config.pattern))?;
+		
+		if !config.url.starts_with("http://") && !config.url.starts_with("https://") {
+			return Err(format!("invalid webhook url {}", config.url));
+		}
+		
+		let agent = ureq::AgentBuilder::new()
+			.timeout(Duration::from_secs(config.timeout))
+			.build();
+		
+		Ok(Webhook {
+			server: server.clone(),
+			pattern,
+			events: config.events,
+			deliverer: Deliverer {
+				server,
+				agent,
+				url: config.url,
+				headers: config.headers.into_iter().collect(),
+				retries: config.retries,
+			},
+		})
+	}
+	
+	pub async fn serve(self) {
+		let mut client = self.server.client_connect();
+		
+		if let Err(e) = self.server.query(&self.pattern, false, false, &client) {
+			println!("webhook error: {}", e);
+			return;
+		}
+		
+		println!("webhook posting to {}", self.deliverer.url);
+		
+		let (payloads_tx, payloads_rx) = channel();
+		let deliverer = self.deliverer;
+		
+		thread::Builder::new()
+			.name("objtalk-webhook".to_string())
+			.spawn(move || deliverer.run(payloads_rx))
+			.unwrap();
+		
+		while let Some(msg) = client.inbox_next().await {
+			if let Some(payload) = payload(msg, self.events) {
+				if payloads_tx.send(payload).is_err() {
+					break;
+				}
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::Object;
+	use crate::server::logger::NullLogger;
+	use chrono::Utc;
+	use std::io::{BufRead, BufReader, Read, Write};
+	use std::net::TcpListener;
+	use uuid::Uuid;
+	
+	fn deliverer(url: String, retries: u32) -> Deliverer {
+		Deliverer {
+			server: Server::new(None, Box::new(NullLogger)),
+			agent: Agent::new(),
+			url,
+			headers: vec![("authorization".to_string(), "Bearer secret".to_string())],
+			retries,
+		}
+	}
+	
+	/// Answers one request with `status` and returns its head and body.
+	fn respond(listener: &TcpListener, status: u16) -> (String, String) {
+		let (stream, _) = listener.accept().unwrap();
+		let mut reader = BufReader::new(stream);
+		let mut head = String::new();
+		
+		loop {
+			let len = head.len();
+			reader.read_line(&mut head).unwrap();
+			
+			if head[len..].trim().is_empty() {
+				break;
+			}
+		}
+		
+		let length: usize = head.lines()
+			.find_map(|line| line.to_lowercase().strip_prefix("content-length:").map(|len| len.trim().parse().unwrap()))
+			.unwrap();
+		let mut body = vec![0; length];
+		reader.read_exact(&mut body).unwrap();
+		
+		write!(reader.get_mut(), "HTTP/1.1 {} Status\r\ncontent-length: 0\r\nconnection: close\r\n\r\n", status).unwrap();
+		(head, String::from_utf8(body).unwrap())
+	}
+	
+	#[test]
+	fn test_backoff() {
+		assert_eq!(backoff(1), Duration::from_secs(1));
+		assert_eq!(backoff(3), Duration::from_secs(4));
+		assert_eq!(backoff(10), MAX_BACKOFF);
+		assert_eq!(backoff(u32::MAX), MAX_BACKOFF);
+	}
+	
+	#[test]
+	fn test_payload() {
+		let object = Object { name: "sensor".to_string(), value: json!(21), last_modified: Utc::now() };
+		let query_id = Uuid::new_v4();
+		
+		let (name, body) = payload(Message::QueryChange { query_id, object, previous: None }, true).unwrap();
+		assert_eq!(name, "sensor");
+		assert_eq!(body["type"], "change");
+		assert_eq!(body["object"]["value"], json!(21));
+		
+		let event = || Message::QueryEvent { query_id, object: "button".to_string(), event: "pressed".to_string(), data: json!(1) };
+		assert_eq!(payload(event(), true).unwrap().1, json!({ "type": "event", "object": "button", "event": "pressed", "data": 1 }));
+		assert_eq!(payload(event(), false), None);
+	}
+	
+	#[test]
+	fn test_deliver() {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let url = format!("http://{}/hook", listener.local_addr().unwrap());
+		
+		let server = thread::spawn(move || respond(&listener, 204));
+		let delivery = deliverer(url.clone(), 0).deliver("sensor".to_string(), &json!({ "type": "remove" }));
+		let (head, body) = server.join().unwrap();
+		
+		assert!(head.starts_with("POST /hook HTTP/1.1"));
+		assert!(head.to_lowercase().contains("authorization: bearer secret"));
+		assert_eq!(body, r#"{"type":"remove"}"#);
+		assert_eq!(delivery, WebhookDelivery { url, object: "sensor".to_string(), attempts: 1, status: Some(204), error: None });
+	}
+	
+	#[test]
+	fn test_deliver_retry() {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let url = format!("http://{}/hook", listener.local_addr().unwrap());
+		
+		let server = thread::spawn(move || {
+			respond(&listener, 503);
+			respond(&listener, 500);
+		});
+		let delivery = deliverer(url, 1).deliver("sensor".to_string(), &json!({}));
+		server.join().unwrap();
+		
+		assert_eq!(delivery.attempts, 2);
+		assert_eq!(delivery.status, Some(500));
+		assert_eq!(delivery.error, Some("status 500".to_string()));
+	}
+}